@@ -0,0 +1,11 @@
+//! Lists and searches directories generated from the fuzzer's input. See
+//! `fat32::vfat::dir_corpus`.
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate fat32;
+
+fuzz_target!(|data: &[u8]| {
+    fat32::vfat::dir_corpus::fuzz_dir(data);
+});
@@ -0,0 +1,683 @@
+//! A write-back sector cache over any `BlockDevice`.
+//!
+//! `CachedDevice` keeps sectors in memory once they are read, holds writes
+//! until `flush()`, and can present a partition with a larger logical sector
+//! size than the underlying device. It makes no assumptions about the file
+//! system stored on the device.
+//!
+//! Before every access, the device is asked whether its medium has changed
+//! (see `BlockDevice::media_changed()`). If it has, every cached sector is
+//! dropped, _including_ dirty ones, since writing them back would corrupt the
+//! new medium, and the cache's generation is advanced.
+
+use std::collections::HashMap;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+use std::{cmp, fmt, io};
+
+use traits::BlockDevice;
+use vfat::{IoMetrics, IoOp, SlowIoHook};
+
+/// The most bytes a flush writes to the device at once. Longer runs of dirty
+/// sectors are split.
+const MAX_FLUSH_WRITE: usize = 1 << 20;
+
+#[derive(Debug)]
+struct CacheEntry {
+    data: Vec<u8>,
+    dirty: bool,
+    /// The cache's clock when the sector was last accessed.
+    last_used: u64,
+}
+
+/// Options controlling a `CachedDevice`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheOptions {
+    /// The most sectors to keep cached, or `None` for no limit. When the
+    /// cache is full, the least recently used clean sector is evicted to make
+    /// room; if every sector is dirty, the cache is flushed first. The limit
+    /// is at least one sector.
+    pub capacity: Option<usize>,
+    /// If `Some(n)`, sectors are read with `BlockDevice::read_sector_checked()`
+    /// and a read failing its checksum is retried up to `n` times before the
+    /// error is returned. Failures are counted in `IoMetrics`. If `None`,
+    /// sectors are read with `read_sector()`. Defaults to `None`.
+    pub checksum_retries: Option<u32>,
+    /// If `true`, sectors cannot be modified, as if the device were not
+    /// `BlockDevice::writable()`. Defaults to `false`.
+    pub read_only: bool,
+}
+
+/// The region of a device that a `CachedDevice` addresses in logical
+/// sectors. To cache a whole device, use a `start` of `0` and the device's
+/// own sector size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Partition {
+    /// The physical sector where the partition begins.
+    pub start: u64,
+    /// The size, in bytes, of a logical sector in the partition.
+    pub sector_size: u64,
+}
+
+pub struct CachedDevice {
+    device: Box<BlockDevice>,
+    cache: HashMap<u64, CacheEntry>,
+    partition: Partition,
+    options: CacheOptions,
+    /// Incremented on every access, to order sectors by recency.
+    clock: u64,
+    /// The number of media changes the device has reported.
+    generation: u64,
+    /// The number of dirty sectors dropped by media changes.
+    discarded: u64,
+    metrics: IoMetrics,
+    slow_io: Option<(Duration, SlowIoHook)>,
+    /// The device time allowed since the budget was set, if limited.
+    budget: Option<Duration>,
+    /// The device time taken since the budget was set.
+    spent: Duration,
+    /// Whether sectors may be modified: the device is writable and the cache
+    /// is not read-only.
+    writable: bool,
+    /// The sectors only `get_mut_guarded()` and `overwrite_guarded()` may
+    /// modify. See `guard()`.
+    guarded: Vec<Range<u64>>,
+}
+
+impl CachedDevice {
+    /// Creates a new `CachedDevice` that transparently caches sectors from
+    /// `device` and maps physical sectors to logical sectors inside of
+    /// `partition`. All reads and writes from `CacheDevice` are performed on
+    /// in-memory caches.
+    ///
+    /// The `partition` parameter determines the size of a logical sector and
+    /// where logical sectors begin. An access to a sector `n` _before_
+    /// `partition.start` is made to physical sector `n`. Cached sectors before
+    /// `partition.start` are the size of a physical sector. An access to a
+    /// sector `n` at or after `partition.start` is made to the _logical_ sector
+    /// `n - partition.start`. Cached sectors at or after `partition.start` are
+    /// the size of a logical sector, `partition.sector_size`.
+    ///
+    /// `partition.sector_size` must be an integer multiple of
+    /// `device.sector_size()`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the partition's sector size is < the device's sector size.
+    pub fn new<T>(device: T, partition: Partition) -> CachedDevice
+    where
+        T: BlockDevice + 'static,
+    {
+        CachedDevice::with_options(device, partition, CacheOptions::default())
+    }
+
+    /// Creates a new `CachedDevice` as `new()` does, configured by `options`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the partition's sector size is < the device's sector size.
+    pub fn with_options<T>(device: T, partition: Partition, options: CacheOptions) -> CachedDevice
+    where
+        T: BlockDevice + 'static,
+    {
+        assert!(partition.sector_size >= device.sector_size());
+        let writable = device.writable() && !options.read_only;
+
+        CachedDevice {
+            device: Box::new(device),
+            cache: HashMap::new(),
+            partition: partition,
+            options,
+            clock: 0,
+            generation: 0,
+            discarded: 0,
+            metrics: IoMetrics::default(),
+            slow_io: None,
+            budget: None,
+            spent: Duration::from_secs(0),
+            writable,
+            guarded: Vec::new(),
+        }
+    }
+
+    /// The size in bytes of a sector of the underlying device.
+    pub fn device_sector_size(&self) -> u64 {
+        self.device.sector_size()
+    }
+
+    /// Returns `true` if sectors can be modified. If not, `get_mut()` and
+    /// `overwrite()` fail with an error of kind `PermissionDenied`.
+    pub fn writable(&self) -> bool {
+        self.writable
+    }
+
+    /// Fails with an error of kind `PermissionDenied` if sectors cannot be
+    /// modified.
+    fn check_writable(&self) -> io::Result<()> {
+        if self.writable {
+            return Ok(());
+        }
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "device is read-only",
+        ))
+    }
+
+    /// Guards the (logical or physical) sectors in `sectors` against stray
+    /// writes: `get_mut()` and `overwrite()` of them, and so writes through
+    /// `BlockDevice::write_sector()`, fail with an error of kind
+    /// `PermissionDenied`, and panic in debug builds so that the write is
+    /// caught where it is made. Only `get_mut_guarded()` and
+    /// `overwrite_guarded()`, meant for the code that owns the sectors, may
+    /// modify them.
+    pub fn guard(&mut self, sectors: Range<u64>) {
+        self.guarded.push(sectors);
+    }
+
+    /// Lifts every guard set by `guard()`.
+    pub fn clear_guards(&mut self) {
+        self.guarded.clear();
+    }
+
+    /// Returns `true` if sector `sector` is guarded. See `guard()`.
+    pub fn is_guarded(&self, sector: u64) -> bool {
+        self.guarded
+            .iter()
+            .any(|range| range.start <= sector && sector < range.end)
+    }
+
+    /// Fails with an error of kind `PermissionDenied`, or panics in debug
+    /// builds, if sector `sector` is guarded.
+    fn check_guard(&self, sector: u64) -> io::Result<()> {
+        if !self.is_guarded(sector) {
+            return Ok(());
+        }
+        if cfg!(debug_assertions) {
+            panic!("write to guarded sector {}", sector);
+        }
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "sector is guarded",
+        ))
+    }
+
+    /// The number of sectors currently cached.
+    pub fn cached_sectors(&self) -> usize {
+        self.cache.len()
+    }
+
+    /// The number of bytes of sector data currently cached.
+    pub fn cached_bytes(&self) -> usize {
+        self.cache.values().map(|entry| entry.data.len()).sum()
+    }
+
+    /// The number of cached sectors that have not been written to the disk.
+    pub fn dirty_sectors(&self) -> usize {
+        self.cache.values().filter(|entry| entry.dirty).count()
+    }
+
+    /// The number of media changes the device has reported so far, counting
+    /// each `replace_device()`. Polls the device first.
+    pub fn generation(&mut self) -> u64 {
+        self.check_media();
+        self.generation
+    }
+
+    /// The number of dirty sectors dropped, unwritten, on media changes so
+    /// far.
+    pub fn discarded_writes(&self) -> u64 {
+        self.discarded
+    }
+
+    /// Drops every cached sector and advances the generation if the device
+    /// reports a media change.
+    fn check_media(&mut self) {
+        if self.device.media_changed() {
+            self.discard_all();
+        }
+    }
+
+    /// Drops every cached sector, dirty or not, and advances the generation.
+    fn discard_all(&mut self) {
+        self.discarded += self.dirty_sectors() as u64;
+        self.cache.clear();
+        self.generation += 1;
+    }
+
+    /// Replaces the underlying device with `device`, as after the medium was
+    /// removed and inserted again. This counts as a media change: every
+    /// cached sector is dropped and the generation is advanced. Guards and
+    /// options are kept.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the partition's sector size is < the new device's sector
+    /// size.
+    pub fn replace_device<T>(&mut self, device: T)
+    where
+        T: BlockDevice + 'static,
+    {
+        assert!(self.partition.sector_size >= device.sector_size());
+        self.writable = device.writable() && !self.options.read_only;
+        self.device = Box::new(device);
+        self.discard_all();
+    }
+
+    /// Advances the cache's clock and returns its new value.
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// Makes room for one more sector if the cache is at capacity, evicting
+    /// the least recently used clean sector.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the cache must be flushed and writing fails.
+    fn make_room(&mut self) -> io::Result<()> {
+        match self.options.capacity {
+            Some(capacity) => self.evict_to(cmp::max(capacity, 1) - 1),
+            None => Ok(()),
+        }
+    }
+
+    /// Evicts the least recently used clean sectors until at most `len`
+    /// remain, flushing the cache whenever every sector is dirty.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the cache must be flushed and writing fails.
+    fn evict_to(&mut self, len: usize) -> io::Result<()> {
+        while self.cache.len() > len {
+            if self.cache.values().all(|entry| entry.dirty) {
+                self.flush()?;
+            }
+
+            let victim = self
+                .cache
+                .iter()
+                .filter(|&(_, entry)| !entry.dirty)
+                .min_by_key(|&(_, entry)| entry.last_used)
+                .map(|(&sector, _)| sector);
+            match victim {
+                Some(sector) => self.cache.remove(&sector),
+                None => return Ok(()),
+            };
+        }
+        Ok(())
+    }
+
+    /// Changes the most sectors kept cached, as `CacheOptions::capacity`,
+    /// evicting sectors if the cache holds more.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the cache must be flushed to shrink and writing
+    /// fails.
+    pub fn set_capacity(&mut self, capacity: Option<usize>) -> io::Result<()> {
+        self.options.capacity = capacity;
+        match capacity {
+            Some(capacity) => self.evict_to(cmp::max(capacity, 1)),
+            None => Ok(()),
+        }
+    }
+
+    /// Inserts the contents of sector `sector` into the cache, making room
+    /// for it first.
+    fn insert(&mut self, sector: u64, data: Vec<u8>, dirty: bool) -> io::Result<()> {
+        self.make_room()?;
+        let last_used = self.tick();
+        self.cache.insert(
+            sector,
+            CacheEntry {
+                data,
+                dirty,
+                last_used,
+            },
+        );
+        Ok(())
+    }
+
+    /// Maps a user's request for a sector `virt` to the physical sector and
+    /// number of physical sectors required to access `virt`.
+    fn virtual_to_physical(&self, virt: u64) -> (u64, u64) {
+        if self.device.sector_size() == self.partition.sector_size {
+            (virt, 1)
+        } else if virt < self.partition.start {
+            (virt, 1)
+        } else {
+            let factor = self.partition.sector_size / self.device.sector_size();
+            let logical_offset = virt - self.partition.start;
+            let physical_offset = logical_offset * factor;
+            let physical_sector = self.partition.start + physical_offset;
+            (physical_sector, factor)
+        }
+    }
+
+    /// The size in bytes of the (logical or physical) sector `virt`.
+    pub fn sector_len(&self, virt: u64) -> usize {
+        let (_, num_sectors) = self.virtual_to_physical(virt);
+        (self.device.sector_size() * num_sectors) as usize
+    }
+
+    /// Returns a mutable reference to the cached sector `sector`. If the sector
+    /// is not already cached, the sector is first read from the disk.
+    ///
+    /// The sector is marked dirty as a result of calling this method as it is
+    /// presumed that the sector will be written to. If this is not intended,
+    /// use `get()` instead.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is an error reading the sector from the disk,
+    /// or an error of kind `PermissionDenied` if the cache is not
+    /// `writable()` or the sector is guarded (see `guard()`).
+    pub fn get_mut(&mut self, sector: u64) -> io::Result<&mut [u8]> {
+        self.check_guard(sector)?;
+        self.get_mut_guarded(sector)
+    }
+
+    /// Returns a mutable reference to the cached sector `sector` as
+    /// `get_mut()` does, whether or not the sector is guarded.
+    pub fn get_mut_guarded(&mut self, sector: u64) -> io::Result<&mut [u8]> {
+        self.check_writable()?;
+        self.check_media();
+        if self.cache.get(&sector).is_none() {
+            let data_bytes = self.read_sector_from_disk(sector)?;
+            self.insert(sector, data_bytes, true)?;
+        }
+
+        let now = self.tick();
+        let cache = self.cache.get_mut(&sector).unwrap();
+        cache.dirty = true;
+        cache.last_used = now;
+
+        Ok(&mut cache.data[..])
+    }
+
+    /// Replaces the cached contents of sector `sector` with `data` without
+    /// reading the sector from the disk first. The sector is marked dirty.
+    ///
+    /// This is the fast path for writes that cover a whole sector.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the cache is full of dirty sectors and flushing it
+    /// to make room fails, or an error of kind `PermissionDenied` if the cache
+    /// is not `writable()` or the sector is guarded (see `guard()`).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `data` is not exactly one logical sector long.
+    pub fn overwrite(&mut self, sector: u64, data: &[u8]) -> io::Result<()> {
+        self.check_guard(sector)?;
+        self.overwrite_guarded(sector, data)
+    }
+
+    /// Replaces the cached contents of sector `sector` with `data` as
+    /// `overwrite()` does, whether or not the sector is guarded.
+    pub fn overwrite_guarded(&mut self, sector: u64, data: &[u8]) -> io::Result<()> {
+        self.check_writable()?;
+        self.check_media();
+        assert_eq!(
+            data.len(),
+            self.sector_len(sector),
+            "overwrite must cover exactly one sector"
+        );
+
+        if self.cache.get(&sector).is_none() {
+            return self.insert(sector, data.to_vec(), true);
+        }
+
+        let now = self.tick();
+        let entry = self.cache.get_mut(&sector).unwrap();
+        entry.data.clear();
+        entry.data.extend_from_slice(data);
+        entry.dirty = true;
+        entry.last_used = now;
+        Ok(())
+    }
+
+    /// Returns the metrics recorded since they were last taken, without
+    /// resetting them.
+    pub fn metrics(&self) -> &IoMetrics {
+        &self.metrics
+    }
+
+    /// Returns the latency metrics recorded since the last call and starts
+    /// recording afresh.
+    pub fn take_metrics(&mut self) -> IoMetrics {
+        ::std::mem::replace(&mut self.metrics, IoMetrics::default())
+    }
+
+    /// Calls `hook` for every disk operation that takes longer than
+    /// `threshold`, replacing any previous hook.
+    pub fn set_slow_io_hook(&mut self, threshold: Duration, hook: SlowIoHook) {
+        self.slow_io = Some((threshold, hook));
+    }
+
+    /// Records the latency of an operation on sector `virt` that began at
+    /// `start`.
+    /// Limits device reads and writes to `budget` of device time from now,
+    /// or lifts the limit if `None`. Once the budget is spent, accesses that
+    /// would reach the device fail with an error of kind `TimedOut`; cached
+    /// sectors can still be read and written. An access already under way
+    /// when the budget runs out is not interrupted.
+    pub fn set_io_budget(&mut self, budget: Option<Duration>) {
+        self.budget = budget;
+        self.spent = Duration::from_secs(0);
+    }
+
+    /// The device time left in the budget set by `set_io_budget()`, or
+    /// `None` if device time is not limited.
+    pub fn io_budget_remaining(&self) -> Option<Duration> {
+        self.budget.map(|budget| {
+            budget
+                .checked_sub(self.spent)
+                .unwrap_or(Duration::from_secs(0))
+        })
+    }
+
+    /// Returns an error of kind `TimedOut` if the device time budget is
+    /// spent.
+    fn check_budget(&self) -> io::Result<()> {
+        match self.budget {
+            Some(budget) if self.spent >= budget => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "device time budget exhausted",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn record(&mut self, op: IoOp, virt: u64, start: Instant) {
+        let latency = start.elapsed();
+        self.spent += latency;
+        self.metrics.record(op, latency);
+        if let Some((threshold, ref mut hook)) = self.slow_io {
+            if latency > threshold {
+                hook(op, virt, latency);
+            }
+        }
+    }
+
+    /// Removes sector `sector` from the cache if it is cached and not dirty.
+    pub fn evict(&mut self, sector: u64) {
+        if self.cache.get(&sector).map_or(false, |entry| !entry.dirty) {
+            self.cache.remove(&sector);
+        }
+    }
+
+    /// Writes every dirty cached sector back to the disk.
+    ///
+    /// Sectors are written in order of sector number, and each run of dirty
+    /// sectors that are consecutive on the device is written with a single
+    /// `BlockDevice::write_sectors()` call. See `IoMetrics::flush_writes`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if writing any sector fails. Sectors not yet written
+    /// remain dirty.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.check_media();
+        let mut dirty: Vec<u64> = self
+            .cache
+            .iter()
+            .filter(|&(_, entry)| entry.dirty)
+            .map(|(&sector, _)| sector)
+            .collect();
+        if dirty.is_empty() {
+            return Ok(());
+        }
+        dirty.sort();
+        self.metrics.flushes += 1;
+
+        let mut run: Vec<u64> = Vec::new();
+        let mut run_bytes = 0;
+        for sector in dirty {
+            let len = self.sector_len(sector);
+            if let Some(&last) = run.last() {
+                let (last_physical, last_count) = self.virtual_to_physical(last);
+                let (physical, _) = self.virtual_to_physical(sector);
+                if physical != last_physical + last_count || run_bytes + len > MAX_FLUSH_WRITE {
+                    self.write_run(&run)?;
+                    run.clear();
+                    run_bytes = 0;
+                }
+            }
+            run.push(sector);
+            run_bytes += len;
+        }
+        self.write_run(&run)
+    }
+
+    /// Writes the cached sectors `run`, which are consecutive on the device,
+    /// with one device write, and marks them clean.
+    fn write_run(&mut self, run: &[u64]) -> io::Result<()> {
+        self.check_budget()?;
+        let start = Instant::now();
+        let mut data = Vec::new();
+        for sector in run {
+            data.extend_from_slice(&self.cache[sector].data);
+        }
+
+        let (physical_sector, _) = self.virtual_to_physical(run[0]);
+        self.device.write_sectors(physical_sector, &data)?;
+        self.record(IoOp::Write, run[0], start);
+
+        for sector in run {
+            self.cache.get_mut(sector).unwrap().dirty = false;
+        }
+        self.metrics.flushed_sectors += run.len() as u64;
+        self.metrics.flush_writes += 1;
+        Ok(())
+    }
+
+    /// Reads physical sector `sector` into `buf`, verifying and retrying it
+    /// if `options.checksum_retries` is set.
+    fn read_physical(&mut self, sector: u64, buf: &mut [u8]) -> io::Result<()> {
+        let retries = match self.options.checksum_retries {
+            Some(retries) => retries,
+            None => return self.device.read_sector(sector, buf).map(|_| ()),
+        };
+
+        let mut attempt = 0;
+        loop {
+            match self.device.read_sector_checked(sector, buf) {
+                Ok(_) => return Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::InvalidData && attempt < retries => {
+                    self.metrics.checksum_failures += 1;
+                    attempt += 1;
+                }
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::InvalidData {
+                        self.metrics.checksum_failures += 1;
+                        self.metrics.unrecovered_reads += 1;
+                    }
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    fn read_sector_from_disk(&mut self, virt: u64) -> io::Result<Vec<u8>> {
+        self.check_budget()?;
+        let start = Instant::now();
+        let (physical_sector, num_sectors) = self.virtual_to_physical(virt);
+        let mut data = vec![0; self.sector_len(virt)];
+        for i in 0..num_sectors {
+            let start = (i * self.device.sector_size()) as usize;
+            let end = start + self.device.sector_size() as usize;
+            self.read_physical(physical_sector + i, &mut data[start..end])?;
+        }
+
+        self.record(IoOp::Read, virt, start);
+        Ok(data)
+    }
+
+    /// Returns a copy of sector `sector`, from the cache if it is cached and
+    /// from the disk otherwise. Unlike `get()`, a sector read from the disk
+    /// is not added to the cache, so bulk reads do not evict or grow it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is an error reading the sector from the disk.
+    pub fn read_through(&mut self, sector: u64) -> io::Result<Vec<u8>> {
+        self.check_media();
+        match self.cache.get(&sector) {
+            Some(entry) => Ok(entry.data.clone()),
+            None => self.read_sector_from_disk(sector),
+        }
+    }
+
+    /// Returns a reference to the cached sector `sector`. If the sector is not
+    /// already cached, the sector is first read from the disk.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is an error reading the sector from the disk.
+    pub fn get(&mut self, sector: u64) -> io::Result<&[u8]> {
+        self.check_media();
+        if self.cache.get(&sector).is_none() {
+            let data_bytes = self.read_sector_from_disk(sector)?;
+            self.insert(sector, data_bytes, false)?;
+        }
+
+        let now = self.tick();
+        let entry = self.cache.get_mut(&sector).unwrap();
+        entry.last_used = now;
+        Ok(&entry.data[..])
+    }
+}
+
+impl BlockDevice for CachedDevice {
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let amount_to_read = cmp::min(self.partition.sector_size as usize, buf.len());
+        buf.copy_from_slice(&(self.get(n)?)[..amount_to_read]);
+        Ok(amount_to_read)
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        let sector_size = self.sector_len(n);
+        if buf.len() < sector_size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "buffer smaller than a sector",
+            ));
+        }
+
+        self.overwrite(n, &buf[..sector_size])?;
+        Ok(sector_size)
+    }
+}
+
+impl fmt::Debug for CachedDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CachedDevice")
+            .field("device", &"<block device>")
+            .field("options", &self.options)
+            .field("cache", &self.cache)
+            .finish()
+    }
+}
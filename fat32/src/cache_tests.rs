@@ -0,0 +1,260 @@
+use std::io::{self, Cursor};
+use std::sync::{Arc, Mutex};
+
+use cache::{CacheOptions, CachedDevice, Partition};
+use traits::BlockDevice;
+
+/// Returns a device of `sectors` 512-byte sectors, each filled with its own
+/// sector number.
+fn numbered_device(sectors: u8) -> Cursor<Vec<u8>> {
+    let mut data = Vec::new();
+    for sector in 0..sectors {
+        data.extend_from_slice(&[sector; 512]);
+    }
+    Cursor::new(data)
+}
+
+fn whole_device() -> Partition {
+    Partition {
+        start: 0,
+        sector_size: 512,
+    }
+}
+
+#[test]
+fn test_cache_logical_sectors() {
+    let mut cache = CachedDevice::new(
+        numbered_device(8),
+        Partition {
+            start: 2,
+            sector_size: 1024,
+        },
+    );
+
+    assert_eq!(cache.sector_len(1), 512);
+    assert_eq!(cache.sector_len(2), 1024);
+    assert_eq!(cache.get(1).unwrap(), &[1; 512][..]);
+
+    let logical = cache.get(3).unwrap().to_vec();
+    assert_eq!(&logical[..512], &[4; 512][..]);
+    assert_eq!(&logical[512..], &[5; 512][..]);
+}
+
+#[test]
+fn test_cache_write_back() {
+    let mut cache = CachedDevice::new(numbered_device(4), whole_device());
+    cache.get_mut(1).unwrap()[0] = 0xAA;
+    cache.write_sector(2, &[0xBB; 512]).unwrap();
+    assert_eq!(cache.dirty_sectors(), 2);
+
+    let mut buf = [0; 512];
+    cache.read_sector(1, &mut buf).unwrap();
+    assert_eq!(buf[..2], [0xAA, 1]);
+
+    cache.flush().unwrap();
+    assert_eq!(cache.dirty_sectors(), 0);
+    assert_eq!(cache.cached_sectors(), 2);
+}
+
+#[test]
+fn test_cache_capacity() {
+    let options = CacheOptions {
+        capacity: Some(2),
+        ..CacheOptions::default()
+    };
+    let mut cache = CachedDevice::with_options(numbered_device(8), whole_device(), options);
+
+    cache.get(0).unwrap();
+    cache.get(1).unwrap();
+    cache.get(0).unwrap();
+    cache.get(2).unwrap();
+    assert_eq!(cache.cached_sectors(), 2);
+
+    // Sector 1 was the least recently used, so it was evicted.
+    cache.get_mut(0).unwrap()[0] = 0xAA;
+    cache.get_mut(2).unwrap()[0] = 0xBB;
+    assert_eq!(cache.dirty_sectors(), 2);
+
+    // With every sector dirty, making room flushes the cache.
+    assert_eq!(cache.get(5).unwrap()[0], 5);
+    assert_eq!(cache.cached_sectors(), 2);
+    assert_eq!(cache.dirty_sectors(), 0);
+
+    let mut buf = [0; 512];
+    cache.read_sector(0, &mut buf).unwrap();
+    assert_eq!(buf[0], 0xAA);
+    cache.read_sector(2, &mut buf).unwrap();
+    assert_eq!(buf[0], 0xBB);
+}
+
+/// A device whose checked reads fail their checksum until `failures` runs
+/// out.
+struct FlakyDevice {
+    inner: Cursor<Vec<u8>>,
+    failures: u32,
+}
+
+impl BlockDevice for FlakyDevice {
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read_sector(n, buf)
+    }
+
+    fn read_sector_checked(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        if self.failures > 0 {
+            self.failures -= 1;
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "bad crc"));
+        }
+        self.read_sector(n, buf)
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write_sector(n, buf)
+    }
+}
+
+#[test]
+fn test_cache_checksum_retries() {
+    let options = CacheOptions {
+        checksum_retries: Some(2),
+        ..CacheOptions::default()
+    };
+    let device = FlakyDevice {
+        inner: numbered_device(4),
+        failures: 2,
+    };
+    let mut cache = CachedDevice::with_options(device, whole_device(), options);
+
+    assert_eq!(cache.get(1).unwrap()[0], 1);
+    let metrics = cache.take_metrics();
+    assert_eq!(metrics.checksum_failures, 2);
+    assert_eq!(metrics.unrecovered_reads, 0);
+
+    let device = FlakyDevice {
+        inner: numbered_device(4),
+        failures: 3,
+    };
+    let mut cache = CachedDevice::with_options(device, whole_device(), options);
+    let err = cache.get(1).expect_err("retries exhausted");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(cache.cached_sectors(), 0);
+    let metrics = cache.take_metrics();
+    assert_eq!(metrics.checksum_failures, 3);
+    assert_eq!(metrics.unrecovered_reads, 1);
+}
+
+/// A device recording the start sector and length of every multi-sector
+/// write.
+struct RunDevice {
+    inner: Cursor<Vec<u8>>,
+    runs: Arc<Mutex<Vec<(u64, usize)>>>,
+}
+
+impl BlockDevice for RunDevice {
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read_sector(n, buf)
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write_sector(n, buf)
+    }
+
+    fn write_sectors(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        self.runs.lock().unwrap().push((n, buf.len()));
+        self.inner.write_sectors(n, buf)
+    }
+}
+
+#[test]
+fn test_cache_flush_merges_runs() {
+    let runs = Arc::new(Mutex::new(Vec::new()));
+    let device = RunDevice {
+        inner: numbered_device(8),
+        runs: runs.clone(),
+    };
+    let mut cache = CachedDevice::new(device, whole_device());
+    for &sector in &[6, 2, 1, 5, 3] {
+        cache.write_sector(sector, &[0xAA; 512]).unwrap();
+    }
+    cache.flush().unwrap();
+    cache.flush().unwrap();
+    assert_eq!(*runs.lock().unwrap(), vec![(1, 3 * 512), (5, 2 * 512)]);
+
+    let metrics = cache.take_metrics();
+    assert_eq!(metrics.flushes, 1);
+    assert_eq!(metrics.flushed_sectors, 5);
+    assert_eq!(metrics.flush_writes, 2);
+    assert_eq!(cache.dirty_sectors(), 0);
+    for sector in 0..8 {
+        let expected = if [1, 2, 3, 5, 6].contains(&sector) {
+            0xAA
+        } else {
+            sector as u8
+        };
+        assert_eq!(cache.read_through(sector).unwrap(), vec![expected; 512]);
+    }
+
+    // A physical sector before the partition and the logical sector after
+    // it are consecutive on the device.
+    runs.lock().unwrap().clear();
+    let device = RunDevice {
+        inner: numbered_device(8),
+        runs: runs.clone(),
+    };
+    let partition = Partition {
+        start: 2,
+        sector_size: 1024,
+    };
+    let mut cache = CachedDevice::new(device, partition);
+    cache.write_sector(1, &[0xAA; 512]).unwrap();
+    cache.write_sector(2, &[0xBB; 1024]).unwrap();
+    cache.write_sector(4, &[0xCC; 1024]).unwrap();
+    cache.flush().unwrap();
+    assert_eq!(cache.take_metrics().flush_writes, 2);
+    assert_eq!(*runs.lock().unwrap(), vec![(1, 3 * 512), (6, 2 * 512)]);
+}
+
+/// A device that takes `delay` for every read.
+struct SlowDevice {
+    inner: Cursor<Vec<u8>>,
+    delay: ::std::time::Duration,
+}
+
+impl BlockDevice for SlowDevice {
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        ::std::thread::sleep(self.delay);
+        self.inner.read_sector(n, buf)
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write_sector(n, buf)
+    }
+}
+
+#[test]
+fn test_cache_io_budget() {
+    use std::time::Duration;
+
+    let device = SlowDevice {
+        inner: numbered_device(8),
+        delay: Duration::from_millis(2),
+    };
+    let mut cache = CachedDevice::new(device, whole_device());
+    cache.get(0).unwrap();
+
+    cache.set_io_budget(Some(Duration::from_millis(5)));
+    let mut read = 0;
+    let err = loop {
+        match cache.get(1 + read) {
+            Ok(_) => read += 1,
+            Err(e) => break e,
+        }
+    };
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    assert!(read >= 2 && read < 7, "read {} sectors", read);
+    assert_eq!(cache.io_budget_remaining(), Some(Duration::from_secs(0)));
+
+    // Cached sectors are still served, and lifting the budget allows reads.
+    assert_eq!(cache.get(0).unwrap()[0], 0);
+    cache.set_io_budget(None);
+    assert_eq!(cache.get(7).unwrap()[0], 7);
+}
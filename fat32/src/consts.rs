@@ -0,0 +1,90 @@
+//! Constants of the on-disk FAT32 format.
+//!
+//! These values are fixed by the format, not by this crate, and will not
+//! change between releases. Tools that parse or build structures by hand can
+//! use them rather than repeating the numbers.
+
+/// The size in bytes of a directory entry, short or long.
+pub const DIR_ENTRY_SIZE: usize = 32;
+
+/// The most entry slots a directory may hold, 2 MiB of entries. Slots past
+/// it are never read.
+pub const MAX_DIR_ENTRIES: usize = 65536;
+
+/// The first name byte of the entry that ends a directory. No entry after it
+/// is in use.
+pub const END_OF_ENTRIES: u8 = 0x00;
+/// The first name byte of a deleted entry.
+pub const DELETED_ENTRY: u8 = 0xE5;
+/// The first name byte standing for `DELETED_ENTRY` in a short name that
+/// really begins with `0xE5`.
+pub const ESCAPED_DELETED_ENTRY: u8 = 0x05;
+
+/// The attribute bit of read-only entries.
+pub const ATTR_READ_ONLY: u8 = 0x01;
+/// The attribute bit of hidden entries.
+pub const ATTR_HIDDEN: u8 = 0x02;
+/// The attribute bit of system entries.
+pub const ATTR_SYSTEM: u8 = 0x04;
+/// The attribute bit of the volume label entry.
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+/// The attribute bit of directories.
+pub const ATTR_DIRECTORY: u8 = 0x10;
+/// The attribute bit of entries changed since they were last backed up.
+pub const ATTR_ARCHIVE: u8 = 0x20;
+/// The attribute byte marking a long file name entry.
+pub const ATTR_LFN: u8 = 0x0F;
+/// Attribute bits no valid entry sets.
+pub const ATTR_RESERVED: u8 = 0xC0;
+
+/// The bit of an LFN entry's sequence number marking the last entry.
+pub const LAST_LFN_ENTRY: u8 = 0x40;
+/// The bits of an LFN entry's sequence number holding its position.
+pub const LFN_SEQUENCE_MASK: u8 = 0x1F;
+
+/// The size in bytes of a FAT entry.
+pub const FAT_ENTRY_SIZE: usize = 4;
+/// The bits of a FAT entry holding its value. The high 4 bits are reserved.
+pub const FAT_ENTRY_MASK: u32 = 0x0FFFFFFF;
+/// The highest FAT entry value naming a data cluster.
+pub const MAX_DATA_CLUSTER: u32 = 0x0FFFFFEF;
+/// The first of the reserved FAT entry values.
+pub const RESERVED_MIN: u32 = 0x0FFFFFF0;
+/// The last of the reserved FAT entry values.
+pub const RESERVED_MAX: u32 = 0x0FFFFFF6;
+/// The FAT entry value marking a bad cluster.
+pub const BAD_CLUSTER: u32 = 0x0FFFFFF7;
+/// The most data clusters a volume may have. Clusters are numbered from 2,
+/// so the last of them is `MAX_DATA_CLUSTER`.
+pub const MAX_CLUSTERS: u32 = MAX_DATA_CLUSTER - 1;
+/// The lowest FAT entry value marking the end of a chain. Every value from
+/// here to `EOC_MARKER` does.
+pub const EOC_MIN: u32 = 0x0FFFFFF8;
+/// The end of chain marker written by this crate and by most formatters.
+pub const EOC_MARKER: u32 = 0x0FFFFFFF;
+/// The bits of the first FAT entry above the media descriptor, which fills its
+/// low byte.
+pub const MEDIA_ENTRY_HIGH_BITS: u32 = 0x0FFFFF00;
+/// The bit of the second FAT entry set once the volume was cleanly unmounted.
+pub const CLEAN_SHUTDOWN: u32 = 0x08000000;
+/// The bit of the second FAT entry set while no disk I/O error has been
+/// recorded on the volume.
+pub const NO_HARD_ERROR: u32 = 0x04000000;
+
+/// The bit of the BPB's extended flags set when only the active FAT is kept
+/// up to date, rather than every copy.
+pub const FAT_MIRRORING_DISABLED: u16 = 1 << 7;
+
+/// The last two bytes of the master boot record and of the boot sector.
+pub const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+/// The signature at the start of the FSInfo sector.
+pub const FSINFO_LEAD_SIGNATURE: u32 = 0x41615252;
+/// The signature before the FSInfo sector's free cluster count.
+pub const FSINFO_STRUCT_SIGNATURE: u32 = 0x61417272;
+/// The signature at the end of the FSInfo sector.
+pub const FSINFO_TRAIL_SIGNATURE: u32 = 0xAA550000;
+
+/// The partition type of FAT32 partitions addressed by CHS.
+pub const FAT32_CHS_PARTITION: u8 = 0x0B;
+/// The partition type of FAT32 partitions addressed by LBA.
+pub const FAT32_LBA_PARTITION: u8 = 0x0C;
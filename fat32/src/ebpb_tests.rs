@@ -4,7 +4,7 @@ use std::io::prelude::*;
 use std::io::Cursor;
 
 use tests;
-use vfat::BiosParameterBlock;
+use vfat::ebpb::BiosParameterBlock;
 
 #[test]
 fn test_ebpb_data() {
@@ -31,9 +31,9 @@ fn test_ebpb_data() {
         "NO NAME    "
     );
 
-    assert_eq!(ebpb1.num_fats, 2);
-    assert_eq!(ebpb2.num_fats, 2);
+    assert_eq!({ ebpb1.num_fats }, 2);
+    assert_eq!({ ebpb2.num_fats }, 2);
 
-    assert_eq!(ebpb1.bytes_per_sector, 0x200);
-    assert_eq!(ebpb2.bytes_per_sector, 0x400);
+    assert_eq!({ ebpb1.bytes_per_sector }, 0x200);
+    assert_eq!({ ebpb2.bytes_per_sector }, 0x400);
 }
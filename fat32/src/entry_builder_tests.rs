@@ -0,0 +1,100 @@
+use byteorder::{ByteOrder, LittleEndian};
+
+use vfat::{lfn_checksum, Attributes, DirEntryBuilder};
+
+/// Returns the UCS-2 units held by the LFN entry `entry`.
+fn lfn_units(entry: &[u8; 32]) -> Vec<u16> {
+    [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30]
+        .iter()
+        .map(|&offset| LittleEndian::read_u16(&entry[offset..offset + 2]))
+        .collect()
+}
+
+#[test]
+fn test_build_short_only() {
+    let entries = DirEntryBuilder::new("KERNEL8.IMG")
+        .cluster(0x12345)
+        .size(1234)
+        .build()
+        .unwrap();
+    assert_eq!(entries.len(), 1);
+
+    let entry = &entries[0];
+    assert_eq!(&entry[..11], b"KERNEL8 IMG");
+    assert_eq!(entry[11], 0x20);
+    assert_eq!(LittleEndian::read_u16(&entry[20..22]), 0x1);
+    assert_eq!(LittleEndian::read_u16(&entry[26..28]), 0x2345);
+    assert_eq!(LittleEndian::read_u32(&entry[28..32]), 1234);
+}
+
+#[test]
+fn test_build_long_name() {
+    let entries = DirEntryBuilder::new("Long File Name.text")
+        .attributes(Attributes(0x10))
+        .build()
+        .unwrap();
+    assert_eq!(entries.len(), 3);
+
+    let short = &entries[2];
+    assert_eq!(&short[..11], b"LONGFI~1TEX");
+    assert_eq!(short[11], 0x10);
+
+    let mut checksum_name = [0; 11];
+    checksum_name.copy_from_slice(&short[..11]);
+    let checksum = lfn_checksum(&checksum_name);
+
+    assert_eq!(entries[0][0], 0x42);
+    assert_eq!(entries[1][0], 0x01);
+    for entry in &entries[..2] {
+        assert_eq!(entry[11], 0x0F);
+        assert_eq!(entry[13], checksum);
+    }
+
+    let mut units = lfn_units(&entries[1]);
+    units.extend(lfn_units(&entries[0]));
+    let name: Vec<u16> = "Long File Name.text".encode_utf16().collect();
+    assert_eq!(&units[..name.len()], &name[..]);
+    assert_eq!(units[name.len()], 0);
+    assert!(units[name.len() + 1..].iter().all(|&unit| unit == 0xFFFF));
+}
+
+#[test]
+fn test_build_lowercase_and_exact_fill() {
+    // Case is kept in the long name even when the name otherwise fits 8.3.
+    let entries = DirEntryBuilder::new("readme.txt").build().unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(&entries[1][..11], b"README  TXT");
+
+    // A name filling its last LFN entry has no terminator.
+    let entries = DirEntryBuilder::new("thirteen char").build().unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0][0], 0x41);
+    let name: Vec<u16> = "thirteen char".encode_utf16().collect();
+    assert_eq!(lfn_units(&entries[0]), name);
+
+    let entries = DirEntryBuilder::new("x.txt")
+        .short_name(*b"\xE5       TXT")
+        .build()
+        .unwrap();
+    assert_eq!(entries[1][0], 0x05);
+
+    assert!(DirEntryBuilder::new("a/b").build().is_err());
+}
+
+#[test]
+fn test_build_surrogate_pair_name() {
+    // Twelve units, then an emoji whose surrogate pair straddles the first
+    // and second LFN entries.
+    let name = "abcdefghijkl\u{1F600}.txt";
+    let entries = DirEntryBuilder::new(name).build().unwrap();
+    assert_eq!(entries.len(), 3);
+    assert_eq!(&entries[2][..11], b"ABCDEF~1TXT");
+
+    let mut units = lfn_units(&entries[1]);
+    units.extend(lfn_units(&entries[0]));
+    assert_eq!(units[12], 0xD83D);
+    assert_eq!(units[13], 0xDE00);
+    let name_units: Vec<u16> = name.encode_utf16().collect();
+    assert_eq!(&units[..name_units.len()], &name_units[..]);
+    assert_eq!(units[name_units.len()], 0);
+}
@@ -1,13 +1,21 @@
 #![feature(decl_macro)]
 #![feature(extern_prelude)]
-#![allow(safe_packed_borrows)]
+#![deny(safe_packed_borrows)]
 #![feature(vec_resize_default)]
 #![feature(nll)]
+#![feature(try_from)]
+#![feature(non_exhaustive)]
+#![cfg_attr(test, feature(test))]
+// The crate holds no unsafe code but for `Shared`'s `Send` and `Sync` impls
+// on ROS and the tests' counting allocator, the places it is allowed.
+#![cfg_attr(not(any(target_os = "ros", test)), forbid(unsafe_code))]
+#![cfg_attr(any(target_os = "ros", test), deny(unsafe_code))]
 
 #[cfg(not(target_endian = "little"))]
 compile_error!("only little endian platforms supported");
 
 extern crate byteorder;
+extern crate fat32_traits;
 
 #[cfg(test)]
 #[macro_use]
@@ -19,10 +27,22 @@ mod mbr_tests;
 #[cfg(test)]
 mod ebpb_tests;
 
+#[cfg(test)]
+mod cache_tests;
+
+#[cfg(test)]
+mod short_name_tests;
+
+#[cfg(test)]
+mod entry_builder_tests;
+
 mod mbr;
-mod util;
 
-pub mod traits;
+pub mod cache;
+pub mod consts;
+pub mod prelude;
 pub mod vfat;
 
+pub use fat32_traits as traits;
+pub use fat32_traits::path;
 pub use mbr::*;
@@ -1,8 +1,12 @@
-use std::{fmt, io};
+use std::{error, fmt, io};
 
 use byteorder::{ByteOrder, LittleEndian};
+use consts::{BOOT_SIGNATURE, FAT32_CHS_PARTITION, FAT32_LBA_PARTITION};
 use traits::BlockDevice;
 
+/// The size of the bootstrap code area at the start of the MBR.
+pub const BOOTSTRAP_SIZE: usize = 436;
+
 #[repr(C, packed)]
 #[derive(Copy, Clone, Debug, Default)]
 pub struct CHS {
@@ -12,6 +16,29 @@ pub struct CHS {
     sector_starting_cylinder: u16,
 }
 
+impl CHS {
+    /// The address's head.
+    pub fn head(&self) -> u8 {
+        self.head
+    }
+
+    /// The address's sector, counted from 1. A sector of 0 is invalid.
+    pub fn sector(&self) -> u8 {
+        (self.sector_starting_cylinder & 0x3F) as u8
+    }
+
+    /// The address's cylinder.
+    pub fn cylinder(&self) -> u16 {
+        (self.sector_starting_cylinder >> 8) | ((self.sector_starting_cylinder & 0xC0) << 2)
+    }
+
+    /// Whether every field of the address is zero, as tools that only fill
+    /// in the LBA fields leave it.
+    fn is_zero(&self) -> bool {
+        self.head == 0 && self.sector_starting_cylinder == 0
+    }
+}
+
 #[repr(C, packed)]
 #[derive(Debug, Clone, Copy, Default)]
 pub struct PartitionEntry {
@@ -23,10 +50,78 @@ pub struct PartitionEntry {
     pub total_sectors: u32,
 }
 
+impl PartitionEntry {
+    /// Whether the entry is unused: its partition type or size is zero.
+    pub fn is_empty(&self) -> bool {
+        self.partition_type == 0 || self.total_sectors == 0
+    }
+
+    /// The sector just past the partition's last.
+    fn end(&self) -> u64 {
+        self.relative_sector as u64 + self.total_sectors as u64
+    }
+
+    /// Whether the entry's CHS addresses cannot be right: either has a sector
+    /// of 0 without being all zero, or the last address precedes the first.
+    /// Addresses past the reach of CHS are commonly given as the maximum,
+    /// which is accepted.
+    fn has_bogus_chs(&self) -> bool {
+        let (start, end) = (self.starting_chs, self.ending_chs);
+        if start.is_zero() && end.is_zero() {
+            return false;
+        }
+        let address = |chs: CHS| (chs.cylinder(), chs.head(), chs.sector());
+        start.sector() == 0 || end.sector() == 0 || address(start) > address(end)
+    }
+}
+
+/// How `MasterBootRecord::validate()` classifies a partition table entry.
+/// An entry with several problems is classified by the first that applies,
+/// in the order of the variants.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PartitionStatus {
+    /// The entry is unused: its partition type or size is zero.
+    Empty,
+    /// The partition runs to sector `end`, past the `device_sectors` sectors
+    /// of the device.
+    ExtendsPastDevice { end: u64, device_sectors: u64 },
+    /// The partition shares sectors with the partition in entry `other`, or,
+    /// if `None`, covers sector 0, which holds the MBR itself.
+    Overlapping { other: Option<usize> },
+    /// The entry's CHS addresses are malformed, or the last precedes the
+    /// first. Its LBA fields may still be right, but the entry was likely
+    /// written by a broken tool.
+    BogusChs,
+    /// None of the above.
+    Valid,
+}
+
+impl fmt::Display for PartitionStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PartitionStatus::Empty => write!(f, "empty"),
+            PartitionStatus::ExtendsPastDevice {
+                end,
+                device_sectors,
+            } => write!(
+                f,
+                "ends at sector {} but the device holds {} sectors",
+                end, device_sectors
+            ),
+            PartitionStatus::Overlapping { other: Some(other) } => {
+                write!(f, "overlaps partition {}", other)
+            }
+            PartitionStatus::Overlapping { other: None } => write!(f, "overlaps the MBR"),
+            PartitionStatus::BogusChs => write!(f, "has malformed CHS addresses"),
+            PartitionStatus::Valid => write!(f, "valid"),
+        }
+    }
+}
+
 /// The master boot record (MBR).
 #[repr(C, packed)]
 pub struct MasterBootRecord {
-    pub mbr_bootstrap: [u8; 436],
+    pub mbr_bootstrap: [u8; BOOTSTRAP_SIZE],
     pub disk_id: [u8; 10],
     pub partition_table_entries: [PartitionEntry; 4],
     pub bootsector_signature: [u8; 2],
@@ -40,8 +135,29 @@ pub enum Error {
     UnknownBootIndicator(u8),
     /// The MBR magic signature was invalid.
     BadSignature,
+    /// Boot code of `.0` bytes does not fit in the bootstrap area.
+    BootCodeTooLarge(usize),
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(error) => write!(f, "I/O error: {}", error),
+            Error::UnknownBootIndicator(i) => {
+                write!(f, "partition {} has an unknown boot indicator", i)
+            }
+            Error::BadSignature => write!(f, "invalid MBR signature"),
+            Error::BootCodeTooLarge(len) => write!(
+                f,
+                "boot code of {} bytes does not fit in the {}-byte bootstrap area",
+                len, BOOTSTRAP_SIZE
+            ),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
 impl MasterBootRecord {
     /// Reads and returns the master boot record (MBR) from `device`.
     ///
@@ -58,12 +174,12 @@ impl MasterBootRecord {
             return Err(Error::Io(err));
         }
 
-        if &mbr_sector[device.sector_size() as usize - 2..] != &[0x55, 0xaa] {
+        if &mbr_sector[device.sector_size() as usize - 2..] != &BOOT_SIGNATURE {
             return Err(Error::BadSignature);
         }
 
-        let mut mbr_bootstrap: [u8; 436] = [0; 436];
-        mbr_bootstrap.copy_from_slice(&mbr_sector[0..436]);
+        let mut mbr_bootstrap: [u8; BOOTSTRAP_SIZE] = [0; BOOTSTRAP_SIZE];
+        mbr_bootstrap.copy_from_slice(&mbr_sector[0..BOOTSTRAP_SIZE]);
         let mut disk_id: [u8; 10] = [0; 10];
         disk_id.copy_from_slice(&mbr_sector[436..446]);
 
@@ -105,13 +221,85 @@ impl MasterBootRecord {
         })
     }
 
+    /// Installs `code` into the bootstrap area of the MBR on `device`,
+    /// zeroing the rest of the area. The disk ID, partition table, and
+    /// signature are left untouched.
+    ///
+    /// # Errors
+    ///
+    /// Returns `BootCodeTooLarge(n)` if `code` is longer than
+    /// `BOOTSTRAP_SIZE` bytes. Returns `BadSignature` if `device` does not
+    /// hold a valid MBR. Returns `Io(err)` if the I/O error `err` occured
+    /// while reading or writing the MBR.
+    pub fn install_bootstrap<T: BlockDevice>(
+        &mut self,
+        device: &mut T,
+        code: &[u8],
+    ) -> Result<(), Error> {
+        if code.len() > BOOTSTRAP_SIZE {
+            return Err(Error::BootCodeTooLarge(code.len()));
+        }
+
+        let mut mbr_sector = vec![0u8; device.sector_size() as usize];
+        device
+            .read_sector(0, &mut mbr_sector[..])
+            .map_err(Error::Io)?;
+        if &mbr_sector[device.sector_size() as usize - 2..] != &BOOT_SIGNATURE {
+            return Err(Error::BadSignature);
+        }
+
+        self.mbr_bootstrap = [0; BOOTSTRAP_SIZE];
+        self.mbr_bootstrap[..code.len()].copy_from_slice(code);
+        mbr_sector[..BOOTSTRAP_SIZE].copy_from_slice(&self.mbr_bootstrap);
+        device.write_sector(0, &mbr_sector).map_err(Error::Io)?;
+        Ok(())
+    }
+
     pub fn get_fat_partition_offset(&self) -> Option<u32> {
-        for partition in self.partition_table_entries.iter() {
-            if partition.partition_type == 0x0b || partition.partition_type == 0x0c {
-                return Some(partition.relative_sector);
-            }
+        self.get_fat_partition()
+            .map(|partition| partition.relative_sector)
+    }
+
+    /// Classifies each entry of the partition table, for explaining why a
+    /// partition is unusable. `device_sectors` is the size of the device in
+    /// its own sectors, the unit of the entries' LBA fields, if known.
+    ///
+    /// Entries are not checked against their partition types, and
+    /// `get_fat_partition()` picks its partition regardless of these
+    /// classifications.
+    pub fn validate(&self, device_sectors: Option<u64>) -> [PartitionStatus; 4] {
+        let entries = self.partition_table_entries;
+        let mut statuses = [PartitionStatus::Valid; 4];
+        for (i, entry) in entries.iter().enumerate() {
+            let overlapping = (0..entries.len()).find(|&j| {
+                let other = &entries[j];
+                j != i
+                    && !other.is_empty()
+                    && (entry.relative_sector as u64) < other.end()
+                    && (other.relative_sector as u64) < entry.end()
+            });
+            statuses[i] = match device_sectors {
+                _ if entry.is_empty() => PartitionStatus::Empty,
+                Some(device_sectors) if entry.end() > device_sectors => {
+                    PartitionStatus::ExtendsPastDevice {
+                        end: entry.end(),
+                        device_sectors,
+                    }
+                }
+                _ if entry.relative_sector == 0 => PartitionStatus::Overlapping { other: None },
+                _ if overlapping.is_some() => PartitionStatus::Overlapping { other: overlapping },
+                _ if entry.has_bogus_chs() => PartitionStatus::BogusChs,
+                _ => PartitionStatus::Valid,
+            };
         }
-        None
+        statuses
+    }
+
+    /// Returns the first partition entry of type FAT32 (CHS or LBA), if any.
+    pub fn get_fat_partition(&self) -> Option<&PartitionEntry> {
+        self.partition_table_entries.iter().find(|p| {
+            p.partition_type == FAT32_CHS_PARTITION || p.partition_type == FAT32_LBA_PARTITION
+        })
     }
 }
 
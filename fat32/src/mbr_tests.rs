@@ -13,3 +13,40 @@ fn test_mbr_data() {
     mbr.read_exact(&mut data).expect("read resource data");
     let _mbr_record = MasterBootRecord::from(&mut Cursor::new(&mut data[..])).expect("valid MBR");
 }
+
+#[test]
+fn test_mbr_validate() {
+    use byteorder::{ByteOrder, LittleEndian};
+    use mbr::PartitionStatus;
+
+    let mut data = [0u8; 512];
+    let mut entry = |i: usize, chs: [u8; 3], start: u32, sectors: u32| {
+        let entry = &mut data[446 + i * 16..446 + (i + 1) * 16];
+        entry[1..4].copy_from_slice(&chs);
+        entry[4] = 0x0C;
+        entry[5..8].copy_from_slice(&chs);
+        LittleEndian::write_u32(&mut entry[8..12], start);
+        LittleEndian::write_u32(&mut entry[12..16], sectors);
+    };
+    entry(0, [0xFE, 0xFF, 0xFF], 2048, 2048);
+    entry(1, [0x00, 0x00, 0x00], 3072, 1024);
+    entry(2, [0x01, 0x00, 0x00], 8192, 1024);
+    data[510..].copy_from_slice(&[0x55, 0xAA]);
+    let mbr = MasterBootRecord::from(&mut Cursor::new(&mut data[..])).expect("valid MBR");
+
+    assert_eq!(
+        mbr.validate(Some(9000)),
+        [
+            PartitionStatus::Overlapping { other: Some(1) },
+            PartitionStatus::Overlapping { other: Some(0) },
+            PartitionStatus::ExtendsPastDevice {
+                end: 9216,
+                device_sectors: 9000,
+            },
+            PartitionStatus::Empty,
+        ]
+    );
+    let statuses = mbr.validate(None);
+    assert_eq!(statuses[2], PartitionStatus::BogusChs);
+    assert_eq!(statuses[2].to_string(), "has malformed CHS addresses");
+}
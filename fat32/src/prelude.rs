@@ -0,0 +1,9 @@
+//! Convenience re-exports for users of the crate.
+//!
+//! `use fat32::prelude::*;` brings every trait into scope, so their methods
+//! resolve on VFat handles, along with the types needed to mount a volume.
+//! The VFat handle types share their names with the traits they implement;
+//! refer to them through `fat32::vfat` or the `FileSystem` associated types.
+
+pub use traits::{BlockDevice, Dir, Entry, File, FileSystem, Metadata, OpenOptions, Timestamp};
+pub use vfat::{Error, MountOptions, Shared, VFat};
@@ -0,0 +1,102 @@
+use vfat::{lfn_checksum, short_name_for, validate_long_name, ShortNameStrategy};
+
+fn short(name: &str, taken: &[&[u8; 11]]) -> [u8; 11] {
+    short_name_for(name, ShortNameStrategy::NumericTail, |s| taken.contains(&s))
+        .expect("short name")
+}
+
+#[test]
+fn test_short_name_fits() {
+    assert_eq!(&short("README.TXT", &[]), b"README  TXT");
+    assert_eq!(&short("readme.txt", &[]), b"README  TXT");
+    assert_eq!(&short("KERNEL8", &[]), b"KERNEL8    ");
+}
+
+#[test]
+fn test_short_name_numeric_tail() {
+    assert_eq!(&short("Long File Name.text", &[]), b"LONGFI~1TEX");
+    assert_eq!(
+        &short("Long File Name.text", &[b"LONGFI~1TEX"]),
+        b"LONGFI~2TEX"
+    );
+    assert_eq!(&short(".bashrc", &[]), b"BASHRC~1   ");
+    assert_eq!(&short("a+b.c", &[]), b"A_B~1   C  ");
+}
+
+#[test]
+fn test_short_name_hash_fallback() {
+    let taken: Vec<[u8; 11]> = (1..5u8)
+        .map(|n| {
+            let mut s = *b"LONGFI~1TXT";
+            s[7] = b'0' + n;
+            s
+        })
+        .collect();
+    let taken: Vec<&[u8; 11]> = taken.iter().collect();
+
+    // After `~4`, aliases are hashed, as on Windows.
+    let alias = short("Long File Name.txt", &taken);
+    assert_eq!(&alias[..2], b"LO");
+    assert_eq!(&alias[6..], b"~1TXT");
+    assert_eq!(alias, short("Long File Name.txt", &taken));
+    assert_eq!(&short("Long File Name.txt", &taken[..3]), b"LONGFI~4TXT");
+
+    let mut taken = taken.clone();
+    taken.push(&alias);
+    let next = short("Long File Name.txt", &taken);
+    assert_eq!(&next[..6], &alias[..6]);
+    assert_eq!(&next[6..], b"~2TXT");
+}
+
+#[test]
+fn test_short_name_fail_strategy() {
+    let e = short_name_for("Long File Name.txt", ShortNameStrategy::Fail, |_| false).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidInput);
+
+    let e = short_name_for("A.TXT", ShortNameStrategy::Fail, |_| true).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::AlreadyExists);
+}
+
+#[test]
+fn test_lfn_checksum() {
+    assert_eq!(lfn_checksum(b"README  TXT"), 0x73);
+    assert_eq!(lfn_checksum(b"README~1TXT"), 0x6B);
+    assert_eq!(lfn_checksum(b"FOO     BAR"), 0x53);
+    assert_eq!(lfn_checksum(b"           "), 0xF7);
+}
+
+#[test]
+fn test_validate_long_name() {
+    let valid = |name: &str, compat: bool| validate_long_name(name, compat).is_ok();
+
+    assert!(valid("Long File Name.text", true));
+    assert!(valid("CONSOLE.TXT", true));
+    assert!(valid(&"a".repeat(255), true));
+    assert!(!valid(&"a".repeat(256), false));
+    assert!(!valid("", false));
+    assert!(!valid("..", false));
+    assert!(!valid("a/b", false));
+
+    for name in [
+        "a:b",
+        "what?",
+        "tab\tname",
+        "trailing.",
+        "con",
+        "Lpt1.txt",
+        "NUL .c",
+    ]
+    .iter()
+    {
+        assert!(
+            valid(name, false),
+            "{:?} is valid without windows_compat",
+            name
+        );
+        assert!(
+            !valid(name, true),
+            "{:?} is invalid with windows_compat",
+            name
+        );
+    }
+}
@@ -1,4 +1,5 @@
 extern crate rand;
+extern crate test;
 
 use std::io::prelude::*;
 use std::io::Cursor;
@@ -6,7 +7,8 @@ use std::path::Path;
 
 use mbr::{MasterBootRecord, PartitionEntry, CHS};
 use traits::*;
-use vfat::{BiosParameterBlock, Shared, VFat};
+use vfat::ebpb::BiosParameterBlock;
+use vfat::{Shared, VFat};
 
 macro check_size($T:ty, $size:expr) {
     assert_eq!(
@@ -99,6 +101,29 @@ fn check_mbr_boot_indicator() {
     MasterBootRecord::from(&mut Cursor::new(&mut data[..])).unwrap();
 }
 
+#[test]
+fn check_mbr_install_bootstrap() {
+    let mut data = [0u8; 512];
+    data[..446].copy_from_slice(&[0xCC; 446]);
+    data[446] = 0x80;
+    data[510..].copy_from_slice(&[0x55, 0xAA]);
+
+    let mut mbr = MasterBootRecord::from(&mut Cursor::new(&mut data[..])).unwrap();
+    let e = mbr
+        .install_bootstrap(&mut Cursor::new(&mut data[..]), &[0x90; 437])
+        .unwrap_err();
+    expect_variant!(e, ::mbr::Error::BootCodeTooLarge(437));
+
+    mbr.install_bootstrap(&mut Cursor::new(&mut data[..]), &[0xFA, 0xF4])
+        .unwrap();
+    assert_eq!(&data[..2], &[0xFA, 0xF4]);
+    assert!(data[2..436].iter().all(|&b| b == 0));
+    assert_eq!(&data[436..446], &[0xCC; 10]);
+    assert_eq!(data[446], 0x80);
+    assert_eq!(&data[510..], &[0x55, 0xAA]);
+    assert_eq!(&mbr.mbr_bootstrap[..2], &[0xFA, 0xF4]);
+}
+
 #[test]
 fn test_mbr() {
     let mut mbr = resource!("mbr.img");
@@ -378,3 +403,5314 @@ fn shared_fs_is_sync_send_static() {
     fn f<T: Sync + Send + 'static>() {}
     f::<Shared<VFat>>();
 }
+
+#[test]
+fn test_entry_identity() {
+    use std::collections::HashSet;
+
+    let vfat = vfat_from_resource!("mock1.fat32.img");
+    let root = (&vfat).open_dir("/").expect("root directory");
+    let first: HashSet<_> = root.entries().expect("entries iterator").collect();
+    let second: Vec<_> = root.entries().expect("entries iterator").collect();
+
+    assert_eq!(first.len(), second.len());
+    for entry in second {
+        assert!(
+            first.contains(&entry),
+            "entry {} not equal to itself",
+            entry.name()
+        );
+    }
+}
+
+#[test]
+fn test_timestamp_system_time_round_trip() {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    // 2018-03-04 05:06:08 UTC.
+    let time = UNIX_EPOCH + Duration::from_secs(1_520_139_968);
+
+    let ts = ::vfat::Timestamp::from_system_time(time, 0);
+    assert_eq!((ts.year(), ts.month(), ts.day()), (2018, 3, 4));
+    assert_eq!((ts.hour(), ts.minute(), ts.second()), (5, 6, 8));
+    assert_eq!(ts.to_system_time(0), time);
+
+    let ts = ::vfat::Timestamp::from_system_time(time, -480);
+    assert_eq!((ts.day(), ts.hour()), (3, 21));
+    assert_eq!(ts.to_system_time(-480), time);
+}
+
+#[test]
+fn test_timestamp_epoch_and_ordering() {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let at =
+        |secs: u64| ::vfat::Timestamp::from_system_time(UNIX_EPOCH + Duration::from_secs(secs), 0);
+    let (older, newer) = (at(1_520_139_968), at(1_520_226_370));
+    assert_eq!(older.to_unix_epoch(0), 1_520_139_968);
+    assert_eq!(older.to_unix_epoch(-480), 1_520_139_968 + 480 * 60);
+    assert_eq!(
+        newer.duration_since(&older),
+        Some(Duration::from_secs(86_402))
+    );
+    assert_eq!(older.duration_since(&newer), None);
+    assert_eq!(older.duration_since(&older), Some(Duration::from_secs(0)));
+
+    // The earlier day sorts first even though its time of day is later.
+    let (evening, morning) = (at(1_520_190_000), at(1_520_230_000));
+    assert!(evening.hour() > morning.hour());
+    let mut stamps = vec![morning, newer, older, evening];
+    stamps.sort();
+    assert_eq!(stamps, vec![older, evening, newer, morning]);
+
+    // A zeroed day reads as the 1st, before the 2nd.
+    let zeroed = ::vfat::Timestamp::default();
+    assert_eq!(zeroed.to_unix_epoch(0), at(0).to_unix_epoch(0));
+    assert!(zeroed < at(315_619_200));
+}
+
+#[test]
+fn test_visible_entries_skip_hidden() {
+    use consts::{ATTR_ARCHIVE, ATTR_DIRECTORY, ATTR_HIDDEN, ATTR_SYSTEM};
+
+    let mut image = mock_volume(16);
+    let entries = [
+        (b"PLAIN   TXT", ATTR_ARCHIVE),
+        (b"HIDDEN  TXT", ATTR_ARCHIVE | ATTR_HIDDEN),
+        (b"SYSTEM  SYS", ATTR_ARCHIVE | ATTR_SYSTEM),
+        (b"SUBDIR     ", ATTR_DIRECTORY),
+        (b"SECRET     ", ATTR_DIRECTORY | ATTR_HIDDEN),
+    ];
+    for (slot, &(name, attributes)) in entries.iter().enumerate() {
+        mock_write_slot(&mut image, 2, slot, &mock_dir_entry(name, attributes, 0, 0));
+    }
+
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    let root = (&vfat).open_dir("/").expect("root directory");
+    let names = |iter: ::vfat::DirIter| -> Vec<String> {
+        iter.map(|entry| entry.name().to_string()).collect()
+    };
+
+    assert_eq!(
+        names(root.visible_entries().unwrap()),
+        vec!["PLAIN.TXT", "SUBDIR"]
+    );
+    assert_eq!(
+        names(root.entries().unwrap()),
+        vec!["PLAIN.TXT", "HIDDEN.TXT", "SYSTEM.SYS", "SUBDIR", "SECRET"]
+    );
+    assert_eq!(root.find("hidden.txt").unwrap().name(), "HIDDEN.TXT");
+}
+
+#[test]
+fn test_entry_refresh() {
+    let vfat = vfat_from_resource!("mock1.fat32.img");
+    let root = (&vfat).open_dir("/").expect("root directory");
+
+    for mut entry in root.entries().expect("entries iterator") {
+        let name = entry.name().to_string();
+        let id = entry.id();
+        entry.refresh().expect("entry is not stale");
+        assert_eq!(entry.name(), name);
+        assert_eq!(entry.id(), id);
+    }
+}
+
+/// Layout of the synthetic volumes built by `mock_volume()`.
+const MOCK_PARTITION_START: usize = 8;
+const MOCK_RESERVED_SECTORS: usize = 4;
+
+/// Builds an in-memory disk image with an MBR and a single FAT32 partition of
+/// `num_clusters` one-sector clusters. Cluster 2 is the (empty) root
+/// directory; every other cluster is free.
+fn mock_volume(num_clusters: u32) -> Vec<u8> {
+    use byteorder::{ByteOrder, LittleEndian};
+
+    let sectors_per_fat = ((num_clusters as usize + 2) * 4 + 511) / 512;
+    let total_sectors = MOCK_RESERVED_SECTORS + 2 * sectors_per_fat + num_clusters as usize;
+    let mut image = vec![0u8; (MOCK_PARTITION_START + total_sectors) * 512];
+
+    {
+        let mbr = &mut image[..512];
+        let entry = &mut mbr[446..462];
+        entry[4] = 0x0C;
+        LittleEndian::write_u32(&mut entry[8..12], MOCK_PARTITION_START as u32);
+        LittleEndian::write_u32(&mut entry[12..16], total_sectors as u32);
+        mbr[510..].copy_from_slice(&[0x55, 0xAA]);
+    }
+
+    {
+        let start = MOCK_PARTITION_START * 512;
+        let bpb = &mut image[start..start + 512];
+        bpb[..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+        bpb[3..11].copy_from_slice(b"MSWIN4.1");
+        LittleEndian::write_u16(&mut bpb[11..13], 512);
+        bpb[13] = 1;
+        LittleEndian::write_u16(&mut bpb[14..16], MOCK_RESERVED_SECTORS as u16);
+        bpb[16] = 2;
+        bpb[21] = 0xF8;
+        LittleEndian::write_u32(&mut bpb[28..32], MOCK_PARTITION_START as u32);
+        LittleEndian::write_u32(&mut bpb[32..36], total_sectors as u32);
+        LittleEndian::write_u32(&mut bpb[36..40], sectors_per_fat as u32);
+        LittleEndian::write_u32(&mut bpb[44..48], 2);
+        LittleEndian::write_u16(&mut bpb[48..50], 1);
+        LittleEndian::write_u16(&mut bpb[50..52], 6);
+        bpb[66] = 0x29;
+        LittleEndian::write_u32(&mut bpb[67..71], 0xC0FFEE);
+        bpb[71..82].copy_from_slice(b"MOCK       ");
+        bpb[82..90].copy_from_slice(b"FAT32   ");
+        bpb[510..].copy_from_slice(&[0x55, 0xAA]);
+    }
+
+    mock_set_fat(&mut image, 0, 0x0FFFFFF8);
+    mock_set_fat(&mut image, 1, 0x0FFFFFFF);
+    mock_set_fat(&mut image, 2, 0x0FFFFFFF);
+    image
+}
+
+/// Sets the raw FAT entry for `cluster` in every FAT of a `mock_volume()`.
+fn mock_set_fat(image: &mut Vec<u8>, cluster: u32, value: u32) {
+    use byteorder::{ByteOrder, LittleEndian};
+
+    let sectors_per_fat = {
+        let bpb = &image[MOCK_PARTITION_START * 512..];
+        LittleEndian::read_u32(&bpb[36..40]) as usize
+    };
+
+    for fat in 0..2 {
+        let fat_start =
+            (MOCK_PARTITION_START + MOCK_RESERVED_SECTORS + fat * sectors_per_fat) * 512;
+        let offset = fat_start + cluster as usize * 4;
+        LittleEndian::write_u32(&mut image[offset..offset + 4], value);
+    }
+}
+
+/// Returns the byte offset of `cluster` in a `mock_volume()` image.
+fn mock_cluster_offset(image: &[u8], cluster: u32) -> usize {
+    use byteorder::{ByteOrder, LittleEndian};
+
+    let bpb = &image[MOCK_PARTITION_START * 512..];
+    let sectors_per_fat = LittleEndian::read_u32(&bpb[36..40]) as usize;
+    let data_start = MOCK_PARTITION_START + MOCK_RESERVED_SECTORS + 2 * sectors_per_fat;
+    (data_start + cluster as usize - 2) * 512
+}
+
+/// Builds a regular directory entry with the raw 11-byte name `name`.
+fn mock_dir_entry(name: &[u8; 11], attributes: u8, cluster: u32, size: u32) -> [u8; 32] {
+    use byteorder::{ByteOrder, LittleEndian};
+
+    let mut entry = [0u8; 32];
+    entry[..11].copy_from_slice(name);
+    entry[11] = attributes;
+    LittleEndian::write_u16(&mut entry[20..22], (cluster >> 16) as u16);
+    LittleEndian::write_u16(&mut entry[26..28], cluster as u16);
+    LittleEndian::write_u32(&mut entry[28..32], size);
+    entry
+}
+
+/// Builds a raw long file name entry holding the 13 UCS-2 units `units`.
+fn mock_lfn_entry(seq_no: u8, units: &[u16; 13], checksum: u8) -> [u8; 32] {
+    use byteorder::{ByteOrder, LittleEndian};
+
+    let mut entry = [0u8; 32];
+    entry[0] = seq_no;
+    entry[11] = 0x0F;
+    entry[13] = checksum;
+    let offsets = (1..11)
+        .step_by(2)
+        .chain((14..26).step_by(2))
+        .chain((28..32).step_by(2));
+    for (offset, unit) in offsets.zip(units.iter()) {
+        LittleEndian::write_u16(&mut entry[offset..offset + 2], *unit);
+    }
+    entry
+}
+
+/// Writes the raw 32-byte directory entry `entry` to slot `slot` of
+/// `cluster` in a `mock_volume()` image.
+fn mock_write_slot(image: &mut Vec<u8>, cluster: u32, slot: usize, entry: &[u8; 32]) {
+    let offset = mock_cluster_offset(image, cluster) + slot * 32;
+    image[offset..offset + 32].copy_from_slice(entry);
+}
+
+#[test]
+fn test_free_extents() {
+    let mut image = mock_volume(64);
+    // A three-cluster chain at 5..8 and a single cluster at 20.
+    mock_set_fat(&mut image, 5, 6);
+    mock_set_fat(&mut image, 6, 7);
+    mock_set_fat(&mut image, 7, 0x0FFFFFFF);
+    mock_set_fat(&mut image, 20, 0x0FFFFFFF);
+
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    let space = vfat.borrow_mut().free_extents().expect("free extents");
+
+    assert_eq!(vfat.borrow().num_clusters(), 64);
+    assert_eq!(space.free_clusters(), 64 - 5);
+    assert_eq!(space.extents.len(), 3);
+    assert_eq!(space.extents[0].start.0, 3);
+    assert_eq!(space.extents[0].len, 2);
+    assert_eq!(space.largest().unwrap().start.0, 21);
+    assert_eq!(space.histogram[1], 1);
+    assert_eq!(space.histogram[3], 1);
+    assert_eq!(space.histogram[5], 1);
+}
+
+/// A block device backed by a shared in-memory image, so tests can inspect
+/// the image after handing the device to a `VFat`. Counts device accesses.
+#[derive(Clone)]
+struct SharedImage(::std::sync::Arc<::std::sync::Mutex<SharedImageInner>>);
+
+struct SharedImageInner {
+    image: Cursor<Vec<u8>>,
+    reads: usize,
+    writes: usize,
+    media_changed: bool,
+    fail_writes: bool,
+}
+
+impl SharedImage {
+    fn new(image: Vec<u8>) -> SharedImage {
+        SharedImage(::std::sync::Arc::new(::std::sync::Mutex::new(
+            SharedImageInner {
+                image: Cursor::new(image),
+                reads: 0,
+                writes: 0,
+                media_changed: false,
+                fail_writes: false,
+            },
+        )))
+    }
+
+    fn image(&self) -> Vec<u8> {
+        self.0.lock().unwrap().image.get_ref().clone()
+    }
+
+    fn reads(&self) -> usize {
+        self.0.lock().unwrap().reads
+    }
+
+    fn writes(&self) -> usize {
+        self.0.lock().unwrap().writes
+    }
+
+    /// Makes every write fail, as a dying card's would, until called again
+    /// with `false`.
+    fn fail_writes(&self, fail: bool) {
+        self.0.lock().unwrap().fail_writes = fail;
+    }
+
+    /// Flips the bits of byte `offset` of the medium, as bit rot would.
+    fn flip(&self, offset: usize) {
+        let mut inner = self.0.lock().unwrap();
+        inner.image.get_mut()[offset] ^= 0xFF;
+    }
+
+    /// Replaces the medium with `image`, as if a card were swapped.
+    fn swap(&self, image: Vec<u8>) {
+        let mut inner = self.0.lock().unwrap();
+        inner.image = Cursor::new(image);
+        inner.media_changed = true;
+    }
+}
+
+impl BlockDevice for SharedImage {
+    fn media_changed(&mut self) -> bool {
+        ::std::mem::replace(&mut self.0.lock().unwrap().media_changed, false)
+    }
+
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> ::std::io::Result<usize> {
+        let mut inner = self.0.lock().unwrap();
+        inner.reads += 1;
+        inner.image.read_sector(n, buf)
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> ::std::io::Result<usize> {
+        let mut inner = self.0.lock().unwrap();
+        if inner.fail_writes {
+            return Err(::std::io::Error::new(
+                ::std::io::ErrorKind::Other,
+                "write failed",
+            ));
+        }
+        inner.writes += 1;
+        inner.image.write_sector(n, buf)
+    }
+}
+
+/// A device that records every sector written to it, in order.
+#[derive(Clone)]
+struct RecordingDevice(::std::sync::Arc<::std::sync::Mutex<RecordingInner>>);
+
+struct RecordingInner {
+    image: Cursor<Vec<u8>>,
+    log: Vec<(u64, Vec<u8>)>,
+}
+
+impl RecordingDevice {
+    fn new(image: Vec<u8>) -> RecordingDevice {
+        RecordingDevice(::std::sync::Arc::new(::std::sync::Mutex::new(
+            RecordingInner {
+                image: Cursor::new(image),
+                log: Vec::new(),
+            },
+        )))
+    }
+
+    /// The number of sectors written so far.
+    fn writes(&self) -> usize {
+        self.0.lock().unwrap().log.len()
+    }
+
+    /// Returns `image` with the first `count` recorded writes applied, as the
+    /// medium would be left by losing power after them.
+    fn replay(&self, image: &[u8], count: usize) -> Vec<u8> {
+        let mut replayed = Cursor::new(image.to_vec());
+        for &(sector, ref data) in &self.0.lock().unwrap().log[..count] {
+            replayed.write_sector(sector, data).unwrap();
+        }
+        replayed.into_inner()
+    }
+}
+
+impl BlockDevice for RecordingDevice {
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> ::std::io::Result<usize> {
+        self.0.lock().unwrap().image.read_sector(n, buf)
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> ::std::io::Result<usize> {
+        let mut inner = self.0.lock().unwrap();
+        inner
+            .log
+            .push((n, buf[..self.sector_size() as usize].to_vec()));
+        inner.image.write_sector(n, buf)
+    }
+}
+
+/// One mutation of a power-loss torture run: `run` mutates the volume, and
+/// `modifies` names the files whose contents it may change.
+struct TortureStep<'a> {
+    modifies: &'a [&'a str],
+    run: &'a Fn(&Shared<VFat>),
+}
+
+/// Runs `steps` in order against a volume on `image`, syncing after each,
+/// then replays every prefix of the sectors written onto a copy of `image`,
+/// as if power were lost after it. Each copy must mount and be repaired by
+/// `VFat::repair()`, after which every file in `files` must hold its contents
+/// as of the last completed sync, unless the interrupted step modifies it.
+fn power_loss_torture(image: Vec<u8>, files: &[&str], steps: &[TortureStep]) {
+    use vfat::RepairOptions;
+
+    let contents = |vfat: &Shared<VFat>| -> Vec<Vec<u8>> {
+        files
+            .iter()
+            .map(|path| {
+                let mut data = Vec::new();
+                vfat.open_file(path)
+                    .and_then(|mut file| file.read_to_end(&mut data))
+                    .unwrap_or_else(|e| panic!("reading {}: {}", path, e));
+                data
+            })
+            .collect()
+    };
+
+    let device = RecordingDevice::new(image.clone());
+    let vfat = VFat::from(device.clone()).expect("volume mounts");
+    let mut synced = vec![(0, contents(&vfat))];
+    for step in steps {
+        (step.run)(&vfat);
+        vfat.borrow_mut().sync().expect("sync");
+        synced.push((device.writes(), contents(&vfat)));
+    }
+
+    for count in 0..device.writes() + 1 {
+        // The last sync completed by `count` writes, and the step after it.
+        let last = synced.iter().rposition(|&(end, _)| end <= count).unwrap();
+        let interrupted = if count == synced[last].0 {
+            &[][..]
+        } else {
+            steps[last].modifies
+        };
+
+        let replayed = device.replay(&image, count);
+        let vfat = VFat::from(Cursor::new(replayed))
+            .unwrap_or_else(|e| panic!("unmountable after {} writes: {:?}", count, e));
+        vfat.borrow_mut()
+            .repair(RepairOptions::default())
+            .unwrap_or_else(|e| panic!("unrepairable after {} writes: {}", count, e));
+
+        let found = contents(&vfat);
+        for (i, path) in files.iter().enumerate() {
+            if !interrupted.contains(path) {
+                assert!(
+                    found[i] == synced[last].1[i],
+                    "{} lost after {} writes",
+                    path,
+                    count
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_power_loss_torture() {
+    let mut image = mock_volume(32);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"A       BIN", 0x20, 3, 3),
+    );
+    mock_write_slot(
+        &mut image,
+        2,
+        2,
+        &mock_dir_entry(b"B       BIN", 0x20, 4, 3),
+    );
+    mock_set_fat(&mut image, 3, 0x0FFFFFFF);
+    mock_set_fat(&mut image, 4, 0x0FFFFFFF);
+    let (a, b) = (
+        mock_cluster_offset(&image, 3),
+        mock_cluster_offset(&image, 4),
+    );
+    image[a..a + 3].copy_from_slice(b"aaa");
+    image[b..b + 3].copy_from_slice(b"bbb");
+    let mut deleted = mock_dir_entry(b"C       BIN", 0x20, 0, 0);
+    deleted[0] = 0xE5;
+    mock_write_slot(&mut image, 2, 1, &deleted);
+
+    let replace = |path: &'static str, data: Vec<u8>| {
+        move |vfat: &Shared<VFat>| {
+            let mut file = vfat.open_file(path).expect("file");
+            file.replace_contents(&data).expect("replace");
+        }
+    };
+    let grow_a = replace("/A.BIN", (0..1500).map(|i| i as u8).collect());
+    let grow_b = replace("/B.BIN", vec![0xBB; 700]);
+    let shrink_a = replace("/A.BIN", b"short".to_vec());
+    let compact = |vfat: &Shared<VFat>| {
+        vfat.open_dir("/").unwrap().compact().expect("compaction");
+    };
+
+    power_loss_torture(
+        image,
+        &["/A.BIN", "/B.BIN"],
+        &[
+            TortureStep {
+                modifies: &["/A.BIN"],
+                run: &grow_a,
+            },
+            TortureStep {
+                modifies: &["/B.BIN"],
+                run: &grow_b,
+            },
+            TortureStep {
+                modifies: &["/A.BIN"],
+                run: &shrink_a,
+            },
+            TortureStep {
+                modifies: &[],
+                run: &compact,
+            },
+        ],
+    );
+}
+
+#[test]
+fn test_write_cluster_skips_read_when_aligned() {
+    let device = SharedImage::new(mock_volume(16));
+    let vfat = VFat::from(device.clone()).expect("mock volume mounts");
+    let data_start = (MOCK_PARTITION_START + MOCK_RESERVED_SECTORS + 2) * 512;
+
+    let reads = device.reads();
+    let full = [0xAB; 512];
+    assert_eq!(
+        vfat.borrow_mut()
+            .write_cluster(::vfat::Cluster(4), 0, &full)
+            .unwrap(),
+        512
+    );
+    assert_eq!(device.reads(), reads, "aligned write read from the device");
+
+    let partial = [0xCD; 16];
+    assert_eq!(
+        vfat.borrow_mut()
+            .write_cluster(::vfat::Cluster(5), 100, &partial)
+            .unwrap(),
+        16
+    );
+    assert_eq!(
+        device.reads(),
+        reads + 1,
+        "partial write must read the sector"
+    );
+
+    assert_eq!(device.writes(), 0);
+    vfat.borrow_mut().sync().expect("sync");
+    assert_eq!(device.writes(), 2);
+
+    let image = device.image();
+    let cluster4 = data_start + 2 * 512;
+    let cluster5 = data_start + 3 * 512;
+    assert!(image[cluster4..cluster4 + 512].iter().all(|&b| b == 0xAB));
+    assert!(image[cluster5..cluster5 + 100].iter().all(|&b| b == 0));
+    assert!(image[cluster5 + 100..cluster5 + 116]
+        .iter()
+        .all(|&b| b == 0xCD));
+    assert!(image[cluster5 + 116..cluster5 + 512]
+        .iter()
+        .all(|&b| b == 0));
+}
+
+#[test]
+fn test_allocate_batches_and_mirrors() {
+    use byteorder::{ByteOrder, LittleEndian};
+
+    let mut image = mock_volume(300);
+    // Cluster 3 is free but has junk in the reserved high nibble.
+    mock_set_fat(&mut image, 3, 0xF0000000);
+    mock_set_fat(&mut image, 10, 0x0FFFFFFF);
+
+    let device = SharedImage::new(image);
+    let vfat = VFat::from(device.clone()).expect("mock volume mounts");
+    let first = vfat.borrow_mut().allocate(200, None).expect("allocation");
+    let tail = vfat
+        .borrow_mut()
+        .allocate(2, Some(::vfat::Cluster(10)))
+        .expect("allocation");
+    let e = vfat.borrow_mut().allocate(0, None).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidInput);
+    vfat.borrow_mut().sync().expect("sync");
+
+    assert_eq!(first.0, 3);
+    assert_eq!(tail.0, 204);
+
+    let image = device.image();
+    let sectors_per_fat = (302 * 4 + 511) / 512;
+    let fat = |copy: usize, cluster: u32| {
+        let start = (MOCK_PARTITION_START + MOCK_RESERVED_SECTORS + copy * sectors_per_fat) * 512;
+        LittleEndian::read_u32(&image[start + cluster as usize * 4..])
+    };
+
+    assert_eq!(fat(0, 3), 0xF0000004);
+    assert_eq!(fat(0, 9), 11);
+    assert_eq!(fat(0, 10), 204);
+    assert_eq!(fat(0, 202), 203);
+    assert_eq!(fat(0, 203), 0x0FFFFFFF);
+    assert_eq!(fat(0, 204), 205);
+    assert_eq!(fat(0, 205), 0x0FFFFFFF);
+    for cluster in 0..302 {
+        assert_eq!(
+            fat(0, cluster),
+            fat(1, cluster),
+            "FAT mirror differs at {}",
+            cluster
+        );
+    }
+}
+
+#[test]
+fn test_dir_compact() {
+    let mut image = mock_volume(16);
+    mock_set_fat(&mut image, 2, 3);
+    mock_set_fat(&mut image, 3, 0x0FFFFFFF);
+
+    let mut deleted = mock_dir_entry(b"B       TXT", 0x20, 0, 0);
+    deleted[0] = 0xE5;
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"A       TXT", 0x20, 0, 0),
+    );
+    for slot in 1..16 {
+        mock_write_slot(&mut image, 2, slot, &deleted);
+    }
+    mock_write_slot(
+        &mut image,
+        3,
+        0,
+        &mock_dir_entry(b"C       TXT", 0x20, 0, 0),
+    );
+
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    let root = (&vfat).open_dir("/").expect("root directory");
+    assert_eq!(root.deleted_slots().unwrap(), 15);
+    assert_eq!(root.compact().expect("compaction"), 15);
+    assert_eq!(root.deleted_slots().unwrap(), 0);
+
+    let names: Vec<_> = root
+        .entries()
+        .expect("entries iterator")
+        .map(|e| e.name().to_string())
+        .collect();
+    assert_eq!(names, vec!["A.TXT", "C.TXT"]);
+    assert_eq!(
+        vfat.borrow_mut().chain(::vfat::Cluster(2)).unwrap(),
+        vec![::vfat::Cluster(2)]
+    );
+    assert!(vfat.borrow_mut().free_extents().unwrap().extents[0].contains(::vfat::Cluster(3)));
+}
+
+#[test]
+fn test_auto_compact() {
+    use vfat::MountOptions;
+
+    let mut image = mock_volume(16);
+    let mut deleted = mock_dir_entry(b"B       TXT", 0x20, 0, 0);
+    deleted[0] = 0xE5;
+    for slot in 0..3 {
+        mock_write_slot(&mut image, 2, slot, &deleted);
+    }
+    mock_write_slot(
+        &mut image,
+        2,
+        3,
+        &mock_dir_entry(b"A       TXT", 0x20, 0, 0),
+    );
+
+    // A read-only volume is left untouched, and its handles stay usable.
+    let read_only = MountOptions {
+        read_only: true,
+        ..Default::default()
+    };
+    let vfat = VFat::with_options(Cursor::new(image.clone()), read_only).unwrap();
+    let root = (&vfat).open_dir("/").expect("root directory");
+    let mut a = (&vfat).open_file("/A.TXT").expect("file");
+    let e = root.compact().unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::PermissionDenied);
+    assert_eq!(root.deleted_slots().unwrap(), 3);
+    a.refresh_metadata().expect("handle is not stale");
+
+    // Below the threshold, a new entry reuses the first deleted slot.
+    let options = MountOptions {
+        auto_compact_threshold: Some(4),
+        ..Default::default()
+    };
+    let vfat = VFat::with_options(Cursor::new(image.clone()), options).unwrap();
+    let root = (&vfat).open_dir("/").expect("root directory");
+    assert_eq!(root.compact_if_needed().unwrap(), 0);
+    let new = (&vfat).create_file("/NEW.TXT").expect("create");
+    assert_eq!(new.location.map(|location| location.slot), Some(0));
+    assert_eq!(root.deleted_slots().unwrap(), 2);
+
+    // At it, the directory is compacted before the file is created.
+    let options = MountOptions {
+        auto_compact_threshold: Some(3),
+        ..Default::default()
+    };
+    let vfat = VFat::with_options(Cursor::new(image), options).unwrap();
+    let root = (&vfat).open_dir("/").expect("root directory");
+    let new = (&vfat).create_file("/NEW.TXT").expect("create");
+    assert_eq!(new.location.map(|location| location.slot), Some(1));
+    assert_eq!(root.deleted_slots().unwrap(), 0);
+}
+
+#[test]
+fn test_fixed_root_region() {
+    use byteorder::{ByteOrder, LittleEndian};
+
+    // Declare a one-sector root directory region of 16 slots, as FAT12 and
+    // FAT16 do, in place of the root cluster. The region takes the sector
+    // that was cluster 2's, so cluster `n` now lies where `n + 1` was.
+    let mut image = mock_volume(16);
+    {
+        let bpb = &mut image[MOCK_PARTITION_START * 512..];
+        LittleEndian::write_u16(&mut bpb[17..19], 16);
+        LittleEndian::write_u32(&mut bpb[44..48], 0);
+    }
+    let region = mock_cluster_offset(&image, 2);
+
+    let mut deleted = mock_dir_entry(b"OLD     TXT", 0x20, 0, 0);
+    deleted[0] = 0xE5;
+    mock_write_slot(&mut image, 2, 0, &deleted);
+    mock_write_slot(
+        &mut image,
+        2,
+        1,
+        &mock_dir_entry(b"KERNEL  IMG", 0x20, 3, 5),
+    );
+    mock_write_slot(
+        &mut image,
+        2,
+        2,
+        &mock_dir_entry(b"BOOT       ", 0x10, 4, 0),
+    );
+    mock_set_fat(&mut image, 3, 0x0FFFFFFF);
+    mock_set_fat(&mut image, 4, 0x0FFFFFFF);
+    let kernel = mock_cluster_offset(&image, 4);
+    image[kernel..kernel + 5].copy_from_slice(b"hello");
+    mock_write_slot(
+        &mut image,
+        5,
+        0,
+        &mock_dir_entry(b"CONFIG  TXT", 0x20, 0, 0),
+    );
+
+    let device = SharedImage::new(image);
+    let vfat = VFat::from(device.clone()).expect("mock volume mounts");
+    let root = (&vfat).open_dir("/").expect("root directory");
+    let names: Vec<_> = root
+        .entries()
+        .expect("entries iterator")
+        .map(|e| e.name().to_string())
+        .collect();
+    assert_eq!(names, vec!["KERNEL.IMG", "BOOT"]);
+
+    let mut data = Vec::new();
+    (&vfat)
+        .open_file("/KERNEL.IMG")
+        .expect("kernel")
+        .read_to_end(&mut data)
+        .expect("read");
+    assert_eq!(data, b"hello");
+    (&vfat).open_file("/BOOT/CONFIG.TXT").expect("config");
+
+    // Entries are rewritten in place, and the region keeps its size.
+    assert_eq!(root.compact().expect("compaction"), 1);
+    let mut kernel = (&vfat).open_file("/KERNEL.IMG").expect("kernel");
+    assert_eq!(kernel.location().map(|l| l.slot), Some(0));
+    kernel.replace_contents(b"hi").expect("replace");
+    vfat.borrow_mut().sync().expect("sync");
+
+    let image = device.image();
+    assert_eq!(&image[region..region + 11], b"KERNEL  IMG");
+    assert_eq!(LittleEndian::read_u32(&image[region + 28..region + 32]), 2);
+    assert_eq!(&image[region + 32..region + 43], b"BOOT       ");
+    assert!(image[region + 64..region + 512].iter().all(|&b| b == 0));
+}
+
+#[test]
+fn test_size_and_cluster_mismatch() {
+    use vfat::{extract, ClusterlessFile, FatMode, MountOptions};
+
+    // An empty file pointing at a free cluster, and a sized file with no
+    // cluster, which would otherwise read the root directory's cluster.
+    let mut image = mock_volume(16);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"EMPTY   BIN", 0x20, 5, 0),
+    );
+    mock_write_slot(
+        &mut image,
+        2,
+        1,
+        &mock_dir_entry(b"GHOST   BIN", 0x20, 0, 100),
+    );
+
+    let mount = |clusterless_files| {
+        let options = MountOptions {
+            fat_mode: FatMode::Strict,
+            clusterless_files,
+            ..Default::default()
+        };
+        VFat::with_options(Cursor::new(image.clone()), options).expect("mock volume mounts")
+    };
+
+    let vfat = mount(ClusterlessFile::Empty);
+    let mut data = Vec::new();
+    let mut empty = (&vfat).open_file("/EMPTY.BIN").expect("empty");
+    assert_eq!(empty.read_to_end(&mut data).expect("read"), 0);
+
+    let mut ghost = (&vfat).open_file("/GHOST.BIN").expect("ghost");
+    assert_eq!(ghost.size(), 100);
+    assert_eq!(ghost.read_to_end(&mut data).expect("read"), 0);
+    assert_eq!(extract(&ghost, &mut data, None, None).expect("extract"), 0);
+    assert!(data.is_empty());
+
+    let vfat = mount(ClusterlessFile::Fail);
+    let mut ghost = (&vfat).open_file("/GHOST.BIN").expect("ghost");
+    let e = ghost.read_to_end(&mut data).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidData);
+    let mut empty = (&vfat).open_file("/EMPTY.BIN").expect("empty");
+    assert_eq!(empty.read_to_end(&mut data).expect("read"), 0);
+}
+
+#[test]
+fn test_fat_mode_strictness() {
+    use vfat::{FatMode, MountOptions};
+
+    let mut image = mock_volume(16);
+    mock_set_fat(&mut image, 2, 0xFFFFFFF8);
+
+    let tolerant = VFat::from(Cursor::new(image.clone())).expect("mock volume mounts");
+    (&tolerant)
+        .open_dir("/")
+        .unwrap()
+        .entries()
+        .expect("tolerant read");
+
+    let options = MountOptions {
+        fat_mode: FatMode::Strict,
+        ..Default::default()
+    };
+    let strict = VFat::with_options(Cursor::new(image.clone()), options.clone()).unwrap();
+    match (&strict).open_dir("/").unwrap().entries() {
+        Err(e) => assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidData),
+        Ok(_) => panic!("strict mode accepted a nonconforming FAT entry"),
+    }
+
+    mock_set_fat(&mut image, 2, 0x0FFFFFF8);
+    let strict = VFat::with_options(Cursor::new(image), options).unwrap();
+    (&strict)
+        .open_dir("/")
+        .unwrap()
+        .entries()
+        .expect("strict read");
+}
+
+#[test]
+fn test_fat_entries() {
+    use vfat::{Cluster, FatEntry, Status};
+
+    let mut image = mock_volume(8);
+    mock_set_fat(&mut image, 3, 4);
+    mock_set_fat(&mut image, 4, 0x0FFFFFF8);
+    mock_set_fat(&mut image, 5, 0x0FFFFFF7);
+
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    let entries: Vec<_> = vfat
+        .borrow_mut()
+        .fat_entries()
+        .collect::<::std::io::Result<_>>()
+        .expect("fat entries");
+    assert_eq!(entries.len(), 10);
+
+    let (cluster, entry) = entries[3];
+    assert_eq!(cluster, Cluster(3));
+    assert_eq!(entry.raw(), 4);
+    assert_eq!(entry.next(), Some(Cluster(4)));
+    assert_eq!(entry.to_string(), "next 4");
+
+    assert!(entries[4].1.is_eoc());
+    assert_eq!(entries[4].1.status(), Status::Eoc(0x0FFFFFF8));
+    assert!(entries[5].1.is_bad());
+    assert!(entries[6].1.is_free());
+    assert_eq!(entries[6].1.next(), None);
+
+    // Every cluster a volume may have can be linked to.
+    let last = ::consts::MAX_CLUSTERS + 1;
+    assert_eq!(FatEntry(last).status(), Status::Data(Cluster(last)));
+    assert_eq!(FatEntry(last + 1).status(), Status::Reserved);
+}
+
+#[test]
+fn test_chain_loop() {
+    use vfat::Cluster;
+
+    let mut image = mock_volume(8);
+    mock_set_fat(&mut image, 3, 4);
+    mock_set_fat(&mut image, 4, 5);
+    mock_set_fat(&mut image, 5, 3);
+
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    let e = vfat.borrow_mut().chain(Cluster(3)).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_set_fat_entry_mirrors() {
+    use byteorder::{ByteOrder, LittleEndian};
+    use vfat::{Cluster, Status};
+
+    let mut image = mock_volume(8);
+    mock_set_fat(&mut image, 5, 0xF0000000);
+    let device = SharedImage::new(image);
+    let vfat = VFat::from(device.clone()).expect("mock volume mounts");
+
+    vfat.borrow_mut()
+        .set_fat_entry(Cluster(5), Status::Bad)
+        .expect("set entry");
+    vfat.borrow_mut()
+        .set_fat_entry(Cluster(6), Status::Data(Cluster(7)))
+        .expect("set entry");
+    assert!(vfat
+        .borrow_mut()
+        .set_fat_entry(Cluster(10), Status::Free)
+        .is_err());
+    vfat.borrow_mut().sync().expect("sync");
+
+    let image = device.image();
+    for fat in 0..2 {
+        let start = (MOCK_PARTITION_START + MOCK_RESERVED_SECTORS + fat) * 512;
+        assert_eq!(LittleEndian::read_u32(&image[start + 20..]), 0xFFFFFFF7);
+        assert_eq!(LittleEndian::read_u32(&image[start + 24..]), 7);
+    }
+}
+
+#[test]
+fn test_geometry_mismatch() {
+    use vfat::{Error, GeometryCheck, MountOptions};
+
+    let image = mock_volume(16);
+    let vfat = VFat::from(Cursor::new(image.clone())).expect("mock volume mounts");
+    assert!(vfat.borrow().geometry_warning().is_none());
+    assert_eq!(vfat.borrow().num_clusters(), 16);
+
+    let mut short = image.clone();
+    let len = short.len();
+    short.truncate(len - 4 * 512);
+
+    let vfat = VFat::from(Cursor::new(short.clone())).expect("clamped mount");
+    assert_eq!(vfat.borrow().num_clusters(), 12);
+    match vfat.borrow().geometry_warning() {
+        Some(&Error::GeometryMismatch { source, actual, .. }) => {
+            assert_eq!(source, "device");
+            assert_eq!(actual, 12 + (MOCK_RESERVED_SECTORS + 2) as u64);
+        }
+        other => panic!("unexpected geometry warning: {:?}", other),
+    }
+
+    let options = MountOptions {
+        geometry_check: GeometryCheck::Fail,
+        ..Default::default()
+    };
+    match VFat::with_options(Cursor::new(short), options) {
+        Err(Error::GeometryMismatch { source, .. }) => assert_eq!(source, "device"),
+        _ => panic!("expected a geometry mismatch"),
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn test_find_non_utf8_name() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    use vfat::lfn_checksum;
+
+    let short = *b"_X      TXT";
+    let mut units = [0xFFFF; 13];
+    units[..7].copy_from_slice(&[0xD800, 0x78, 0x2E, 0x74, 0x78, 0x74, 0]);
+
+    let mut image = mock_volume(16);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_lfn_entry(0x41, &units, lfn_checksum(&short)),
+    );
+    mock_write_slot(&mut image, 2, 1, &mock_dir_entry(&short, 0x20, 0, 0));
+
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    let root = (&vfat).open_dir("/").expect("root directory");
+
+    let name = OsStr::from_bytes(b"\xED\xA0\x80X.TXT");
+    let entry = root.find(name).expect("lone surrogate name");
+    assert_eq!(entry.metadata().long_name, &units[..6]);
+
+    let e = root.find(OsStr::from_bytes(b"\xFFx.txt")).err().unwrap();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidInput);
+    let e = root
+        .find(OsStr::from_bytes(b"\xED\xA0\x81x.txt"))
+        .err()
+        .unwrap();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::NotFound);
+}
+
+#[test]
+fn test_extract_progress_and_cancel() {
+    use vfat::{extract, CancelToken};
+
+    let mut image = mock_volume(16);
+    mock_set_fat(&mut image, 3, 4);
+    mock_set_fat(&mut image, 4, 5);
+    mock_set_fat(&mut image, 5, 0x0FFFFFFF);
+    for (i, cluster) in (3..6).enumerate() {
+        let offset = mock_cluster_offset(&image, cluster);
+        for byte in &mut image[offset..offset + 512] {
+            *byte = i as u8 + 1;
+        }
+    }
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"DATA    BIN", 0x20, 3, 1100),
+    );
+
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    let file = (&vfat).open_file("/DATA.BIN").expect("file");
+
+    let mut out = Vec::new();
+    let mut reports = Vec::new();
+    let copied = extract(
+        &file,
+        &mut out,
+        Some(&mut |done, total| reports.push((done, total))),
+        None,
+    )
+    .expect("extract");
+    assert_eq!(copied, 1100);
+    assert_eq!(out.len(), 1100);
+    assert_eq!((out[0], out[600], out[1099]), (1, 2, 3));
+    assert_eq!(reports, vec![(512, 1100), (1024, 1100), (1100, 1100)]);
+
+    let token = CancelToken::new();
+    let canceller = token.clone();
+    let mut out = Vec::new();
+    let e = extract(
+        &file,
+        &mut out,
+        Some(&mut |done, _| {
+            if done >= 512 {
+                canceller.cancel()
+            }
+        }),
+        Some(&token),
+    )
+    .unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::Other);
+    assert_eq!(out.len(), 512);
+}
+
+#[test]
+fn test_dir_snapshot() {
+    let mut image = mock_volume(16);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"A       TXT", 0x20, 0, 0),
+    );
+    mock_write_slot(
+        &mut image,
+        2,
+        1,
+        &mock_dir_entry(b"B       TXT", 0x22, 0, 0),
+    );
+
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    let root = (&vfat).open_dir("/").expect("root directory");
+    let snapshot = root.snapshot().expect("snapshot");
+
+    vfat.borrow_mut()
+        .write_cluster(::vfat::Cluster(2), 0, &[0xE5])
+        .expect("delete entry");
+
+    let names: Vec<_> = snapshot.iter().map(|m| m.name.as_str()).collect();
+    assert_eq!(names, vec!["A.TXT", "B.TXT"]);
+
+    let after = root.snapshot().expect("snapshot");
+    assert_eq!(after.len(), 1);
+    assert_eq!(after[0].name, "B.TXT");
+}
+
+#[test]
+fn test_grow_relocates_clusters() {
+    use vfat::resize;
+
+    let mut image = mock_volume(120);
+    mock_set_fat(&mut image, 3, 4);
+    mock_set_fat(&mut image, 4, 0x0FFFFFFF);
+    for cluster in 3..5 {
+        let offset = mock_cluster_offset(&image, cluster);
+        for byte in &mut image[offset..offset + 512] {
+            *byte = cluster as u8;
+        }
+    }
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"DATA    BIN", 0x20, 3, 1024),
+    );
+
+    let new_total = 1024;
+    image.resize((MOCK_PARTITION_START + new_total) * 512, 0);
+    let mut device = Cursor::new(image);
+    assert!(resize::grow(&mut device, 16).is_err());
+    let clusters = resize::grow(&mut device, new_total as u64).expect("grow");
+    assert_eq!(clusters, 1024 - 4 - 2 * 8);
+
+    let vfat = VFat::from(device).expect("grown volume mounts");
+    assert!(vfat.borrow().geometry_warning().is_none());
+    assert_eq!(vfat.borrow().num_clusters(), clusters);
+    assert_eq!(
+        vfat.borrow_mut().free_extents().unwrap().free_clusters(),
+        clusters as u64 - 3
+    );
+
+    let mut file = (&vfat).open_file("/DATA.BIN").expect("file");
+    let mut data = Vec::new();
+    file.read_to_end(&mut data).expect("read");
+    assert_eq!(data.len(), 1024);
+    assert!(data[..512].iter().all(|&b| b == 3));
+    assert!(data[512..].iter().all(|&b| b == 4));
+}
+
+#[test]
+fn test_used_sector_bitmap() {
+    let mut image = mock_volume(64);
+    mock_set_fat(&mut image, 5, 6);
+    mock_set_fat(&mut image, 6, 0x0FFFFFFF);
+    mock_set_fat(&mut image, 9, 0x0FFFFFF7);
+
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    let bitmap = vfat.borrow_mut().used_sector_bitmap().expect("bitmap");
+
+    // 4 reserved sectors and two one-sector FATs precede cluster 2.
+    let data_start = (MOCK_RESERVED_SECTORS + 2) as u64;
+    assert_eq!(bitmap.len(), data_start + 64);
+    assert_eq!(bitmap.used_sectors(), data_start + 3);
+    assert_eq!(
+        bitmap.used_runs(),
+        vec![(0, data_start + 1), (data_start + 3, 2)]
+    );
+    assert!(!bitmap.is_used(data_start + 7));
+    assert!(!bitmap.is_used(data_start + 64));
+}
+
+#[test]
+fn test_sparse_round_trip() {
+    use vfat::restore_sparse;
+
+    let mut image = mock_volume(256);
+    mock_set_fat(&mut image, 3, 0x0FFFFFFF);
+    let offset = mock_cluster_offset(&image, 3);
+    image[offset..offset + 5].copy_from_slice(b"hello");
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"HELLO   TXT", 0x20, 3, 5),
+    );
+
+    let vfat = VFat::from(Cursor::new(image.clone())).expect("mock volume mounts");
+    let mut dump = Vec::new();
+    let dumped = vfat.borrow_mut().dump_sparse(&mut dump).expect("dump");
+    // The MBR area, 4 reserved sectors, 2 three-sector FATs and 2 clusters.
+    assert_eq!(dumped, (MOCK_PARTITION_START + 4 + 6 + 2) as u64 * 512);
+    assert!(dump.len() < image.len() / 10);
+
+    let mut restored = Cursor::new(vec![0u8; image.len()]);
+    assert_eq!(
+        restore_sparse(&mut Cursor::new(&dump[..]), &mut restored).expect("restore"),
+        dumped
+    );
+
+    let vfat = VFat::from(restored).expect("restored volume mounts");
+    let mut file = (&vfat).open_file("/HELLO.TXT").expect("file");
+    let mut data = String::new();
+    file.read_to_string(&mut data).expect("read");
+    assert_eq!(data, "hello");
+
+    assert!(restore_sparse(&mut Cursor::new(&image[..]), &mut Cursor::new(Vec::new())).is_err());
+}
+
+#[test]
+fn test_file_advice() {
+    use std::io::SeekFrom;
+    use vfat::Advice;
+
+    let mut image = mock_volume(64);
+    for cluster in 3..24 {
+        mock_set_fat(&mut image, cluster, cluster + 1);
+        let offset = mock_cluster_offset(&image, cluster);
+        for byte in &mut image[offset..offset + 512] {
+            *byte = cluster as u8;
+        }
+    }
+    mock_set_fat(&mut image, 23, 0x0FFFFFFF);
+    let size = 20 * 512 + 100;
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"DATA    BIN", 0x20, 3, size),
+    );
+
+    let device = SharedImage::new(image);
+    let vfat = VFat::from(device.clone()).expect("mock volume mounts");
+    let mut file = (&vfat).open_file("/DATA.BIN").expect("file");
+
+    file.advise(Advice::Random);
+    let reads = device.reads();
+    let mut byte = [0];
+    file.seek(SeekFrom::Start(10 * 512 + 7)).unwrap();
+    file.read_exact(&mut byte).unwrap();
+    assert_eq!(byte[0], 13);
+    // Only the cluster holding the byte is read; the FAT is already cached.
+    assert_eq!(device.reads() - reads, 1);
+
+    file.advise(Advice::Sequential);
+    file.seek(SeekFrom::Start(0)).unwrap();
+    let mut data = Vec::new();
+    file.read_to_end(&mut data).unwrap();
+    assert_eq!(data.len(), size as usize);
+    assert!((0..21).all(|i| data[i * 512] == i as u8 + 3));
+
+    // Sequentially read clusters were evicted and must be read again.
+    let reads = device.reads();
+    let mut other = (&vfat).open_file("/DATA.BIN").expect("file");
+    other.read_to_end(&mut Vec::new()).unwrap();
+    assert!(device.reads() - reads >= 16);
+}
+
+#[test]
+fn test_io_metrics_and_slow_hook() {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use vfat::IoOp;
+
+    let vfat = VFat::from(Cursor::new(mock_volume(16))).expect("mock volume mounts");
+    vfat.borrow_mut().take_io_metrics();
+
+    let slow = Arc::new(Mutex::new(Vec::new()));
+    let seen = slow.clone();
+    vfat.borrow_mut().set_slow_io_hook(
+        Duration::from_secs(0),
+        Box::new(move |op, sector, _| seen.lock().unwrap().push((op, sector))),
+    );
+
+    vfat.borrow_mut()
+        .write_cluster(::vfat::Cluster(3), 0, &[1])
+        .expect("write");
+    vfat.borrow_mut().sync().expect("sync");
+
+    let metrics = vfat.borrow_mut().take_io_metrics();
+    assert_eq!(metrics.reads.count(), 1);
+    assert_eq!(metrics.writes.count(), 1);
+    assert_eq!(vfat.borrow_mut().take_io_metrics().reads.count(), 0);
+
+    let data_sector = (MOCK_PARTITION_START + MOCK_RESERVED_SECTORS + 2 + 1) as u64;
+    let slow = slow.lock().unwrap();
+    // Operations that take no measurable time do not exceed the threshold.
+    assert!(slow.len() <= 2);
+    assert!(slow
+        .iter()
+        .all(|&(op, sector)| sector == data_sector && (op == IoOp::Read || op == IoOp::Write)));
+}
+
+#[test]
+fn test_describe() {
+    let mut image = mock_volume(64);
+    mock_set_fat(&mut image, 1, 0x0FFFFFFF);
+    mock_set_fat(&mut image, 5, 0x0FFFFFFF);
+
+    let vfat = VFat::from(Cursor::new(image.clone())).expect("mock volume mounts");
+    let report = vfat.borrow_mut().describe().expect("report");
+    assert_eq!(report.fat_type, "FAT32");
+    assert_eq!(report.label, "MOCK");
+    assert_eq!(report.serial, 0xC0FFEE);
+    assert_eq!(report.num_clusters, 64);
+    assert_eq!(report.free_clusters, 62);
+    assert_eq!(report.active_fat, None);
+    assert!(!report.dirty);
+    assert!(!report.used_backup_boot_sector);
+    assert!(report
+        .to_string()
+        .starts_with("FAT32 volume \"MOCK\" (serial 00C0-FFEE)"));
+    assert!(!report.to_string().contains("hidden sectors"));
+
+    // Break the primary boot sector, keep a backup, and mark the volume dirty.
+    let bpb = MOCK_PARTITION_START * 512;
+    let backup = (MOCK_PARTITION_START + 6) * 512;
+    let sector = image[bpb..bpb + 512].to_vec();
+    mock_set_fat(&mut image, 1, 0x07FFFFFF);
+    image[backup..backup + 512].copy_from_slice(&sector);
+    image[bpb + 510] = 0;
+
+    let vfat = VFat::from(Cursor::new(image)).expect("mounts from backup");
+    let report = vfat.borrow_mut().describe().expect("report");
+    assert!(report.dirty);
+    assert!(report.used_backup_boot_sector);
+}
+
+#[test]
+fn test_fat_header() {
+    use byteorder::{ByteOrder, LittleEndian};
+    use vfat::{Cluster, Error, FatMode, MountOptions};
+
+    let vfat = VFat::from(Cursor::new(mock_volume(16))).expect("mock volume mounts");
+    let header = vfat.borrow_mut().fat_header().unwrap();
+    assert_eq!(header.media_descriptor(), 0xF8);
+    assert!(header.clean_shutdown());
+    assert!(!header.hard_error());
+    assert!(vfat.borrow().media_warning().is_none());
+    assert_eq!(vfat.borrow_mut().describe().unwrap().media_descriptor, 0xF8);
+
+    // A FAT whose media descriptor differs from the BPB's only mounts in
+    // tolerant mode.
+    let mut image = mock_volume(16);
+    mock_set_fat(&mut image, 0, 0x0FFFFFF0);
+    let vfat = VFat::from(Cursor::new(image.clone())).expect("tolerant mount");
+    match vfat.borrow().media_warning() {
+        Some(&Error::MediaMismatch {
+            bpb: 0xF8,
+            fat: 0xF0,
+        }) => {}
+        warning => panic!("unexpected warning: {:?}", warning),
+    }
+    let mut options = MountOptions::default();
+    options.fat_mode = FatMode::Strict;
+    match VFat::with_options(Cursor::new(image), options) {
+        Err(Error::MediaMismatch {
+            bpb: 0xF8,
+            fat: 0xF0,
+        }) => {}
+        other => panic!("unexpected mount result: {:?}", other.map(|_| ())),
+    }
+
+    // The second entry of both FATs, as on disk.
+    let states = |image: &[u8]| -> Vec<u32> {
+        let fat = (MOCK_PARTITION_START + MOCK_RESERVED_SECTORS) * 512;
+        vec![
+            LittleEndian::read_u32(&image[fat + 4..]),
+            LittleEndian::read_u32(&image[fat + 512 + 4..]),
+        ]
+    };
+
+    // Without the option, the entry is left alone.
+    let device = SharedImage::new(mock_volume(16));
+    let vfat = VFat::from(device.clone()).expect("mock volume mounts");
+    vfat.borrow_mut()
+        .write_cluster(Cluster(3), 0, &[1])
+        .unwrap();
+    vfat.borrow_mut().sync().unwrap();
+    assert_eq!(states(&device.image()), vec![0x0FFFFFFF; 2]);
+
+    // With it, the volume is marked dirty on disk until changes are synced.
+    let mut options = MountOptions::default();
+    options.mark_dirty = true;
+    let device = SharedImage::new(mock_volume(16));
+    let vfat = VFat::with_options(device.clone(), options.clone()).expect("mounts");
+    vfat.borrow_mut()
+        .write_cluster(Cluster(3), 0, &[1])
+        .unwrap();
+    assert_eq!(states(&device.image()), vec![0x07FFFFFF; 2]);
+    assert!(vfat.borrow_mut().describe().unwrap().dirty);
+    vfat.borrow_mut().sync().unwrap();
+    assert_eq!(states(&device.image()), vec![0x0FFFFFFF; 2]);
+    assert!(!vfat.borrow_mut().describe().unwrap().dirty);
+
+    // A failed write records a hard error, which survives later syncs.
+    vfat.borrow_mut()
+        .write_cluster(Cluster(3), 0, &[2])
+        .unwrap();
+    device.fail_writes(true);
+    assert!(vfat.borrow_mut().sync().is_err());
+    device.fail_writes(false);
+    vfat.borrow_mut().sync().unwrap();
+    assert_eq!(states(&device.image()), vec![0x0BFFFFFF; 2]);
+    let report = vfat.borrow_mut().describe().unwrap();
+    assert!(report.hard_error);
+    assert!(report.to_string().contains("clean, disk errors recorded"));
+
+    // A volume dirty at mount stays dirty until marked clean.
+    let mut image = mock_volume(16);
+    mock_set_fat(&mut image, 1, 0x07FFFFFF);
+    let device = SharedImage::new(image);
+    let vfat = VFat::with_options(device.clone(), options).expect("mounts");
+    vfat.borrow_mut()
+        .write_cluster(Cluster(3), 0, &[1])
+        .unwrap();
+    vfat.borrow_mut().sync().unwrap();
+    assert_eq!(states(&device.image()), vec![0x07FFFFFF; 2]);
+    vfat.borrow_mut().mark_clean().unwrap();
+    vfat.borrow_mut().sync().unwrap();
+    assert_eq!(states(&device.image()), vec![0x0FFFFFFF; 2]);
+}
+
+#[test]
+fn test_xattr_sidecar() {
+    // A sidecar still holding the attributes of a `GONE.TXT` removed by
+    // another implementation.
+    let mut sidecar = b"FXA1".to_vec();
+    sidecar.extend_from_slice(b"\x08\x00GONE.TXT\x01\x00\x03tag\x01\x00x");
+    let mut image = mock_volume(16);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"XATTRS  SYS", 0x06, 3, sidecar.len() as u32),
+    );
+    mock_set_fat(&mut image, 3, 0x0FFFFFFF);
+    let offset = mock_cluster_offset(&image, 3);
+    image[offset..offset + sidecar.len()].copy_from_slice(&sidecar);
+    mock_write_slot(
+        &mut image,
+        2,
+        1,
+        &mock_dir_entry(b"BOOT    CFG", 0x20, 0, 0),
+    );
+    mock_write_slot(
+        &mut image,
+        2,
+        2,
+        &mock_dir_entry(b"SUB        ", 0x10, 5, 0),
+    );
+    mock_set_fat(&mut image, 5, 0x0FFFFFFF);
+    mock_write_slot(
+        &mut image,
+        5,
+        0,
+        &mock_dir_entry(b"KERNEL  IMG", 0x20, 0, 0),
+    );
+
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    let entry = (&vfat).open("/boot.cfg").expect("entry");
+    assert_eq!(entry.get_xattr("priority").unwrap(), None);
+
+    // Setting an attribute prunes the stale record.
+    entry.set_xattr("priority", b"1").expect("set");
+    let sidecar = (&vfat).open_file("/XATTRS.SYS").expect("sidecar");
+    assert_eq!(sidecar.size(), 4 + 2 + 8 + 2 + 1 + 8 + 2 + 1);
+    entry
+        .set_xattr("user.tag", &[0xAB; 700])
+        .expect("set large");
+    entry.set_xattr("priority", b"2").expect("replace");
+
+    let entry = (&vfat).open("/BOOT.CFG").expect("entry");
+    assert_eq!(entry.get_xattr("priority").unwrap(), Some(b"2".to_vec()));
+    assert_eq!(entry.get_xattr("user.tag").unwrap(), Some(vec![0xAB; 700]));
+
+    entry.remove_xattr("user.tag").expect("remove");
+    assert_eq!(entry.get_xattr("user.tag").unwrap(), None);
+    let sidecar = (&vfat).open_file("/XATTRS.SYS").expect("sidecar");
+    assert_eq!(sidecar.size(), 4 + 2 + 8 + 2 + 1 + 8 + 2 + 1);
+
+    let nested = (&vfat).open("/SUB/KERNEL.IMG").expect("entry");
+    let e = nested.set_xattr("priority", b"1").unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::NotFound);
+    assert!((&vfat).open("/").unwrap().get_xattr("a").is_err());
+}
+
+#[test]
+fn test_watch_prefix() {
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use vfat::mkfs::{build_image, ImageLayout, PartitionLayout, Tree};
+    use vfat::Event;
+
+    let mut tree = Tree::new();
+    tree.add_file("config/boot.txt", b"old".to_vec()).unwrap();
+    tree.add_file("kernel.img", b"kernel".to_vec()).unwrap();
+    let mut layout = ImageLayout::default();
+    layout.partitions.push(PartitionLayout::fat32(None, tree));
+    let mut device = Cursor::new(vec![0; 4 << 20]);
+    build_image(&mut device, &layout).expect("build");
+    device.set_position(0);
+
+    let vfat = VFat::from(device).expect("mount");
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let config = seen.clone();
+    let id = vfat.borrow_mut().watch(
+        "/CONFIG/",
+        Box::new(move |event| config.lock().unwrap().push(("config", event.clone()))),
+    );
+    let all = seen.clone();
+    vfat.borrow_mut().watch(
+        "/",
+        Box::new(move |event| all.lock().unwrap().push(("all", event.clone()))),
+    );
+
+    let boot = Event::Modify("/config/boot.txt".to_string());
+    let kernel = Event::Modify("/kernel.img".to_string());
+
+    // Appends are reported once, when they are flushed.
+    let mut file = (&vfat).open_file("/config/boot.txt").expect("open");
+    file.seek(::std::io::SeekFrom::End(0)).unwrap();
+    file.write_all(b" and").unwrap();
+    file.write_all(b" new").unwrap();
+    file.flush().unwrap();
+    file.write_at(0, b"OLD").unwrap();
+    drop(file);
+    vfat.write_atomic("/kernel.img", b"new kernel")
+        .expect("write");
+
+    assert!(vfat.borrow_mut().unwatch(id));
+    assert!(!vfat.borrow_mut().unwatch(id));
+    vfat.write_atomic("/config/boot.txt", b"new")
+        .expect("write");
+
+    assert_eq!(
+        *seen.lock().unwrap(),
+        vec![
+            ("config", boot.clone()),
+            ("all", boot.clone()),
+            ("config", boot.clone()),
+            ("all", boot.clone()),
+            ("all", kernel),
+            ("all", boot),
+        ]
+    );
+}
+
+#[test]
+fn test_find_all_matching() {
+    let mut image = mock_volume(16);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"KERNEL  IMG", 0x20, 0, 0),
+    );
+    mock_write_slot(
+        &mut image,
+        2,
+        1,
+        &mock_dir_entry(b"KERNEL8 IMG", 0x20, 0, 0),
+    );
+    mock_write_slot(
+        &mut image,
+        2,
+        2,
+        &mock_dir_entry(b"CONFIG  TXT", 0x20, 0, 0),
+    );
+
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    let root = (&vfat).open_dir("/").expect("root directory");
+    let names = |pattern: &str| {
+        root.find_all_matching(pattern)
+            .expect("search")
+            .iter()
+            .map(|entry| entry.name().to_string())
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(names("ker"), vec!["KERNEL.IMG", "KERNEL8.IMG"]);
+    assert_eq!(names("Kernel8"), vec!["KERNEL8.IMG"]);
+    assert_eq!(names("*.txt"), vec!["CONFIG.TXT"]);
+    assert_eq!(names("kernel?.img"), vec!["KERNEL8.IMG"]);
+    assert_eq!(names("k*l*g"), vec!["KERNEL.IMG", "KERNEL8.IMG"]);
+    assert_eq!(names("").len(), 3);
+    assert_eq!(names("*"), names(""));
+    assert!(names("x").is_empty());
+    assert!(names("*.img?").is_empty());
+}
+
+/// Returns a ustar header block for a member at `path`.
+fn mock_tar_header(path: &str, typeflag: u8, size: usize) -> Vec<u8> {
+    mock_tar_header_at(path, typeflag, size, 0)
+}
+
+/// Returns a ustar header block for a member at `path` last modified `mtime`
+/// seconds after the Unix epoch.
+fn mock_tar_header_at(path: &str, typeflag: u8, size: usize, mtime: u64) -> Vec<u8> {
+    let mut block = vec![0; 512];
+    block[..path.len()].copy_from_slice(path.as_bytes());
+    block[100..107].copy_from_slice(b"0000644");
+    block[124..135].copy_from_slice(format!("{:011o}", size).as_bytes());
+    block[136..147].copy_from_slice(format!("{:011o}", mtime).as_bytes());
+    block[156] = typeflag;
+    block[257..263].copy_from_slice(b"ustar\0");
+    block[263..265].copy_from_slice(b"00");
+
+    block[148..156].copy_from_slice(b"        ");
+    let sum: u32 = block.iter().map(|&b| b as u32).sum();
+    block[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+    block
+}
+
+/// Appends a file member holding `data` to the tar archive `tar`.
+fn mock_tar_file(tar: &mut Vec<u8>, path: &str, data: &[u8]) {
+    tar.extend(mock_tar_header(path, b'0', data.len()));
+    tar.extend_from_slice(data);
+    let padded = (tar.len() + 511) / 512 * 512;
+    tar.resize(padded, 0);
+}
+
+#[test]
+fn test_apply_tar() {
+    use vfat::apply_tar;
+
+    let mut image = mock_volume(16);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"BOOT       ", 0x10, 5, 0),
+    );
+    mock_set_fat(&mut image, 5, 0x0FFFFFFF);
+    mock_write_slot(
+        &mut image,
+        5,
+        0,
+        &mock_dir_entry(b"CONFIG  TXT", 0x20, 0, 0),
+    );
+    mock_write_slot(
+        &mut image,
+        5,
+        1,
+        &mock_dir_entry(b"KERNEL  IMG", 0x20, 6, 3),
+    );
+    mock_set_fat(&mut image, 6, 0x0FFFFFFF);
+
+    let config: Vec<u8> = (0..600).map(|i| i as u8).collect();
+    let mut tar = mock_tar_header("./boot/", b'5', 0);
+    mock_tar_file(&mut tar, "./boot/config.txt", &config);
+    tar.extend(mock_tar_header("boot/link", b'2', 0));
+    mock_tar_file(&mut tar, "boot/kernel.img", b"k");
+    tar.extend(vec![0; 1024]);
+
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    let root = (&vfat).open_dir("/").expect("root directory");
+    assert_eq!(apply_tar(&mut Cursor::new(tar), &root).expect("apply"), 2);
+
+    let mut data = Vec::new();
+    (&vfat)
+        .open_file("/BOOT/CONFIG.TXT")
+        .expect("config")
+        .read_to_end(&mut data)
+        .expect("read");
+    assert_eq!(data, config);
+    let kernel = (&vfat).open_file("/BOOT/KERNEL.IMG").expect("kernel");
+    assert_eq!(kernel.size(), 1);
+
+    // Missing files are created; missing directories are not.
+    let mut missing = Vec::new();
+    mock_tar_file(&mut missing, "boot/new.txt", b"new");
+    mock_tar_file(&mut missing, "efi/boot.efi", b"efi");
+    let e = apply_tar(&mut Cursor::new(missing), &root).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::NotFound);
+    data.clear();
+    (&vfat)
+        .open_file("/BOOT/NEW.TXT")
+        .expect("created")
+        .read_to_end(&mut data)
+        .expect("read");
+    assert_eq!(data, b"new");
+
+    let mut escaping = Vec::new();
+    mock_tar_file(&mut escaping, "../boot/kernel.img", b"x");
+    let e = apply_tar(&mut Cursor::new(escaping), &root).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidData);
+
+    let mut corrupt = mock_tar_header("boot/kernel.img", b'0', 0);
+    corrupt[0] = b'B';
+    let e = apply_tar(&mut Cursor::new(corrupt), &root).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_read_config_lines() {
+    let config = b"# For more options, see the documentation\r\n\
+                   kernel=kernel8.img\r\n\
+                   \r\n\
+                   [pi4]\n  arm_64bit = 1 \n\
+                   dtoverlay=vc4-kms-v3d,cma-128\n\
+                   dtoverlay=disable-bt";
+    let mut image = mock_volume(16);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"CONFIG  TXT", 0x20, 3, config.len() as u32),
+    );
+    mock_set_fat(&mut image, 3, 0x0FFFFFFF);
+    let offset = mock_cluster_offset(&image, 3);
+    image[offset..offset + config.len()].copy_from_slice(config);
+    mock_write_slot(
+        &mut image,
+        2,
+        1,
+        &mock_dir_entry(b"BINARY  DAT", 0x20, 4, 2),
+    );
+    mock_set_fat(&mut image, 4, 0x0FFFFFFF);
+    let offset = mock_cluster_offset(&image, 4);
+    image[offset..offset + 2].copy_from_slice(&[0xFF, 0xFE]);
+
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    assert_eq!(
+        (&vfat).read_lines("/config.txt").expect("lines"),
+        vec![
+            "kernel=kernel8.img",
+            "[pi4]",
+            "arm_64bit = 1",
+            "dtoverlay=vc4-kms-v3d,cma-128",
+            "dtoverlay=disable-bt",
+        ]
+    );
+
+    let pairs = (&vfat)
+        .read_key_values("/config.txt", '=')
+        .expect("key-value pairs");
+    let pairs: Vec<_> = pairs
+        .iter()
+        .map(|&(ref key, ref value)| (key.as_str(), value.as_str()))
+        .collect();
+    assert_eq!(
+        pairs,
+        vec![
+            ("kernel", "kernel8.img"),
+            ("arm_64bit", "1"),
+            ("dtoverlay", "vc4-kms-v3d,cma-128"),
+            ("dtoverlay", "disable-bt"),
+        ]
+    );
+
+    let e = (&vfat).read_lines("/binary.dat").unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidData);
+    let e = (&vfat).read_lines("/cmdline.txt").unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::NotFound);
+}
+
+#[test]
+fn test_deterministic_apply_tar() {
+    use std::time::{Duration, UNIX_EPOCH};
+    use vfat::{apply_tar, DeterministicOptions, MountOptions, Timestamp};
+
+    let mut image = mock_volume(16);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"A       BIN", 0x20, 0, 0),
+    );
+    mock_write_slot(
+        &mut image,
+        2,
+        1,
+        &mock_dir_entry(b"B       BIN", 0x20, 0, 0),
+    );
+
+    let mut first = Vec::new();
+    mock_tar_file(&mut first, "a.bin", &[1; 1200]);
+    first.extend(vec![0; 1024]);
+    let mut second = Vec::new();
+    mock_tar_file(&mut second, "a.bin", b"");
+    second.extend(mock_tar_header_at("b.bin", b'0', 3, 1_500_000_000));
+    second.extend(b"bbb".iter().cloned().chain(vec![0; 509]));
+    second.extend(vec![0; 1024]);
+
+    // Where B's data lands after applying `second`, optionally once
+    // `first` has been applied and replaced in the same mount.
+    let apply = |deterministic: Option<DeterministicOptions>, churn: bool| {
+        let options = MountOptions {
+            deterministic,
+            ..Default::default()
+        };
+        let vfat = VFat::with_options(Cursor::new(image.clone()), options).expect("mounts");
+        let root = (&vfat).open_dir("/").expect("root directory");
+        if churn {
+            apply_tar(&mut Cursor::new(first.clone()), &root).expect("apply");
+        }
+        assert_eq!(
+            apply_tar(&mut Cursor::new(second.clone()), &root).expect("apply"),
+            2
+        );
+        (&vfat).open_file("/B.BIN").expect("b")
+    };
+
+    let stamp = Timestamp::from_system_time(UNIX_EPOCH + Duration::from_secs(1_000_000_000), 0);
+    let fixed = Some(DeterministicOptions { timestamp: stamp });
+    let fresh = apply(fixed, false);
+    let churned = apply(fixed, true);
+    assert_eq!(fresh.start_cluster, churned.start_cluster);
+    assert_eq!(churned.metadata.last_modified, stamp);
+    assert_eq!(churned.metadata.accessed, stamp.date);
+
+    // Otherwise allocation continues after the last, and the archived
+    // modification time is kept.
+    let churned = apply(None, true);
+    assert_ne!(fresh.start_cluster, churned.start_cluster);
+    let archived = Timestamp::from_system_time(UNIX_EPOCH + Duration::from_secs(1_500_000_000), 0);
+    assert_eq!(churned.metadata.last_modified, archived);
+}
+
+#[test]
+fn test_install_boot_code() {
+    use byteorder::{ByteOrder, LittleEndian};
+    use vfat::BOOT_CODE_SIZE;
+
+    let boot = MOCK_PARTITION_START * 512;
+    let backup = (MOCK_PARTITION_START + 2) * 512;
+    let mut image = mock_volume(16);
+    LittleEndian::write_u16(&mut image[boot + 50..boot + 52], 2);
+    for b in image[boot + 90..boot + 510].iter_mut() {
+        *b = 0xCC;
+    }
+    let bpb = image[boot..boot + 512].to_vec();
+    image[backup..backup + 512].copy_from_slice(&bpb);
+
+    let device = SharedImage::new(image);
+    let vfat = VFat::from(device.clone()).expect("mock volume mounts");
+    let e = vfat
+        .borrow_mut()
+        .install_boot_code(&[0x90; BOOT_CODE_SIZE + 1])
+        .unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidInput);
+
+    vfat.borrow_mut()
+        .install_boot_code(&[0xFA, 0xF4])
+        .expect("install");
+    vfat.borrow_mut().sync().expect("sync");
+
+    let image = device.image();
+    for &start in &[boot, backup] {
+        let sector = &image[start..start + 512];
+        assert_eq!(&sector[..90], &bpb[..90]);
+        assert_eq!(&sector[90..92], &[0xFA, 0xF4]);
+        assert!(sector[92..510].iter().all(|&b| b == 0));
+        assert_eq!(&sector[510..], &[0x55, 0xAA]);
+    }
+}
+
+#[test]
+fn test_hidden_sectors_mismatch() {
+    use byteorder::{ByteOrder, LittleEndian};
+
+    let boot = MOCK_PARTITION_START * 512;
+    let backup = (MOCK_PARTITION_START + 2) * 512;
+    let mut image = mock_volume(16);
+    LittleEndian::write_u16(&mut image[boot + 50..boot + 52], 2);
+    LittleEndian::write_u32(&mut image[boot + 28..boot + 32], 63);
+    let bpb = image[boot..boot + 512].to_vec();
+    image[backup..backup + 512].copy_from_slice(&bpb);
+
+    let device = SharedImage::new(image);
+    let vfat = VFat::from(device.clone()).expect("mounts from the MBR's offset");
+    assert_eq!(vfat.borrow().partition_start(), MOCK_PARTITION_START as u64);
+    assert_eq!(vfat.borrow().hidden_sectors(), 63);
+    let report = vfat.borrow_mut().describe().expect("report");
+    assert!(report
+        .to_string()
+        .ends_with("BPB hidden sectors (63) disagree with partition start (8)"));
+
+    assert!(vfat.borrow_mut().repair_hidden_sectors().expect("repair"));
+    assert!(!vfat.borrow_mut().repair_hidden_sectors().expect("repair"));
+    vfat.borrow_mut().sync().expect("sync");
+
+    let image = device.image();
+    for &start in &[boot, backup] {
+        let hidden = LittleEndian::read_u32(&image[start + 28..start + 32]);
+        assert_eq!(hidden, MOCK_PARTITION_START as u32);
+    }
+    let vfat = VFat::from(device.clone()).expect("mounts");
+    assert_eq!(vfat.borrow().hidden_sectors(), MOCK_PARTITION_START as u32);
+}
+
+#[test]
+fn test_cluster_sector_translation() {
+    use vfat::Cluster;
+
+    let vfat = VFat::from(Cursor::new(mock_volume(16))).expect("mock volume mounts");
+    let vfat = vfat.borrow();
+    let data_start = (MOCK_PARTITION_START + MOCK_RESERVED_SECTORS + 2) as u64;
+
+    assert_eq!(vfat.cluster_size_bytes(), 512);
+    assert_eq!(vfat.cluster_to_sector(Cluster(2)), Some(data_start));
+    assert_eq!(vfat.cluster_to_sector(Cluster(17)), Some(data_start + 15));
+    assert_eq!(vfat.cluster_to_sector(Cluster(1)), None);
+    assert_eq!(vfat.cluster_to_sector(Cluster(18)), None);
+
+    assert_eq!(vfat.sector_to_cluster(data_start + 3), Some(Cluster(5)));
+    assert_eq!(vfat.sector_to_cluster(data_start - 1), None);
+    assert_eq!(vfat.sector_to_cluster(data_start + 16), None);
+    for n in 2..18 {
+        let sector = vfat.cluster_to_sector(Cluster(n)).unwrap();
+        assert_eq!(vfat.sector_to_cluster(sector), Some(Cluster(n)));
+    }
+}
+
+#[test]
+fn test_sha256() {
+    use vfat::sha256::sha256;
+
+    let hex = |digest: [u8; 32]| {
+        digest
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>()
+    };
+    assert_eq!(
+        hex(sha256(b"")),
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+    );
+    assert_eq!(
+        hex(sha256(b"abc")),
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+    assert_eq!(
+        hex(sha256(&[b'a'; 1000])),
+        "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3"
+    );
+}
+
+#[test]
+fn test_file_integrity() {
+    use std::io::SeekFrom;
+    use vfat::{lfn_checksum, Advice, HashTree};
+
+    let contents: Vec<u8> = (0..1200).map(|i| (i * 7) as u8).collect();
+    let mut image = mock_volume(16);
+    mock_set_fat(&mut image, 3, 4);
+    mock_set_fat(&mut image, 4, 5);
+    mock_set_fat(&mut image, 5, 0x0FFFFFFF);
+    for (i, chunk) in contents.chunks(512).enumerate() {
+        let offset = mock_cluster_offset(&image, 3 + i as u32);
+        image[offset..offset + chunk.len()].copy_from_slice(chunk);
+    }
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"A       BIN", 0x20, 3, 1200),
+    );
+
+    let tree = {
+        let vfat = VFat::from(Cursor::new(image.clone())).expect("mock volume mounts");
+        let file = (&vfat).open_file("/A.BIN").expect("file");
+        HashTree::build(&file).expect("hash tree")
+    };
+    let bytes = tree.to_bytes();
+    assert_eq!(HashTree::from_bytes(&bytes).unwrap(), tree);
+    let root = tree.root();
+
+    let short = *b"A~1     HAS";
+    let name: Vec<u16> = "A.BIN.hashtree".encode_utf16().collect();
+    let mut first = [0; 13];
+    first.copy_from_slice(&name[..13]);
+    let mut second = [0xFFFF; 13];
+    second[..2].copy_from_slice(&[name[13], 0]);
+    let checksum = lfn_checksum(&short);
+    mock_write_slot(&mut image, 2, 1, &mock_lfn_entry(0x42, &second, checksum));
+    mock_write_slot(&mut image, 2, 2, &mock_lfn_entry(0x01, &first, checksum));
+    mock_write_slot(
+        &mut image,
+        2,
+        3,
+        &mock_dir_entry(&short, 0x20, 8, bytes.len() as u32),
+    );
+    mock_set_fat(&mut image, 8, 0x0FFFFFFF);
+    let offset = mock_cluster_offset(&image, 8);
+    image[offset..offset + bytes.len()].copy_from_slice(&bytes);
+
+    let vfat = VFat::from(Cursor::new(image.clone())).expect("mock volume mounts");
+    let mut file = (&vfat).open_file("/A.BIN").expect("file");
+    let e = file.enable_integrity(Some(&[0; 32])).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidData);
+    file.enable_integrity(Some(&root)).expect("valid hash tree");
+    let mut data = Vec::new();
+    file.read_to_end(&mut data).expect("verified read");
+    assert_eq!(data, contents);
+
+    // Corrupt the second cluster of the file.
+    let offset = mock_cluster_offset(&image, 4);
+    image[offset] ^= 0xFF;
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    let mut file = (&vfat).open_file("/A.BIN").expect("file");
+    file.enable_integrity(Some(&root)).expect("valid hash tree");
+    let e = file.read_to_end(&mut Vec::new()).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidData);
+
+    file.advise(Advice::Random);
+    file.seek(SeekFrom::Start(0)).unwrap();
+    let mut buf = [0; 512];
+    file.read_exact(&mut buf).expect("first cluster is intact");
+    assert!(file.read(&mut buf).is_err());
+    file.seek(SeekFrom::Start(1024)).unwrap();
+    assert_eq!(file.read(&mut buf).expect("third cluster is intact"), 176);
+}
+
+#[test]
+fn test_shared_file_data() {
+    use vfat::MountOptions;
+
+    let mut image = mock_volume(16);
+    let files: [(&[u8; 11], u32, u32); 3] = [
+        (b"A       TXT", 3, 1200),
+        (b"B       TXT", 6, 600),
+        (b"C       TXT", 8, 300),
+    ];
+    for (slot, &(name, cluster, size)) in files.iter().enumerate() {
+        let clusters = (size + 511) / 512;
+        for n in cluster..cluster + clusters {
+            let next = if n + 1 == cluster + clusters {
+                0x0FFFFFFF
+            } else {
+                n + 1
+            };
+            mock_set_fat(&mut image, n, next);
+        }
+        mock_write_slot(
+            &mut image,
+            2,
+            slot,
+            &mock_dir_entry(name, 0x20, cluster, size),
+        );
+    }
+
+    let options = MountOptions {
+        file_data_cache_bytes: 1500,
+        ..Default::default()
+    };
+    let vfat = VFat::with_options(Cursor::new(image), options).expect("mock volume mounts");
+    let read_all = |file: &mut ::vfat::File| {
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).expect("read");
+        data.len()
+    };
+
+    let mut a1 = (&vfat).open_file("/A.TXT").expect("file");
+    let mut a2 = (&vfat).open_file("/a.txt").expect("file");
+    assert_eq!(read_all(&mut a1), 1200);
+    assert_eq!(read_all(&mut a2), 1200);
+    assert_eq!(vfat.borrow().cached_file_bytes(), 1200);
+
+    // Data in use by a handle is kept even when over budget.
+    let mut b = (&vfat).open_file("/B.TXT").expect("file");
+    assert_eq!(read_all(&mut b), 600);
+    assert_eq!(vfat.borrow().cached_file_bytes(), 1800);
+
+    // Once unused, it is evicted to make room.
+    drop(a1);
+    drop(a2);
+    let mut c = (&vfat).open_file("/C.TXT").expect("file");
+    assert_eq!(read_all(&mut c), 300);
+    assert_eq!(vfat.borrow().cached_file_bytes(), 900);
+
+    vfat.borrow_mut()
+        .write_cluster(::vfat::Cluster(10), 0, &[1])
+        .expect("write");
+    assert_eq!(vfat.borrow().cached_file_bytes(), 0);
+    b.seek(::std::io::SeekFrom::Start(0)).unwrap();
+    assert_eq!(read_all(&mut b), 600);
+}
+
+#[test]
+fn test_file_try_clone() {
+    use std::io::SeekFrom;
+
+    let mut image = mock_volume(16);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"DATA    BIN", 0x20, 4, 10),
+    );
+    mock_set_fat(&mut image, 4, 0x0FFFFFFF);
+    let offset = mock_cluster_offset(&image, 4);
+    image[offset..offset + 10].copy_from_slice(b"0123456789");
+
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    let mut file = (&vfat).open_file("/DATA.BIN").expect("file");
+    file.seek(SeekFrom::Start(2)).unwrap();
+    let mut clone = file.try_clone().expect("clone");
+    assert_eq!(clone.metadata().name, file.metadata().name);
+    assert_eq!(clone.metadata().size, 10);
+    assert_eq!(clone.metadata().attributes, file.metadata().attributes);
+    assert_eq!(clone.id(), file.id());
+
+    // The clone starts at the original's offset, then seeks on its own.
+    let mut buf = [0; 3];
+    clone.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"234");
+    file.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"234");
+    clone.seek(SeekFrom::Start(7)).unwrap();
+    file.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"567");
+    clone.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"789");
+}
+
+#[test]
+fn test_file_locks() {
+    use std::io::ErrorKind;
+    use vfat::LockKind::{Exclusive, Shared};
+
+    let mut image = mock_volume(16);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"DATA    BIN", 0x20, 0, 0),
+    );
+    mock_write_slot(
+        &mut image,
+        2,
+        1,
+        &mock_dir_entry(b"OTHER   BIN", 0x20, 0, 0),
+    );
+
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    let a = (&vfat).open_file("/DATA.BIN").expect("file");
+    let b = (&vfat).open_file("/data.bin").expect("file");
+    let other = (&vfat).open_file("/OTHER.BIN").expect("file");
+
+    let shared = a.try_lock(Shared).expect("shared lock");
+    let shared2 = b.try_lock(Shared).expect("shared locks overlap");
+    assert_eq!(shared.kind(), Shared);
+    let e = b.try_lock(Exclusive).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::WouldBlock);
+    let _other = other.try_lock(Exclusive).expect("locks are per file");
+    drop(shared);
+    assert_eq!(
+        a.try_lock_range(0, 10, Exclusive).unwrap_err().kind(),
+        ErrorKind::WouldBlock
+    );
+    drop(shared2);
+
+    let low = a.try_lock_range(0, 100, Exclusive).expect("range lock");
+    let high = b
+        .try_lock_range(100, 100, Exclusive)
+        .expect("disjoint range");
+    let e = b.try_lock_range(99, 2, Shared).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::WouldBlock);
+    drop(low);
+    drop(high);
+    a.try_lock(Exclusive).expect("all locks released");
+}
+
+#[test]
+fn test_lock_after_compact() {
+    use std::io::ErrorKind;
+    use vfat::Error;
+    use vfat::LockKind::Exclusive;
+
+    let mut image = mock_volume(16);
+    let mut deleted = mock_dir_entry(b"B       TXT", 0x20, 0, 0);
+    deleted[0] = 0xE5;
+    mock_write_slot(&mut image, 2, 0, &deleted);
+    mock_write_slot(
+        &mut image,
+        2,
+        1,
+        &mock_dir_entry(b"DATA    BIN", 0x20, 0, 0),
+    );
+    mock_write_slot(
+        &mut image,
+        2,
+        2,
+        &mock_dir_entry(b"OTHER   BIN", 0x20, 0, 0),
+    );
+
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    let data = (&vfat).open_file("/DATA.BIN").expect("file");
+    let other = (&vfat).open_file("/OTHER.BIN").expect("file");
+    let lock = data.try_lock(Exclusive).expect("lock");
+    let _other_lock = other.try_lock(Exclusive).expect("empty files lock apart");
+
+    // DATA.BIN moves into the freed slot 1 and OTHER.BIN into its old one;
+    // the lock moves with DATA.BIN rather than staying on the slot.
+    let root = (&vfat).open_dir("/").expect("root directory");
+    assert_eq!(root.compact().unwrap(), 1);
+    let e = data.try_lock(Exclusive).unwrap_err();
+    match e.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+        Some(&Error::StaleHandle) => {}
+        _ => panic!("expected a stale handle, got {:?}", e),
+    }
+    let data = (&vfat).open_file("/DATA.BIN").expect("file");
+    let other = (&vfat).open_file("/OTHER.BIN").expect("file");
+    assert_eq!(data.location.map(|location| location.slot), Some(0));
+    let e = data.try_lock(Exclusive).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::WouldBlock);
+    let e = other.try_lock(Exclusive).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::WouldBlock);
+    drop(lock);
+    data.try_lock(Exclusive).expect("lock released");
+}
+
+#[test]
+fn test_resolve_prefix() {
+    use std::path::PathBuf;
+    use vfat::{Cluster, MountOptions};
+
+    let mut image = mock_volume(16);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"DATA       ", 0x10, 5, 0),
+    );
+    mock_set_fat(&mut image, 5, 0x0FFFFFFF);
+    mock_write_slot(
+        &mut image,
+        5,
+        0,
+        &mock_dir_entry(b"SENSORS    ", 0x10, 6, 0),
+    );
+    mock_set_fat(&mut image, 6, 0x0FFFFFFF);
+    mock_write_slot(
+        &mut image,
+        6,
+        0,
+        &mock_dir_entry(b"LOG     TXT", 0x20, 0, 0),
+    );
+
+    let vfat = VFat::from(Cursor::new(image.clone())).expect("mock volume mounts");
+    let (dir, rest) = (&vfat).resolve_prefix("/data/sensors/log.txt").unwrap();
+    assert_eq!(dir.start_cluster, Cluster(2));
+    assert_eq!(rest, PathBuf::from("data/sensors/log.txt"));
+
+    (&vfat).open("/DATA/SENSORS/LOG.TXT").expect("entry");
+    let (dir, rest) = (&vfat).resolve_prefix("/data/sensors/x/y").unwrap();
+    assert_eq!(dir.start_cluster, Cluster(6));
+    assert_eq!(rest, PathBuf::from("x/y"));
+    assert_eq!(dir.find("log.txt").expect("entry").name(), "LOG.TXT");
+
+    let (dir, rest) = (&vfat).resolve_prefix("/Data/Sensors/../other").unwrap();
+    assert_eq!(dir.start_cluster, Cluster(5));
+    assert_eq!(rest, PathBuf::from("other"));
+    let (dir, rest) = (&vfat).resolve_prefix("/data/sensors").unwrap();
+    assert_eq!(dir.start_cluster, Cluster(6));
+    assert_eq!(rest, PathBuf::new());
+    assert_eq!(
+        (&vfat)
+            .open("/data/./sensors/../sensors/log.txt")
+            .unwrap()
+            .name(),
+        "LOG.TXT"
+    );
+
+    let options = MountOptions {
+        dir_cache_entries: 0,
+        ..Default::default()
+    };
+    let vfat = VFat::with_options(Cursor::new(image), options).expect("mock volume mounts");
+    (&vfat).open("/DATA/SENSORS/LOG.TXT").expect("entry");
+    let (dir, _) = (&vfat).resolve_prefix("/data/sensors/log.txt").unwrap();
+    assert_eq!(dir.start_cluster, Cluster(2));
+}
+
+#[test]
+fn test_media_change() {
+    use std::io::{self, SeekFrom};
+    use vfat::Error;
+
+    let card = |contents: &[u8]| {
+        let mut image = mock_volume(16);
+        mock_write_slot(
+            &mut image,
+            2,
+            0,
+            &mock_dir_entry(b"HELLO   TXT", 0x20, 5, contents.len() as u32),
+        );
+        mock_set_fat(&mut image, 5, 0x0FFFFFFF);
+        let offset = mock_cluster_offset(&image, 5);
+        image[offset..offset + contents.len()].copy_from_slice(contents);
+        image
+    };
+
+    let device = SharedImage::new(card(b"first"));
+    let vfat = VFat::from(device.clone()).expect("mock volume mounts");
+    let mut file = (&vfat).open_file("/hello.txt").expect("file");
+    let mut buf = String::new();
+    file.read_to_string(&mut buf).unwrap();
+    assert_eq!(buf, "first");
+    assert_eq!(vfat.borrow_mut().media_generation(), 0);
+
+    device.swap(card(b"other"));
+    file.seek(SeekFrom::Start(0)).unwrap();
+    let err = file.read_to_string(&mut buf).expect_err("stale handle");
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    match err.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+        Some(Error::MediaChanged) => (),
+        other => panic!("expected MediaChanged, got {:?}", other),
+    }
+    assert_eq!(vfat.borrow_mut().media_generation(), 1);
+
+    let mut file = (&vfat).open_file("/hello.txt").expect("file");
+    buf.clear();
+    file.read_to_string(&mut buf).unwrap();
+    assert_eq!(buf, "other");
+}
+
+#[test]
+fn test_handles_are_send_and_sync() {
+    use vfat::{Dir, DirIter, Entry, File, FileLock};
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    assert_send_sync::<Shared<VFat>>();
+    assert_send_sync::<File>();
+    assert_send_sync::<Dir>();
+    assert_send_sync::<DirIter>();
+    assert_send_sync::<Entry>();
+    assert_send_sync::<FileLock>();
+
+    let mut image = mock_volume(16);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"HELLO   TXT", 0x20, 5, 5),
+    );
+    mock_set_fat(&mut image, 5, 0x0FFFFFFF);
+    let offset = mock_cluster_offset(&image, 5);
+    image[offset..offset + 5].copy_from_slice(b"hello");
+
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    let mut file = (&vfat).open_file("/hello.txt").expect("file");
+    let contents = ::std::thread::spawn(move || {
+        let mut buf = String::new();
+        file.read_to_string(&mut buf).map(|_| buf)
+    })
+    .join()
+    .expect("reader thread");
+    assert_eq!(contents.unwrap(), "hello");
+}
+
+#[test]
+fn test_prelude() {
+    use prelude::*;
+
+    let vfat: Shared<VFat> =
+        VFat::with_options(Cursor::new(mock_volume(16)), MountOptions::default())
+            .expect("mock volume mounts");
+    let root = (&vfat).open_dir("/").expect("root");
+    assert_eq!(root.entries().expect("entries").count(), 0);
+    let entry = (&vfat).open("/").expect("root");
+    assert!(entry.is_dir());
+    assert!(!entry.metadata().read_only());
+}
+
+#[test]
+fn test_dir_entry_builder_round_trip() {
+    use vfat::DirEntryBuilder;
+
+    let mut image = mock_volume(16);
+    let entries = DirEntryBuilder::new("Long File Name.text")
+        .cluster(5)
+        .size(5)
+        .build()
+        .unwrap();
+    for (slot, entry) in entries.iter().enumerate() {
+        mock_write_slot(&mut image, 2, slot, entry);
+    }
+    mock_set_fat(&mut image, 5, 0x0FFFFFFF);
+    let offset = mock_cluster_offset(&image, 5);
+    image[offset..offset + 5].copy_from_slice(b"hello");
+
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    let mut file = (&vfat).open_file("/long file name.TEXT").expect("file");
+    assert_eq!(file.metadata.name, "Long File Name.text");
+    let mut buf = String::new();
+    file.read_to_string(&mut buf).unwrap();
+    assert_eq!(buf, "hello");
+}
+
+#[test]
+fn test_corrupt_entries() {
+    use vfat::{lfn_checksum, EntryMode, Error, MountOptions};
+
+    let units = |name: &str| {
+        let mut units = [0xFFFF; 13];
+        for (i, unit) in name.encode_utf16().chain(Some(0)).enumerate() {
+            units[i] = unit;
+        }
+        units
+    };
+
+    let mut image = mock_volume(16);
+    // A long name whose checksum does not match: the short name is kept.
+    let bad_checksum = lfn_checksum(b"GOOD    TXT").wrapping_add(1);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_lfn_entry(0x41, &units("good.txt"), bad_checksum),
+    );
+    mock_write_slot(
+        &mut image,
+        2,
+        1,
+        &mock_dir_entry(b"GOOD    TXT", 0x20, 0, 0),
+    );
+    // Reserved attribute bits.
+    mock_write_slot(
+        &mut image,
+        2,
+        2,
+        &mock_dir_entry(b"BAD     TXT", 0xE0, 0, 0),
+    );
+    // A truncated run followed by a deleted entry.
+    mock_write_slot(&mut image, 2, 3, &mock_lfn_entry(0x42, &units("orphan"), 0));
+    let mut deleted = mock_dir_entry(b"GONE    TXT", 0x20, 0, 0);
+    deleted[0] = 0xE5;
+    mock_write_slot(&mut image, 2, 4, &deleted);
+    mock_write_slot(
+        &mut image,
+        2,
+        5,
+        &mock_dir_entry(b"OK      TXT", 0x20, 0, 0),
+    );
+    mock_write_slot(
+        &mut image,
+        2,
+        6,
+        &mock_dir_entry(b"SUB        ", 0x10, 5, 0),
+    );
+
+    // A run cut off by the end of the directory.
+    mock_set_fat(&mut image, 5, 0x0FFFFFFF);
+    for slot in 0..15 {
+        mock_write_slot(&mut image, 5, slot, &deleted);
+    }
+    mock_write_slot(&mut image, 5, 15, &mock_lfn_entry(0x41, &units("tail"), 0));
+
+    let vfat = VFat::from(Cursor::new(image.clone())).expect("mock volume mounts");
+    let root = (&vfat).open_dir("/").expect("root");
+    let mut entries = root.entries().unwrap();
+    let names: Vec<String> = entries.by_ref().map(|e| e.name().to_string()).collect();
+    assert_eq!(names, vec!["GOOD.TXT", "OK.TXT", "SUB"]);
+    assert_eq!(entries.skipped(), 3);
+    assert_eq!(vfat.borrow().corrupt_entries(), 3);
+
+    // Opening `/sub` lists the root again, counting its entries again.
+    let sub = (&vfat).open_dir("/sub").expect("sub");
+    assert_eq!(sub.entries().unwrap().count(), 0);
+    assert_eq!(vfat.borrow().corrupt_entries(), 7);
+
+    let options = MountOptions {
+        entry_mode: EntryMode::Strict,
+        ..MountOptions::default()
+    };
+    let vfat = VFat::with_options(Cursor::new(image), options).expect("mock volume mounts");
+    let err = match (&vfat).open_dir("/").unwrap().entries() {
+        Ok(_) => panic!("expected a corrupt entry"),
+        Err(err) => err,
+    };
+    assert_eq!(err.kind(), ::std::io::ErrorKind::InvalidData);
+    match err.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+        Some(Error::CorruptEntry { location, .. }) => assert_eq!(location.slot, 0),
+        other => panic!("expected CorruptEntry, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_orphaned_lfns() {
+    use vfat::{lfn_checksum, LfnProblem, OrphanedLfn};
+
+    let units = [0xFFFF; 13];
+    let checksum = lfn_checksum(b"KEPT    TXT");
+    let mut image = mock_volume(16);
+    // A sound run.
+    mock_write_slot(&mut image, 2, 0, &mock_lfn_entry(0x41, &units, checksum));
+    mock_write_slot(
+        &mut image,
+        2,
+        1,
+        &mock_dir_entry(b"KEPT    TXT", 0x20, 0, 0),
+    );
+    // A run left behind by a deleted short entry.
+    mock_write_slot(&mut image, 2, 2, &mock_lfn_entry(0x42, &units, 7));
+    mock_write_slot(&mut image, 2, 3, &mock_lfn_entry(0x01, &units, 7));
+    let mut deleted = mock_dir_entry(b"GONE    TXT", 0x20, 0, 0);
+    deleted[0] = 0xE5;
+    mock_write_slot(&mut image, 2, 4, &deleted);
+    // Runs out of sequence and with a stale checksum.
+    mock_write_slot(&mut image, 2, 5, &mock_lfn_entry(0x42, &units, checksum));
+    mock_write_slot(&mut image, 2, 6, &mock_lfn_entry(0x03, &units, checksum));
+    mock_write_slot(
+        &mut image,
+        2,
+        7,
+        &mock_dir_entry(b"KEPT    TXT", 0x20, 0, 0),
+    );
+    mock_write_slot(&mut image, 2, 8, &mock_lfn_entry(0x41, &units, 7));
+    mock_write_slot(
+        &mut image,
+        2,
+        9,
+        &mock_dir_entry(b"KEPT    TXT", 0x20, 0, 0),
+    );
+
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    let root = (&vfat).open_dir("/").expect("root");
+    assert_eq!(
+        root.orphaned_lfns().unwrap(),
+        vec![
+            OrphanedLfn {
+                slots: 2..4,
+                problem: LfnProblem::NoShortEntry,
+            },
+            OrphanedLfn {
+                slots: 5..7,
+                problem: LfnProblem::OutOfSequence,
+            },
+            OrphanedLfn {
+                slots: 8..9,
+                problem: LfnProblem::ChecksumMismatch,
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_open_nested_while_reading() {
+    use vfat::Advice;
+
+    let mut image = mock_volume(16);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"BIG     BIN", 0x20, 8, 1024),
+    );
+    mock_set_fat(&mut image, 8, 9);
+    mock_set_fat(&mut image, 9, 0x0FFFFFFF);
+    for (i, cluster) in [8, 9].iter().enumerate() {
+        let offset = mock_cluster_offset(&image, *cluster);
+        for byte in &mut image[offset..offset + 512] {
+            *byte = i as u8 + 1;
+        }
+    }
+    mock_write_slot(
+        &mut image,
+        2,
+        1,
+        &mock_dir_entry(b"A          ", 0x10, 5, 0),
+    );
+    mock_set_fat(&mut image, 5, 0x0FFFFFFF);
+    mock_write_slot(
+        &mut image,
+        5,
+        0,
+        &mock_dir_entry(b"B          ", 0x10, 6, 0),
+    );
+    mock_set_fat(&mut image, 6, 0x0FFFFFFF);
+    mock_write_slot(
+        &mut image,
+        6,
+        0,
+        &mock_dir_entry(b"C       TXT", 0x20, 0, 0),
+    );
+
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    let mut big = (&vfat).open_file("/big.bin").expect("file");
+    big.advise(Advice::Random);
+    let mut buf = [0; 512];
+    big.read_exact(&mut buf).unwrap();
+    assert!(buf.iter().all(|&b| b == 1));
+
+    for _ in 0..2 {
+        let nested = (&vfat).open("/a/b/c.txt").expect("nested entry");
+        assert_eq!(nested.name(), "C.TXT");
+        assert!((&vfat).open("/a/big.bin").is_err());
+    }
+
+    big.read_exact(&mut buf).unwrap();
+    assert!(buf.iter().all(|&b| b == 2));
+}
+
+#[test]
+fn test_stat_many() {
+    let mut image = mock_volume(16);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"A          ", 0x10, 5, 0),
+    );
+    mock_set_fat(&mut image, 5, 0x0FFFFFFF);
+    mock_write_slot(
+        &mut image,
+        2,
+        1,
+        &mock_dir_entry(b"TOP     TXT", 0x20, 0, 3),
+    );
+    mock_write_slot(
+        &mut image,
+        5,
+        0,
+        &mock_dir_entry(b"ONE     TXT", 0x20, 0, 1),
+    );
+    mock_write_slot(
+        &mut image,
+        5,
+        1,
+        &mock_dir_entry(b"TWO     TXT", 0x20, 0, 2),
+    );
+
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    let results = (&vfat).stat_many(&[
+        "/a/two.txt",
+        "/top.txt",
+        "/a/missing",
+        "/",
+        "/a/one.txt",
+        "/top.txt/x",
+        "/nope/x",
+    ]);
+    assert_eq!(results.len(), 7);
+
+    let name = |i: usize| results[i].as_ref().expect("entry").name().to_string();
+    assert_eq!(name(0), "TWO.TXT");
+    assert_eq!(name(1), "TOP.TXT");
+    assert!(results[3].as_ref().expect("root").as_dir().is_some());
+    assert_eq!(name(4), "ONE.TXT");
+
+    let kind = |i: usize| results[i].as_ref().err().expect("error").kind();
+    assert_eq!(kind(2), ::std::io::ErrorKind::NotFound);
+    assert_eq!(kind(5), ::std::io::ErrorKind::InvalidInput);
+    assert_eq!(kind(6), ::std::io::ErrorKind::NotFound);
+}
+
+#[test]
+fn test_dir_index() {
+    use vfat::{MountOptions, INDEX_NAME};
+
+    let mut image = mock_volume(16);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"DIRINDEXSYS", 0x02, 0, 0),
+    );
+    mock_write_slot(
+        &mut image,
+        2,
+        1,
+        &mock_dir_entry(b"ONE     TXT", 0x20, 0, 1),
+    );
+    mock_write_slot(
+        &mut image,
+        2,
+        2,
+        &mock_dir_entry(b"TWO     TXT", 0x20, 0, 2),
+    );
+    let options = MountOptions {
+        dir_index: true,
+        ..Default::default()
+    };
+    let sidecar = format!("/{}", INDEX_NAME);
+    // The magic, then each of the three names with its length and slot.
+    let index_size = 4 + (2 + 2 * 12 + 4) + 2 * (2 + 2 * 7 + 4);
+
+    // Without the option, the sidecar is left alone.
+    let vfat = VFat::from(Cursor::new(image.clone())).expect("mock volume mounts");
+    assert_eq!((&vfat).open("/two.txt").expect("entry").name(), "TWO.TXT");
+    assert_eq!((&vfat).open_file(&sidecar).expect("sidecar").size(), 0);
+
+    // An empty sidecar is filled in on first use.
+    let vfat = VFat::with_options(Cursor::new(image.clone()), options.clone()).unwrap();
+    assert_eq!((&vfat).open("/two.txt").expect("entry").name(), "TWO.TXT");
+    assert_eq!((&vfat).open("/one.txt").expect("entry").name(), "ONE.TXT");
+    assert!((&vfat).open("/three.txt").is_err());
+    assert_eq!(
+        (&vfat).open_file(&sidecar).expect("sidecar").size(),
+        index_size
+    );
+
+    // A stale index, naming the wrong slot for ONE.TXT and missing TWO.TXT,
+    // is rebuilt.
+    let mut stale = b"FDX1".to_vec();
+    stale.extend_from_slice(&[7, 0]);
+    for unit in "ONE.TXT".encode_utf16() {
+        stale.extend_from_slice(&[unit as u8, 0]);
+    }
+    stale.extend_from_slice(&[2, 0, 0, 0]);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"DIRINDEXSYS", 0x02, 7, stale.len() as u32),
+    );
+    mock_set_fat(&mut image, 7, 0x0FFFFFFF);
+    let offset = mock_cluster_offset(&image, 7);
+    image[offset..offset + stale.len()].copy_from_slice(&stale);
+
+    let vfat = VFat::with_options(Cursor::new(image.clone()), options.clone()).unwrap();
+    assert_eq!((&vfat).open("/one.txt").expect("entry").name(), "ONE.TXT");
+    assert_eq!(
+        (&vfat).open_file(&sidecar).expect("sidecar").size(),
+        index_size
+    );
+    assert_eq!((&vfat).open("/two.txt").expect("entry").name(), "TWO.TXT");
+
+    // On a read-only mount or device, the stale index is rebuilt in memory
+    // only, and lookups still succeed.
+    let read_only = MountOptions {
+        read_only: true,
+        ..options.clone()
+    };
+    let vfat = VFat::with_options(Cursor::new(image.clone()), read_only).unwrap();
+    assert_eq!((&vfat).open("/one.txt").expect("entry").name(), "ONE.TXT");
+    assert_eq!((&vfat).open("/two.txt").expect("entry").name(), "TWO.TXT");
+    let sidecar_size = (&vfat).open_file(&sidecar).expect("sidecar").size();
+    assert_eq!(sidecar_size, stale.len() as u64);
+    let image: &'static [u8] = Box::leak(image.into_boxed_slice());
+    let vfat = VFat::with_options(Cursor::new(image), options).unwrap();
+    assert_eq!((&vfat).open("/one.txt").expect("entry").name(), "ONE.TXT");
+    assert_eq!((&vfat).open("/two.txt").expect("entry").name(), "TWO.TXT");
+}
+
+#[test]
+fn test_windows_artifacts() {
+    use vfat::{lfn_checksum, IterOptions};
+
+    let mut image = mock_volume(16);
+    // The volume label Windows writes into the root directory.
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"MYCARD     ", 0x08, 0, 0),
+    );
+
+    // "System Volume Information", a hidden system directory.
+    let short = *b"SYSTEM~1   ";
+    let name: Vec<u16> = "System Volume Information".encode_utf16().collect();
+    let mut first = [0; 13];
+    first.copy_from_slice(&name[..13]);
+    let mut second = [0; 13];
+    second[..12].copy_from_slice(&name[13..]);
+    let checksum = lfn_checksum(&short);
+    mock_write_slot(&mut image, 2, 1, &mock_lfn_entry(0x42, &second, checksum));
+    mock_write_slot(&mut image, 2, 2, &mock_lfn_entry(0x01, &first, checksum));
+    mock_write_slot(&mut image, 2, 3, &mock_dir_entry(&short, 0x16, 5, 0));
+    mock_set_fat(&mut image, 5, 0x0FFFFFFF);
+    mock_write_slot(
+        &mut image,
+        5,
+        0,
+        &mock_dir_entry(b"WPSETT~1DAT", 0x26, 0, 12),
+    );
+
+    // A slot with the LFN attributes that is not laid out as an LFN entry.
+    mock_write_slot(
+        &mut image,
+        2,
+        4,
+        &mock_dir_entry(b"GARBAGE    ", 0x0F, 3, 0),
+    );
+
+    // An all-lower-case 8.3 name, stored by Windows without an LFN.
+    let mut readme = mock_dir_entry(b"README  TXT", 0x20, 0, 0);
+    readme[12] = 0x18;
+    mock_write_slot(&mut image, 2, 5, &readme);
+
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    let root = (&vfat).open_dir("/").expect("root");
+    let names = |options| -> Vec<String> {
+        root.entries_with(options)
+            .expect("entries")
+            .map(|entry| entry.name().to_string())
+            .collect()
+    };
+    assert_eq!(names(IterOptions::default()), vec!["readme.txt"]);
+    assert_eq!(
+        names(IterOptions::all()),
+        vec!["System Volume Information", "readme.txt"]
+    );
+    assert_eq!(vfat.borrow().corrupt_entries(), 2);
+    let short_names = IterOptions {
+        short_names: true,
+        ..IterOptions::all()
+    };
+    assert_eq!(names(short_names), vec!["SYSTEM~1", "README.TXT"]);
+
+    let svi = (&vfat)
+        .open_dir("/system volume information")
+        .expect("Windows system directory");
+    assert!(svi.metadata.hidden());
+    let settings = (&vfat)
+        .open_file("/System Volume Information/WPSETT~1.DAT")
+        .expect("file in system directory");
+    assert_eq!(settings.size(), 12);
+    assert!((&vfat).open("/MYCARD").is_err());
+    assert!((&vfat).open("/README.TXT").is_ok());
+}
+
+#[test]
+fn test_repair() {
+    use byteorder::{ByteOrder, LittleEndian};
+    use vfat::{Cluster, EntryLocation, LfnProblem, OrphanedLfn};
+    use vfat::{RepairAction, RepairOptions};
+
+    let mut image = mock_volume(16);
+    {
+        let info = &mut image[(MOCK_PARTITION_START + 1) * 512..];
+        LittleEndian::write_u32(&mut info[0..4], 0x41615252);
+        LittleEndian::write_u32(&mut info[484..488], 0x61417272);
+    }
+
+    // An LFN run followed by a deleted entry.
+    mock_write_slot(&mut image, 2, 0, &mock_lfn_entry(0x41, &[0x41; 13], 0));
+    let mut deleted = mock_dir_entry(b"GONE    TXT", 0x20, 0, 0);
+    deleted[0] = 0xE5;
+    mock_write_slot(&mut image, 2, 1, &deleted);
+    // A chain that loops back on itself.
+    mock_write_slot(
+        &mut image,
+        2,
+        2,
+        &mock_dir_entry(b"LOOP    BIN", 0x20, 5, 3 * 512),
+    );
+    mock_set_fat(&mut image, 5, 6);
+    mock_set_fat(&mut image, 6, 5);
+    // A chain longer than its file.
+    mock_write_slot(
+        &mut image,
+        2,
+        3,
+        &mock_dir_entry(b"BIG     BIN", 0x20, 7, 100),
+    );
+    mock_set_fat(&mut image, 7, 8);
+    mock_set_fat(&mut image, 8, 0x0FFFFFFF);
+    // A chain running into LOOP.BIN's.
+    mock_write_slot(
+        &mut image,
+        2,
+        4,
+        &mock_dir_entry(b"CROSS   BIN", 0x20, 9, 1024),
+    );
+    mock_set_fat(&mut image, 9, 6);
+    // A file beginning at a free cluster.
+    mock_write_slot(
+        &mut image,
+        2,
+        5,
+        &mock_dir_entry(b"BAD     BIN", 0x20, 15, 10),
+    );
+    // Two lost chains.
+    mock_set_fat(&mut image, 11, 12);
+    mock_set_fat(&mut image, 12, 0x0FFFFFFF);
+    mock_set_fat(&mut image, 13, 0x0FFFFFFF);
+
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    let location = |slot| {
+        Some(EntryLocation {
+            dir_cluster: Cluster(2),
+            slot,
+        })
+    };
+    let actions = vfat
+        .borrow_mut()
+        .repair(RepairOptions::default())
+        .expect("repair");
+    assert_eq!(
+        actions,
+        vec![
+            RepairAction::RemovedOrphanedLfn {
+                dir_cluster: Cluster(2),
+                orphan: OrphanedLfn {
+                    slots: 0..1,
+                    problem: LfnProblem::NoShortEntry,
+                },
+            },
+            RepairAction::TruncatedChain {
+                location: location(2),
+                clusters: 2,
+                reason: "loops",
+            },
+            RepairAction::FixedSize {
+                location: location(2).unwrap(),
+                old: 1536,
+                new: 1024,
+            },
+            RepairAction::TruncatedChain {
+                location: location(3),
+                clusters: 1,
+                reason: "is longer than the file",
+            },
+            RepairAction::TruncatedChain {
+                location: location(4),
+                clusters: 1,
+                reason: "runs into another chain",
+            },
+            RepairAction::FixedSize {
+                location: location(4).unwrap(),
+                old: 1024,
+                new: 512,
+            },
+            RepairAction::TruncatedChain {
+                location: location(5),
+                clusters: 0,
+                reason: "begins at an unusable cluster",
+            },
+            RepairAction::ReclaimedChain {
+                start: Cluster(11),
+                clusters: 2,
+                path: "/FOUND.000/FILE0000.CHK".to_string(),
+            },
+            RepairAction::ReclaimedChain {
+                start: Cluster(13),
+                clusters: 1,
+                path: "/FOUND.000/FILE0001.CHK".to_string(),
+            },
+            RepairAction::RebuiltFsInfo {
+                free_clusters: 7,
+                next_free: Cluster(4),
+            },
+        ]
+    );
+    assert_eq!(
+        actions[1].to_string(),
+        "entry 2:2: chain loops, cut to 2 clusters"
+    );
+
+    assert_eq!((&vfat).open_file("/loop.bin").expect("file").size(), 1024);
+    assert_eq!((&vfat).open_file("/bad.bin").expect("file").size(), 0);
+    let found = (&vfat)
+        .open_file("/FOUND.000/FILE0000.CHK")
+        .expect("reclaimed");
+    assert_eq!(found.size(), 1024);
+    let dir = (&vfat).open_dir("/FOUND.000").expect("FOUND.000");
+    assert_eq!(dir.orphaned_lfns().unwrap(), vec![]);
+
+    // A repaired volume needs no further repairs.
+    let again = vfat.borrow_mut().repair(RepairOptions::default()).unwrap();
+    assert_eq!(again, vec![]);
+}
+
+#[test]
+fn test_clone_volume() {
+    use vfat::clone_volume;
+
+    let mut image = mock_volume(16);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"MOCK       ", 0x08, 0, 0),
+    );
+    mock_write_slot(
+        &mut image,
+        2,
+        1,
+        &mock_dir_entry(b"DATA    BIN", 0x20, 5, 5),
+    );
+    mock_set_fat(&mut image, 5, 0x0FFFFFFF);
+    let offset = mock_cluster_offset(&image, 5);
+    image[offset..offset + 5].copy_from_slice(b"hello");
+    // A free cluster holding stale data, which is not copied.
+    let stale = mock_cluster_offset(&image, 9);
+    image[stale..stale + 5].copy_from_slice(b"stale");
+
+    let mut small = Cursor::new(vec![0; image.len() - 512]);
+    let e = clone_volume(Cursor::new(image.clone()), &mut small, None).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidInput);
+    let mut dst = Cursor::new(vec![0; image.len()]);
+    let e = clone_volume(Cursor::new(image.clone()), &mut dst, Some("a/b")).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidInput);
+
+    let copied = clone_volume(Cursor::new(image.clone()), &mut dst, Some("line 2")).unwrap();
+    assert!(copied < image.len() as u64);
+    let clone = dst.into_inner();
+    assert_eq!(&clone[stale..stale + 5], &[0; 5]);
+    let entry = mock_cluster_offset(&clone, 2);
+    assert_eq!(&clone[entry..entry + 11], b"LINE 2     ");
+
+    let vfat = VFat::from(Cursor::new(clone)).expect("clone mounts");
+    assert_eq!(vfat.borrow_mut().describe().unwrap().label, "LINE 2");
+    let mut data = String::new();
+    (&vfat)
+        .open_file("/data.bin")
+        .expect("copied file")
+        .read_to_string(&mut data)
+        .unwrap();
+    assert_eq!(data, "hello");
+}
+
+#[test]
+fn test_iter_all_files() {
+    use vfat::FileExtents;
+
+    // A fragmented file and an empty one in the root, a file in a
+    // subdirectory, and a file whose chain ends early.
+    let mut image = mock_volume(32);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"FRAG    BIN", 0x20, 4, 1500),
+    );
+    mock_set_fat(&mut image, 4, 5);
+    mock_set_fat(&mut image, 5, 9);
+    mock_set_fat(&mut image, 9, 0x0FFFFFFF);
+    mock_write_slot(
+        &mut image,
+        2,
+        1,
+        &mock_dir_entry(b"SUB        ", 0x10, 3, 0),
+    );
+    mock_set_fat(&mut image, 3, 0x0FFFFFFF);
+    mock_write_slot(
+        &mut image,
+        3,
+        0,
+        &mock_dir_entry(b".          ", 0x10, 3, 0),
+    );
+    mock_write_slot(
+        &mut image,
+        3,
+        1,
+        &mock_dir_entry(b"..         ", 0x10, 0, 0),
+    );
+    mock_write_slot(
+        &mut image,
+        3,
+        2,
+        &mock_dir_entry(b"INNER   TXT", 0x22, 12, 10),
+    );
+    mock_set_fat(&mut image, 12, 0x0FFFFFFF);
+    mock_write_slot(
+        &mut image,
+        2,
+        2,
+        &mock_dir_entry(b"EMPTY   BIN", 0x20, 0, 0),
+    );
+    mock_write_slot(
+        &mut image,
+        2,
+        3,
+        &mock_dir_entry(b"SHORT   BIN", 0x20, 14, 1000),
+    );
+    mock_set_fat(&mut image, 14, 0x0FFFFFFF);
+
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    let files: Vec<_> = VFat::iter_all_files(&vfat).expect("root").collect();
+    assert_eq!(files.len(), 4);
+
+    let extents = |file: &FileExtents| {
+        file.extents
+            .iter()
+            .map(|extent| (extent.start.0, extent.len))
+            .collect::<Vec<_>>()
+    };
+    let frag = files[0].as_ref().expect("fragmented file");
+    assert_eq!(frag.path, "/FRAG.BIN");
+    assert_eq!(frag.metadata.size, 1500);
+    assert_eq!(extents(frag), vec![(4, 2), (9, 1)]);
+
+    let inner = files[1].as_ref().expect("nested file");
+    assert_eq!(inner.path, "/SUB/INNER.TXT");
+    assert!(inner.metadata.hidden());
+    assert_eq!(extents(inner), vec![(12, 1)]);
+
+    let empty = files[2].as_ref().expect("empty file");
+    assert_eq!(empty.path, "/EMPTY.BIN");
+    assert!(empty.extents.is_empty());
+
+    let short = files[3].as_ref().expect_err("short chain");
+    assert_eq!(short.kind(), ::std::io::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn test_search_find() {
+    use std::time::{Duration, UNIX_EPOCH};
+    use vfat::search::{find, Query};
+    use vfat::{EntryKind, Timestamp};
+
+    let mut image = mock_volume(32);
+    let mut recent = mock_dir_entry(b"A       TXT", 0x20, 4, 100);
+    // Last modified on 2010-06-15.
+    recent[24..26].copy_from_slice(&((30u16 << 9) | (6 << 5) | 15).to_le_bytes());
+    mock_write_slot(&mut image, 2, 0, &recent);
+    mock_write_slot(
+        &mut image,
+        2,
+        1,
+        &mock_dir_entry(b"SUB        ", 0x10, 3, 0),
+    );
+    mock_write_slot(
+        &mut image,
+        3,
+        0,
+        &mock_dir_entry(b".          ", 0x10, 3, 0),
+    );
+    mock_write_slot(
+        &mut image,
+        3,
+        1,
+        &mock_dir_entry(b"..         ", 0x10, 0, 0),
+    );
+    mock_write_slot(
+        &mut image,
+        3,
+        2,
+        &mock_dir_entry(b"B       TXT", 0x20, 5, 2000),
+    );
+    mock_write_slot(
+        &mut image,
+        3,
+        3,
+        &mock_dir_entry(b"C       BIN", 0x20, 6, 10),
+    );
+    mock_write_slot(
+        &mut image,
+        3,
+        4,
+        &mock_dir_entry(b"DEEP       ", 0x10, 7, 0),
+    );
+    mock_write_slot(
+        &mut image,
+        7,
+        0,
+        &mock_dir_entry(b".          ", 0x10, 7, 0),
+    );
+    mock_write_slot(
+        &mut image,
+        7,
+        1,
+        &mock_dir_entry(b"..         ", 0x10, 3, 0),
+    );
+    mock_write_slot(
+        &mut image,
+        7,
+        2,
+        &mock_dir_entry(b"D       TXT", 0x20, 8, 50),
+    );
+    for &cluster in &[3, 4, 5, 6, 7, 8] {
+        mock_set_fat(&mut image, cluster, 0x0FFFFFFF);
+    }
+
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    let root = (&vfat).open_dir("/").expect("root");
+    let paths = |query: Query| {
+        find(&root, query)
+            .expect("search")
+            .map(|found| found.expect("entry").0)
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        paths(Query {
+            name: Some("*.txt".to_string()),
+            ..Default::default()
+        }),
+        vec!["A.TXT", "SUB/B.TXT", "SUB/DEEP/D.TXT"]
+    );
+    assert_eq!(
+        paths(Query {
+            name: Some("*.txt".to_string()),
+            max_depth: Some(2),
+            ..Default::default()
+        }),
+        vec!["A.TXT", "SUB/B.TXT"]
+    );
+    assert_eq!(
+        paths(Query {
+            min_size: Some(20),
+            max_size: Some(1000),
+            ..Default::default()
+        }),
+        vec!["A.TXT", "SUB/DEEP/D.TXT"]
+    );
+    assert_eq!(
+        paths(Query {
+            kind: Some(EntryKind::Dir),
+            ..Default::default()
+        }),
+        vec!["SUB", "SUB/DEEP"]
+    );
+    let y2k = Timestamp::from_system_time(UNIX_EPOCH + Duration::from_secs(946_684_800), 0);
+    assert_eq!(
+        paths(Query {
+            modified_after: Some(y2k),
+            ..Default::default()
+        }),
+        vec!["A.TXT"]
+    );
+
+    let sub = (&vfat).open_dir("/SUB").expect("sub");
+    let found = find(&sub, Query::default()).expect("search").count();
+    assert_eq!(found, 4);
+}
+
+#[test]
+fn test_validate_chain_on_open() {
+    use vfat::{Cluster, Error, MountOptions};
+
+    let mut image = mock_volume(16);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"GOOD    BIN", 0x20, 4, 1000),
+    );
+    mock_set_fat(&mut image, 4, 5);
+    mock_set_fat(&mut image, 5, 0x0FFFFFFF);
+    mock_write_slot(
+        &mut image,
+        2,
+        1,
+        &mock_dir_entry(b"SHORT   BIN", 0x20, 6, 1000),
+    );
+    mock_set_fat(&mut image, 6, 0x0FFFFFFF);
+    mock_write_slot(
+        &mut image,
+        2,
+        2,
+        &mock_dir_entry(b"FREE    BIN", 0x20, 7, 10),
+    );
+    mock_write_slot(
+        &mut image,
+        2,
+        3,
+        &mock_dir_entry(b"BAD     BIN", 0x20, 8, 1000),
+    );
+    mock_set_fat(&mut image, 8, 9);
+    mock_set_fat(&mut image, 9, 0x0FFFFFF7);
+
+    let lax = VFat::from(Cursor::new(image.clone())).expect("mock volume mounts");
+    (&lax)
+        .open_file("/SHORT.BIN")
+        .expect("opened without validation");
+
+    let options = MountOptions {
+        validate_on_open: true,
+        ..Default::default()
+    };
+    let vfat = VFat::with_options(Cursor::new(image), options).expect("mock volume mounts");
+    (&vfat).open_file("/GOOD.BIN").expect("sound chain");
+
+    for &(path, cluster) in &[("/SHORT.BIN", 6), ("/FREE.BIN", 7), ("/BAD.BIN", 9)] {
+        let e = (&vfat).open_file(path).expect_err(path);
+        assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidData);
+        match e.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+            Some(&Error::CorruptChain { cluster: found, .. }) => {
+                assert_eq!(found, Cluster(cluster), "{}", path)
+            }
+            other => panic!("{}: unexpected error {:?}", path, other),
+        }
+    }
+}
+
+#[test]
+fn test_buffered_appends() {
+    use std::io::SeekFrom;
+
+    let mut image = mock_volume(16);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"LOG     TXT", 0x20, 4, 500),
+    );
+    mock_set_fat(&mut image, 4, 0x0FFFFFFF);
+    let offset = mock_cluster_offset(&image, 4);
+    for byte in &mut image[offset..offset + 500] {
+        *byte = b'-';
+    }
+
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    let on_disk_size = || (&vfat).open_file("/LOG.TXT").expect("log").size();
+
+    let mut log = (&vfat).open_file("/LOG.TXT").expect("log");
+    assert!(log.write_all(b"too early").is_err());
+    log.seek(SeekFrom::End(0)).unwrap();
+
+    // Eleven appends of ten bytes fill the first cluster before the buffer
+    // holds a cluster's worth, so nothing is written until it is flushed.
+    for _ in 0..11 {
+        log.write_all(b"line 1234\n").expect("append");
+    }
+    assert_eq!(log.size(), 610);
+    assert_eq!(on_disk_size(), 500);
+    log.flush().expect("flush");
+    assert_eq!(on_disk_size(), 610);
+
+    // Unbuffered appends reach the entry at once.
+    log.set_append_buffering(false).unwrap();
+    log.write_all(b"tail").expect("append");
+    assert_eq!(on_disk_size(), 614);
+
+    log.set_append_buffering(true).unwrap();
+    log.write_all(&[b'x'; 600]).expect("append");
+    assert_eq!(on_disk_size(), 1214);
+    log.write_all(b"!").expect("append");
+    drop(log);
+    assert_eq!(on_disk_size(), 1215);
+
+    let mut data = Vec::new();
+    (&vfat)
+        .open_file("/LOG.TXT")
+        .unwrap()
+        .read_to_end(&mut data)
+        .expect("read");
+    assert_eq!(data.len(), 1215);
+    assert!(data[..500].iter().all(|&b| b == b'-'));
+    assert_eq!(&data[500..510], b"line 1234\n");
+    assert_eq!(&data[610..614], b"tail");
+    assert!(data[614..1214].iter().all(|&b| b == b'x'));
+    assert_eq!(data[1214], b'!');
+    assert_eq!(
+        vfat.borrow_mut().chain(::vfat::Cluster(4)).unwrap().len(),
+        3
+    );
+}
+
+#[test]
+fn test_failed_append() {
+    use std::io::SeekFrom;
+
+    let mut image = mock_volume(16);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"LOG     TXT", 0x20, 4, 500),
+    );
+    mock_set_fat(&mut image, 4, 0x0FFFFFFF);
+
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    let mut log = (&vfat).open_file("/LOG.TXT").expect("log");
+    log.seek(SeekFrom::End(0)).unwrap();
+    log.write_all(b"kept").expect("append");
+
+    // With the volume full, an append needing a new cluster fails, and
+    // neither the flush nor dropping the handle writes it later.
+    let free = vfat.borrow_mut().free_clusters().unwrap();
+    vfat.borrow_mut().allocate(free as u32, None).unwrap();
+    let e = log.write(&[b'x'; 600]).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::Other);
+    assert_eq!(log.seek(SeekFrom::Current(0)).unwrap(), 504);
+    log.flush().expect("flush");
+    drop(log);
+
+    let log = (&vfat).open_file("/LOG.TXT").expect("log");
+    assert_eq!(log.size(), 504);
+    assert_eq!(&log.read_to_vec().unwrap()[500..], b"kept");
+}
+
+#[test]
+fn test_open_traced() {
+    let mut image = mock_volume(16);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"OTHER   TXT", 0x20, 0, 0),
+    );
+    mock_write_slot(
+        &mut image,
+        2,
+        1,
+        &mock_dir_entry(b"SUB        ", 0x10, 3, 0),
+    );
+    mock_set_fat(&mut image, 3, 0x0FFFFFFF);
+    mock_write_slot(
+        &mut image,
+        3,
+        0,
+        &mock_dir_entry(b".          ", 0x10, 3, 0),
+    );
+    mock_write_slot(
+        &mut image,
+        3,
+        1,
+        &mock_dir_entry(b"..         ", 0x10, 0, 0),
+    );
+    mock_write_slot(
+        &mut image,
+        3,
+        2,
+        &mock_dir_entry(b"A       TXT", 0x20, 0, 0),
+    );
+
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    let (result, trace) = (&vfat).open_traced("/SUB/A.TXT");
+    result.expect("opened");
+    assert_eq!(trace.cached_components, 0);
+    assert_eq!(trace.steps.len(), 2);
+    assert_eq!(trace.steps[0].component, "SUB");
+    assert_eq!(trace.steps[0].dir, 2);
+    assert_eq!(trace.steps[0].entries_parsed, 2);
+    assert!(trace.steps[0].device_reads > 0);
+    assert_eq!(trace.steps[1].dir, 3);
+    assert_eq!(trace.steps[1].entries_parsed, 3);
+    assert!(trace.steps.iter().all(|step| step.found && !step.indexed));
+
+    // The directory is now cached by path and its sectors are in the cache.
+    let (result, trace) = (&vfat).open_traced("/SUB/MISSING.TXT");
+    assert_eq!(result.unwrap_err().kind(), ::std::io::ErrorKind::NotFound);
+    assert_eq!(trace.cached_components, 1);
+    assert_eq!(trace.steps.len(), 1);
+    assert_eq!(trace.steps[0].component, "MISSING.TXT");
+    assert_eq!(trace.steps[0].device_reads, 0);
+    assert!(!trace.steps[0].found);
+}
+
+#[test]
+fn test_raw_sector_passthrough() {
+    use byteorder::{ByteOrder, LittleEndian};
+    use vfat::{Cluster, Status};
+
+    let image = mock_volume(16);
+    let sectors_per_fat = 1;
+    let device = SharedImage::new(image);
+    let vfat = VFat::from(device.clone()).expect("mock volume mounts");
+    let mut vfat = vfat.borrow_mut();
+
+    let mut sector = [0u8; 512];
+    assert_eq!(
+        vfat.read_raw_sector(0, &mut sector).expect("boot sector"),
+        512
+    );
+    assert_eq!(&sector[82..90], b"FAT32   ");
+    assert_eq!(&sector[510..], &[0x55, 0xAA]);
+
+    // Patch the primary FAT directly; the change is seen at once and copied
+    // to the mirror on sync.
+    let fat = MOCK_RESERVED_SECTORS as u64;
+    vfat.read_raw_sector(fat, &mut sector).unwrap();
+    LittleEndian::write_u32(&mut sector[5 * 4..], 0x0FFFFFF7);
+    vfat.write_raw_sector(fat, &sector).expect("patched FAT");
+    assert_eq!(vfat.fat_entry(Cluster(5)).unwrap().status(), Status::Bad);
+    let mut mirror = [0u8; 512];
+    vfat.read_raw_sector(fat + sectors_per_fat, &mut mirror)
+        .unwrap();
+    assert_ne!(&mirror[..], &sector[..]);
+
+    vfat.sync().unwrap();
+    vfat.read_raw_sector(fat + sectors_per_fat, &mut mirror)
+        .unwrap();
+    assert_eq!(&mirror[..], &sector[..]);
+    let offset = (MOCK_PARTITION_START + MOCK_RESERVED_SECTORS + 1) * 512 + 5 * 4;
+    assert_eq!(
+        LittleEndian::read_u32(&device.image()[offset..offset + 4]),
+        0x0FFFFFF7
+    );
+
+    let total = (MOCK_RESERVED_SECTORS + 2 + 16) as u64;
+    let e = vfat.read_raw_sector(total, &mut sector).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidInput);
+    let e = vfat.write_raw_sector(0, &sector[..100]).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+#[cfg_attr(debug_assertions, should_panic(expected = "guarded sector"))]
+fn test_guard_metadata() {
+    use vfat::{Cluster, MountOptions, Status};
+
+    let mut options = MountOptions::default();
+    options.guard_metadata = true;
+    options.mark_dirty = true;
+    let device = SharedImage::new(mock_volume(16));
+    let vfat = VFat::with_options(device.clone(), options).expect("mounts");
+    let mut vfat = vfat.borrow_mut();
+
+    // The volume's own updates to the FATs and their state bits get through.
+    let start = vfat.write_chain(Cluster(0), &[7; 1000]).unwrap();
+    vfat.set_fat_entry(Cluster(9), Status::Bad).unwrap();
+    vfat.sync().unwrap();
+    assert_eq!(
+        vfat.fat_entry(start).unwrap().status(),
+        Status::Data(Cluster(start.0 + 1))
+    );
+    assert_eq!(vfat.fat_entry(Cluster(9)).unwrap().status(), Status::Bad);
+
+    // Raw writes to the data region are allowed; those to the FATs are not.
+    let mut sector = [0u8; 512];
+    let data = (MOCK_RESERVED_SECTORS + 2) as u64;
+    vfat.read_raw_sector(data, &mut sector).unwrap();
+    vfat.write_raw_sector(data, &sector).expect("data sector");
+    let fat = MOCK_RESERVED_SECTORS as u64;
+    vfat.read_raw_sector(fat, &mut sector).unwrap();
+    let e = vfat.write_raw_sector(fat, &sector).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::PermissionDenied);
+}
+
+#[test]
+fn test_max_file_size_boundaries() {
+    use std::io::SeekFrom;
+    use vfat::{Advice, Error, MountOptions};
+
+    let max = u32::max_value() as u64;
+    let mut image = mock_volume(16);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"HUGE    BIN", 0x20, 4, max as u32),
+    );
+    mock_set_fat(&mut image, 4, 0x0FFFFFFF);
+    mock_write_slot(
+        &mut image,
+        2,
+        1,
+        &mock_dir_entry(b"SMALL   BIN", 0x20, 0, 0),
+    );
+
+    let vfat = VFat::from(Cursor::new(image.clone())).expect("mock volume mounts");
+    let mut huge = (&vfat).open_file("/HUGE.BIN").expect("huge");
+    huge.advise(Advice::Random);
+    assert_eq!(huge.size(), max);
+    assert_eq!(huge.seek(SeekFrom::End(0)).unwrap(), max);
+    assert_eq!(huge.seek(SeekFrom::End(-1)).unwrap(), max - 1);
+    assert_eq!(huge.seek(SeekFrom::Start(max - 1)).unwrap(), max - 1);
+    for &pos in &[
+        SeekFrom::Current(2),
+        SeekFrom::End(1),
+        SeekFrom::End(i64::max_value()),
+        SeekFrom::Current(i64::max_value()),
+        SeekFrom::Current(i64::min_value()),
+        SeekFrom::End(i64::min_value()),
+        SeekFrom::Start(max + 1),
+        SeekFrom::Start(u64::max_value()),
+    ] {
+        let e = huge.seek(pos).expect_err("seek past the cap");
+        assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidInput);
+    }
+    assert_eq!(huge.seek(SeekFrom::Current(0)).unwrap(), max - 1);
+
+    // The chain holds one cluster, so reading near the cap finds no data.
+    let e = huge.read(&mut [0; 4]).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::UnexpectedEof);
+
+    huge.seek(SeekFrom::End(0)).unwrap();
+    let e = huge.write(b"x").unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidInput);
+    assert_eq!(huge.size(), max);
+
+    let options = MountOptions {
+        max_file_size: 1000,
+        ..Default::default()
+    };
+    let vfat = VFat::with_options(Cursor::new(image), options).expect("mock volume mounts");
+    let mut small = (&vfat).open_file("/SMALL.BIN").expect("small");
+    small.write_all(&[7; 1000]).expect("up to the limit");
+    match small.write(b"x") {
+        Err(ref e) => match e.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+            Some(&Error::FileTooLarge { limit: 1000 }) => {}
+            other => panic!("unexpected error {:?}", other),
+        },
+        Ok(_) => panic!("wrote past the limit"),
+    }
+    small.flush().unwrap();
+    assert_eq!((&vfat).open_file("/SMALL.BIN").unwrap().size(), 1000);
+}
+
+#[test]
+fn test_with_io_budget() {
+    use std::time::Duration;
+
+    let mut image = mock_volume(16);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"DATA    BIN", 0x20, 4, 4096),
+    );
+    for cluster in 4..11 {
+        mock_set_fat(&mut image, cluster, cluster + 1);
+    }
+    mock_set_fat(&mut image, 11, 0x0FFFFFFF);
+
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    let read = || {
+        let mut data = Vec::new();
+        (&vfat).open_file("/DATA.BIN")?.read_to_end(&mut data)
+    };
+
+    let e = VFat::with_io_budget(&vfat, Duration::from_secs(0), &read).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::TimedOut);
+    let read_all = VFat::with_io_budget(&vfat, Duration::from_secs(60), || {
+        VFat::with_io_budget(&vfat, Duration::from_secs(30), &read)
+    });
+    assert_eq!(read_all.expect("within budget"), 4096);
+
+    // The budget is lifted afterwards.
+    vfat.borrow_mut().forget_dirs();
+    assert_eq!(read().expect("unlimited"), 4096);
+}
+
+#[test]
+fn test_memory_budget() {
+    use vfat::{MemoryBudget, MountOptions};
+
+    let mut image = mock_volume(32);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"DATA    BIN", 0x20, 4, 4096),
+    );
+    for cluster in 4..11 {
+        mock_set_fat(&mut image, cluster, cluster + 1);
+    }
+    mock_set_fat(&mut image, 11, 0x0FFFFFFF);
+    for (slot, &name) in [b"D1         ", b"D2         ", b"D3         "]
+        .iter()
+        .enumerate()
+    {
+        let cluster = 20 + slot as u32;
+        mock_write_slot(
+            &mut image,
+            2,
+            slot + 1,
+            &mock_dir_entry(name, 0x10, cluster, 0),
+        );
+        mock_set_fat(&mut image, cluster, 0x0FFFFFFF);
+    }
+
+    // 4 sectors, 2048 bytes of file data, and 2 directories.
+    let budget = MemoryBudget {
+        cap: 4608,
+        ..MemoryBudget::default()
+    };
+    let vfat = VFat::with_options(
+        Cursor::new(image),
+        MountOptions {
+            memory_budget: Some(budget),
+            ..Default::default()
+        },
+    )
+    .expect("mock volume mounts");
+    assert_eq!(vfat.borrow().memory_budget(), Some(budget));
+
+    let mut data = Vec::new();
+    (&vfat)
+        .open_file("/DATA.BIN")
+        .unwrap()
+        .read_to_end(&mut data)
+        .unwrap();
+    for dir in &["/D1/X", "/D2/X", "/D3/X"] {
+        assert!((&vfat).open(dir).is_err());
+    }
+    let usage = vfat.borrow().memory_usage();
+    assert_eq!(data.len(), 4096);
+    assert!(usage.sectors <= 2048, "{:?}", usage);
+    assert_eq!(usage.file_data, 0);
+    assert_eq!(usage.dirs, 2 * ::vfat::DIR_CACHE_ENTRY_BYTES);
+    assert!(usage.total() <= budget.cap);
+
+    // Lifting the budget lets the caches grow; tightening it shrinks them.
+    vfat.borrow_mut().set_memory_budget(None).unwrap();
+    data.clear();
+    (&vfat)
+        .open_file("/DATA.BIN")
+        .unwrap()
+        .read_to_end(&mut data)
+        .unwrap();
+    assert!(vfat.borrow().memory_usage().sectors > 2048);
+    let tight = MemoryBudget {
+        cap: 1024,
+        sector_weight: 1,
+        file_data_weight: 0,
+        dir_weight: 0,
+    };
+    vfat.borrow_mut().set_memory_budget(Some(tight)).unwrap();
+    assert_eq!(
+        vfat.borrow().memory_usage(),
+        ::vfat::MemoryUsage {
+            sectors: 1024,
+            file_data: 0,
+            dirs: 0,
+        }
+    );
+}
+
+#[test]
+fn test_dir_iter_end_and_limits() {
+    use consts::MAX_DIR_ENTRIES;
+
+    let mut image = mock_volume(16);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"A       TXT", 0x20, 0, 0),
+    );
+    mock_write_slot(
+        &mut image,
+        2,
+        1,
+        &mock_dir_entry(b"LOOP       ", 0x10, 5, 0),
+    );
+    // Nothing after the end marker is an entry, even if it looks like one.
+    mock_write_slot(
+        &mut image,
+        2,
+        3,
+        &mock_dir_entry(b"AFTER   TXT", 0x20, 0, 0),
+    );
+
+    // A directory of full clusters whose chain loops back on itself.
+    mock_set_fat(&mut image, 5, 6);
+    mock_set_fat(&mut image, 6, 5);
+    for &cluster in &[5, 6] {
+        for slot in 0..16 {
+            mock_write_slot(
+                &mut image,
+                cluster,
+                slot,
+                &mock_dir_entry(b"F       TXT", 0x20, 0, 0),
+            );
+        }
+    }
+
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    let mut entries = (&vfat).open_dir("/").unwrap().entries().unwrap();
+    let names: Vec<String> = entries.by_ref().map(|e| e.name().to_string()).collect();
+    assert_eq!(names, vec!["A.TXT", "LOOP"]);
+    assert!(entries.next().is_none());
+    assert!(entries.next().is_none());
+
+    let looped = (&vfat).open_dir("/loop").unwrap().entries().unwrap();
+    assert_eq!(looped.count(), MAX_DIR_ENTRIES);
+}
+
+/// Builds a volume whose root holds the directory `BIG` of `entries` files
+/// named `F0000000.TXT` onward, filling whole clusters.
+fn mock_large_dir(entries: usize) -> Shared<VFat> {
+    let clusters = (entries + 15) / 16;
+    let mut image = mock_volume(clusters as u32 + 8);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"BIG        ", 0x10, 4, 0),
+    );
+    for i in 0..clusters as u32 {
+        mock_set_fat(&mut image, 4 + i, 5 + i);
+    }
+    mock_set_fat(&mut image, 4 + clusters as u32 - 1, 0x0FFFFFFF);
+    for i in 0..entries {
+        let mut name = [0u8; 11];
+        name.copy_from_slice(format!("F{:07}TXT", i).as_bytes());
+        mock_write_slot(
+            &mut image,
+            4 + (i / 16) as u32,
+            i % 16,
+            &mock_dir_entry(&name, 0x20, 0, 0),
+        );
+    }
+    VFat::from(Cursor::new(image)).expect("mock volume mounts")
+}
+
+#[test]
+fn test_find_stops_at_match() {
+    let mut image = mock_volume(16);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"BIG        ", 0x10, 4, 0),
+    );
+    mock_set_fat(&mut image, 4, 5);
+    mock_set_fat(&mut image, 5, 0x0FFFFFF7);
+    mock_write_slot(
+        &mut image,
+        4,
+        0,
+        &mock_dir_entry(b"EARLY   TXT", 0x20, 0, 0),
+    );
+    for slot in 1..16 {
+        mock_write_slot(
+            &mut image,
+            4,
+            slot,
+            &mock_dir_entry(b"FILL    TXT", 0x20, 0, 0),
+        );
+    }
+
+    // The chain runs into a bad cluster after the first, which a lookup only
+    // notices if it reads past the entry it is looking for.
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    let big = (&vfat).open_dir("/big").unwrap();
+    assert_eq!(big.find("early.txt").unwrap().name(), "EARLY.TXT");
+    let e = big.find("late.txt").unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidData);
+}
+
+/// A directory that implements only `entries()` and the case-folding hook, so
+/// that lookups through it use the trait's default `find()`.
+struct ListedDir(::vfat::Dir, bool);
+
+impl Dir for ListedDir {
+    type Entry = ::vfat::Entry;
+    type Iter = ::vfat::DirIter;
+
+    fn entries(&self) -> ::std::io::Result<Self::Iter> {
+        self.0.entries()
+    }
+
+    fn names_match(&self, entry: &Self::Entry, name: &str) -> bool {
+        if self.1 {
+            self.0.names_match(entry, name)
+        } else {
+            entry.name() == name
+        }
+    }
+}
+
+#[test]
+fn test_trait_find() {
+    let vfat = mock_large_dir(40);
+    let big = (&vfat).open_dir("/big").unwrap();
+
+    // The default implementation agrees with the indexed lookup.
+    let listed = ListedDir(big.clone(), true);
+    for name in &["F0000000.TXT", "f0000017.txt", "F0000039.Txt"] {
+        let found = listed.find(name).expect("listed entry");
+        assert_eq!(found.name(), big.find(name).unwrap().name());
+        assert_eq!(found.name(), Dir::find(&big, name).unwrap().name());
+    }
+    for name in &["F0000040.TXT", "F0000000", ""] {
+        let e = listed.find(name).unwrap_err();
+        assert_eq!(e.kind(), ::std::io::ErrorKind::NotFound);
+        let e = Dir::find(&big, name).unwrap_err();
+        assert_eq!(e.kind(), ::std::io::ErrorKind::NotFound);
+    }
+
+    // Without the hook, names compare exactly.
+    let exact = ListedDir(big, false);
+    assert_eq!(exact.find("F0000017.TXT").unwrap().name(), "F0000017.TXT");
+    let e = exact.find("f0000017.txt").unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::NotFound);
+
+    // A long name with an unpaired surrogate, shown as `a_b.txt`, is compared
+    // as stored by both lookups, so neither finds it by its shown name.
+    let mut image = mock_volume(16);
+    let short = *b"AB~1    TXT";
+    let mut units = [0xFFFF; 13];
+    units[..8].copy_from_slice(&[0x61, 0xD800, 0x62, 0x2E, 0x74, 0x78, 0x74, 0]);
+    let checksum = ::vfat::lfn_checksum(&short);
+    mock_write_slot(&mut image, 2, 0, &mock_lfn_entry(0x41, &units, checksum));
+    mock_write_slot(&mut image, 2, 1, &mock_dir_entry(&short, 0x20, 0, 0));
+    mock_write_slot(
+        &mut image,
+        2,
+        2,
+        &mock_dir_entry(b"A_B     TXT", 0x20, 0, 0),
+    );
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    let root = (&vfat).open_dir("/").unwrap();
+    let names: Vec<_> = root
+        .entries()
+        .unwrap()
+        .map(|e| e.name().to_string())
+        .collect();
+    assert_eq!(names, vec!["a_b.txt", "A_B.TXT"]);
+
+    let listed = ListedDir(root.clone(), true);
+    for name in &["a_b.txt", "A_B.TXT"] {
+        let found = listed.find(name).expect("listed entry");
+        assert_eq!(found.name(), "A_B.TXT");
+        assert_eq!(found.name(), root.find(name).unwrap().name());
+        assert_eq!(found.name(), Dir::find(&root, name).unwrap().name());
+    }
+}
+
+fn bench_find(b: &mut test::Bencher, position: usize) {
+    let vfat = mock_large_dir(16384);
+    let big = (&vfat).open_dir("/big").unwrap();
+    let name = format!("F{:07}.TXT", position);
+    b.iter(|| big.find(&name).unwrap());
+}
+
+#[bench]
+fn bench_find_first_entry(b: &mut test::Bencher) {
+    bench_find(b, 0);
+}
+
+#[bench]
+fn bench_find_middle_entry(b: &mut test::Bencher) {
+    bench_find(b, 8192);
+}
+
+#[bench]
+fn bench_find_last_entry(b: &mut test::Bencher) {
+    bench_find(b, 16383);
+}
+
+#[test]
+fn test_read_only_devices() {
+    use std::io::ErrorKind;
+    use vfat::MountOptions;
+
+    let mut image = mock_volume(16);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"DATA    BIN", 0x20, 4, 5),
+    );
+    mock_set_fat(&mut image, 4, 0x0FFFFFFF);
+    let offset = mock_cluster_offset(&image, 4);
+    image[offset..offset + 5].copy_from_slice(b"hello");
+
+    // A device that cannot write mounts read-only.
+    let image: &'static [u8] = Box::leak(image.into_boxed_slice());
+    let vfat = VFat::from(Cursor::new(image)).expect("read-only device mounts");
+    assert!(vfat.borrow().read_only());
+    let mut file = (&vfat).open_file("/DATA.BIN").unwrap();
+    let mut data = String::new();
+    file.read_to_string(&mut data).unwrap();
+    assert_eq!(data, "hello");
+    assert_eq!(
+        file.write(b"!").unwrap_err().kind(),
+        ErrorKind::PermissionDenied
+    );
+    let e = vfat
+        .borrow_mut()
+        .write_raw_sector(0, &[0; 512])
+        .unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+    let e = (&vfat).create_file("/NEW.TXT").unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+    let e = (&vfat).create_dir("/NEW", false).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+
+    // So does a writable device with `MountOptions::read_only` set.
+    let options = MountOptions {
+        read_only: true,
+        ..Default::default()
+    };
+    let vfat = VFat::with_options(Cursor::new(image.to_vec()), options).unwrap();
+    assert!(vfat.borrow().read_only());
+    let mut file = (&vfat).open_file("/DATA.BIN").unwrap();
+    file.seek(::std::io::SeekFrom::End(0)).unwrap();
+    assert_eq!(
+        file.write(b"!").unwrap_err().kind(),
+        ErrorKind::PermissionDenied
+    );
+    let e = (&vfat).remove("/DATA.BIN", false).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+
+    // A writable volume reports the changes it cannot make as unsupported.
+    let vfat = VFat::from(Cursor::new(image.to_vec())).unwrap();
+    assert!(!vfat.borrow().read_only());
+    let e = (&vfat).create_dir("/NEW", false).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::Other);
+    let e = (&vfat).rename("/DATA.BIN", "/MOVED.BIN").unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::Other);
+    let e = (&vfat).remove("/DATA.BIN", false).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::Other);
+}
+
+#[test]
+fn test_device_by_reference() {
+    struct LargeSectors;
+
+    impl BlockDevice for LargeSectors {
+        fn sector_size(&self) -> u64 {
+            4096
+        }
+
+        fn read_sector(&mut self, _n: u64, buf: &mut [u8]) -> ::std::io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn write_sector(&mut self, _n: u64, buf: &[u8]) -> ::std::io::Result<usize> {
+            Ok(buf.len())
+        }
+    }
+
+    // A borrowed device describes itself as the device does.
+    let mut device = LargeSectors;
+    assert_eq!((&mut device).sector_size(), 4096);
+    let mut device = Cursor::new(&[0u8; 512][..]);
+    assert!(!(&mut device).writable());
+    let e = (&mut device).write_sector(0, &[0; 512]).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::PermissionDenied);
+}
+
+#[test]
+fn test_entry_conversions() {
+    use std::convert::TryFrom;
+    use vfat::{Dir, EntryKind, File};
+
+    let mut image = mock_volume(16);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"DATA    BIN", 0x20, 4, 5),
+    );
+    mock_write_slot(
+        &mut image,
+        2,
+        1,
+        &mock_dir_entry(b"SUB        ", 0x10, 5, 0),
+    );
+    mock_set_fat(&mut image, 4, 0x0FFFFFFF);
+    mock_set_fat(&mut image, 5, 0x0FFFFFFF);
+
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    let root = (&vfat).open_dir("/").expect("root directory");
+    let file = root.find("DATA.BIN").expect("file");
+    let dir = root.find("SUB").expect("directory");
+    assert_eq!(file.kind(), EntryKind::File);
+    assert_eq!(dir.kind(), EntryKind::Dir);
+
+    // A failed conversion hands the entry back.
+    let file = Dir::try_from(file).unwrap_err();
+    assert_eq!(file.name(), "DATA.BIN");
+    let dir = File::try_from(dir).unwrap_err();
+    assert_eq!(dir.name(), "SUB");
+
+    let file = File::try_from(file).expect("file converts");
+    assert_eq!(file.size(), 5);
+    let dir = Dir::try_from(dir).expect("directory converts");
+    assert_eq!(dir.entries().unwrap().count(), 0);
+}
+
+#[test]
+fn test_entry_read() {
+    use std::io::ErrorKind;
+
+    let mut image = mock_volume(16);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"DATA    BIN", 0x20, 4, 5),
+    );
+    mock_write_slot(
+        &mut image,
+        2,
+        1,
+        &mock_dir_entry(b"SUB        ", 0x10, 5, 0),
+    );
+    mock_set_fat(&mut image, 4, 0x0FFFFFFF);
+    mock_set_fat(&mut image, 5, 0x0FFFFFFF);
+    let offset = mock_cluster_offset(&image, 4);
+    image[offset..offset + 5].copy_from_slice(b"hello");
+
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    let root = (&vfat).open_dir("/").expect("root directory");
+
+    let mut file = root.find("DATA.BIN").expect("file");
+    let mut data = String::new();
+    file.read_to_string(&mut data).expect("file entries read");
+    assert_eq!(data, "hello");
+
+    let mut dir = root.find("SUB").expect("directory");
+    let e = dir.read(&mut [0; 4]).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::Other);
+}
+
+#[test]
+fn test_entry_updates_during_iteration() {
+    use vfat::{Entry, Error};
+
+    let mut image = mock_volume(16);
+    let mut deleted = mock_dir_entry(b"GONE    TXT", 0x20, 0, 0);
+    deleted[0] = 0xE5;
+    mock_write_slot(&mut image, 2, 0, &deleted);
+    mock_write_slot(
+        &mut image,
+        2,
+        1,
+        &mock_dir_entry(b"A       TXT", 0x20, 4, 5),
+    );
+    mock_write_slot(
+        &mut image,
+        2,
+        2,
+        &mock_dir_entry(b"B       TXT", 0x20, 5, 3),
+    );
+    mock_set_fat(&mut image, 4, 0x0FFFFFFF);
+    mock_set_fat(&mut image, 5, 0x0FFFFFFF);
+    let stale_error =
+        |e: ::std::io::Error| match e.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+            Some(&Error::StaleHandle) => true,
+            _ => false,
+        };
+
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    let root = (&vfat).open_dir("/").unwrap();
+    let mut entries = root.entries().unwrap();
+    let mut listed_a = match entries.next() {
+        Some(Entry::File(file)) => file,
+        _ => panic!("expected A.TXT"),
+    };
+
+    // Entries updated and moved mid-listing do not disturb it.
+    let mut a = (&vfat).open_file("/A.TXT").unwrap();
+    a.set_append_buffering(false).unwrap();
+    a.seek(::std::io::SeekFrom::End(0)).unwrap();
+    a.write_all(b"!").unwrap();
+    assert_eq!(root.compact().unwrap(), 1);
+    let rest: Vec<(String, u32)> = entries
+        .map(|e| (e.name().to_string(), e.metadata().size))
+        .collect();
+    assert_eq!(rest, vec![("B.TXT".to_string(), 3)]);
+    assert_eq!(listed_a.size(), 5);
+
+    // Handles read before the compaction can no longer update their entries.
+    assert!(stale_error(a.write_all(b"?").unwrap_err()));
+    listed_a.seek(::std::io::SeekFrom::End(0)).unwrap();
+    listed_a.write_all(b"?").unwrap();
+    assert!(stale_error(listed_a.flush().unwrap_err()));
+    assert_eq!((&vfat).open_file("/B.TXT").unwrap().size(), 3);
+
+    let mut a = (&vfat).open_file("/A.TXT").unwrap();
+    assert_eq!(a.size(), 6);
+    a.seek(::std::io::SeekFrom::End(0)).unwrap();
+    a.write_all(b"?").unwrap();
+    a.flush().unwrap();
+    let mut data = String::new();
+    (&vfat)
+        .open_file("/A.TXT")
+        .unwrap()
+        .read_to_string(&mut data)
+        .unwrap();
+    assert_eq!(data.len(), 7);
+    assert!(data.ends_with("!?"));
+}
+
+#[test]
+fn test_surrogate_names() {
+    use vfat::{lfn_checksum, MountOptions, UnpairedSurrogates};
+
+    // A long name whose emoji is split across its two LFN entries, and one
+    // holding an unpaired low surrogate.
+    let paired = *b"ABCDEF~1TXT";
+    let mut first = [0; 13];
+    for (unit, c) in first.iter_mut().zip("abcdefghijkl".encode_utf16()) {
+        *unit = c;
+    }
+    first[12] = 0xD83D;
+    let mut second = [0xFFFF; 13];
+    second[..6].copy_from_slice(&[0xDE00, 0x2E, 0x74, 0x78, 0x74, 0]);
+
+    let lone = *b"_X      TXT";
+    let mut units = [0xFFFF; 13];
+    units[..7].copy_from_slice(&[0xDC00, 0x78, 0x2E, 0x74, 0x78, 0x74, 0]);
+
+    let mut image = mock_volume(16);
+    let checksum = lfn_checksum(&paired);
+    mock_write_slot(&mut image, 2, 0, &mock_lfn_entry(0x42, &second, checksum));
+    mock_write_slot(&mut image, 2, 1, &mock_lfn_entry(0x01, &first, checksum));
+    mock_write_slot(&mut image, 2, 2, &mock_dir_entry(&paired, 0x20, 0, 0));
+    mock_write_slot(
+        &mut image,
+        2,
+        3,
+        &mock_lfn_entry(0x41, &units, lfn_checksum(&lone)),
+    );
+    mock_write_slot(&mut image, 2, 4, &mock_dir_entry(&lone, 0x20, 0, 0));
+
+    let names = |vfat: &Shared<VFat>| -> Vec<String> {
+        vfat.open_dir("/")
+            .unwrap()
+            .entries()
+            .unwrap()
+            .map(|e| e.name().to_string())
+            .collect()
+    };
+
+    let vfat = VFat::from(Cursor::new(image.clone())).expect("mock volume mounts");
+    assert_eq!(names(&vfat), vec!["abcdefghijkl\u{1F600}.txt", "_x.txt"]);
+    let entry = (&vfat)
+        .open("/abcdefghijkl\u{1F600}.txt")
+        .expect("emoji name");
+    assert_eq!(entry.name(), "abcdefghijkl\u{1F600}.txt");
+
+    let mut options = MountOptions::default();
+    options.unpaired_surrogates = UnpairedSurrogates::ReplacementCharacter;
+    let vfat = VFat::with_options(Cursor::new(image), options).expect("mock volume mounts");
+    assert_eq!(
+        names(&vfat),
+        vec!["abcdefghijkl\u{1F600}.txt", "\u{FFFD}x.txt"]
+    );
+}
+
+#[test]
+fn test_reserved_clusters_and_protected_paths() {
+    use vfat::MountOptions;
+
+    let mut image = mock_volume(16);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"BOOT       ", 0x10, 3, 0),
+    );
+    mock_write_slot(
+        &mut image,
+        2,
+        1,
+        &mock_dir_entry(b"LOG     TXT", 0x20, 0, 0),
+    );
+    mock_write_slot(
+        &mut image,
+        3,
+        0,
+        &mock_dir_entry(b"CONFIG  TXT", 0x20, 4, 3),
+    );
+    mock_set_fat(&mut image, 3, 0x0FFFFFFF);
+    mock_set_fat(&mut image, 4, 0x0FFFFFFF);
+    let offset = mock_cluster_offset(&image, 4);
+    image[offset..offset + 3].copy_from_slice(b"abc");
+
+    let mut options = MountOptions::default();
+    options.reserved_clusters = 11;
+    options.protected_paths = vec!["/boot".into()];
+    let vfat = VFat::with_options(Cursor::new(image), options).expect("mock volume mounts");
+    assert_eq!(vfat.borrow_mut().free_clusters().unwrap(), 13);
+
+    // Writes may use free clusters down to the reserve, and no further.
+    let mut log = (&vfat).open_file("/log.txt").unwrap();
+    log.set_append_buffering(false).unwrap();
+    log.write_all(&[1; 1024]).unwrap();
+    assert_eq!(vfat.borrow_mut().free_clusters().unwrap(), 11);
+    let e = log.write_all(&[2]).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::Other);
+    assert_eq!((&vfat).open_file("/log.txt").unwrap().size(), 1024);
+    assert_eq!(vfat.borrow_mut().free_clusters().unwrap(), 11);
+
+    // Nothing beneath a protected path can be changed.
+    let mut config = (&vfat).open_file("/boot/config.txt").unwrap();
+    config.seek(::std::io::SeekFrom::End(0)).unwrap();
+    let e = config.write_all(b"d").unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::PermissionDenied);
+    let e = (&vfat).open_dir("/boot").unwrap().compact().unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::PermissionDenied);
+    let mut data = String::new();
+    (&vfat)
+        .open_file("/boot/config.txt")
+        .unwrap()
+        .read_to_string(&mut data)
+        .unwrap();
+    assert_eq!(data, "abc");
+}
+
+#[test]
+fn test_build_image() {
+    use vfat::mkfs::{build_image, ImageLayout, PartitionLayout, Tree};
+    use vfat::RepairOptions;
+
+    let mut boot = Tree::new();
+    boot.add_file("config.txt", b"arm_64bit=1\n".to_vec())
+        .unwrap();
+    boot.add_file("overlays/Long Overlay Name.dtbo", vec![7; 1500])
+        .unwrap();
+    boot.add_file("overlays/long overlay name.dtbo", vec![9; 1500])
+        .unwrap();
+    boot.add_file("empty", Vec::new()).unwrap();
+    boot.add_dir("logs").unwrap();
+    assert!(boot.add_file("config.txt/nested", Vec::new()).is_err());
+    assert!(boot.add_file("logs", Vec::new()).is_err());
+    assert!(boot.add_file("../escape", Vec::new()).is_err());
+
+    let mut data = Tree::new();
+    data.add_file("docs/readme.txt", b"hello".to_vec()).unwrap();
+
+    let mut layout = ImageLayout::default();
+    layout.disk_id = 0xC0FFEE;
+    let mut first = PartitionLayout::fat32(Some(8192), boot);
+    first.bootable = true;
+    first.format.label = Some("boot".to_string());
+    first.format.volume_id = 0x1234_5678;
+    layout.partitions.push(first);
+    layout
+        .partitions
+        .push(PartitionLayout::fat32(Some(8192), data));
+    let mut linux = PartitionLayout::fat32(None, Tree::new());
+    linux.partition_type = 0x83;
+    layout.partitions.push(linux);
+
+    let mut device = Cursor::new(vec![0; 12 << 20]);
+    let placed = build_image(&mut device, &layout).expect("build");
+    assert_eq!(placed, vec![(2048, 8192), (10240, 8192), (18432, 6144)]);
+
+    let mut image = device.into_inner();
+    let mbr = MasterBootRecord::from(&mut Cursor::new(image.clone())).expect("mbr");
+    let entries = mbr.partition_table_entries;
+    assert_eq!(entries[0].boot_indicator_flag, 0x80);
+    assert_eq!(entries[1].boot_indicator_flag, 0x00);
+    assert_eq!(entries[2].partition_type, 0x83);
+
+    let vfat = VFat::from(Cursor::new(image.clone())).expect("first partition mounts");
+    let report = vfat.borrow_mut().describe().expect("describe");
+    assert_eq!(report.label, "BOOT");
+    assert_eq!(report.serial, 0x1234_5678);
+    assert!(vfat.borrow().geometry_warning().is_none());
+    assert!(vfat.borrow().media_warning().is_none());
+
+    let mut text = String::new();
+    (&vfat)
+        .open_file("/CONFIG.TXT")
+        .and_then(|mut file| file.read_to_string(&mut text))
+        .expect("read config");
+    assert_eq!(text, "arm_64bit=1\n");
+    let mut overlay = Vec::new();
+    (&vfat)
+        .open_file("/overlays/Long Overlay Name.dtbo")
+        .and_then(|mut file| file.read_to_end(&mut overlay))
+        .expect("read overlay");
+    assert_eq!(overlay, vec![9; 1500]);
+    assert_eq!((&vfat).open_file("/empty").unwrap().size(), 0);
+    let names: Vec<String> = (&vfat)
+        .open_dir("/")
+        .unwrap()
+        .entries()
+        .unwrap()
+        .map(|entry| entry.name().to_string())
+        .collect();
+    assert_eq!(names, vec!["config.txt", "empty", "logs", "overlays"]);
+    let names: Vec<String> = (&vfat)
+        .open_dir("/logs")
+        .unwrap()
+        .entries()
+        .unwrap()
+        .map(|entry| entry.name().to_string())
+        .collect();
+    assert_eq!(names, vec![".", ".."]);
+
+    let free = vfat.borrow_mut().free_clusters().unwrap();
+    let used = vfat.borrow().num_clusters() as u64 - free;
+    // The root, config.txt, the overlay, and two directories.
+    assert_eq!(used, 1 + 1 + 3 + 2);
+    assert!(vfat
+        .borrow_mut()
+        .repair(RepairOptions::default())
+        .unwrap()
+        .is_empty());
+
+    // Swap the first two partition entries to mount the second.
+    let (a, b) = image[446..478].split_at_mut(16);
+    a.swap_with_slice(b);
+    let vfat = VFat::from(Cursor::new(image)).expect("second partition mounts");
+    assert_eq!(vfat.borrow().partition_start(), 10240);
+    let mut text = String::new();
+    (&vfat)
+        .open_file("/docs/readme.txt")
+        .and_then(|mut file| file.read_to_string(&mut text))
+        .expect("read readme");
+    assert_eq!(text, "hello");
+}
+
+#[test]
+fn test_apply_manifest() {
+    use vfat::mkfs::{build_image, ImageLayout, PartitionLayout, Tree};
+    use vfat::sha256::sha256;
+    use vfat::{apply_manifest, Manifest, RepairAction, RepairOptions};
+
+    let old_kernel: Vec<u8> = (0..1500).map(|i| i as u8).collect();
+    let new_kernel = vec![0x4B; 2100];
+    let mut tree = Tree::new();
+    tree.add_file("kernel.img", old_kernel.clone()).unwrap();
+    tree.add_file("config.txt", b"old".to_vec()).unwrap();
+    tree.add_file("cmdline.txt", b"quiet".to_vec()).unwrap();
+    let mut layout = ImageLayout::default();
+    layout.partitions.push(PartitionLayout::fat32(None, tree));
+    let mut device = Cursor::new(vec![0; 4 << 20]);
+    build_image(&mut device, &layout).expect("build");
+    let image = device.into_inner();
+
+    let read = |vfat: &Shared<VFat>, path: &str| {
+        let mut data = Vec::new();
+        vfat.open_file(path)
+            .and_then(|mut file| file.read_to_end(&mut data))
+            .unwrap_or_else(|e| panic!("reading {}: {}", path, e));
+        data
+    };
+
+    // Manifests that do not match the volume change nothing.
+    let vfat = VFat::from(Cursor::new(image.clone())).expect("mount");
+    let mut wrong = Manifest::new();
+    wrong.replace("/kernel.img", sha256(&old_kernel), new_kernel.clone());
+    wrong.replace("/config.txt", sha256(b"other"), b"new".to_vec());
+    let e = apply_manifest(&vfat, &wrong).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidData);
+    let mut twice = Manifest::new();
+    twice.replace("/kernel.img", sha256(&old_kernel), new_kernel.clone());
+    twice.replace("/KERNEL.IMG", sha256(&old_kernel), new_kernel.clone());
+    let e = apply_manifest(&vfat, &twice).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidInput);
+    let mut orphan = Manifest::new();
+    orphan.replace("/kernel.img", sha256(&old_kernel), new_kernel.clone());
+    orphan.add("/missing/new.txt", b"new".to_vec());
+    let e = apply_manifest(&vfat, &orphan).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidInput);
+    assert_eq!(read(&vfat, "/kernel.img"), old_kernel);
+
+    let mut manifest = Manifest::new();
+    manifest.replace("/kernel.img", sha256(&old_kernel), new_kernel.clone());
+    manifest.replace("/config.txt", sha256(b"old"), b"new".to_vec());
+    manifest.add("/cmdline.txt", b"quiet".to_vec());
+    manifest.add("/overlay.dtbo", vec![0x0D; 700]);
+
+    let device = RecordingDevice::new(image.clone());
+    let vfat = VFat::from(device.clone()).expect("mount");
+    let free = vfat.borrow_mut().free_clusters().unwrap();
+    let diff = apply_manifest(&vfat, &manifest).expect("apply");
+    assert_eq!(diff.changed.len(), 3);
+    assert_eq!(diff.changed[0].path, "/kernel.img");
+    assert_eq!(diff.changed[0].before, Some((1500, sha256(&old_kernel))));
+    assert_eq!(diff.changed[0].after, (2100, sha256(&new_kernel)));
+    assert_eq!(diff.changed[2].before, None);
+    assert_eq!(diff.unchanged, vec!["/cmdline.txt"]);
+    assert!(diff
+        .to_string()
+        .starts_with("~ /kernel.img: 1500 -> 2100 bytes\n"));
+    assert!(diff.to_string().contains("+ /overlay.dtbo: 700 bytes\n"));
+    assert_eq!(read(&vfat, "/kernel.img"), new_kernel);
+    assert_eq!(read(&vfat, "/config.txt"), b"new");
+    assert_eq!(read(&vfat, "/overlay.dtbo"), vec![0x0D; 700]);
+    assert_eq!(vfat.borrow_mut().free_clusters().unwrap(), free - 4);
+
+    // Applying it again finds every file already updated.
+    let writes = device.writes();
+    let again = apply_manifest(&vfat, &manifest).expect("apply again");
+    assert!(again.changed.is_empty());
+    assert_eq!(again.unchanged.len(), 4);
+
+    // Losing power at any point leaves each file whole, old or new, and an
+    // added file missing, empty, or whole. Applying the manifest again
+    // completes the update.
+    for count in 0..writes + 1 {
+        let vfat = VFat::from(Cursor::new(device.replay(&image, count)))
+            .unwrap_or_else(|e| panic!("unmountable after {} writes: {:?}", count, e));
+        let repairs = vfat
+            .borrow_mut()
+            .repair(RepairOptions::default())
+            .expect("repair");
+        // Once the update completes, no chain is left to reclaim.
+        if count == writes {
+            assert!(
+                repairs.iter().all(|repair| match *repair {
+                    RepairAction::RebuiltFsInfo { .. } => true,
+                    _ => false,
+                }),
+                "{:?}",
+                repairs
+            );
+        }
+        let kernel = read(&vfat, "/kernel.img");
+        assert!(kernel == old_kernel || kernel == new_kernel, "{}", count);
+        let config = read(&vfat, "/config.txt");
+        assert!(config == b"old" || config == b"new", "{}", count);
+        match (&vfat).open_file("/overlay.dtbo") {
+            Ok(file) => assert!(file.size() == 0 || file.size() == 700, "{}", count),
+            Err(e) => assert_eq!(e.kind(), ::std::io::ErrorKind::NotFound, "{}", count),
+        }
+
+        apply_manifest(&vfat, &manifest).unwrap_or_else(|e| panic!("{}: {}", count, e));
+        assert_eq!(read(&vfat, "/kernel.img"), new_kernel);
+        assert_eq!(read(&vfat, "/overlay.dtbo"), vec![0x0D; 700]);
+    }
+}
+
+#[test]
+fn test_write_atomic() {
+    use vfat::mkfs::{build_image, ImageLayout, PartitionLayout, Tree};
+    use vfat::{Error, MountOptions};
+
+    let mut tree = Tree::new();
+    tree.add_file("config.txt", vec![b'o'; 700]).unwrap();
+    let mut layout = ImageLayout::default();
+    layout.partitions.push(PartitionLayout::fat32(None, tree));
+    let mut device = Cursor::new(vec![0; 4 << 20]);
+    build_image(&mut device, &layout).expect("build");
+    let image = device.into_inner();
+    let new = vec![b'n'; 1300];
+
+    let read = |vfat: &Shared<VFat>| {
+        let mut data = Vec::new();
+        vfat.open_file("/config.txt")
+            .and_then(|mut file| file.read_to_end(&mut data))
+            .expect("read");
+        data
+    };
+
+    let device = RecordingDevice::new(image.clone());
+    let vfat = VFat::from(device.clone()).expect("mount");
+    let free = vfat.borrow_mut().free_clusters().unwrap();
+    vfat.write_atomic("/config.txt", &new).expect("write");
+    assert_eq!(read(&vfat), new);
+    assert_eq!(vfat.borrow_mut().free_clusters().unwrap(), free - 1);
+
+    // Losing power at any point leaves the old contents or the new.
+    let writes = device.writes();
+    for count in 0..writes + 1 {
+        let vfat = VFat::from(Cursor::new(device.replay(&image, count))).expect("mount");
+        let data = read(&vfat);
+        assert!(data == vec![b'o'; 700] || data == new, "{}", count);
+    }
+
+    // A missing file is created.
+    vfat.write_atomic("/new.cfg", b"x").expect("create");
+    let mut data = Vec::new();
+    (&vfat)
+        .open_file("/new.cfg")
+        .and_then(|mut file| file.read_to_end(&mut data))
+        .expect("read");
+    assert_eq!(data, b"x");
+    let e = vfat.write_atomic("/missing/new.cfg", b"x").unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidInput);
+
+    let options = MountOptions {
+        max_file_size: 1000,
+        ..Default::default()
+    };
+    let vfat = VFat::with_options(Cursor::new(image), options).expect("mount");
+    let e = vfat.write_atomic("/config.txt", &new).unwrap_err();
+    match e.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+        Some(&Error::FileTooLarge { limit: 1000 }) => {}
+        other => panic!("unexpected error {:?}", other),
+    }
+}
+
+#[test]
+fn test_open_with() {
+    use std::io::ErrorKind;
+    use vfat::MountOptions;
+
+    let vfat = VFat::from(SharedImage::new(mock_volume(16))).expect("mock volume mounts");
+    let open = |path: &str, options: &mut OpenOptions| (&vfat).open_with(path, options);
+
+    let mut file = open("/b.txt", OpenOptions::new().write(true).create(true)).expect("create");
+    file.write_all(b"hello").unwrap();
+    file.flush().unwrap();
+    drop(file);
+
+    let e = open("/b.txt", OpenOptions::new().write(true).create_new(true)).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::AlreadyExists);
+    let e = open("/c.txt", OpenOptions::new().write(true)).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::NotFound);
+
+    let mut file = open("/b.txt", OpenOptions::new().append(true)).expect("append");
+    file.write_all(b", world").unwrap();
+    file.flush().unwrap();
+    let mut data = String::new();
+    (&vfat)
+        .open_file("/b.txt")
+        .unwrap()
+        .read_to_string(&mut data)
+        .unwrap();
+    assert_eq!(data, "hello, world");
+
+    let file = open("/b.txt", OpenOptions::new().write(true).truncate(true)).expect("truncate");
+    assert_eq!(file.size(), 0);
+    let file = open("/d.txt", OpenOptions::new().write(true).create_new(true)).expect("create");
+    assert_eq!(file.size(), 0);
+
+    // A read-only volume refuses options that may modify it.
+    let options = MountOptions {
+        read_only: true,
+        ..Default::default()
+    };
+    let vfat = VFat::with_options(Cursor::new(mock_volume(16)), options).unwrap();
+    let e = (&vfat)
+        .open_with("/b.txt", OpenOptions::new().write(true).create(true))
+        .unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+}
+
+#[test]
+fn test_dir_timestamps() {
+    use std::time::{Duration, UNIX_EPOCH};
+    use vfat::mkfs::{build_image, ImageLayout, PartitionLayout, Tree};
+    use vfat::{DeterministicOptions, DirTimestamps, MountOptions, Timestamp};
+
+    let mut tree = Tree::new();
+    tree.add_file("boot/config.txt", b"old".to_vec()).unwrap();
+    tree.add_file("cmdline.txt", b"old".to_vec()).unwrap();
+    let mut layout = ImageLayout::default();
+    layout.partitions.push(PartitionLayout::fat32(None, tree));
+    let mut device = Cursor::new(vec![0; 4 << 20]);
+    build_image(&mut device, &layout).expect("build");
+    let image = device.into_inner();
+
+    let stamp = Timestamp::from_system_time(UNIX_EPOCH + Duration::from_secs(1_000_000_000), 0);
+    let modified = |policy: DirTimestamps, path: &str| {
+        let options = MountOptions {
+            deterministic: Some(DeterministicOptions { timestamp: stamp }),
+            dir_timestamps: policy,
+            ..Default::default()
+        };
+        let device = SharedImage::new(image.clone());
+        let vfat = VFat::with_options(device.clone(), options).expect("mount");
+        vfat.write_atomic(path, b"new").expect("write");
+        let mut file = (&vfat).open_file(path).unwrap();
+        file.seek(::std::io::SeekFrom::End(0)).unwrap();
+        file.write_all(b"er").unwrap();
+        file.flush().unwrap();
+        vfat.borrow_mut().sync().unwrap();
+        let vfat = VFat::from(Cursor::new(device.image())).expect("remount");
+        let dir = (&vfat).open_dir("/boot").unwrap();
+        dir.metadata.last_modified
+    };
+
+    let before = modified(DirTimestamps::Never, "/cmdline.txt");
+    assert_ne!(before, stamp);
+    assert_eq!(modified(DirTimestamps::Never, "/boot/config.txt"), before);
+    assert_eq!(
+        modified(DirTimestamps::OnCreateDelete, "/boot/config.txt"),
+        before
+    );
+    assert_eq!(modified(DirTimestamps::Always, "/boot/config.txt"), stamp);
+    assert_eq!(
+        modified(DirTimestamps::OnCreateDelete, "/boot/new.txt"),
+        stamp
+    );
+}
+
+#[test]
+fn test_volume_manager_from_table() {
+    use vfat::mkfs::{build_image, ImageLayout, PartitionLayout, Tree};
+    use vfat::{MountEntry, MountError, MountOptions, PartitionSelector, VolumeManager};
+
+    let mut boot = Tree::new();
+    boot.add_file("config.txt", b"boot".to_vec()).unwrap();
+    let mut data = Tree::new();
+    data.add_file("config.txt", b"data".to_vec()).unwrap();
+    let mut layout = ImageLayout::default();
+    layout
+        .partitions
+        .push(PartitionLayout::fat32(Some(8192), boot));
+    let mut second = PartitionLayout::fat32(None, data);
+    second.format.volume_id = 0xD47A;
+    layout.partitions.push(second);
+    let mut device = Cursor::new(vec![0; 8 << 20]);
+    build_image(&mut device, &layout).expect("build");
+    let image = device.into_inner();
+
+    let entry = |device: &str, partition, prefix: &str| MountEntry {
+        device: device.to_string(),
+        partition,
+        options: MountOptions::default(),
+        prefix: prefix.into(),
+    };
+    let table = vec![
+        entry("sd", PartitionSelector::FirstFat, "/"),
+        entry("sd", PartitionSelector::VolumeId(0xD47A), "/data"),
+        entry("sd", PartitionSelector::Index(1), "/data/"),
+        entry("sd", PartitionSelector::Index(3), "/spare"),
+        entry("usb", PartitionSelector::FirstFat, "/usb"),
+        entry("sd", PartitionSelector::FirstFat, "relative"),
+    ];
+    let mut opened = Vec::new();
+    let (manager, failures) = VolumeManager::from_table(&table, |device| {
+        opened.push(device.to_string());
+        match device {
+            "sd" => Ok(Cursor::new(image.clone())),
+            _ => Err(::std::io::Error::new(
+                ::std::io::ErrorKind::NotFound,
+                "no such device",
+            )),
+        }
+    });
+    // The volume id is found on the second partition tried.
+    assert_eq!(opened, vec!["sd", "sd", "sd", "sd", "usb"]);
+
+    let failed: Vec<_> = failures
+        .iter()
+        .map(|failure| match failure.error {
+            MountError::PrefixInUse => (failure.index, "in use"),
+            MountError::NoPartition => (failure.index, "no partition"),
+            MountError::Device(_) => (failure.index, "device"),
+            MountError::BadPrefix => (failure.index, "bad prefix"),
+            _ => panic!("unexpected failure: {}", failure),
+        })
+        .collect();
+    assert_eq!(
+        failed,
+        vec![
+            (2, "in use"),
+            (3, "no partition"),
+            (4, "device"),
+            (5, "bad prefix"),
+        ]
+    );
+    assert_eq!(manager.mounts().count(), 2);
+
+    let read = |path: &str| {
+        let (volume, rest) = manager.resolve(path).expect("resolves");
+        let mut data = String::new();
+        volume
+            .open_file(rest)
+            .and_then(|mut file| file.read_to_string(&mut data))
+            .expect("read");
+        data
+    };
+    assert_eq!(read("/config.txt"), "boot");
+    assert_eq!(read("/data/config.txt"), "data");
+    assert_eq!(read("/data/../config.txt"), "boot");
+}
+
+#[test]
+fn test_cluster_checksums() {
+    use vfat::crc32::crc32;
+    use vfat::{Cluster, Error, MemoryBudget, MountOptions};
+
+    assert_eq!(crc32(b"123456789"), 0xCBF43926);
+
+    let mut image = mock_volume(16);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"A       BIN", 0x20, 3, 1536),
+    );
+    mock_set_fat(&mut image, 3, 4);
+    mock_set_fat(&mut image, 4, 5);
+    mock_set_fat(&mut image, 5, 0x0FFFFFFF);
+    let start = mock_cluster_offset(&image, 3);
+    for (i, byte) in image[start..start + 1536].iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+
+    // With room for a single sector and no file data, every read of a
+    // cluster refills it from the device.
+    let options = MountOptions {
+        cluster_checksums: true,
+        memory_budget: Some(MemoryBudget {
+            cap: 512,
+            sector_weight: 1,
+            file_data_weight: 0,
+            dir_weight: 0,
+        }),
+        ..Default::default()
+    };
+    let device = SharedImage::new(image);
+    let vfat = VFat::with_options(device.clone(), options).expect("mounts");
+    let read = || {
+        let mut data = Vec::new();
+        (&vfat)
+            .open_file("/A.BIN")
+            .and_then(|mut file| file.read_to_end(&mut data))
+            .map(|_| data)
+    };
+    assert_eq!(read().expect("first read").len(), 1536);
+    assert_eq!(read().expect("unchanged").len(), 1536);
+
+    device.flip(start + 512 + 7);
+    let e = read().unwrap_err();
+    match e.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+        Some(&Error::BitRotDetected {
+            cluster: Cluster(4),
+            file: Some((Cluster(3), 512)),
+        }) => {}
+        other => panic!("unexpected error {:?}", other),
+    }
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidData);
+
+    // A cluster written through the volume is checksummed anew.
+    vfat.borrow_mut()
+        .write_cluster(Cluster(4), 0, &[0; 512])
+        .unwrap();
+    vfat.borrow_mut().sync().unwrap();
+    assert_eq!(read().expect("rewritten")[512..1024], [0; 512][..]);
+}
+
+#[test]
+fn test_read_into_and_read_to_vec() {
+    use std::io::SeekFrom;
+    use std::sync::Arc;
+
+    let mut image = mock_volume(16);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"A       BIN", 0x20, 3, 1200),
+    );
+    mock_set_fat(&mut image, 3, 4);
+    mock_set_fat(&mut image, 4, 5);
+    mock_set_fat(&mut image, 5, 0x0FFFFFFF);
+    let start = mock_cluster_offset(&image, 3);
+    let expected: Vec<u8> = (0..1200).map(|i| (i % 251) as u8).collect();
+    image[start..start + 1200].copy_from_slice(&expected);
+
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    let mut file = (&vfat).open_file("/A.BIN").expect("file");
+    file.seek(SeekFrom::Start(100)).unwrap();
+
+    let data = file.read_to_vec().expect("read_to_vec");
+    assert_eq!(data, expected);
+    assert_eq!(data.capacity(), 1200);
+
+    let mut buf = [0; 300];
+    assert_eq!(file.read_into(500, &mut buf).unwrap(), 300);
+    assert_eq!(&buf[..], &expected[500..800]);
+    assert_eq!(file.read_into(1000, &mut buf).unwrap(), 200);
+    assert_eq!(&buf[..200], &expected[1000..]);
+    assert_eq!(file.read_into(1200, &mut buf).unwrap(), 0);
+    assert_eq!(file.read_into(5000, &mut buf).unwrap(), 0);
+    assert_eq!(file.seek(SeekFrom::Current(0)).unwrap(), 100);
+
+    // Disjoint ranges read at once through one shared handle.
+    let file = Arc::new(file);
+    let readers: Vec<_> = (0..4u64)
+        .map(|i| {
+            let file = file.clone();
+            ::std::thread::spawn(move || {
+                let mut buf = vec![0; 300];
+                file.read_into(i * 300, &mut buf).map(|_| buf)
+            })
+        })
+        .collect();
+    for (i, reader) in readers.into_iter().enumerate() {
+        let buf = reader.join().expect("reader thread").unwrap();
+        assert_eq!(&buf[..], &expected[i * 300..(i + 1) * 300]);
+    }
+}
+
+#[test]
+fn test_write_at() {
+    use std::io::SeekFrom;
+
+    let mut image = mock_volume(16);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"RECORDS DAT", 0x20, 3, 1000),
+    );
+    mock_set_fat(&mut image, 3, 4);
+    mock_set_fat(&mut image, 4, 0x0FFFFFFF);
+    let start = mock_cluster_offset(&image, 3);
+    let mut expected = vec![0xAA; 1000];
+    image[start..start + 1000].copy_from_slice(&expected);
+
+    let device = SharedImage::new(image);
+    let vfat = VFat::from(device.clone()).expect("mock volume mounts");
+    let mut file = (&vfat).open_file("/records.dat").expect("file");
+    file.seek(SeekFrom::Start(10)).unwrap();
+    assert_eq!(file.read_to_vec().unwrap(), expected);
+
+    // A record straddling the cluster boundary is updated in place.
+    assert_eq!(file.write_at(500, &[1; 20]).unwrap(), 20);
+    expected[500..520].copy_from_slice(&[1; 20]);
+    assert_eq!(file.read_to_vec().unwrap(), expected);
+
+    // A record running past the end of the file extends it.
+    assert_eq!(file.write_at(990, &[2; 100]).unwrap(), 100);
+    expected.truncate(990);
+    expected.extend_from_slice(&[2; 100]);
+    assert_eq!(file.metadata().size, 1090);
+    assert_eq!(file.seek(SeekFrom::Current(0)).unwrap(), 10);
+
+    let e = file.write_at(2000, &[3]).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidInput);
+
+    // With the volume full, a record that would need a new cluster fails
+    // without changing the bytes it overlaps, now or when flushed.
+    let free = vfat.borrow_mut().free_clusters().unwrap();
+    vfat.borrow_mut().allocate(free as u32, None).unwrap();
+    let e = file.write_at(1000, &[4; 600]).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::Other);
+    file.flush().expect("flush");
+    assert_eq!(file.metadata().size, 1090);
+    assert_eq!(file.read_to_vec().unwrap(), expected);
+
+    vfat.borrow_mut().sync().unwrap();
+    drop(file);
+    let remounted = VFat::from(Cursor::new(device.image())).expect("remounts");
+    let file = (&remounted).open_file("/records.dat").expect("file");
+    assert_eq!(file.read_to_vec().unwrap(), expected);
+}
+
+#[test]
+fn test_remount() {
+    use std::io::SeekFrom;
+    use vfat::{Error, File};
+
+    let mut image = mock_volume(16);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"HELLO   TXT", 0x20, 5, 5),
+    );
+    mock_set_fat(&mut image, 5, 0x0FFFFFFF);
+    let offset = mock_cluster_offset(&image, 5);
+    image[offset..offset + 5].copy_from_slice(b"hello");
+
+    let device = SharedImage::new(image.clone());
+    let vfat = VFat::from(device.clone()).expect("mock volume mounts");
+    let mut file = (&vfat).open_file("/hello.txt").expect("file");
+    let read = |file: &mut File| {
+        let mut buf = String::new();
+        file.seek(SeekFrom::Start(0))
+            .and_then(|_| file.read_to_string(&mut buf))
+            .map(|_| buf)
+    };
+    assert_eq!(read(&mut file).unwrap(), "hello");
+
+    // The card is pulled: the old device reports a media change.
+    device.swap(vec![0; image.len()]);
+    let e = read(&mut file).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::NotFound);
+
+    // Another volume is refused and the old device is kept.
+    let mut other = image.clone();
+    other[MOCK_PARTITION_START * 512 + 67] ^= 0xFF;
+    match vfat.borrow_mut().remount(SharedImage::new(other)) {
+        Err(Error::VolumeMismatch { .. }) => {}
+        result => panic!("unexpected result {:?}", result.map(|_| ())),
+    }
+
+    // The same card, through a new device, rebinds the open handle.
+    let device = SharedImage::new(image.clone());
+    vfat.borrow_mut()
+        .remount(device.clone())
+        .expect("same volume");
+    assert_eq!(read(&mut file).unwrap(), "hello");
+
+    // Writes lost with the old device leave earlier handles stale.
+    file.set_append_buffering(false).unwrap();
+    file.seek(::std::io::SeekFrom::End(0)).unwrap();
+    file.write_all(b", world").unwrap();
+    vfat.borrow_mut()
+        .remount(SharedImage::new(image))
+        .expect("same volume");
+    let e = read(&mut file).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::NotFound);
+    let mut file = (&vfat).open_file("/hello.txt").expect("file");
+    assert_eq!(read(&mut file).unwrap(), "hello");
+}
+
+#[test]
+fn test_stat() {
+    use byteorder::{ByteOrder, LittleEndian};
+    use vfat::MountOptions;
+
+    let mut image = mock_volume(16);
+    let mut entry = mock_dir_entry(b"HELLO   TXT", 0x23, 5, 5);
+    // Modified 2018-03-04 12:00:00, local time.
+    LittleEndian::write_u16(&mut entry[22..24], 12 << 11);
+    LittleEndian::write_u16(&mut entry[24..26], (38 << 9) | (3 << 5) | 4);
+    mock_write_slot(&mut image, 2, 0, &entry);
+    mock_set_fat(&mut image, 5, 0x0FFFFFFF);
+
+    let modified = days_from_civil(2018, 3, 4) * 24 * 60 * 60 + 12 * 60 * 60;
+    let options = MountOptions {
+        timezone_offset: 60,
+        ..Default::default()
+    };
+    let vfat = VFat::with_options(Cursor::new(image), options).expect("mounts");
+    let stat = (&vfat).stat("/hello.txt").expect("stat");
+    assert_eq!(
+        stat,
+        FileStat {
+            size: 5,
+            is_dir: false,
+            attributes: 0x23,
+            read_only: true,
+            hidden: true,
+            created: stat.created,
+            accessed: stat.accessed,
+            modified: modified - 60 * 60,
+            start_cluster: 5,
+            nlink: 1,
+        }
+    );
+
+    let root = (&vfat).stat("/").expect("stat");
+    assert!(root.is_dir);
+    assert_eq!(root.size, 0);
+    assert_eq!(root.start_cluster, 2);
+    let e = (&vfat).stat("/missing").unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::NotFound);
+}
+
+#[test]
+fn test_name_collisions() {
+    use vfat::mkfs::{build_image, CaseCollisions, ImageLayout, PartitionLayout, Tree};
+    use vfat::NameCollision;
+
+    // Names differing in ASCII case are merged; others make two entries.
+    let mut tree = Tree::new();
+    tree.add_file("docs/Readme.txt", b"one".to_vec()).unwrap();
+    tree.add_file("docs/README.TXT", b"two".to_vec()).unwrap();
+    tree.add_file("docs/\u{e4}rger.txt", b"three".to_vec())
+        .unwrap();
+    tree.add_file("docs/\u{c4}RGER.TXT", b"four".to_vec())
+        .unwrap();
+    tree.add_file("other.txt", b"five".to_vec()).unwrap();
+
+    let mut strict = tree.clone();
+    strict.set_case_collisions(CaseCollisions::Reject);
+    let e = strict.add_file("docs/readme.TXT", Vec::new()).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidInput);
+    let e = strict.add_file("OTHER.txt", Vec::new()).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidInput);
+    strict.add_file("other.txt", b"six".to_vec()).unwrap();
+    strict.add_file("docs/new.txt", Vec::new()).unwrap();
+
+    let mut layout = ImageLayout::default();
+    layout.partitions.push(PartitionLayout::fat32(None, tree));
+    let mut device = Cursor::new(vec![0; 4 << 20]);
+    build_image(&mut device, &layout).expect("build");
+
+    let vfat = VFat::from(Cursor::new(device.into_inner())).expect("mount");
+    let collisions = vfat.borrow_mut().name_collisions().expect("check");
+    assert_eq!(
+        collisions,
+        vec![NameCollision {
+            dir: "/docs".to_string(),
+            names: vec!["\u{c4}RGER.TXT".to_string(), "\u{e4}rger.txt".to_string()],
+        }]
+    );
+}
+
+/// A global allocator that counts the bytes allocated by each thread, so
+/// tests can bound the memory an operation takes.
+mod counting_alloc {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    struct Counting;
+
+    thread_local! {
+        static LIVE: Cell<isize> = Cell::new(0);
+        static PEAK: Cell<isize> = Cell::new(0);
+    }
+
+    fn adjust(delta: isize) {
+        let _ = LIVE.try_with(|live| {
+            live.set(live.get() + delta);
+            let _ = PEAK.try_with(|peak| peak.set(::std::cmp::max(peak.get(), live.get())));
+        });
+    }
+
+    #[allow(unsafe_code)]
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            adjust(layout.size() as isize);
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            adjust(-(layout.size() as isize));
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            adjust(new_size as isize - layout.size() as isize);
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: Counting = Counting;
+
+    /// Runs `f` and returns its result with the most bytes this thread held
+    /// allocated at once while it ran, beyond those held before.
+    pub fn peak_during<T, F: FnOnce() -> T>(f: F) -> (T, usize) {
+        let base = LIVE.with(|live| live.get());
+        PEAK.with(|peak| peak.set(base));
+        let result = f();
+        let peak = PEAK.with(|peak| peak.get());
+        (result, (peak - base) as usize)
+    }
+}
+
+#[test]
+fn test_bounded_transfers() {
+    use self::counting_alloc::peak_during;
+    use vfat::mkfs::{build_image, ImageLayout, PartitionLayout, Tree};
+    use vfat::{apply_tar, extract, MemoryBudget, MountOptions};
+
+    const SIZE: usize = 1 << 20;
+    const BOUND: usize = 256 << 10;
+
+    let data: Vec<u8> = (0..SIZE).map(|i| (i / 7) as u8).collect();
+    let mut tree = Tree::new();
+    tree.add_file("big.bin", data.clone()).unwrap();
+    let mut layout = ImageLayout::default();
+    layout.partitions.push(PartitionLayout::fat32(None, tree));
+    let mut image = Cursor::new(vec![0; 4 << 20]);
+    build_image(&mut image, &layout).expect("image builds");
+    let image = image.into_inner();
+
+    let options = MountOptions {
+        memory_budget: Some(MemoryBudget {
+            cap: 64 << 10,
+            ..Default::default()
+        }),
+        transfer_chunk_size: Some(16 << 10),
+        ..Default::default()
+    };
+    let vfat = VFat::with_options(Cursor::new(image.clone()), options).expect("mounts");
+
+    let file = (&vfat).open_file("/big.bin").expect("file");
+    let (copied, peak) = peak_during(|| extract(&file, &mut ::std::io::sink(), None, None));
+    assert_eq!(copied.expect("extract"), SIZE as u64);
+    assert!(peak < BOUND, "extract peaked at {} bytes", peak);
+
+    let reversed: Vec<u8> = data.iter().rev().cloned().collect();
+    let mut tar = Vec::new();
+    mock_tar_file(&mut tar, "big.bin", &reversed);
+    tar.extend(vec![0; 1024]);
+    let root = (&vfat).open_dir("/").expect("root directory");
+    let (applied, peak) = peak_during(|| apply_tar(&mut Cursor::new(&tar[..]), &root));
+    assert_eq!(applied.expect("apply"), 1);
+    assert!(peak < BOUND, "apply_tar peaked at {} bytes", peak);
+    vfat.borrow_mut().sync().expect("sync");
+
+    let mut dst = Cursor::new(vec![0; image.len()]);
+    let (copied, peak) = peak_during(|| vfat.borrow_mut().clone_to(&mut dst, None));
+    assert!(copied.expect("clone") > SIZE as u64);
+    assert!(peak < BOUND, "clone peaked at {} bytes", peak);
+
+    let clone = VFat::from(dst).expect("clone mounts");
+    let mut out = Vec::new();
+    (&clone)
+        .open_file("/big.bin")
+        .expect("cloned file")
+        .read_to_end(&mut out)
+        .expect("read");
+    assert!(out == reversed);
+}
+
+#[test]
+fn test_dir_corpus() {
+    use std::collections::HashSet;
+    use tests::rand::{Rng, SeedableRng, XorShiftRng};
+    use vfat::dir_corpus::{check, generate, Choices, Piece, MAX_SLOTS};
+
+    let empty = generate(&mut Choices::new(&[]), MAX_SLOTS);
+    assert!(empty.slots.is_empty() && empty.entries.is_empty());
+    check(&empty);
+
+    // Each piece on its own, with its first choices and with its last.
+    for i in 0..Piece::ALL.len() {
+        for &fill in &[0u8, 0xFF] {
+            let mut input = vec![fill; 64];
+            input[0] = i as u8;
+            let dir = generate(&mut Choices::new(&input), MAX_SLOTS);
+            assert_eq!(dir.pieces[0], Piece::ALL[i]);
+            check(&dir);
+        }
+    }
+
+    let mut rng = XorShiftRng::from_seed([0x2000, 0xFA7, 0x32, 1]);
+    let mut seen = HashSet::new();
+    for _ in 0..200 {
+        let mut input = vec![0; rng.gen_range(16, 2048)];
+        rng.fill_bytes(&mut input);
+        let dir = generate(&mut Choices::new(&input), MAX_SLOTS);
+        assert!(dir.slots.len() <= MAX_SLOTS * 32);
+        seen.extend(dir.pieces.iter().cloned());
+        check(&dir);
+    }
+    assert_eq!(seen.len(), Piece::ALL.len());
+}
+
+#[test]
+fn test_create_file_side_effects() {
+    use std::sync::{Arc, Mutex};
+    use vfat::Event;
+
+    // A sidecar still holding the attributes of a removed `new.txt`.
+    let mut sidecar = b"FXA1".to_vec();
+    sidecar.extend_from_slice(b"\x07\x00new.txt\x01\x00\x03tag\x05\x00stale");
+    let mut image = mock_volume(16);
+    mock_write_slot(
+        &mut image,
+        2,
+        0,
+        &mock_dir_entry(b"XATTRS  SYS", 0x22, 3, sidecar.len() as u32),
+    );
+    mock_set_fat(&mut image, 3, 0x0FFFFFFF);
+    let offset = mock_cluster_offset(&image, 3);
+    image[offset..offset + sidecar.len()].copy_from_slice(&sidecar);
+
+    let vfat = VFat::from(Cursor::new(image)).expect("mock volume mounts");
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let (handle, events) = (vfat.clone(), seen.clone());
+    let id = vfat.borrow_mut().watch(
+        "/",
+        Box::new(move |event| {
+            // The volume is not locked, so the callback may use it.
+            if let Event::Create(ref path) = *event {
+                let size = (&handle).open_file(path).map(|file| file.size());
+                events.lock().unwrap().push(size.ok());
+            }
+        }),
+    );
+
+    (&vfat).create_file("/NEW.TXT").expect("create");
+    assert_eq!(*seen.lock().unwrap(), vec![Some(0)]);
+    let entry = (&vfat).open("/new.txt").unwrap();
+    assert_eq!(entry.get_xattr("tag").unwrap(), None);
+    assert_eq!((&vfat).open_file("/XATTRS.SYS").unwrap().size(), 4);
+    assert!(vfat.borrow_mut().unwatch(id));
+}
+
+#[test]
+fn test_create_file() {
+    use std::io::ErrorKind;
+    use std::sync::{Arc, Mutex};
+    use vfat::{Entry, Event, IterOptions, RepairOptions};
+
+    // A root directory whose one cluster holds a deleted slot, then is
+    // full.
+    let mut image = mock_volume(16);
+    let mut deleted = mock_dir_entry(b"OLD     TXT", 0x20, 0, 0);
+    deleted[0] = 0xE5;
+    mock_write_slot(&mut image, 2, 0, &deleted);
+    for slot in 1..16 {
+        let mut name = *b"FILE    BIN";
+        name[4] = b'A' + slot as u8;
+        mock_write_slot(&mut image, 2, slot, &mock_dir_entry(&name, 0x20, 0, 0));
+    }
+
+    let device = SharedImage::new(image);
+    let vfat = VFat::from(device.clone()).expect("mock volume mounts");
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let seen = events.clone();
+    vfat.borrow_mut().watch(
+        "/",
+        Box::new(move |event| seen.lock().unwrap().push(event.clone())),
+    );
+
+    // An 8.3 name takes the deleted slot.
+    let mut file = (&vfat).create_file("/NEW.TXT").expect("create");
+    assert_eq!(file.location.map(|location| location.slot), Some(0));
+    assert_eq!(file.size(), 0);
+    file.write_all(b"hello").expect("write");
+    file.flush().expect("flush");
+
+    // A long name needs three slots, so the directory grows.
+    let mut long = (&vfat)
+        .create_file("/A much longer name.txt")
+        .expect("create");
+    assert_eq!(long.location.map(|location| location.slot), Some(18));
+    long.write_all(b"world").expect("write");
+    long.flush().expect("flush");
+    let alias = (&vfat)
+        .create_file("/A much longer name.bin")
+        .expect("create");
+    assert_eq!(alias.location.map(|location| location.slot), Some(21));
+
+    let e = (&vfat).create_file("/new.txt").unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::AlreadyExists);
+    let e = (&vfat).create_file("/NEW.TXT/x").unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    let e = (&vfat).create_file("/missing/x").unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    let e = (&vfat).create_file("relative.txt").unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    let e = (&vfat)
+        .create_file(format!("/{}", "x".repeat(256)))
+        .unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidInput);
+
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![
+            Event::Create("/NEW.TXT".to_string()),
+            Event::Modify("/NEW.TXT".to_string()),
+            Event::Create("/A much longer name.txt".to_string()),
+            Event::Modify("/A much longer name.txt".to_string()),
+            Event::Create("/A much longer name.bin".to_string()),
+        ]
+    );
+    vfat.borrow_mut().sync().expect("sync");
+
+    // The files are intact on the disk, with numbered aliases.
+    let vfat = VFat::from(Cursor::new(device.image())).expect("remounts");
+    let mut data = String::new();
+    (&vfat)
+        .open_file("/a much longer name.txt")
+        .expect("created file")
+        .read_to_string(&mut data)
+        .unwrap();
+    assert_eq!(data, "world");
+    let root = (&vfat).open_dir("/").expect("root");
+    let short_names = IterOptions {
+        include_hidden: true,
+        short_names: true,
+    };
+    let names: Vec<String> = root
+        .entries_with(short_names)
+        .unwrap()
+        .map(|entry| match entry {
+            Entry::File(file) => file.metadata.name.clone(),
+            Entry::Dir(dir) => dir.metadata.name.clone(),
+        })
+        .filter(|name| !name.starts_with("FILE"))
+        .collect();
+    assert_eq!(names, vec!["NEW.TXT", "AMUCHL~1.TXT", "AMUCHL~1.BIN"]);
+    let again = vfat.borrow_mut().repair(RepairOptions::default()).unwrap();
+    assert_eq!(again, vec![]);
+}
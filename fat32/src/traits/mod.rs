@@ -1,9 +0,0 @@
-mod block_device;
-mod dummy;
-mod fs;
-mod metadata;
-
-pub use self::block_device::BlockDevice;
-pub use self::dummy::Dummy;
-pub use self::fs::{Dir, Entry, File, FileSystem};
-pub use self::metadata::{Metadata, Timestamp};
@@ -0,0 +1,67 @@
+/// One bit per logical sector of a volume, set for sectors holding live data.
+/// Returned by `VFat::used_sector_bitmap()`.
+///
+/// Sector `0` is the first sector of the partition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SectorBitmap {
+    words: Vec<u64>,
+    len: u64,
+}
+
+impl SectorBitmap {
+    /// Returns a bitmap of `len` sectors, none of which are used.
+    pub(crate) fn new(len: u64) -> SectorBitmap {
+        SectorBitmap {
+            words: vec![0; ((len + 63) / 64) as usize],
+            len,
+        }
+    }
+
+    /// Marks the `count` sectors beginning at `start` as used.
+    pub(crate) fn mark(&mut self, start: u64, count: u64) {
+        for sector in start..::std::cmp::min(start + count, self.len) {
+            self.words[(sector / 64) as usize] |= 1 << (sector % 64);
+        }
+    }
+
+    /// The number of sectors covered by the bitmap.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns `true` if the bitmap covers no sectors.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns `true` if sector `sector` holds live data. Sectors past the
+    /// end of the bitmap are never used.
+    pub fn is_used(&self, sector: u64) -> bool {
+        sector < self.len && self.words[(sector / 64) as usize] & (1 << (sector % 64)) != 0
+    }
+
+    /// The number of used sectors.
+    pub fn used_sectors(&self) -> u64 {
+        self.words.iter().map(|w| w.count_ones() as u64).sum()
+    }
+
+    /// Every run of consecutive used sectors as `(start, count)`, in
+    /// ascending order.
+    pub fn used_runs(&self) -> Vec<(u64, u64)> {
+        let mut runs: Vec<(u64, u64)> = Vec::new();
+        for sector in 0..self.len {
+            if !self.is_used(sector) {
+                continue;
+            }
+
+            if let Some(last) = runs.last_mut() {
+                if last.0 + last.1 == sector {
+                    last.1 += 1;
+                    continue;
+                }
+            }
+            runs.push((sector, 1));
+        }
+        runs
+    }
+}
@@ -0,0 +1,57 @@
+//! Duplication of a volume onto another device.
+
+use std::io;
+
+use traits::BlockDevice;
+use vfat::VFat;
+
+/// Characters FAT does not allow in a volume label.
+const INVALID_LABEL_CHARS: &[u8] = b"\"*+,./:;<=>?[\\]|";
+
+/// Encodes `label` as the 11-byte, space-padded, upper-case form stored in
+/// the boot sector and the root directory.
+///
+/// # Errors
+///
+/// Returns an error kind of `InvalidInput` if `label` is empty, longer than
+/// 11 bytes, or holds a character other than printable ASCII or one FAT does
+/// not allow in labels.
+pub(crate) fn encode_label(label: &str) -> io::Result<[u8; 11]> {
+    let bytes = label.as_bytes();
+    if bytes.is_empty()
+        || bytes.len() > 11
+        || bytes
+            .iter()
+            .any(|&b| b < 0x20 || b > 0x7E || INVALID_LABEL_CHARS.contains(&b))
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "volume labels must be 1 to 11 printable ASCII characters",
+        ));
+    }
+
+    let mut encoded = [b' '; 11];
+    for (dst, src) in encoded.iter_mut().zip(bytes) {
+        *dst = src.to_ascii_uppercase();
+    }
+    Ok(encoded)
+}
+
+/// Mounts the FAT32 volume on `src` and copies it to `dst`, setting the
+/// copy's volume label to `label` if given, and returns the number of bytes
+/// copied. Only the sectors before the partition, the reserved sectors, the
+/// FATs, and allocated clusters are copied. See `VFat::clone_to()`.
+///
+/// # Errors
+///
+/// Returns the error mounting `src` fails with, if it does, and otherwise as
+/// `VFat::clone_to()`.
+pub fn clone_volume<S, D>(src: S, dst: &mut D, label: Option<&str>) -> io::Result<u64>
+where
+    S: BlockDevice + 'static,
+    D: BlockDevice,
+{
+    let vfat = VFat::from(src)?;
+    let mut vfat = vfat.borrow_mut();
+    vfat.clone_to(dst, label)
+}
@@ -0,0 +1,81 @@
+//! Finding names that differ only in case, which Windows takes for the same
+//! name.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+
+use vfat::dir::RawEntries;
+use vfat::{Cluster, VFat};
+
+/// Names in one directory that differ only in case, as found by
+/// `VFat::name_collisions()`. Windows compares names without regard to case,
+/// so it can reach only one of them; a tree copied from a case-sensitive
+/// file system may hold such names, e.g. `Readme.txt` and `README.TXT`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameCollision {
+    /// The directory's absolute path, with `/` separators.
+    pub dir: String,
+    /// The colliding names, in directory order.
+    pub names: Vec<String>,
+}
+
+/// Returns `name` with its case folded as Windows compares names: every
+/// character that upper-cases to a single character is upper-cased, and the
+/// rest are kept.
+pub(crate) fn fold_case(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            let mut upper = c.to_uppercase();
+            match (upper.next(), upper.next()) {
+                (Some(upper), None) => upper,
+                _ => c,
+            }
+        })
+        .collect()
+}
+
+/// Finds the collisions in every directory beneath, and including, the root
+/// directory at `root`, breadth first.
+pub(crate) fn find(vfat: &mut VFat, root: Cluster) -> io::Result<Vec<NameCollision>> {
+    let mut collisions = Vec::new();
+    let mut visited = HashSet::new();
+    visited.insert(root);
+    let mut pending = VecDeque::new();
+    pending.push_back((String::from("/"), root));
+    while let Some((path, dir_cluster)) = pending.pop_front() {
+        let mut groups: Vec<Vec<String>> = Vec::new();
+        let mut by_name = HashMap::new();
+        let mut entries = RawEntries::read(vfat, dir_cluster)?;
+        while let Some(entry) = entries.next_entry() {
+            let is_dir = entry.is_dir();
+            let name = entry.metadata.name;
+            if name == "." || name == ".." {
+                continue;
+            }
+            // A directory reached twice, through a loop, is checked once.
+            if is_dir && entry.start_cluster.0 >= 2 && visited.insert(entry.start_cluster) {
+                let child = match path.as_str() {
+                    "/" => format!("/{}", name),
+                    _ => format!("{}/{}", path, name),
+                };
+                pending.push_back((child, entry.start_cluster));
+            }
+
+            let group = *by_name.entry(fold_case(&name)).or_insert(groups.len());
+            if group == groups.len() {
+                groups.push(Vec::new());
+            }
+            groups[group].push(name);
+        }
+
+        for names in groups {
+            if names.len() > 1 {
+                collisions.push(NameCollision {
+                    dir: path.clone(),
+                    names,
+                });
+            }
+        }
+    }
+    Ok(collisions)
+}
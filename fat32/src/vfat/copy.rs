@@ -0,0 +1,101 @@
+use std::io;
+
+use vfat::{File, Shared};
+
+/// A flag shared between a long-running copy and whoever may cancel it.
+///
+/// Clones of a token share the same flag. Copies check the flag between
+/// chunks, so cancellation takes effect within one chunk of I/O.
+#[derive(Debug, Clone)]
+pub struct CancelToken(Shared<bool>);
+
+impl CancelToken {
+    /// Returns a new token that is not cancelled.
+    pub fn new() -> CancelToken {
+        CancelToken(Shared::new(false))
+    }
+
+    /// Requests cancellation of every copy watching this token.
+    pub fn cancel(&self) {
+        *self.0.borrow_mut() = true;
+    }
+
+    /// Returns `true` if `cancel()` has been called on this token or a clone
+    /// of it.
+    pub fn is_cancelled(&self) -> bool {
+        *self.0.borrow()
+    }
+}
+
+impl Default for CancelToken {
+    fn default() -> CancelToken {
+        CancelToken::new()
+    }
+}
+
+/// Copies the contents of `file` into `writer` one chunk at a time and
+/// returns the number of bytes copied. A chunk is
+/// `MountOptions::transfer_chunk_size` bytes, one cluster by default. The
+/// clusters copied are dropped from the sector cache, so that copying a
+/// large file does not fill it.
+///
+/// After each chunk, `progress` (if any) is called with the number of bytes
+/// copied so far and the total size of the file. Before each chunk, `cancel`
+/// (if any) is checked.
+///
+/// # Errors
+///
+/// Returns an error kind of `Other` if the copy is cancelled through
+/// `cancel`; the bytes already written to `writer` are left in place. Errors
+/// reading the volume or writing to `writer` are returned as they occur.
+pub fn extract<W: io::Write>(
+    file: &File,
+    writer: &mut W,
+    mut progress: Option<&mut FnMut(u64, u64)>,
+    cancel: Option<&CancelToken>,
+) -> io::Result<u64> {
+    let total = file.data_len()? as u64;
+    let mut copied = 0u64;
+    let mut cluster = Some(file.start_cluster);
+    let (cluster_size, chunk) = {
+        let vfat = file.vfat.borrow();
+        (vfat.cluster_size(), vfat.transfer_chunk())
+    };
+    let mut buf = vec![0; chunk];
+
+    while copied < total {
+        if cancel.map_or(false, |token| token.is_cancelled()) {
+            return Err(io::Error::new(io::ErrorKind::Other, "copy cancelled"));
+        }
+
+        let mut filled = 0;
+        {
+            let mut vfat = file.vfat.borrow_mut();
+            while filled < chunk && copied + (filled as u64) < total {
+                let current = match cluster {
+                    Some(current) => current,
+                    None => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "cluster chain is shorter than the file",
+                        ))
+                    }
+                };
+                vfat.read_cluster(current, &mut buf[filled..filled + cluster_size])?;
+                cluster = vfat.next_in_chain(current)?;
+                vfat.evict_cluster(current);
+                filled += cluster_size;
+            }
+        }
+
+        let n = ::std::cmp::min(filled as u64, total - copied) as usize;
+        writer.write_all(&buf[..n])?;
+        copied += n as u64;
+
+        if let Some(ref mut progress) = progress {
+            progress(copied, total);
+        }
+    }
+
+    Ok(copied)
+}
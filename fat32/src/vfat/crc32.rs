@@ -0,0 +1,18 @@
+//! CRC-32 (IEEE 802.3), as used by zlib and Ethernet.
+
+/// The CRC of each 4-bit value, for the reflected polynomial `0xEDB88320`.
+const NIBBLES: [u32; 16] = [
+    0x00000000, 0x1DB71064, 0x3B6E20C8, 0x26D930AC, 0x76DC4190, 0x6B6B51F4, 0x4DB26158, 0x5005713C,
+    0xEDB88320, 0xF00F9344, 0xD6D6A3E8, 0xCB61B38C, 0x9B64C2B0, 0x86D3D2D4, 0xA00AE278, 0xBDBDF21C,
+];
+
+/// Returns the CRC-32 of `data`.
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        crc = (crc >> 4) ^ NIBBLES[(crc & 0xF) as usize];
+        crc = (crc >> 4) ^ NIBBLES[(crc & 0xF) as usize];
+    }
+    !crc
+}
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use vfat::{Cluster, Shared};
+
+/// Whole-file data loaded by `File` handles, shared between the handles of
+/// the same file so that concurrent readers hold one copy.
+///
+/// Files are identified by start cluster and size. The cache holds at most
+/// `budget` bytes of data that no handle is using; data in use by a handle
+/// occupies memory anyway and is never evicted.
+pub(crate) struct FileDataCache {
+    budget: usize,
+    entries: HashMap<(Cluster, u32), (Shared<Vec<u8>>, usize)>,
+}
+
+impl FileDataCache {
+    pub(crate) fn new(budget: usize) -> FileDataCache {
+        FileDataCache {
+            budget,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Returns the data of the file beginning at `start` of `size` bytes, if
+    /// it is cached.
+    pub(crate) fn get(&self, start: Cluster, size: u32) -> Option<Shared<Vec<u8>>> {
+        self.entries
+            .get(&(start, size))
+            .map(|&(ref data, _)| data.clone())
+    }
+
+    /// Wraps `data`, the contents of the file beginning at `start` of `size`
+    /// bytes, for sharing and caches it if it fits in the budget.
+    pub(crate) fn insert(&mut self, start: Cluster, size: u32, data: Vec<u8>) -> Shared<Vec<u8>> {
+        let len = data.len();
+        let data = Shared::new(data);
+        if start.0 < 2 || len > self.budget {
+            return data;
+        }
+
+        self.entries.insert((start, size), (data.clone(), len));
+        self.trim();
+        data
+    }
+
+    /// Changes the cache's budget, evicting data no handle is using if the
+    /// cache holds more.
+    pub(crate) fn set_budget(&mut self, budget: usize) {
+        self.budget = budget;
+        self.trim();
+    }
+
+    /// Evicts data no handle is using until the cache fits in its budget.
+    fn trim(&mut self) {
+        while self.bytes() > self.budget {
+            let unused = self
+                .entries
+                .iter()
+                .filter(|&(_, &(ref data, _))| data.ref_count() == 1)
+                .map(|(&key, _)| key)
+                .next();
+            match unused {
+                Some(key) => self.entries.remove(&key),
+                None => return,
+            };
+        }
+    }
+
+    /// Drops every cached file. Handles keep the data they hold.
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// The number of bytes of data cached.
+    pub(crate) fn bytes(&self) -> usize {
+        self.entries.values().map(|&(_, len)| len).sum()
+    }
+}
+
+impl fmt::Debug for FileDataCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FileDataCache")
+            .field("budget", &self.budget)
+            .field("files", &self.entries.len())
+            .field("bytes", &self.bytes())
+            .finish()
+    }
+}
@@ -1,18 +1,86 @@
 use std::char::decode_utf16;
 use std::ffi::OsStr;
-use std::{fmt, io, mem};
+use std::hash::{Hash, Hasher};
+use std::iter::FusedIterator;
+use std::ops::Range;
+use std::{fmt, io};
 
+use byteorder::{ByteOrder, LittleEndian};
+use consts::{
+    ATTR_DIRECTORY, ATTR_HIDDEN, ATTR_LFN, ATTR_RESERVED, ATTR_SYSTEM, ATTR_VOLUME_ID,
+    DELETED_ENTRY, DIR_ENTRY_SIZE, END_OF_ENTRIES, LAST_LFN_ENTRY, LFN_SEQUENCE_MASK,
+    MAX_DIR_ENTRIES,
+};
 use traits;
-use vfat::{Attributes, Date, Metadata, Timestamp};
-use vfat::{Cluster, Entry, File, Shared, VFat};
+use vfat::dir_index::{self, Probe};
+use vfat::dir_source::DirSource;
+use vfat::{lfn_checksum, Attributes, Date, Metadata, Time, Timestamp};
+use vfat::{Cluster, Entry, EntryId, EntryLocation, Error, File, Shared, VFat};
 
-const BYTES_IN_ENTRY: usize = 32;
-const DIR_MASK: u8 = 0x10;
+/// Bits of a short entry's case flags set by Windows NT for 8.3 names it
+/// stores without an LFN: the base name, then the extension, is lower case.
+const LOWER_CASE_BASE: u8 = 0x08;
+const LOWER_CASE_EXTENSION: u8 = 0x10;
 
+/// How strictly directory entries are checked while iterating.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EntryMode {
+    /// Skip entries with reserved attribute bits set, slots with the LFN
+    /// attributes that are not laid out as LFN entries, and long file name
+    /// runs that are out of sequence, truncated, or do not match their short
+    /// entry's checksum. A short entry whose long name is skipped is kept
+    /// under its short name. Skipped entries are counted; see
+    /// `DirIter::skipped()` and `VFat::corrupt_entries()`. Volume labels are
+    /// never listed, and are not counted.
+    Tolerant,
+    /// Fail with `Error::CorruptEntry` when listing a directory holding any
+    /// entry tolerant mode would skip.
+    Strict,
+}
+
+impl Default for EntryMode {
+    fn default() -> EntryMode {
+        EntryMode::Tolerant
+    }
+}
+
+/// How unpaired UTF-16 surrogates in a long name are shown in the entry's
+/// `Metadata::name`. Surrogate pairs, such as those of emoji, always decode to
+/// the character they encode, and `Metadata::long_name` always holds the
+/// name's units exactly as stored.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UnpairedSurrogates {
+    /// Replace each unpaired surrogate with `_`.
+    Underscore,
+    /// Replace each unpaired surrogate with U+FFFD REPLACEMENT CHARACTER, as
+    /// `String::from_utf16_lossy()` does.
+    ReplacementCharacter,
+}
+
+impl UnpairedSurrogates {
+    /// Returns the character an unpaired surrogate is replaced with.
+    fn replacement(&self) -> char {
+        match *self {
+            UnpairedSurrogates::Underscore => '_',
+            UnpairedSurrogates::ReplacementCharacter => '\u{FFFD}',
+        }
+    }
+}
+
+impl Default for UnpairedSurrogates {
+    fn default() -> UnpairedSurrogates {
+        UnpairedSurrogates::Underscore
+    }
+}
+
+#[derive(Clone)]
 pub struct Dir {
-    pub metadata: Metadata,
-    pub start_cluster: Cluster,
-    pub vfat: Shared<VFat>,
+    pub(crate) metadata: Metadata,
+    pub(crate) start_cluster: Cluster,
+    pub(crate) vfat: Shared<VFat>,
+    pub(crate) location: Option<EntryLocation>,
+    /// The media generation the handle was opened under.
+    pub(crate) generation: u64,
 }
 
 #[repr(C, packed)]
@@ -21,7 +89,7 @@ pub struct VFatRegularDirEntry {
     filename: [u8; 8],
     extension: [u8; 3],
     attributes: Attributes,
-    _reserved: u8,
+    case_flags: u8,
     created_cs: u8,
     created: Timestamp,
     accessed: Date,
@@ -40,7 +108,7 @@ pub struct VFatLfnDirEntry {
     dirtype: u8,
     checksum: u8,
     pub chars2: [u8; 12],
-    _r: [u8; 2],
+    cluster: u16,
     pub chars3: [u8; 4],
 }
 
@@ -50,192 +118,1245 @@ pub struct VFatUnknownDirEntry {
     pub _bytes: [u8; 32],
 }
 
-pub union VFatDirEntry {
-    unknown: VFatUnknownDirEntry,
-    regular: VFatRegularDirEntry,
-    long_filename: VFatLfnDirEntry,
+/// A raw 32-byte directory entry slot, decoded as an unknown, regular, or
+/// long file name entry on demand.
+#[derive(Copy, Clone)]
+pub struct VFatDirEntry([u8; DIR_ENTRY_SIZE]);
+
+impl VFatDirEntry {
+    /// The slot's bytes, undecoded.
+    fn unknown(&self) -> VFatUnknownDirEntry {
+        VFatUnknownDirEntry { _bytes: self.0 }
+    }
+
+    /// The slot decoded as a regular (short) entry.
+    fn regular(&self) -> VFatRegularDirEntry {
+        let b = &self.0;
+        let timestamp = |at: usize| Timestamp {
+            time: Time::from_raw(LittleEndian::read_u16(&b[at..at + 2])),
+            date: Date::from_raw(LittleEndian::read_u16(&b[at + 2..at + 4])),
+        };
+        let mut filename = [0; 8];
+        filename.copy_from_slice(&b[0..8]);
+        let mut extension = [0; 3];
+        extension.copy_from_slice(&b[8..11]);
+        VFatRegularDirEntry {
+            filename,
+            extension,
+            attributes: Attributes(b[11]),
+            case_flags: b[12],
+            created_cs: b[13],
+            created: timestamp(14),
+            accessed: Date::from_raw(LittleEndian::read_u16(&b[18..20])),
+            cluster_hi: LittleEndian::read_u16(&b[20..22]),
+            last_modified: timestamp(22),
+            cluster_lo: LittleEndian::read_u16(&b[26..28]),
+            size: LittleEndian::read_u32(&b[28..32]),
+        }
+    }
+
+    /// The slot decoded as a long file name entry.
+    fn long_filename(&self) -> VFatLfnDirEntry {
+        let b = &self.0;
+        let mut chars1 = [0; 10];
+        chars1.copy_from_slice(&b[1..11]);
+        let mut chars2 = [0; 12];
+        chars2.copy_from_slice(&b[14..26]);
+        let mut chars3 = [0; 4];
+        chars3.copy_from_slice(&b[28..32]);
+        VFatLfnDirEntry {
+            seq_no: b[0],
+            chars1,
+            attributes: Attributes(b[11]),
+            dirtype: b[12],
+            checksum: b[13],
+            chars2,
+            cluster: LittleEndian::read_u16(&b[26..28]),
+            chars3,
+        }
+    }
 }
 
 impl Dir {
+    /// The metadata of this directory, as of opening it or the last
+    /// `refresh_metadata()`.
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// The first cluster of this directory.
+    pub fn start_cluster(&self) -> Cluster {
+        self.start_cluster
+    }
+
+    /// The location of this directory's entry, or `None` for the root
+    /// directory.
+    pub fn location(&self) -> Option<EntryLocation> {
+        self.location
+    }
+
+    /// Returns the identity of this directory. See `EntryId`.
+    pub fn id(&self) -> EntryId {
+        EntryId {
+            volume_id: self.vfat.borrow().volume_id(),
+            start_cluster: self.start_cluster,
+            location: self.location,
+        }
+    }
+
+    /// Re-reads this directory's entry from disk, updating `self.metadata`.
+    ///
+    /// # Errors
+    ///
+    /// If the entry has since been removed or renamed, a `StaleHandle` error
+    /// of kind `NotFound` is returned, and if the medium has since changed,
+    /// a `MediaChanged` error of kind `NotFound`.
+    pub fn refresh_metadata(&mut self) -> io::Result<()> {
+        let location = match self.location {
+            Some(location) => location,
+            None => return self.vfat.borrow_mut().check_generation(self.generation),
+        };
+
+        match entry_at(&self.vfat, location, self.generation)? {
+            Some(Entry::Dir(ref dir))
+                if dir.start_cluster == self.start_cluster
+                    && dir.metadata.name == self.metadata.name =>
+            {
+                self.metadata = dir.metadata.clone();
+                Ok(())
+            }
+            _ => Err(Error::StaleHandle.into()),
+        }
+    }
+
+    /// Counts the deleted entry slots before the end of this directory.
+    pub fn deleted_slots(&self) -> io::Result<usize> {
+        let mut buf = Vec::new();
+        let mut vfat = self.vfat.borrow_mut();
+        vfat.check_generation(self.generation)?;
+        let source = vfat.dir_source(self.start_cluster);
+        source.read(&mut vfat, &mut buf)?;
+        Ok(buf
+            .chunks(DIR_ENTRY_SIZE)
+            .take_while(|slot| slot[0] != END_OF_ENTRIES)
+            .filter(|slot| slot[0] == DELETED_ENTRY)
+            .count())
+    }
+
+    /// Returns the runs of long file name entries in this directory that do
+    /// not name a short entry: runs not followed by a live short entry, and
+    /// runs that are truncated, out of sequence, or whose checksum does not
+    /// match the short entry following them. Recovery tooling can repair or
+    /// delete them; listing the directory skips them.
+    pub fn orphaned_lfns(&self) -> io::Result<Vec<OrphanedLfn>> {
+        let mut vfat = self.vfat.borrow_mut();
+        vfat.check_generation(self.generation)?;
+        let mut buf = Vec::new();
+        let source = vfat.dir_source(self.start_cluster);
+        source.read(&mut vfat, &mut buf)?;
+
+        Ok(orphans_in(&buf))
+    }
+
+    /// Rewrites this directory's live entries contiguously, dropping deleted
+    /// slots, and frees clusters at the end of the directory's chain that are
+    /// no longer needed. A fixed root directory region keeps its size. Returns
+    /// the number of slots reclaimed.
+    ///
+    /// Compaction moves entries, so handles to entries in this directory
+    /// become stale (see `refresh_metadata()`). Changes reach the disk on the
+    /// next `VFat::sync()`. Directories on a read-only volume or beneath
+    /// `MountOptions::protected_paths` cannot be compacted, and are left
+    /// untouched with an error of kind `PermissionDenied`.
+    pub fn compact(&self) -> io::Result<usize> {
+        let mut vfat = self.vfat.borrow_mut();
+        vfat.check_generation(self.generation)?;
+        vfat.check_modifiable(None)?;
+        vfat.check_dir_modifiable(self.start_cluster)?;
+        let mut buf = Vec::new();
+        let source = vfat.dir_source(self.start_cluster);
+        source.read(&mut vfat, &mut buf)?;
+
+        let mut compacted = Vec::with_capacity(buf.len());
+        let mut moves = Vec::new();
+        let mut reclaimed = 0;
+        for (index, slot) in buf.chunks(DIR_ENTRY_SIZE).enumerate() {
+            match slot[0] {
+                END_OF_ENTRIES => break,
+                DELETED_ENTRY => reclaimed += 1,
+                _ => {
+                    if reclaimed > 0 {
+                        moves.push((index as u32, (index - reclaimed) as u32));
+                    }
+                    compacted.extend_from_slice(slot)
+                }
+            }
+        }
+
+        if reclaimed == 0 {
+            return Ok(0);
+        }
+
+        // Subdirectories of this directory move, so their cached locations
+        // become stale, as does the directory's index. Handles to its files
+        // can no longer update their entries; locks on them follow the move.
+        vfat.forget_dirs();
+        vfat.dir_indexes().forget(self.start_cluster);
+        vfat.entries_moved(self.start_cluster, &moves);
+
+        let cluster_size = vfat.cluster_size();
+        let clusters_needed =
+            ::std::cmp::max(1, (compacted.len() + cluster_size - 1) / cluster_size);
+        match source {
+            DirSource::Chain(_) => compacted.resize(clusters_needed * cluster_size, 0),
+            DirSource::Region { .. } => compacted.resize(buf.len(), 0),
+        }
+        source.write(&mut vfat, 0, &compacted)?;
+
+        if let DirSource::Chain(start) = source {
+            vfat.truncate_chain(start, clusters_needed)?;
+        }
+        Ok(reclaimed)
+    }
+
+    /// Compacts this directory if it has at least as many deleted slots as
+    /// the volume's `MountOptions::auto_compact_threshold`. Returns the number
+    /// of slots reclaimed.
+    pub fn compact_if_needed(&self) -> io::Result<usize> {
+        let threshold = match self.vfat.borrow().options().auto_compact_threshold {
+            Some(threshold) => threshold,
+            None => return Ok(0),
+        };
+
+        if self.deleted_slots()? >= threshold {
+            self.compact()
+        } else {
+            Ok(0)
+        }
+    }
+
+    /// Returns the metadata of every entry in this directory, including hidden
+    /// and system entries.
+    ///
+    /// The directory's clusters are read in a single pass while the volume is
+    /// locked, so the listing reflects one consistent state of the directory
+    /// even if other handles modify it concurrently. The returned vector is
+    /// owned and does not change afterwards.
+    pub fn snapshot(&self) -> io::Result<Vec<Metadata>> {
+        Ok(self
+            .entries_with(IterOptions::all())?
+            .map(|entry| traits::Entry::metadata(&entry).clone())
+            .collect())
+    }
+
+    /// Returns an iterator over the entries in this directory selected by
+    /// `options`.
+    pub fn entries_with(&self, options: IterOptions) -> io::Result<DirIter> {
+        DirIter::new(self, options)
+    }
+
+    /// Returns an iterator over the entries in this directory that are
+    /// neither hidden nor system entries, as `ls` without `-a` lists them.
+    ///
+    /// Filtering is opt-in: `entries()` yields every entry, as `ls -a` does,
+    /// so that lookups built on it find hidden entries too.
+    pub fn visible_entries(&self) -> io::Result<DirIter> {
+        self.entries_with(IterOptions::default())
+    }
+
     /// Finds the entry named `name` in `self` and returns it. Comparison is
-    /// case-insensitive.
+    /// case-insensitive for ASCII letters and is made on the UCS-2 form of
+    /// the names, so names that are not valid Unicode (e.g. containing
+    /// unpaired surrogates) can be found. On Unix hosts, non-UTF-8 names are
+    /// interpreted as WTF-8.
     ///
     /// # Errors
     ///
     /// If no entry with name `name` exists in `self`, an error of `NotFound` is
     /// returned.
     ///
-    /// If `name` contains invalid UTF-8 characters, an error of `InvalidInput`
-    /// is returned.
+    /// If `name` cannot be represented in UCS-2, and so cannot name an entry
+    /// on a FAT volume, an error of `InvalidInput` is returned.
     pub fn find<P: AsRef<OsStr>>(&self, name: P) -> io::Result<Entry> {
-        for entry in traits::Dir::entries(self)? {
-            let name = match name.as_ref().to_str() {
-                None => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidInput,
-                        "name not valid utf8",
-                    ))
-                }
-                Some(name) => name,
-            };
+        let name = match ucs2_name(name.as_ref()) {
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "name cannot be represented on FAT",
+                ))
+            }
+            Some(name) => name,
+        };
+
+        let found = {
+            let mut vfat = self.vfat.borrow_mut();
+            vfat.check_generation(self.generation)?;
+            lookup(&mut vfat, self.start_cluster, &name)?
+        };
+
+        match found {
+            Some(entry) => Ok(entry.into_entry(&self.vfat, self.generation)),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "Entry not found")),
+        }
+    }
 
-            if traits::Entry::name(&entry).eq_ignore_ascii_case(name) {
-                return Ok(entry);
+    /// Returns every entry in `self` whose name matches `pattern`, in
+    /// directory order. In `pattern`, `*` matches any run of characters and
+    /// `?` matches any single character; a pattern without wildcards matches
+    /// the names it is a prefix of. As with `find()`, comparison is
+    /// case-insensitive for ASCII letters and is made on the UCS-2 form of the
+    /// names.
+    ///
+    /// The directory is read in a single pass and each entry's name is
+    /// decoded once, so this is cheaper than filtering `entries()` by hand.
+    ///
+    /// # Errors
+    ///
+    /// If `pattern` cannot be represented in UCS-2, an error of `InvalidInput`
+    /// is returned.
+    pub fn find_all_matching<P: AsRef<OsStr>>(&self, pattern: P) -> io::Result<Vec<Entry>> {
+        let mut pattern = match ucs2_name(pattern.as_ref()) {
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "pattern cannot be represented on FAT",
+                ))
             }
+            Some(pattern) => pattern,
+        };
+
+        if !pattern.iter().any(|&unit| unit == STAR || unit == QUESTION) {
+            pattern.push(STAR);
         }
-        Err(io::Error::new(io::ErrorKind::NotFound, "Entry not found"))
+
+        Ok(traits::Dir::entries(self)?
+            .filter(|entry| {
+                with_ucs2_name(traits::Entry::metadata(entry), |name| {
+                    ucs2_matches(name, &pattern)
+                })
+            })
+            .collect())
     }
 }
 
-pub struct DirIter {
-    vfat: Shared<VFat>,
-    dir_entries: Vec<VFatDirEntry>,
-}
+const STAR: u16 = b'*' as u16;
+const QUESTION: u16 = b'?' as u16;
 
-impl DirIter {
-    fn new(dir: &Dir) -> io::Result<DirIter> {
-        let mut vfat = dir.vfat.borrow_mut();
-        let mut dir_entries: Vec<VFatDirEntry> = Vec::new();
-        let mut buf: Vec<u8> = Vec::new();
-        let mut static_buf = [0; BYTES_IN_ENTRY];
-        vfat.read_chain(dir.start_cluster, &mut buf)?;
-        for entry in buf.chunks(BYTES_IN_ENTRY) {
-            static_buf.copy_from_slice(entry);
-            unsafe {
-                dir_entries.push(mem::transmute(static_buf));
-            }
-        }
-        dir_entries.reverse();
+/// Calls `f` with the UCS-2 form of the name in `metadata`: the long name if
+/// there is one, otherwise the short name.
+pub(crate) fn with_ucs2_name<T, F: FnOnce(&[u16]) -> T>(metadata: &Metadata, f: F) -> T {
+    if metadata.long_name.is_empty() {
+        f(&metadata.name.encode_utf16().collect::<Vec<_>>())
+    } else {
+        f(&metadata.long_name)
+    }
+}
 
-        Ok(DirIter {
-            vfat: dir.vfat.clone(),
-            dir_entries,
-        })
+/// Converts `name` to UCS-2. Returns `None` if `name` has no UCS-2 form.
+pub(crate) fn ucs2_name(name: &OsStr) -> Option<Vec<u16>> {
+    if let Some(name) = name.to_str() {
+        return Some(name.encode_utf16().collect());
     }
+
+    host_ucs2_name(name)
 }
 
-impl Iterator for DirIter {
-    type Item = Entry;
+#[cfg(all(unix, not(feature = "no_std")))]
+fn host_ucs2_name(name: &OsStr) -> Option<Vec<u16>> {
+    use std::os::unix::ffi::OsStrExt;
+    wtf8_to_ucs2(name.as_bytes())
+}
 
-    fn next(&mut self) -> Option<Entry> {
-        if self.dir_entries.is_empty() {
+#[cfg(all(windows, not(feature = "no_std")))]
+fn host_ucs2_name(name: &OsStr) -> Option<Vec<u16>> {
+    use std::os::windows::ffi::OsStrExt;
+    Some(name.encode_wide().collect())
+}
+
+#[cfg(any(feature = "no_std", not(any(unix, windows))))]
+fn host_ucs2_name(_name: &OsStr) -> Option<Vec<u16>> {
+    None
+}
+
+/// Decodes WTF-8 (UTF-8 that may also encode surrogate code points) into
+/// UTF-16 code units. Returns `None` if `bytes` is not well-formed WTF-8.
+pub(crate) fn wtf8_to_ucs2(bytes: &[u8]) -> Option<Vec<u16>> {
+    let mut units = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let lead = bytes[i] as u32;
+        let (len, min, init) = match lead {
+            0x00...0x7F => (1, 0, lead),
+            0xC0...0xDF => (2, 0x80, lead & 0x1F),
+            0xE0...0xEF => (3, 0x800, lead & 0x0F),
+            0xF0...0xF7 => (4, 0x10000, lead & 0x07),
+            _ => return None,
+        };
+
+        if i + len > bytes.len() {
             return None;
         }
 
-        let mut next = self.dir_entries.pop().unwrap();
-        let mut unknown = unsafe { next.unknown };
-        while unknown._bytes[0] == 0 || unknown._bytes[0] == 0x0E5 {
-            if unknown._bytes[0] == 0x0E5 {
-                next = match self.dir_entries.pop() {
-                    Some(val) => val,
-                    None => {
-                        return None;
-                    }
-                };
-                unknown = unsafe { next.unknown };
-            } else {
+        let mut code = init;
+        for &byte in &bytes[i + 1..i + len] {
+            if byte & 0xC0 != 0x80 {
                 return None;
             }
+            code = (code << 6) | (byte as u32 & 0x3F);
         }
 
-        let mut name = String::new();
-        let mut name_bytes = Vec::new();
-        let mut is_lfn = false;
+        if code < min || code > 0x10FFFF {
+            return None;
+        }
 
-        while unknown._bytes[11] == 0xF {
-            let lfn = unsafe { next.long_filename };
+        if code >= 0x10000 {
+            let code = code - 0x10000;
+            units.push(0xD800 | (code >> 10) as u16);
+            units.push(0xDC00 | (code & 0x3FF) as u16);
+        } else {
+            units.push(code as u16);
+        }
 
-            if lfn.seq_no != 0xE5 {
-                is_lfn = true;
-                let mut tmp_buf = Vec::new();
-                tmp_buf.extend_from_slice(&lfn.chars1);
-                tmp_buf.extend_from_slice(&lfn.chars2);
-                tmp_buf.extend_from_slice(&lfn.chars3);
+        i += len;
+    }
+
+    Some(units)
+}
+
+/// Upper-cases `unit` if it is an ASCII letter.
+fn fold(unit: u16) -> u16 {
+    match unit {
+        0x61...0x7A => unit - 0x20,
+        _ => unit,
+    }
+}
+
+/// Compares two UCS-2 names, ignoring the case of ASCII letters.
+fn ucs2_eq_ignore_ascii_case(a: &[u16], b: &[u16]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(&x, &y)| fold(x) == fold(y))
+}
+
+/// Matches a UCS-2 name against a pattern of `*` and `?` wildcards, ignoring
+/// the case of ASCII letters.
+pub(crate) fn ucs2_matches(name: &[u16], pattern: &[u16]) -> bool {
+    let (mut n, mut p) = (0, 0);
+    // The position after the last `*` seen, and where in `name` it resumed.
+    let mut backtrack = None;
+    while n < name.len() {
+        if p < pattern.len() && pattern[p] == STAR {
+            p += 1;
+            backtrack = Some((p, n));
+        } else if p < pattern.len() && (pattern[p] == QUESTION || fold(pattern[p]) == fold(name[n]))
+        {
+            p += 1;
+            n += 1;
+        } else if let Some((star_p, star_n)) = backtrack {
+            p = star_p;
+            n = star_n + 1;
+            backtrack = Some((star_p, n));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&unit| unit == STAR)
+}
+
+/// Splits the raw contents of a directory into its entries.
+fn raw_entries(buf: &[u8]) -> Vec<VFatDirEntry> {
+    let mut entries: Vec<VFatDirEntry> = Vec::new();
+    for entry in buf.chunks(DIR_ENTRY_SIZE) {
+        let mut raw = [0; DIR_ENTRY_SIZE];
+        raw.copy_from_slice(entry);
+        entries.push(VFatDirEntry(raw));
+    }
+    entries
+}
 
-                tmp_buf.reverse();
-                name_bytes.extend_from_slice(&tmp_buf);
+/// Returns the on-disk 11-byte short name of `reg`.
+fn short_name(reg: &VFatRegularDirEntry) -> [u8; 11] {
+    let mut short = [0; 11];
+    short[..8].copy_from_slice(&reg.filename);
+    short[8..].copy_from_slice(&reg.extension);
+    short
+}
+
+/// Returns the name shown for the 8.3 name `short`, with the parts
+/// `case_flags` marks lower-cased.
+fn short_display_name(short: &[u8; 11], case_flags: u8) -> String {
+    let mut name = String::new();
+    let (base, extension) = short.split_at(8);
+    let end = match base.iter().position(|n| *n == 0 || *n == 0x20) {
+        Some(n) => n,
+        None => base.len(),
+    };
+
+    let lower_base = case_flags & LOWER_CASE_BASE != 0;
+    let lower_extension = case_flags & LOWER_CASE_EXTENSION != 0;
+    push_short_part(&mut name, &base[..end], lower_base);
+    match extension.iter().position(|b| *b == 0x00 || *b == 0x20) {
+        Some(pos) => {
+            if pos > 0 {
+                name.push_str(".");
+                push_short_part(&mut name, &extension[..pos], lower_extension);
             }
+        }
+        None => {
+            name.push_str(".");
+            push_short_part(&mut name, extension, lower_extension);
+        }
+    }
+    name
+}
+
+/// Whether `lfn`, a slot with the LFN attributes, is laid out as an LFN
+/// entry, with zero type and first cluster.
+fn is_well_formed(lfn: &VFatLfnDirEntry) -> bool {
+    lfn.dirtype == 0 && lfn.cluster == 0
+}
 
-            next = self.dir_entries.pop().unwrap();
-            unknown = unsafe { next.unknown };
+/// Appends the 8.3 name part `part` to `name`, lower-cased if `lower`.
+fn push_short_part(name: &mut String, part: &[u8], lower: bool) {
+    let part = String::from_utf8_lossy(part);
+    if lower {
+        name.push_str(&part.to_ascii_lowercase());
+    } else {
+        name.push_str(&part);
+    }
+}
+
+/// Why a run of long file name entries does not name a short entry.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum LfnProblem {
+    /// The run is not followed by a live short entry.
+    NoShortEntry,
+    /// The run's first entry is not marked last or its count disagrees with
+    /// the run's length.
+    Truncated,
+    /// The run's sequence numbers do not count down to 1.
+    OutOfSequence,
+    /// The run's checksum does not match the short entry following it.
+    ChecksumMismatch,
+}
+
+impl LfnProblem {
+    fn description(&self) -> &'static str {
+        match self {
+            LfnProblem::NoShortEntry => "LFN run has no short entry",
+            LfnProblem::Truncated => "LFN run is truncated",
+            LfnProblem::OutOfSequence => "LFN entries are out of sequence",
+            LfnProblem::ChecksumMismatch => "LFN checksum does not match its short entry",
         }
+    }
+}
+
+/// A run of long file name entries that does not name a short entry, as
+/// found by `Dir::orphaned_lfns()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OrphanedLfn {
+    /// The slots of the run within its directory.
+    pub slots: Range<u32>,
+    pub problem: LfnProblem,
+}
+
+/// A directory entry that `EntryMode::Tolerant` skips.
+enum Defect {
+    Lfn(OrphanedLfn),
+    /// The short entry at the slot has reserved attribute bits set.
+    ReservedAttributes(u32),
+    /// The slot has the LFN attributes but is not laid out as an LFN entry.
+    MalformedLfn(u32),
+}
+
+/// Checks a run of LFN entries, in on-disk order, against the short name of
+/// the entry following it.
+fn check_lfn_run(run: &[VFatLfnDirEntry], short: &[u8; 11]) -> Result<(), LfnProblem> {
+    if run[0].seq_no & LAST_LFN_ENTRY == 0
+        || (run[0].seq_no & LFN_SEQUENCE_MASK) as usize != run.len()
+    {
+        return Err(LfnProblem::Truncated);
+    }
+
+    let checksum = lfn_checksum(short);
+    for (i, lfn) in run.iter().enumerate() {
+        if (lfn.seq_no & LFN_SEQUENCE_MASK) as usize != run.len() - i {
+            return Err(LfnProblem::OutOfSequence);
+        }
+        if lfn.checksum != checksum {
+            return Err(LfnProblem::ChecksumMismatch);
+        }
+    }
+    Ok(())
+}
+
+/// Finds the entries `EntryMode::Tolerant` would skip among the entries of a
+/// directory, in on-disk order.
+fn find_defects(entries: &[VFatDirEntry]) -> Vec<Defect> {
+    let mut defects = Vec::new();
+    let orphan = |start: usize, end: usize, problem| {
+        Defect::Lfn(OrphanedLfn {
+            slots: start as u32..end as u32,
+            problem,
+        })
+    };
 
-        name_bytes.reverse();
+    // The slot where the current run of LFN entries began.
+    let mut run_start = None;
+    for (slot, entry) in entries.iter().enumerate() {
+        let unknown = entry.unknown();
+        if unknown._bytes[0] == END_OF_ENTRIES || unknown._bytes[0] == DELETED_ENTRY {
+            if let Some(start) = run_start.take() {
+                defects.push(orphan(start, slot, LfnProblem::NoShortEntry));
+            }
+            if unknown._bytes[0] == END_OF_ENTRIES {
+                return defects;
+            }
+            continue;
+        }
 
-        let reg = unsafe { next.regular };
+        if unknown._bytes[11] == ATTR_LFN {
+            let lfn = entry.long_filename();
+            if !is_well_formed(&lfn) {
+                if let Some(start) = run_start.take() {
+                    defects.push(orphan(start, slot, LfnProblem::NoShortEntry));
+                }
+                defects.push(Defect::MalformedLfn(slot as u32));
+                continue;
+            }
+            match run_start {
+                Some(start) if lfn.seq_no & LAST_LFN_ENTRY != 0 => {
+                    defects.push(orphan(start, slot, LfnProblem::NoShortEntry));
+                    run_start = Some(slot);
+                }
+                Some(_) => (),
+                None => run_start = Some(slot),
+            }
+            continue;
+        }
 
-        if is_lfn {
-            let mut chars: Vec<u16> = name_bytes
+        let reg = entry.regular();
+        if reg.attributes.0 & ATTR_RESERVED != 0 {
+            if let Some(start) = run_start.take() {
+                defects.push(orphan(start, slot, LfnProblem::NoShortEntry));
+            }
+            defects.push(Defect::ReservedAttributes(slot as u32));
+            continue;
+        }
+        if reg.attributes.0 & ATTR_VOLUME_ID != 0 {
+            // A volume label, not a file.
+            if let Some(start) = run_start.take() {
+                defects.push(orphan(start, slot, LfnProblem::NoShortEntry));
+            }
+            continue;
+        }
+        if let Some(start) = run_start.take() {
+            let run: Vec<VFatLfnDirEntry> = entries[start..slot]
                 .iter()
-                .skip(1)
-                .step_by(2)
-                .zip(name_bytes.iter().step_by(2))
-                .map(|(first, second)| ((*first as u16) << 8) | (*second as u16))
+                .map(|entry| entry.long_filename())
                 .collect();
+            if let Err(problem) = check_lfn_run(&run, &short_name(&reg)) {
+                defects.push(orphan(start, slot, problem));
+            }
+        }
+    }
 
-            let end = match chars.iter().position(|n| *n == 0 || *n == 0xFF) {
-                Some(n) => n,
-                None => chars.len(),
-            };
+    if let Some(start) = run_start {
+        defects.push(orphan(start, entries.len(), LfnProblem::NoShortEntry));
+    }
+    defects
+}
+
+/// Returns the orphaned long file name runs among the raw entries of a
+/// directory in `buf`. See `Dir::orphaned_lfns()`.
+pub(crate) fn orphans_in(buf: &[u8]) -> Vec<OrphanedLfn> {
+    find_defects(&raw_entries(buf))
+        .into_iter()
+        .filter_map(|defect| match defect {
+            Defect::Lfn(orphan) => Some(orphan),
+            Defect::ReservedAttributes(_) | Defect::MalformedLfn(_) => None,
+        })
+        .collect()
+}
+
+/// Checks the entries of the directory at `dir_cluster`, in on-disk order,
+/// returning the first entry `EntryMode::Tolerant` would skip.
+fn check_entries(entries: &[VFatDirEntry], dir_cluster: Cluster) -> Result<(), Error> {
+    let (slot, reason) = match find_defects(entries).into_iter().next() {
+        None => return Ok(()),
+        Some(Defect::Lfn(orphan)) => (orphan.slots.start, orphan.problem.description()),
+        Some(Defect::ReservedAttributes(slot)) => (slot, "reserved attribute bits are set"),
+        Some(Defect::MalformedLfn(slot)) => (slot, "slot has LFN attributes but is no LFN entry"),
+    };
+
+    Err(Error::CorruptEntry {
+        location: EntryLocation { dir_cluster, slot },
+        reason,
+    })
+}
+
+/// Returns the directory holding the entry at `location`, as a handle of
+/// media generation `generation`.
+pub(crate) fn parent_dir(vfat: &Shared<VFat>, location: EntryLocation, generation: u64) -> Dir {
+    Dir {
+        metadata: Default::default(),
+        start_cluster: location.dir_cluster,
+        vfat: vfat.clone(),
+        location: None,
+        generation,
+    }
+}
+
+/// Reads the live entry at `location` on `vfat`, if there is one, failing
+/// if the medium has changed since `generation`.
+pub(crate) fn entry_at(
+    vfat: &Shared<VFat>,
+    location: EntryLocation,
+    generation: u64,
+) -> io::Result<Option<Entry>> {
+    for entry in parent_dir(vfat, location, generation).entries_with(IterOptions::all())? {
+        if entry.location() == Some(location) {
+            return Ok(Some(entry));
+        }
+    }
 
-            name.push_str(
-                &decode_utf16(&mut chars[..end].iter().cloned())
-                    .map(|r| r.unwrap_or('_'))
-                    .collect::<String>(),
-            );
+    Ok(None)
+}
+
+/// Options controlling which entries a `DirIter` yields.
+///
+/// The default options skip hidden and system entries, matching `ls` without
+/// `-a`; see `Dir::visible_entries()`. `traits::Dir::entries()` uses
+/// `IterOptions::all()` instead.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct IterOptions {
+    /// Whether to yield entries with the hidden or system attribute set.
+    pub include_hidden: bool,
+    /// Whether to name entries as a reader of 8.3 names only sees them: by
+    /// their short name, upper-case as stored regardless of the lower-case
+    /// flags, and with no long name in `Metadata::long_name`.
+    pub short_names: bool,
+}
+
+impl IterOptions {
+    /// Options yielding every entry in the directory.
+    pub fn all() -> IterOptions {
+        IterOptions {
+            include_hidden: true,
+            short_names: false,
+        }
+    }
+}
+
+/// A live entry decoded from a directory, before a handle is made for it.
+#[derive(Clone)]
+pub(crate) struct RawEntry {
+    pub metadata: Metadata,
+    pub start_cluster: Cluster,
+    pub location: EntryLocation,
+    /// The layout version of the entry's directory when the entry was read.
+    /// See `VFat::layout_version()`.
+    pub layout: u64,
+    /// The entry's on-disk 8.3 name.
+    pub short_name: [u8; 11],
+}
+
+impl RawEntry {
+    /// Whether the entry is a directory.
+    pub(crate) fn is_dir(&self) -> bool {
+        self.metadata.attributes.0 & ATTR_DIRECTORY != 0
+    }
+
+    /// Makes a handle of media generation `generation` for the entry. Does
+    /// not borrow `vfat`, so may be called while it is borrowed.
+    pub(crate) fn into_entry(self, vfat: &Shared<VFat>, generation: u64) -> Entry {
+        if self.is_dir() {
+            Entry::Dir(Dir {
+                metadata: self.metadata,
+                start_cluster: self.start_cluster,
+                vfat: vfat.clone(),
+                location: Some(self.location),
+                generation,
+            })
         } else {
-            let end = match reg.filename.iter().position(|n| *n == 0 || *n == 0x20) {
-                Some(n) => n,
-                None => reg.filename.len(),
+            Entry::File(File::with_generation(
+                self.metadata,
+                self.start_cluster,
+                vfat.clone(),
+                Some(self.location),
+                self.layout,
+                generation,
+            ))
+        }
+    }
+}
+
+/// The part of a directory's chain not yet read by a streaming
+/// `RawEntries`.
+#[derive(Debug, Copy, Clone)]
+struct Unread {
+    /// The next cluster of the chain, or `None` if the chain has ended.
+    next: Option<Cluster>,
+    /// The slots that may still be read before `MAX_DIR_ENTRIES` is reached.
+    slots_left: usize,
+}
+
+/// The entries of a directory, decoded one at a time.
+pub(crate) struct RawEntries {
+    dir_cluster: Cluster,
+    /// The directory's raw entries read but not yet decoded, last first.
+    dir_entries: Vec<VFatDirEntry>,
+    /// The rest of the directory's chain, if it is read a cluster at a time.
+    /// See `RawEntries::stream()`.
+    unread: Option<Unread>,
+    /// Number of raw entries popped from `dir_entries` so far.
+    slot: u32,
+    /// The number of corrupt entries skipped so far.
+    skipped: usize,
+    /// The layout version of the directory when its slots were read.
+    layout: u64,
+    /// How unpaired surrogates in long names are decoded.
+    unpaired_surrogates: UnpairedSurrogates,
+    /// Whether the end marker or the end of the directory has been reached.
+    /// No entries are decoded once it has.
+    done: bool,
+}
+
+impl RawEntries {
+    /// Reads the entries of the directory at `dir_cluster`, checking them
+    /// up front if the mount's `EntryMode` is strict.
+    pub(crate) fn read(vfat: &mut VFat, dir_cluster: Cluster) -> io::Result<RawEntries> {
+        let mut buf: Vec<u8> = Vec::new();
+        let source = vfat.dir_source(dir_cluster);
+        source.read(vfat, &mut buf)?;
+        if vfat.options().entry_mode == EntryMode::Strict {
+            check_entries(&raw_entries(&buf), dir_cluster)?;
+        }
+        Ok(RawEntries::from_slots(vfat, dir_cluster, &buf, 0))
+    }
+
+    /// Decodes the raw entries in `buf`, which hold the slots of the
+    /// directory at `dir_cluster` of `vfat` beginning at `first_slot`, read
+    /// under the directory's current layout version.
+    pub(crate) fn from_slots(
+        vfat: &VFat,
+        dir_cluster: Cluster,
+        buf: &[u8],
+        first_slot: u32,
+    ) -> RawEntries {
+        let mut dir_entries = raw_entries(buf);
+        dir_entries.reverse();
+        RawEntries {
+            dir_cluster,
+            dir_entries,
+            unread: None,
+            slot: first_slot,
+            skipped: 0,
+            layout: vfat.layout_version(dir_cluster),
+            unpaired_surrogates: vfat.options().unpaired_surrogates,
+            done: false,
+        }
+    }
+
+    /// Prepares to read the entries of the directory at `dir_cluster` a
+    /// cluster at a time, as `next_entry_streamed()` needs them, so that a
+    /// search ending early in the directory reads only its start. Entries are
+    /// read up front, as by `read()`, if the directory is the fixed root
+    /// region of FAT12 or FAT16 or if the mount's `EntryMode` is strict.
+    pub(crate) fn stream(vfat: &mut VFat, dir_cluster: Cluster) -> io::Result<RawEntries> {
+        match vfat.dir_source(dir_cluster) {
+            DirSource::Chain(start) if vfat.options().entry_mode != EntryMode::Strict => {
+                let mut entries = RawEntries::from_slots(vfat, dir_cluster, &[], 0);
+                entries.unread = Some(Unread {
+                    next: Some(start),
+                    slots_left: MAX_DIR_ENTRIES,
+                });
+                Ok(entries)
+            }
+            _ => RawEntries::read(vfat, dir_cluster),
+        }
+    }
+
+    /// Reads the next cluster of a streamed directory into `dir_entries`.
+    /// Does nothing if the directory is not streamed or has been read to its
+    /// end.
+    fn refill(&mut self, vfat: &mut VFat) -> io::Result<()> {
+        let (cluster, slots_left) = match self.unread {
+            Some(Unread {
+                next: Some(cluster),
+                slots_left,
+            }) if slots_left > 0 => (cluster, slots_left),
+            _ => return Ok(()),
+        };
+
+        let mut buf = vec![0; vfat.cluster_size()];
+        let next = vfat.next_in_chain(cluster)?;
+        vfat.read_cluster(cluster, &mut buf)?;
+        let slots = ::std::cmp::min(buf.len() / DIR_ENTRY_SIZE, slots_left);
+        buf.truncate(slots * DIR_ENTRY_SIZE);
+
+        self.dir_entries = raw_entries(&buf);
+        self.dir_entries.reverse();
+        self.unread = Some(Unread {
+            next,
+            slots_left: slots_left - slots,
+        });
+        Ok(())
+    }
+
+    /// The number of corrupt entries skipped so far.
+    pub(crate) fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    /// Pops the next raw entry, or returns `None` once the end marker or the
+    /// end of the directory has been reached.
+    /// The next cluster of a streamed directory is read through `vfat` when
+    /// the entries read so far run out.
+    fn pop(&mut self, vfat: &mut Option<&mut VFat>) -> io::Result<Option<VFatDirEntry>> {
+        if self.done {
+            return Ok(None);
+        }
+        if self.dir_entries.is_empty() {
+            if let Some(ref mut vfat) = *vfat {
+                self.refill(vfat)?;
+            }
+        }
+
+        Ok(match self.dir_entries.pop() {
+            Some(entry) if entry.unknown()._bytes[0] == END_OF_ENTRIES => {
+                self.slot += 1;
+                self.finish();
+                None
+            }
+            Some(entry) => {
+                self.slot += 1;
+                Some(entry)
+            }
+            None => {
+                self.finish();
+                None
+            }
+        })
+    }
+
+    /// Stops decoding, dropping the raw entries left.
+    fn finish(&mut self) {
+        self.done = true;
+        self.dir_entries = Vec::new();
+        self.unread = None;
+    }
+
+    /// Decodes the next live entry, skipping corrupt entries.
+    pub(crate) fn next_entry(&mut self) -> Option<RawEntry> {
+        self.decode(None)
+            .expect("entries read up front are decoded without I/O")
+    }
+
+    /// Decodes the next live entry as `next_entry()` does, reading the next
+    /// cluster of a streamed directory through `vfat` when needed.
+    pub(crate) fn next_entry_streamed(&mut self, vfat: &mut VFat) -> io::Result<Option<RawEntry>> {
+        self.decode(Some(vfat))
+    }
+
+    fn decode(&mut self, mut vfat: Option<&mut VFat>) -> io::Result<Option<RawEntry>> {
+        // The long file name entries preceding the next short entry, in
+        // on-disk order.
+        let mut run: Vec<VFatLfnDirEntry> = Vec::new();
+        loop {
+            let next = match self.pop(&mut vfat)? {
+                Some(next) => next,
+                None => {
+                    if !run.is_empty() {
+                        self.skipped += 1;
+                    }
+                    return Ok(None);
+                }
             };
 
-            name.push_str(&String::from_utf8_lossy(&reg.filename[..end]));
-            match reg.extension.iter().position(|b| *b == 0x00 || *b == 0x20) {
-                Some(pos) => {
-                    if pos > 0 {
-                        name.push_str(".");
-                        name.push_str(&String::from_utf8_lossy(&reg.extension[..pos]));
+            let unknown = next.unknown();
+            if unknown._bytes[0] == DELETED_ENTRY {
+                if !run.is_empty() {
+                    self.skipped += 1;
+                    run.clear();
+                }
+                continue;
+            }
+
+            if unknown._bytes[11] == ATTR_LFN {
+                let lfn = next.long_filename();
+                if !is_well_formed(&lfn) {
+                    if !run.is_empty() {
+                        self.skipped += 1;
+                        run.clear();
                     }
+                    self.skipped += 1;
+                    continue;
                 }
-                None => {
-                    name.push_str(".");
-                    name.push_str(&String::from_utf8_lossy(&reg.extension[..]));
+                if lfn.seq_no & LAST_LFN_ENTRY != 0 && !run.is_empty() {
+                    self.skipped += 1;
+                    run.clear();
                 }
+                run.push(lfn);
+                continue;
             }
-        }
 
-        let start_cluster = ((reg.cluster_hi as u32) << 16) | (reg.cluster_lo as u32);
-        let metadata = Metadata {
-            name,
-            size: reg.size,
-            attributes: reg.attributes,
-            created: reg.created,
-            accessed: reg.accessed,
-            last_modified: reg.last_modified,
-        };
+            let reg = next.regular();
+            if reg.attributes.0 & ATTR_RESERVED != 0 {
+                if !run.is_empty() {
+                    self.skipped += 1;
+                    run.clear();
+                }
+                self.skipped += 1;
+                continue;
+            }
+            if reg.attributes.0 & ATTR_VOLUME_ID != 0 {
+                // A volume label, not a file.
+                if !run.is_empty() {
+                    self.skipped += 1;
+                    run.clear();
+                }
+                continue;
+            }
+
+            let is_lfn = !run.is_empty() && check_lfn_run(&run, &short_name(&reg)).is_ok();
+            if !run.is_empty() && !is_lfn {
+                // The short entry is sound, so it is kept under its short name.
+                self.skipped += 1;
+            }
+
+            let mut name = String::new();
+            let mut long_name = Vec::new();
+            if is_lfn {
+                let mut chars: Vec<u16> = Vec::new();
+                for lfn in run.iter().rev() {
+                    let mut raw = Vec::new();
+                    raw.extend_from_slice(&lfn.chars1);
+                    raw.extend_from_slice(&lfn.chars2);
+                    raw.extend_from_slice(&lfn.chars3);
+                    chars.extend(raw.chunks(2).map(|unit| LittleEndian::read_u16(unit)));
+                }
+
+                let end = match chars.iter().position(|n| *n == 0 || *n == 0xFFFF) {
+                    Some(n) => n,
+                    None => chars.len(),
+                };
+
+                let replacement = self.unpaired_surrogates.replacement();
+                name.extend(
+                    decode_utf16(chars[..end].iter().cloned()).map(|r| r.unwrap_or(replacement)),
+                );
+                chars.truncate(end);
+                long_name = chars;
+            } else {
+                name = short_display_name(&short_name(&reg), reg.case_flags);
+            }
 
-        if reg.attributes.0 & DIR_MASK != 0 {
-            Some(Entry::Dir(Dir {
-                metadata,
+            let start_cluster = ((reg.cluster_hi as u32) << 16) | (reg.cluster_lo as u32);
+            return Ok(Some(RawEntry {
+                metadata: Metadata {
+                    name,
+                    long_name,
+                    size: reg.size,
+                    attributes: reg.attributes,
+                    created: reg.created,
+                    accessed: reg.accessed,
+                    last_modified: reg.last_modified,
+                },
                 start_cluster: Cluster::from(start_cluster),
-                vfat: self.vfat.clone(),
-            }))
-        } else {
-            Some(Entry::File(File::new(
-                metadata,
-                Cluster::from(start_cluster),
-                self.vfat.clone(),
-            )))
+                location: EntryLocation {
+                    dir_cluster: self.dir_cluster,
+                    slot: self.slot - 1,
+                },
+                layout: self.layout,
+                short_name: short_name(&reg),
+            }));
         }
     }
 }
 
+/// Returns `true` if `entry` is named `name`, in UCS-2. Comparison is as for
+/// `Dir::find()`.
+pub(crate) fn is_named(entry: &RawEntry, name: &[u16]) -> bool {
+    with_ucs2_name(&entry.metadata, |entry_name| {
+        ucs2_eq_ignore_ascii_case(entry_name, name)
+    })
+}
+
+/// Returns the UCS-2 `name` with ASCII letters upper-cased, the form in
+/// which `Dir::find()` compares names.
+pub(crate) fn folded(name: &[u16]) -> Vec<u16> {
+    name.iter().map(|&unit| fold(unit)).collect()
+}
+
+/// Returns the name of `entry` as by `folded()`.
+pub(crate) fn folded_name(entry: &RawEntry) -> Vec<u16> {
+    with_ucs2_name(&entry.metadata, folded)
+}
+
+/// Finds the entry named `name`, in UCS-2, in the directory at
+/// `dir_cluster`, under a single borrow of the volume. Comparison is as for
+/// `Dir::find()`. The directory's index is used if it has one; see
+/// `MountOptions::dir_index`.
+pub(crate) fn lookup(
+    vfat: &mut VFat,
+    dir_cluster: Cluster,
+    name: &[u16],
+) -> io::Result<Option<RawEntry>> {
+    lookup_counted(vfat, dir_cluster, name, &mut LookupStats::default())
+}
+
+/// What a lookup did to find, or fail to find, an entry.
+#[derive(Debug, Copy, Clone, Default)]
+pub(crate) struct LookupStats {
+    /// Whether the entry was found in the directory's index.
+    pub(crate) indexed: bool,
+    /// The number of entries parsed while scanning the directory.
+    pub(crate) entries_parsed: u64,
+}
+
+/// Looks up `name` as `lookup()` does, recording in `stats` how it was
+/// found.
+pub(crate) fn lookup_counted(
+    vfat: &mut VFat,
+    dir_cluster: Cluster,
+    name: &[u16],
+    stats: &mut LookupStats,
+) -> io::Result<Option<RawEntry>> {
+    let indexed = match dir_index::probe(vfat, dir_cluster, name)? {
+        Probe::Found(entry) => {
+            stats.indexed = true;
+            return Ok(Some(entry));
+        }
+        Probe::Missing => true,
+        Probe::Unindexed => false,
+    };
+
+    let mut entries = RawEntries::stream(vfat, dir_cluster)?;
+    let mut found = None;
+    while let Some(entry) = entries.next_entry_streamed(vfat)? {
+        stats.entries_parsed += 1;
+        if is_named(&entry, name) {
+            found = Some(entry);
+            break;
+        }
+    }
+
+    stats.entries_parsed += entries.skipped as u64;
+    vfat.note_corrupt_entries(entries.skipped);
+    if indexed && found.is_some() {
+        dir_index::refresh(vfat, dir_cluster);
+    }
+    Ok(found)
+}
+
+/// An iterator over the entries of a directory.
+///
+/// The directory is read when the iterator is created and the entries are
+/// decoded from that snapshot, so changes made to the directory while the
+/// iterator is in use, through this handle or any other, neither appear in
+/// nor disturb the listing. Files opened from entries read before their
+/// directory was compacted cannot update their entries, failing with
+/// `Error::StaleHandle`; open them again by path.
+pub struct DirIter {
+    vfat: Shared<VFat>,
+    options: IterOptions,
+    entries: RawEntries,
+    /// The media generation of the directory, inherited by its entries.
+    generation: u64,
+}
+
+impl DirIter {
+    fn new(dir: &Dir, options: IterOptions) -> io::Result<DirIter> {
+        let mut vfat = dir.vfat.borrow_mut();
+        vfat.check_generation(dir.generation)?;
+
+        Ok(DirIter {
+            vfat: dir.vfat.clone(),
+            options,
+            entries: RawEntries::read(&mut vfat, dir.start_cluster)?,
+            generation: dir.generation,
+        })
+    }
+
+    /// The number of corrupt entries skipped by this iterator so far. See
+    /// `EntryMode::Tolerant`.
+    pub fn skipped(&self) -> usize {
+        self.entries.skipped
+    }
+}
+
+impl Iterator for DirIter {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        loop {
+            let skipped = self.entries.skipped;
+            let next = self.entries.next_entry();
+            if self.entries.skipped != skipped {
+                self.vfat
+                    .borrow_mut()
+                    .note_corrupt_entries(self.entries.skipped - skipped);
+            }
+
+            let mut entry = next?;
+            let attributes = entry.metadata.attributes.0;
+            if !self.options.include_hidden && attributes & (ATTR_HIDDEN | ATTR_SYSTEM) != 0 {
+                continue;
+            }
+            if self.options.short_names {
+                entry.metadata.name = short_display_name(&entry.short_name, 0);
+                entry.metadata.long_name = Vec::new();
+            }
+            return Some(entry.into_entry(&self.vfat, self.generation));
+        }
+    }
+}
+
+/// Once `next()` returns `None`, it always does: iteration ends for good at
+/// the directory's end marker or the end of its chain.
+impl FusedIterator for DirIter {}
+
 impl traits::Dir for Dir {
     type Entry = Entry;
     type Iter = DirIter;
 
+    /// Returns an iterator over every entry in this directory, including
+    /// hidden and system entries.
     fn entries(&self) -> io::Result<Self::Iter> {
-        DirIter::new(&self)
+        DirIter::new(&self, IterOptions::all())
+    }
+
+    /// Compares names as FAT does, and as the inherent `Dir::find()` does:
+    /// on the UCS-2 name as stored, ignoring the case of ASCII letters. A
+    /// long name with unpaired surrogates is compared by its stored units,
+    /// not by its `name()`, in which they are replaced, so it matches no
+    /// `name`.
+    fn names_match(&self, entry: &Entry, name: &str) -> bool {
+        let name: Vec<u16> = name.encode_utf16().collect();
+        with_ucs2_name(traits::Entry::metadata(entry), |entry_name| {
+            ucs2_eq_ignore_ascii_case(entry_name, &name)
+        })
+    }
+
+    /// Finds the entry named `name` as `Dir::find()` does, by way of the
+    /// directory's index when it has one.
+    fn find<N: AsRef<str>>(&self, name: N) -> io::Result<Self::Entry> {
+        Dir::find(self, name.as_ref())
+    }
+}
+
+impl PartialEq for Dir {
+    fn eq(&self, other: &Dir) -> bool {
+        self.id() == other.id()
+    }
+}
+
+impl Eq for Dir {}
+
+impl Hash for Dir {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id().hash(state)
     }
 }
 
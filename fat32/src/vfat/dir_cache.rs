@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+
+use vfat::{Cluster, Dir, EntryLocation, Metadata, Shared, VFat};
+
+/// A directory as remembered by the `DirCache`, without its volume handle.
+#[derive(Debug, Clone)]
+struct CachedDir {
+    metadata: Metadata,
+    start_cluster: Cluster,
+    location: Option<EntryLocation>,
+    last_used: u64,
+}
+
+/// Directories resolved by path, so that repeated opens beneath a common
+/// prefix skip re-reading the directories along it.
+///
+/// Paths are keyed by their normal components, upper-cased as names are
+/// compared case-insensitively. Paths with components that are not valid
+/// UTF-8 are not cached. When full, the least recently used directory is
+/// evicted.
+#[derive(Debug)]
+pub(crate) struct DirCache {
+    capacity: usize,
+    clock: u64,
+    entries: HashMap<String, CachedDir>,
+}
+
+/// Returns the cache key for the path made of `names`.
+fn key<N: AsRef<OsStr>>(names: &[N]) -> Option<String> {
+    let mut key = String::new();
+    for name in names {
+        let name: &OsStr = name.as_ref();
+        key.push('/');
+        key.push_str(&name.to_str()?.to_ascii_uppercase());
+    }
+    Some(key)
+}
+
+impl DirCache {
+    pub(crate) fn new(capacity: usize) -> DirCache {
+        DirCache {
+            capacity,
+            clock: 0,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Returns the deepest cached directory on the path made of `names`, as
+    /// the number of names it covers and the directory, if any is cached.
+    /// The directory's handle belongs to media generation `generation`.
+    pub(crate) fn deepest<N: AsRef<OsStr>>(
+        &mut self,
+        names: &[N],
+        vfat: &Shared<VFat>,
+        generation: u64,
+    ) -> Option<(usize, Dir)> {
+        self.clock += 1;
+        for depth in (1..names.len() + 1).rev() {
+            let key = match key(&names[..depth]) {
+                Some(key) => key,
+                None => continue,
+            };
+
+            if let Some(cached) = self.entries.get_mut(&key) {
+                cached.last_used = self.clock;
+                return Some((
+                    depth,
+                    Dir {
+                        metadata: cached.metadata.clone(),
+                        start_cluster: cached.start_cluster,
+                        vfat: vfat.clone(),
+                        location: cached.location,
+                        generation,
+                    },
+                ));
+            }
+        }
+        None
+    }
+
+    /// Remembers `dir` as the directory at the path made of `names`.
+    pub(crate) fn insert<N: AsRef<OsStr>>(&mut self, names: &[N], dir: &Dir) {
+        let key = match key(names) {
+            Some(key) => key,
+            None => return,
+        };
+        if self.capacity == 0 {
+            return;
+        }
+
+        if !self.entries.contains_key(&key) {
+            let room = self.capacity - 1;
+            self.evict_to(room);
+        }
+
+        self.clock += 1;
+        self.entries.insert(
+            key,
+            CachedDir {
+                metadata: dir.metadata.clone(),
+                start_cluster: dir.start_cluster,
+                location: dir.location,
+                last_used: self.clock,
+            },
+        );
+    }
+
+    /// Evicts the least recently used directories until at most `len`
+    /// remain.
+    fn evict_to(&mut self, len: usize) {
+        while self.entries.len() > len {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|&(_, cached)| cached.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+
+    /// Changes the most directories remembered, evicting the least recently
+    /// used if the cache holds more.
+    pub(crate) fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict_to(capacity);
+    }
+
+    /// The number of directories remembered.
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Forgets every directory, e.g. after entries have moved on disk.
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
@@ -0,0 +1,608 @@
+//! Generation of adversarial directories, for tests and fuzzing.
+//!
+//! `generate()` lays out the slots of a directory from a string of choices:
+//! live short entries, long names of every length up to the maximum, and
+//! the damage `DirIter` must get past, such as deleted slots in the middle
+//! of a long name, wrong checksums, and an end marker part way through a
+//! long name. Any string of choices makes a directory, so a fuzzer's inputs
+//! each become a plausible directory rather than mostly junk, and what
+//! `DirIter` should make of it is known. `check()` puts the directory on a
+//! volume and verifies that it does.
+
+use std::io::Cursor;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use consts::*;
+use traits::{Entry as EntryTrait, FileSystem};
+use vfat::mkfs::{build_image, ImageLayout, PartitionLayout, Tree};
+use vfat::{lfn_checksum, EntryMode, Error, IterOptions, MountOptions, VFat};
+
+/// The most slots `fuzz_dir()` generates, 32 clusters of the volume
+/// `check()` builds.
+pub const MAX_SLOTS: usize = 512;
+
+/// The sectors of the volume `check()` builds, one per cluster.
+const VOLUME_SECTORS: u64 = 256;
+
+/// The UTF-16 units long names are drawn from, besides the surrogate pair
+/// of U+1F600.
+const NAME_UNITS: &[u16] = &[
+    b'a' as u16,
+    b'q' as u16,
+    b'z' as u16,
+    b'A' as u16,
+    b'Q' as u16,
+    b'Z' as u16,
+    b'0' as u16,
+    b'9' as u16,
+    b' ' as u16,
+    b'.' as u16,
+    b'-' as u16,
+    b'_' as u16,
+    b'~' as u16,
+    0xE9,
+    0x3A9,
+    0x4E2D,
+];
+
+/// The bytes short names are drawn from.
+const SHORT_NAME_BYTES: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789_";
+
+/// A source of choices, read from the front of a byte string. Once the
+/// string runs out, every choice is the first.
+#[derive(Debug, Clone)]
+pub struct Choices<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Choices<'a> {
+    pub fn new(bytes: &'a [u8]) -> Choices<'a> {
+        Choices { bytes }
+    }
+
+    /// Whether the choices have run out.
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    fn byte(&mut self) -> u8 {
+        match self.bytes.split_first() {
+            Some((&first, rest)) => {
+                self.bytes = rest;
+                first
+            }
+            None => 0,
+        }
+    }
+
+    /// Chooses a number below `n`, which must not be `0`.
+    fn below(&mut self, n: usize) -> usize {
+        if n <= 256 {
+            self.byte() as usize % n
+        } else {
+            (self.byte() as usize | (self.byte() as usize) << 8) % n
+        }
+    }
+
+    /// Chooses a number in `low..=high`.
+    fn between(&mut self, low: usize, high: usize) -> usize {
+        low + self.below(high - low + 1)
+    }
+}
+
+/// A run of slots laid down by `generate()`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Piece {
+    /// A live short entry without a long name.
+    Short,
+    /// A live entry with a long name of 1 to 255 units.
+    LongName,
+    /// A live entry with a long name of 255 units, in 20 LFN slots.
+    MaxLongName,
+    /// A long name whose checksum does not match its short entry.
+    BadChecksum,
+    /// A long name with a wrong sequence number.
+    OutOfSequence,
+    /// A long name with deleted slots between two of its slots.
+    InterleavedDeleted,
+    /// A long name with a malformed LFN slot between two of its slots.
+    InterleavedMalformed,
+    /// Deleted slots, each laid out as a short entry or an LFN slot.
+    Deleted,
+    /// A volume label.
+    VolumeLabel,
+    /// A short entry with reserved attribute bits set.
+    ReservedAttributes,
+    /// Part of a long name followed by the end marker. Slots after it are
+    /// never decoded.
+    EndMidChain,
+}
+
+impl Piece {
+    /// Every kind of piece.
+    pub const ALL: &'static [Piece] = &[
+        Piece::Short,
+        Piece::LongName,
+        Piece::MaxLongName,
+        Piece::BadChecksum,
+        Piece::OutOfSequence,
+        Piece::InterleavedDeleted,
+        Piece::InterleavedMalformed,
+        Piece::Deleted,
+        Piece::VolumeLabel,
+        Piece::ReservedAttributes,
+        Piece::EndMidChain,
+    ];
+}
+
+/// An entry `DirIter` should yield from a generated directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpectedEntry {
+    pub slot: u32,
+    pub name: String,
+}
+
+/// A directory made by `generate()`, with what `DirIter` should make of it.
+#[derive(Debug, Clone)]
+pub struct GeneratedDir {
+    /// The directory's raw slots.
+    pub slots: Vec<u8>,
+    /// The pieces laid down, in order.
+    pub pieces: Vec<Piece>,
+    /// The entries listed with `IterOptions::all()`, in order.
+    pub entries: Vec<ExpectedEntry>,
+    /// The entries `EntryMode::Tolerant` skips, as counted by
+    /// `DirIter::skipped()`.
+    pub skipped: usize,
+}
+
+/// Lays out a directory of at most `max_slots` slots from `choices`, one
+/// piece at a time until the choices or the slots run out.
+pub fn generate(choices: &mut Choices, max_slots: usize) -> GeneratedDir {
+    let mut builder = Builder {
+        slots: Vec::new(),
+        entries: Vec::new(),
+        skipped: 0,
+        ended: false,
+    };
+    let mut pieces = Vec::new();
+
+    while !choices.is_empty() {
+        let piece = Piece::ALL[choices.below(Piece::ALL.len())];
+        let mut next = builder.clone();
+        next.lay(piece, choices);
+        if next.slots.len() > max_slots * DIR_ENTRY_SIZE {
+            break;
+        }
+        builder = next;
+        pieces.push(piece);
+    }
+
+    GeneratedDir {
+        slots: builder.slots,
+        pieces,
+        entries: builder.entries,
+        skipped: builder.skipped,
+    }
+}
+
+/// Puts `dir` in the root directory of a fresh volume and checks that it
+/// is listed, and its entries found, as `dir` expects: in tolerant mode,
+/// with the expected entries and skip count; in strict mode, failing if and
+/// only if any entry is skipped.
+///
+/// # Panics
+///
+/// Panics if the volume does not behave as `dir` expects.
+pub fn check(dir: &GeneratedDir) {
+    let image = image_with_root(&dir.slots);
+
+    let vfat = VFat::from(Cursor::new(image.clone())).expect("volume mounts");
+    let root = (&vfat).open_dir("/").expect("root directory");
+    let mut iter = root.entries_with(IterOptions::all()).expect("listing");
+    let listed: Vec<ExpectedEntry> = iter
+        .by_ref()
+        .map(|entry| ExpectedEntry {
+            slot: entry.location().expect("entry has a location").slot,
+            name: entry.metadata().name.clone(),
+        })
+        .collect();
+    assert_eq!(listed, dir.entries, "listing of {:?}", dir.pieces);
+    assert_eq!(iter.skipped(), dir.skipped, "skips in {:?}", dir.pieces);
+
+    // Lookups decode the directory a cluster at a time, rather than all at
+    // once as listings do, and find the first entry of a name.
+    for expected in &dir.entries {
+        let first = dir
+            .entries
+            .iter()
+            .find(|entry| entry.name.eq_ignore_ascii_case(&expected.name))
+            .expect("the entry itself matches");
+        let found = root.find(&expected.name).expect("entry is found");
+        assert_eq!(
+            found.location().map(|location| location.slot),
+            Some(first.slot)
+        );
+    }
+
+    let options = MountOptions {
+        entry_mode: EntryMode::Strict,
+        ..Default::default()
+    };
+    let vfat = VFat::with_options(Cursor::new(image), options).expect("volume mounts");
+    let root = (&vfat).open_dir("/").expect("root directory");
+    match root.entries_with(IterOptions::all()) {
+        Ok(iter) => {
+            assert_eq!(dir.skipped, 0, "strict listing of {:?}", dir.pieces);
+            assert_eq!(iter.count(), dir.entries.len());
+        }
+        Err(ref e) if dir.skipped > 0 => {
+            match e.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+                Some(&Error::CorruptEntry { .. }) => {}
+                other => panic!("unexpected error {:?}", other),
+            }
+        }
+        Err(e) => panic!("strict listing of {:?} failed: {}", dir.pieces, e),
+    }
+}
+
+/// Generates a directory from the fuzzer input `data` and checks it. The
+/// entry point of the `dir_entries` fuzz target.
+pub fn fuzz_dir(data: &[u8]) {
+    check(&generate(&mut Choices::new(data), MAX_SLOTS));
+}
+
+/// Returns an image holding a volume whose root directory is made of
+/// `slots`, in as many clusters as they need.
+fn image_with_root(slots: &[u8]) -> Vec<u8> {
+    let mut partition = PartitionLayout::fat32(Some(VOLUME_SECTORS), Tree::new());
+    partition.format.sectors_per_cluster = Some(1);
+    let mut layout = ImageLayout::default();
+    layout.partitions.push(partition);
+    let mut image = Cursor::new(vec![0; (2048 + VOLUME_SECTORS as usize) * 512]);
+    build_image(&mut image, &layout).expect("image builds");
+    let mut image = image.into_inner();
+
+    let start = LittleEndian::read_u32(&image[454..458]) as usize * 512;
+    let (reserved, fats, sectors_per_fat, root) = {
+        let bpb = &image[start..start + 512];
+        (
+            LittleEndian::read_u16(&bpb[14..16]) as usize,
+            bpb[16] as usize,
+            LittleEndian::read_u32(&bpb[36..40]) as usize,
+            LittleEndian::read_u32(&bpb[44..48]) as usize,
+        )
+    };
+
+    let clusters = ::std::cmp::max(1, (slots.len() + 511) / 512);
+    for cluster in root..root + clusters {
+        let next = if cluster + 1 == root + clusters {
+            EOC_MARKER
+        } else {
+            cluster as u32 + 1
+        };
+        for fat in 0..fats {
+            let offset =
+                start + (reserved + fat * sectors_per_fat) * 512 + cluster * FAT_ENTRY_SIZE;
+            LittleEndian::write_u32(&mut image[offset..offset + FAT_ENTRY_SIZE], next);
+        }
+    }
+
+    let data = start + (reserved + fats * sectors_per_fat + root - 2) * 512;
+    image[data..data + slots.len()].copy_from_slice(slots);
+    image
+}
+
+/// The directory being laid out by `generate()`.
+#[derive(Clone)]
+struct Builder {
+    slots: Vec<u8>,
+    entries: Vec<ExpectedEntry>,
+    skipped: usize,
+    /// Whether the end marker has been laid down. Pieces after it are laid
+    /// down but expect nothing.
+    ended: bool,
+}
+
+impl Builder {
+    /// The index of the next slot.
+    fn slot(&self) -> u32 {
+        (self.slots.len() / DIR_ENTRY_SIZE) as u32
+    }
+
+    fn push(&mut self, slot: [u8; DIR_ENTRY_SIZE]) {
+        self.slots.extend_from_slice(&slot);
+    }
+
+    fn skip(&mut self, n: usize) {
+        if !self.ended {
+            self.skipped += n;
+        }
+    }
+
+    /// Lays down a live short entry, expected under `name`.
+    fn entry(&mut self, slot: [u8; DIR_ENTRY_SIZE], name: String) {
+        if !self.ended {
+            let at = self.slot();
+            self.entries.push(ExpectedEntry { slot: at, name });
+        }
+        self.push(slot);
+    }
+
+    fn lay(&mut self, piece: Piece, choices: &mut Choices) {
+        match piece {
+            Piece::Short => {
+                let (slot, name) = short_entry(choices);
+                self.entry(slot, name);
+            }
+            Piece::LongName | Piece::MaxLongName => {
+                let len = match piece {
+                    Piece::MaxLongName => 255,
+                    _ => choices.between(1, 255),
+                };
+                let (lfns, short, name) = long_entry(choices, len);
+                for lfn in lfns {
+                    self.push(lfn);
+                }
+                self.entry(short, name);
+            }
+            Piece::BadChecksum => {
+                let len = choices.between(1, 255);
+                let (mut lfns, short, _) = long_entry(choices, len);
+                let delta = choices.between(1, 255) as u8;
+                let wrong = choices.below(lfns.len());
+                lfns[wrong][13] = lfns[wrong][13].wrapping_add(delta);
+                self.skipped_long(lfns, short);
+            }
+            Piece::OutOfSequence => {
+                let len = choices.between(1, 255);
+                let (mut lfns, short, _) = long_entry(choices, len);
+                // The first slot keeps its last-entry flag, so the run is
+                // not split.
+                let wrong = choices.below(lfns.len());
+                let seq = lfns[wrong][0] & LFN_SEQUENCE_MASK;
+                let other = (seq as usize + choices.below(19)) % 20 + 1;
+                lfns[wrong][0] = lfns[wrong][0] & !LFN_SEQUENCE_MASK | other as u8;
+                self.skipped_long(lfns, short);
+            }
+            Piece::InterleavedDeleted => {
+                let len = choices.between(1, 255);
+                let (lfns, short, name) = long_entry(choices, len);
+                let split = choices.below(lfns.len() + 1);
+                let deleted = choices.between(1, 3);
+                for lfn in &lfns[..split] {
+                    self.push(*lfn);
+                }
+                for _ in 0..deleted {
+                    let slot = deleted_slot(choices);
+                    self.push(slot);
+                }
+                for lfn in &lfns[split..] {
+                    self.push(*lfn);
+                }
+                // The slots before the deleted ones name nothing, and those
+                // after them lack the first slot of the name.
+                match split {
+                    0 => self.entry(short, name),
+                    split if split == lfns.len() => {
+                        self.skip(1);
+                        self.short_named(short);
+                    }
+                    _ => {
+                        self.skip(2);
+                        self.short_named(short);
+                    }
+                }
+            }
+            Piece::InterleavedMalformed => {
+                let len = choices.between(1, 255);
+                let (lfns, short, name) = long_entry(choices, len);
+                let split = choices.below(lfns.len() + 1);
+                let mut malformed = lfns[0];
+                match choices.below(2) {
+                    0 => malformed[12] = choices.between(1, 255) as u8,
+                    _ => malformed[26] = choices.between(1, 255) as u8,
+                }
+                for lfn in &lfns[..split] {
+                    self.push(*lfn);
+                }
+                self.push(malformed);
+                for lfn in &lfns[split..] {
+                    self.push(*lfn);
+                }
+                // The malformed slot is skipped and ends the run before it,
+                // as a deleted slot would.
+                match split {
+                    0 => {
+                        self.skip(1);
+                        self.entry(short, name);
+                    }
+                    split if split == lfns.len() => {
+                        self.skip(2);
+                        self.short_named(short);
+                    }
+                    _ => {
+                        self.skip(3);
+                        self.short_named(short);
+                    }
+                }
+            }
+            Piece::Deleted => {
+                for _ in 0..choices.between(1, 4) {
+                    let slot = deleted_slot(choices);
+                    self.push(slot);
+                }
+            }
+            Piece::VolumeLabel => {
+                let (mut slot, _) = short_entry(choices);
+                slot[11] = ATTR_VOLUME_ID | [0, ATTR_ARCHIVE][choices.below(2)];
+                self.push(slot);
+            }
+            Piece::ReservedAttributes => {
+                let (mut slot, _) = short_entry(choices);
+                slot[11] |= [0x40, 0x80, ATTR_RESERVED][choices.below(3)];
+                self.push(slot);
+                self.skip(1);
+            }
+            Piece::EndMidChain => {
+                let len = choices.between(1, 255);
+                let (lfns, _, _) = long_entry(choices, len);
+                let split = choices.below(lfns.len());
+                for lfn in &lfns[..split] {
+                    self.push(*lfn);
+                }
+                self.push([END_OF_ENTRIES; DIR_ENTRY_SIZE]);
+                if split > 0 {
+                    self.skip(1);
+                }
+                self.ended = true;
+            }
+        }
+    }
+
+    /// Lays down a long name `DirIter` rejects, which costs one skip and
+    /// leaves its short entry under its short name.
+    fn skipped_long(&mut self, lfns: Vec<[u8; DIR_ENTRY_SIZE]>, short: [u8; DIR_ENTRY_SIZE]) {
+        for lfn in lfns {
+            self.push(lfn);
+        }
+        self.skip(1);
+        self.short_named(short);
+    }
+
+    /// Lays down the short entry of a long name, expected under its short
+    /// name.
+    fn short_named(&mut self, short: [u8; DIR_ENTRY_SIZE]) {
+        let name = short_display(&short);
+        self.entry(short, name);
+    }
+}
+
+/// Returns the name a short entry without a long name is listed under.
+fn short_display(slot: &[u8; DIR_ENTRY_SIZE]) -> String {
+    let part = |bytes: &[u8], lower: bool| {
+        let part: String = bytes
+            .iter()
+            .take_while(|&&b| b != b' ')
+            .map(|&b| b as char)
+            .collect();
+        if lower {
+            part.to_ascii_lowercase()
+        } else {
+            part
+        }
+    };
+
+    let mut name = part(&slot[..8], slot[12] & 0x08 != 0);
+    let extension = part(&slot[8..11], slot[12] & 0x10 != 0);
+    if !extension.is_empty() {
+        name.push('.');
+        name.push_str(&extension);
+    }
+    name
+}
+
+/// Chooses a live short entry and the name it is listed under.
+fn short_entry(choices: &mut Choices) -> ([u8; DIR_ENTRY_SIZE], String) {
+    let mut slot = [0; DIR_ENTRY_SIZE];
+    slot[..11].copy_from_slice(b"           ");
+    let (base_len, extension_len) = (choices.between(1, 8), choices.below(4));
+    {
+        let (base, extension) = slot.split_at_mut(8);
+        for byte in base[..base_len]
+            .iter_mut()
+            .chain(&mut extension[..extension_len])
+        {
+            *byte = SHORT_NAME_BYTES[choices.below(SHORT_NAME_BYTES.len())];
+        }
+    }
+
+    const ATTRIBUTES: &[u8] = &[
+        0,
+        ATTR_ARCHIVE,
+        ATTR_READ_ONLY,
+        ATTR_HIDDEN,
+        ATTR_SYSTEM,
+        ATTR_DIRECTORY,
+        ATTR_HIDDEN | ATTR_SYSTEM | ATTR_READ_ONLY | ATTR_ARCHIVE,
+    ];
+    slot[11] = ATTRIBUTES[choices.below(ATTRIBUTES.len())];
+    // The lower-case flags for the base name and the extension.
+    slot[12] = [0, 0x08, 0x10, 0x18][choices.below(4)];
+    if slot[11] & ATTR_DIRECTORY == 0 {
+        LittleEndian::write_u32(&mut slot[28..32], choices.below(1 << 16) as u32);
+    }
+
+    let name = short_display(&slot);
+    (slot, name)
+}
+
+/// Chooses a long name of `len` UTF-16 units and returns its LFN slots, in
+/// on-disk order, its short entry, and the name.
+fn long_entry(
+    choices: &mut Choices,
+    len: usize,
+) -> (Vec<[u8; DIR_ENTRY_SIZE]>, [u8; DIR_ENTRY_SIZE], String) {
+    let mut units: Vec<u16> = Vec::with_capacity(len);
+    while units.len() < len {
+        let last = units.len() + 1 == len;
+        match choices.below(NAME_UNITS.len() + 1) {
+            n if n == NAME_UNITS.len() && units.len() + 2 <= len => {
+                units.extend_from_slice(&[0xD83D, 0xDE00]);
+            }
+            // Names start and end with a letter, never a space or a dot.
+            n if n < NAME_UNITS.len() && (units.is_empty() || last) => {
+                units.push(NAME_UNITS[n % 6]);
+            }
+            n => units.push(NAME_UNITS[n % NAME_UNITS.len()]),
+        }
+    }
+    let name = String::from_utf16(&units).expect("names are valid UTF-16");
+
+    let (mut short, _) = short_entry(choices);
+    // The short entry of a long name is listed under the long name.
+    short[12] = 0;
+    let mut short_name = [0; 11];
+    short_name.copy_from_slice(&short[..11]);
+    let checksum = lfn_checksum(&short_name);
+
+    // Names not filling their last slot are ended with NUL, then padded.
+    let count = (len + 12) / 13;
+    if len % 13 != 0 {
+        units.push(0);
+    }
+    units.resize(count * 13, 0xFFFF);
+
+    let mut lfns = Vec::with_capacity(count);
+    for seq in (1..=count).rev() {
+        let mut lfn = [0; DIR_ENTRY_SIZE];
+        lfn[0] = seq as u8 | if seq == count { LAST_LFN_ENTRY } else { 0 };
+        lfn[11] = ATTR_LFN;
+        lfn[13] = checksum;
+        let chars = &units[(seq - 1) * 13..seq * 13];
+        for (i, &unit) in chars.iter().enumerate() {
+            let offset = match i {
+                0...4 => 1 + i * 2,
+                5...10 => 14 + (i - 5) * 2,
+                _ => 28 + (i - 11) * 2,
+            };
+            LittleEndian::write_u16(&mut lfn[offset..offset + 2], unit);
+        }
+        lfns.push(lfn);
+    }
+    (lfns, short, name)
+}
+
+/// Chooses a deleted slot, laid out as a short entry or an LFN slot.
+fn deleted_slot(choices: &mut Choices) -> [u8; DIR_ENTRY_SIZE] {
+    let mut slot = match choices.below(2) {
+        0 => short_entry(choices).0,
+        _ => {
+            let len = choices.between(1, 13);
+            long_entry(choices, len).0[0]
+        }
+    };
+    slot[0] = DELETED_ENTRY;
+    slot
+}
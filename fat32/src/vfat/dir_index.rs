@@ -0,0 +1,297 @@
+//! Name indexes for very large directories, stored in per-directory sidecar
+//! files.
+//!
+//! Finding an entry by name normally decodes the directory from its first
+//! slot, which is slow for directories of tens of thousands of entries. When
+//! `MountOptions::dir_index` is set, a directory holding a file named
+//! `DIRINDEX.SYS` is looked up through the index in that file instead. The
+//! sidecar is a sequence of little-endian records following the magic
+//! `FDX1`:
+//!
+//! ```text
+//! name_len: u16, name: [u16; name_len]    // the entry's name, UCS-2, folded
+//! slot: u32                               // the slot of its short entry
+//! ```
+//!
+//! Names are folded as `Dir::find()` compares them, upper-casing ASCII
+//! letters. Every slot found through the index is checked against the
+//! directory, and names missing from the index are looked up by reading the
+//! directory, so a stale index is never trusted. An index found to be stale,
+//! like an empty or malformed sidecar, is rebuilt from the directory and
+//! written back when the sidecar may be changed, that is, unless the volume
+//! is read-only, the sidecar is protected by `MountOptions::protected_paths`,
+//! or the write would use `MountOptions::reserved_clusters`. Lookups never
+//! fail for want of an index: errors writing it back are ignored, and a
+//! directory whose index cannot be loaded is read in full. To index a
+//! directory, create an empty `DIRINDEX.SYS` in it;
+//! the sidecar is best created early, as finding it reads the directory up
+//! to it once per mount.
+
+use std::collections::HashMap;
+use std::io;
+
+use byteorder::{ByteOrder, LittleEndian};
+use consts::DIR_ENTRY_SIZE;
+use vfat::dir::{folded, folded_name, RawEntries, RawEntry};
+use vfat::{Cluster, EntryLocation, VFat};
+
+/// The name of the index sidecar file in each indexed directory.
+pub const INDEX_NAME: &str = "DIRINDEX.SYS";
+
+const MAGIC: &[u8; 4] = b"FDX1";
+
+/// The most long file name entries that may precede a short entry.
+const MAX_LFN_ENTRIES: u32 = 20;
+
+/// The index of one directory, as loaded from its sidecar.
+#[derive(Debug)]
+pub(crate) struct DirIndex {
+    /// The location of the sidecar's directory entry.
+    location: EntryLocation,
+    /// The first cluster of the sidecar's data.
+    start_cluster: Cluster,
+    /// The size in bytes of the sidecar's data.
+    size: u32,
+    slots: HashMap<Vec<u16>, u32>,
+}
+
+/// The indexes of the directories looked up since mounting, by first
+/// cluster. `None` records that a directory has no sidecar.
+#[derive(Debug, Default)]
+pub(crate) struct DirIndexes {
+    dirs: HashMap<Cluster, Option<DirIndex>>,
+}
+
+impl DirIndexes {
+    /// Forgets every directory's index.
+    pub(crate) fn clear(&mut self) {
+        self.dirs.clear()
+    }
+
+    /// Forgets the index of the directory at `dir_cluster`, e.g. because
+    /// its entries have moved.
+    pub(crate) fn forget(&mut self, dir_cluster: Cluster) {
+        self.dirs.remove(&dir_cluster);
+    }
+}
+
+/// The result of looking a name up in a directory's index.
+pub(crate) enum Probe {
+    /// The directory has no index.
+    Unindexed,
+    /// The entry, which was checked against the directory.
+    Found(RawEntry),
+    /// The name is not in the index, which may be stale.
+    Missing,
+}
+
+/// Parses a sidecar's contents into a map from folded name to slot.
+fn parse(data: &[u8]) -> Option<HashMap<Vec<u16>, u32>> {
+    if data.len() < MAGIC.len() || &data[..MAGIC.len()] != MAGIC {
+        return None;
+    }
+
+    let mut slots = HashMap::new();
+    let mut pos = MAGIC.len();
+    while pos < data.len() {
+        if pos + 2 > data.len() {
+            return None;
+        }
+        let len = LittleEndian::read_u16(&data[pos..]) as usize;
+        pos += 2;
+        if pos + len * 2 + 4 > data.len() {
+            return None;
+        }
+
+        let name = data[pos..pos + len * 2]
+            .chunks(2)
+            .map(LittleEndian::read_u16)
+            .collect();
+        pos += len * 2;
+        slots.insert(name, LittleEndian::read_u32(&data[pos..]));
+        pos += 4;
+    }
+
+    Some(slots)
+}
+
+/// Serializes `slots` into a sidecar's contents, in slot order.
+fn serialize(slots: &HashMap<Vec<u16>, u32>) -> Vec<u8> {
+    let mut records: Vec<_> = slots.iter().collect();
+    records.sort_by_key(|&(_, slot)| *slot);
+
+    let mut data = MAGIC.to_vec();
+    let mut raw = [0; 4];
+    for (name, slot) in records {
+        LittleEndian::write_u16(&mut raw, name.len() as u16);
+        data.extend_from_slice(&raw[..2]);
+        for &unit in name {
+            LittleEndian::write_u16(&mut raw, unit);
+            data.extend_from_slice(&raw[..2]);
+        }
+        LittleEndian::write_u32(&mut raw, *slot);
+        data.extend_from_slice(&raw);
+    }
+    data
+}
+
+/// Loads the index of the directory at `dir_cluster` from its sidecar,
+/// rebuilding it if the sidecar is empty or malformed. Returns `None` if the
+/// directory has no sidecar.
+fn load(vfat: &mut VFat, dir_cluster: Cluster) -> io::Result<Option<DirIndex>> {
+    let index_name = folded(&INDEX_NAME.encode_utf16().collect::<Vec<_>>());
+    let mut entries = RawEntries::read(vfat, dir_cluster)?;
+    let sidecar = loop {
+        match entries.next_entry() {
+            Some(entry) => {
+                if !entry.is_dir() && folded_name(&entry) == index_name {
+                    break entry;
+                }
+            }
+            None => return Ok(None),
+        }
+    };
+
+    let mut data = Vec::new();
+    if sidecar.start_cluster.0 >= 2 {
+        vfat.read_chain_upto(
+            sidecar.start_cluster,
+            sidecar.metadata.size as usize,
+            &mut data,
+        )?;
+    }
+
+    let mut index = DirIndex {
+        location: sidecar.location,
+        start_cluster: sidecar.start_cluster,
+        size: sidecar.metadata.size,
+        slots: HashMap::new(),
+    };
+    match parse(&data) {
+        // An index always holds at least its own sidecar.
+        Some(ref slots) if !slots.is_empty() => index.slots = slots.clone(),
+        _ => rebuild(vfat, dir_cluster, &mut index)?,
+    }
+    Ok(Some(index))
+}
+
+/// Rebuilds `index` from the directory at `dir_cluster`, and writes it to
+/// the sidecar if the sidecar may be changed. Errors writing it are ignored,
+/// as the index in memory serves lookups either way.
+fn rebuild(vfat: &mut VFat, dir_cluster: Cluster, index: &mut DirIndex) -> io::Result<()> {
+    let mut entries = RawEntries::read(vfat, dir_cluster)?;
+    index.slots.clear();
+    while let Some(entry) = entries.next_entry() {
+        index
+            .slots
+            .entry(folded_name(&entry))
+            .or_insert(entry.location.slot);
+    }
+
+    let _ = write_back(vfat, index);
+    Ok(())
+}
+
+/// Writes `index` to its sidecar, unless the sidecar may not be changed.
+fn write_back(vfat: &mut VFat, index: &mut DirIndex) -> io::Result<()> {
+    let data = serialize(&index.slots);
+    vfat.check_modifiable(Some(index.location))?;
+    vfat.check_reserve(index.size as u64, data.len() as u64)?;
+    index.start_cluster = vfat.write_chain(index.start_cluster, &data)?;
+    index.size = data.len() as u32;
+    vfat.set_entry_extent(index.location, index.start_cluster, index.size)
+}
+
+/// Reads the live entry whose short entry is at `slot` of the directory at
+/// `dir_cluster`, if there is one.
+fn entry_at_slot(vfat: &mut VFat, dir_cluster: Cluster, slot: u32) -> io::Result<Option<RawEntry>> {
+    let first = slot.saturating_sub(MAX_LFN_ENTRIES);
+    let start = first as usize * DIR_ENTRY_SIZE;
+    let end = (slot as usize + 1) * DIR_ENTRY_SIZE;
+
+    let source = vfat.dir_source(dir_cluster);
+    let (buf, offset) = match source.read_range(vfat, start..end)? {
+        Some(read) => read,
+        None => return Ok(None),
+    };
+
+    let mut entries =
+        RawEntries::from_slots(vfat, dir_cluster, &buf[start - offset..end - offset], first);
+    while let Some(entry) = entries.next_entry() {
+        if entry.location.slot == slot {
+            return Ok(Some(entry));
+        }
+    }
+    Ok(None)
+}
+
+/// Looks up the entry named `name`, folded, through `index`.
+fn find(
+    vfat: &mut VFat,
+    dir_cluster: Cluster,
+    index: &mut DirIndex,
+    name: &[u16],
+) -> io::Result<Probe> {
+    let slot = match index.slots.get(name) {
+        Some(&slot) => slot,
+        None => return Ok(Probe::Missing),
+    };
+    if let Some(entry) = entry_at_slot(vfat, dir_cluster, slot)? {
+        if folded_name(&entry) == name {
+            return Ok(Probe::Found(entry));
+        }
+    }
+
+    // The entry has moved or gone, so the index is stale.
+    rebuild(vfat, dir_cluster, index)?;
+    let slot = match index.slots.get(name) {
+        Some(&slot) => slot,
+        None => return Ok(Probe::Missing),
+    };
+    Ok(match entry_at_slot(vfat, dir_cluster, slot)? {
+        Some(entry) => Probe::Found(entry),
+        None => Probe::Missing,
+    })
+}
+
+/// Looks up the entry named `name`, in UCS-2, through the index of the
+/// directory at `dir_cluster`, loading the index if needed.
+pub(crate) fn probe(vfat: &mut VFat, dir_cluster: Cluster, name: &[u16]) -> io::Result<Probe> {
+    if !vfat.options().dir_index {
+        return Ok(Probe::Unindexed);
+    }
+
+    let index = match vfat.dir_indexes().dirs.remove(&dir_cluster) {
+        Some(index) => index,
+        None => match load(vfat, dir_cluster) {
+            Ok(index) => index,
+            // Read the directory instead, trying the index again next time.
+            Err(_) => return Ok(Probe::Unindexed),
+        },
+    };
+    let mut index = match index {
+        Some(index) => index,
+        None => {
+            vfat.dir_indexes().dirs.insert(dir_cluster, None);
+            return Ok(Probe::Unindexed);
+        }
+    };
+
+    let found = find(vfat, dir_cluster, &mut index, &folded(name));
+    vfat.dir_indexes().dirs.insert(dir_cluster, Some(index));
+    found
+}
+
+/// Rebuilds the index of the directory at `dir_cluster`, if it has one,
+/// after a name missing from it was found in the directory. If the directory
+/// cannot be read, the index is dropped, to be loaded afresh next time.
+pub(crate) fn refresh(vfat: &mut VFat, dir_cluster: Cluster) {
+    let mut index = match vfat.dir_indexes().dirs.remove(&dir_cluster) {
+        Some(Some(index)) => index,
+        _ => return,
+    };
+
+    if rebuild(vfat, dir_cluster, &mut index).is_ok() {
+        vfat.dir_indexes().dirs.insert(dir_cluster, Some(index));
+    }
+}
@@ -0,0 +1,148 @@
+//! Where the slots of a directory are stored.
+//!
+//! Every FAT32 directory, the root included, is a cluster chain. On FAT12
+//! and FAT16 the root directory is instead a fixed run of sectors between
+//! the FATs and the data region, which cannot grow. Directory code reads and
+//! writes slots through a `DirSource` so that it works on either.
+
+use std::io;
+use std::ops::Range;
+
+use consts::{DIR_ENTRY_SIZE, MAX_DIR_ENTRIES};
+use vfat::{Cluster, VFat};
+
+/// The storage of one directory's slots.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum DirSource {
+    /// A cluster chain beginning at the given cluster.
+    Chain(Cluster),
+    /// A fixed region of `sectors` sectors beginning at `start`.
+    Region { start: u64, sectors: u32 },
+}
+
+impl DirSource {
+    /// Reads the whole directory, appending it to `buf`. Returns the number
+    /// of bytes appended. At most `MAX_DIR_ENTRIES` slots are read, so that
+    /// a chain looping back on itself is not followed forever.
+    pub(crate) fn read(&self, vfat: &mut VFat, buf: &mut Vec<u8>) -> io::Result<usize> {
+        match *self {
+            DirSource::Chain(start) => {
+                vfat.read_chain_upto(start, MAX_DIR_ENTRIES * DIR_ENTRY_SIZE, buf)
+            }
+            DirSource::Region { start, sectors } => {
+                let old_len = buf.len();
+                buf.resize(old_len + sectors as usize * vfat.sector_size(), 0);
+                vfat.read_sectors(start, &mut buf[old_len..])
+            }
+        }
+    }
+
+    /// Reads the bytes of the directory in `range`, rounded out to whole
+    /// clusters or sectors, returning them and the offset of the first byte
+    /// read. Returns `None` if `range` extends past the end of the directory.
+    pub(crate) fn read_range(
+        &self,
+        vfat: &mut VFat,
+        range: Range<usize>,
+    ) -> io::Result<Option<(Vec<u8>, usize)>> {
+        match *self {
+            DirSource::Chain(start) => {
+                let cluster_size = vfat.cluster_size();
+                let clusters =
+                    range.start / cluster_size..(range.end + cluster_size - 1) / cluster_size;
+                let chain = vfat.chain(start)?;
+                if clusters.end > chain.len() {
+                    return Ok(None);
+                }
+
+                let mut buf = vec![0; clusters.len() * cluster_size];
+                for (i, chunk) in clusters.clone().zip(buf.chunks_mut(cluster_size)) {
+                    vfat.read_cluster(chain[i], chunk)?;
+                }
+                Ok(Some((buf, clusters.start * cluster_size)))
+            }
+            DirSource::Region { start, sectors } => {
+                let sector_size = vfat.sector_size();
+                let region = range.start / sector_size..(range.end + sector_size - 1) / sector_size;
+                if region.end > sectors as usize {
+                    return Ok(None);
+                }
+
+                let mut buf = vec![0; region.len() * sector_size];
+                vfat.read_sectors(start + region.start as u64, &mut buf)?;
+                Ok(Some((buf, region.start * sector_size)))
+            }
+        }
+    }
+
+    /// Returns the sector of the volume holding byte `offset` of the
+    /// directory and the byte's offset within that sector.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if `offset` lies past the end of
+    /// the directory.
+    pub(crate) fn position(&self, vfat: &mut VFat, offset: usize) -> io::Result<(u64, usize)> {
+        let sector_size = vfat.sector_size();
+        let sector = match *self {
+            DirSource::Chain(start) => {
+                let cluster_size = vfat.cluster_size();
+                vfat.chain(start)?
+                    .get(offset / cluster_size)
+                    .map(|&cluster| {
+                        vfat.cluster_start_sector(cluster)
+                            + ((offset % cluster_size) / sector_size) as u64
+                    })
+            }
+            DirSource::Region { start, sectors } if offset / sector_size < sectors as usize => {
+                Some(start + (offset / sector_size) as u64)
+            }
+            DirSource::Region { .. } => None,
+        };
+
+        match sector {
+            Some(sector) => Ok((sector, offset % sector_size)),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "directory entry lies past the end of its directory",
+            )),
+        }
+    }
+
+    /// Writes `data` at byte `offset` of the directory. Data reaches the disk
+    /// on `VFat::sync()`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if the write extends past the
+    /// end of the directory.
+    pub(crate) fn write(&self, vfat: &mut VFat, offset: usize, data: &[u8]) -> io::Result<()> {
+        let past_end = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "write extends past the end of the directory",
+            )
+        };
+
+        match *self {
+            DirSource::Chain(start) => {
+                let chain = vfat.chain(start)?;
+                let cluster_size = vfat.cluster_size();
+                let mut written = 0;
+                while written < data.len() {
+                    let position = offset + written;
+                    let cluster = *chain.get(position / cluster_size).ok_or_else(past_end)?;
+                    written +=
+                        vfat.write_cluster(cluster, position % cluster_size, &data[written..])?;
+                }
+                Ok(())
+            }
+            DirSource::Region { start, sectors } => {
+                if offset + data.len() > sectors as usize * vfat.sector_size() {
+                    return Err(past_end());
+                }
+                vfat.write_sectors(start, offset, data)
+            }
+        }
+    }
+}
@@ -1,9 +1,13 @@
 use std::fmt;
 
 use byteorder::{ByteOrder, LittleEndian};
+use consts::BOOT_SIGNATURE;
 use traits::BlockDevice;
 use vfat::Error;
 
+/// The size of the boot code area of the boot sector.
+pub const BOOT_CODE_SIZE: usize = 420;
+
 #[repr(C, packed)]
 pub struct BiosParameterBlock {
     pub assembly_block: [u8; 3],
@@ -33,7 +37,7 @@ pub struct BiosParameterBlock {
     pub volume_id: u32,
     pub volume_label_string: [u8; 11], // TODO: replace with string?
     pub system_id_string: [u8; 8],
-    pub boot_code: [u8; 420],
+    pub boot_code: [u8; BOOT_CODE_SIZE],
     pub bootable_partition_signature: [u8; 2],
 }
 
@@ -50,7 +54,7 @@ impl BiosParameterBlock {
             return Err(Error::Io(err));
         }
 
-        if &sector_bytes[device.sector_size() as usize - 2..] != &[0x55, 0xaa] {
+        if &sector_bytes[device.sector_size() as usize - 2..] != &BOOT_SIGNATURE {
             return Err(Error::BadSignature);
         }
 
@@ -69,8 +73,8 @@ impl BiosParameterBlock {
         let mut system_id_string: [u8; 8] = [0; 8];
         system_id_string.copy_from_slice(&sector_bytes[82..90]);
 
-        let mut boot_code: [u8; 420] = [0; 420];
-        boot_code.copy_from_slice(&sector_bytes[90..510]);
+        let mut boot_code: [u8; BOOT_CODE_SIZE] = [0; BOOT_CODE_SIZE];
+        boot_code.copy_from_slice(&sector_bytes[90..90 + BOOT_CODE_SIZE]);
 
         let mut bootable_partition_signature: [u8; 2] = [0; 2];
         bootable_partition_signature.copy_from_slice(&sector_bytes[510..512]);
@@ -107,22 +111,32 @@ impl BiosParameterBlock {
             bootable_partition_signature,
         })
     }
+
+    /// The total number of logical sectors in the volume.
+    pub fn total_sectors(&self) -> u64 {
+        match self.total_logical_sectors_small {
+            0 => self.total_logical_sectors_large as u64,
+            small => small as u64,
+        }
+    }
 }
 
+// Fields wider than a byte are copied out before formatting: they may be
+// misaligned in the packed struct, so they must not be borrowed in place.
 impl fmt::Debug for BiosParameterBlock {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("BiosParameterBlock")
             .field("oem_id", &self.oem_id)
-            .field("bytes_per_sector", &self.bytes_per_sector)
-            .field("reserved_sectors", &self.reserved_sectors)
-            .field("sectors_per_fat", &self.sectors_per_fat)
-            .field("sectors_per_cluster", &self.sectors_per_cluster)
-            .field("num_fats", &self.num_fats)
-            .field("root_cluster_num", &self.root_cluster_num)
-            .field("drive_num", &self.drive_num)
-            .field("volume_id", &self.volume_id)
-            .field("signature", &self.signature)
-            .field("signature", &self.signature)
+            .field("bytes_per_sector", &{ self.bytes_per_sector })
+            .field("reserved_sectors", &{ self.reserved_sectors })
+            .field("sectors_per_fat", &{ self.sectors_per_fat })
+            .field("sectors_per_cluster", &{ self.sectors_per_cluster })
+            .field("num_fats", &{ self.num_fats })
+            .field("root_cluster_num", &{ self.root_cluster_num })
+            .field("drive_num", &{ self.drive_num })
+            .field("volume_id", &{ self.volume_id })
+            .field("signature", &{ self.signature })
+            .field("signature", &{ self.signature })
             .field(
                 "volume_label_string",
                 &std::str::from_utf8(&self.volume_label_string).unwrap(),
@@ -1,12 +1,168 @@
+use std::convert::TryFrom;
+use std::io;
+
 use traits;
-use vfat::{Dir, File, Metadata};
+use vfat::xattr;
+use vfat::{Cluster, Dir, File, Metadata};
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq, Hash)]
 pub enum Entry {
     File(File),
     Dir(Dir),
 }
 
+/// The kind of an `Entry`, for matching without borrowing the entry.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum EntryKind {
+    File,
+    Dir,
+}
+
+/// The on-disk position of a directory entry.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct EntryLocation {
+    /// The first cluster of the directory containing the entry.
+    pub dir_cluster: Cluster,
+    /// The index of the entry's regular (8.3) slot within that directory.
+    pub slot: u32,
+}
+
+/// The identity of a file or directory: two handles with equal ids refer to
+/// the same entry on the same volume, regardless of name.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct EntryId {
+    pub volume_id: u32,
+    pub start_cluster: Cluster,
+    /// `None` for the root directory, which has no directory entry.
+    pub location: Option<EntryLocation>,
+}
+
+impl Entry {
+    /// Returns whether this entry is a file or a directory.
+    pub fn kind(&self) -> EntryKind {
+        match self {
+            Entry::File(_) => EntryKind::File,
+            Entry::Dir(_) => EntryKind::Dir,
+        }
+    }
+
+    /// Returns the on-disk location of this entry, or `None` for the root
+    /// directory.
+    pub fn location(&self) -> Option<EntryLocation> {
+        match self {
+            Entry::Dir(dir) => dir.location,
+            Entry::File(file) => file.location,
+        }
+    }
+
+    /// Re-reads this entry's metadata from disk.
+    ///
+    /// # Errors
+    ///
+    /// If the entry has since been removed or renamed, a `StaleHandle` error
+    /// of kind `NotFound` is returned.
+    pub fn refresh(&mut self) -> io::Result<()> {
+        match self {
+            Entry::Dir(dir) => dir.refresh_metadata(),
+            Entry::File(file) => file.refresh_metadata(),
+        }
+    }
+
+    /// Returns the value of the extended attribute `name` of this entry, or
+    /// `None` if it is not set. See the `xattr` module for how attributes are
+    /// stored.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error kind of `InvalidInput` for the root directory and of
+    /// `InvalidData` if the directory's sidecar is malformed.
+    pub fn get_xattr(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        xattr::get(self, name)
+    }
+
+    /// Sets the extended attribute `name` of this entry to `value`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error kind of `NotFound` if the entry's directory has no
+    /// sidecar file, and of `InvalidInput` if `name` is empty or longer than
+    /// 255 bytes or `value` is longer than 65535 bytes.
+    pub fn set_xattr(&self, name: &str, value: &[u8]) -> io::Result<()> {
+        xattr::set(self, name, Some(value))
+    }
+
+    /// Removes the extended attribute `name` of this entry, if set.
+    pub fn remove_xattr(&self, name: &str) -> io::Result<()> {
+        xattr::set(self, name, None)
+    }
+
+    /// Returns the identity of the file or directory behind this entry.
+    pub fn id(&self) -> EntryId {
+        match self {
+            Entry::Dir(dir) => dir.id(),
+            Entry::File(file) => file.id(),
+        }
+    }
+}
+
+impl TryFrom<Entry> for File {
+    type Error = Entry;
+
+    /// Converts `entry` into a `File`.
+    ///
+    /// # Errors
+    ///
+    /// If `entry` is a directory, it is returned unchanged.
+    fn try_from(entry: Entry) -> Result<File, Entry> {
+        match entry {
+            Entry::File(file) => Ok(file),
+            entry => Err(entry),
+        }
+    }
+}
+
+impl TryFrom<Entry> for Dir {
+    type Error = Entry;
+
+    /// Converts `entry` into a `Dir`.
+    ///
+    /// # Errors
+    ///
+    /// If `entry` is a file, it is returned unchanged.
+    fn try_from(entry: Entry) -> Result<Dir, Entry> {
+        match entry {
+            Entry::Dir(dir) => Ok(dir),
+            entry => Err(entry),
+        }
+    }
+}
+
+impl io::Read for Entry {
+    /// Reads from the file behind this entry.
+    ///
+    /// # Errors
+    ///
+    /// If the entry is a directory, an error kind of `Other` is returned.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Entry::File(file) => file.read(buf),
+            Entry::Dir(_) => Err(io::Error::new(io::ErrorKind::Other, "is a directory")),
+        }
+    }
+}
+
+impl From<File> for Entry {
+    fn from(file: File) -> Entry {
+        Entry::File(file)
+    }
+}
+
+impl From<Dir> for Entry {
+    fn from(dir: Dir) -> Entry {
+        Entry::Dir(dir)
+    }
+}
+
 impl traits::Entry for Entry {
     type File = File;
     type Dir = Dir;
@@ -0,0 +1,172 @@
+use std::io;
+
+use byteorder::{ByteOrder, LittleEndian};
+use consts::{ATTR_ARCHIVE, ATTR_LFN, DELETED_ENTRY, ESCAPED_DELETED_ENTRY, LAST_LFN_ENTRY};
+use vfat::{lfn_checksum, short_name_for, validate_long_name, ShortNameStrategy};
+use vfat::{Attributes, Cluster, Date, Timestamp};
+
+/// The number of UCS-2 units held by one LFN entry.
+const UNITS_PER_LFN_ENTRY: usize = 13;
+/// The byte offsets of the UCS-2 units within an LFN entry.
+const LFN_UNIT_OFFSETS: [usize; UNITS_PER_LFN_ENTRY] =
+    [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// Encodes a name and its metadata into raw 32-byte directory entries.
+///
+/// For example, `DirEntryBuilder::new("Long Name.txt").cluster(5).build()`
+/// encodes two long file name entries followed by the short entry
+/// `LONGNA~1TXT`.
+///
+/// The entries are returned in on-disk order: the long file name entries, if
+/// the name does not fit in 8.3 as given, followed by the short entry.
+#[derive(Debug, Clone)]
+pub struct DirEntryBuilder {
+    name: String,
+    short_name: Option<[u8; 11]>,
+    cluster: Cluster,
+    size: u32,
+    attributes: Attributes,
+    created: Timestamp,
+    accessed: Date,
+    last_modified: Timestamp,
+}
+
+impl DirEntryBuilder {
+    /// Starts building the entries for `name`: an empty regular file with no
+    /// clusters and zeroed timestamps.
+    pub fn new(name: &str) -> DirEntryBuilder {
+        DirEntryBuilder {
+            name: name.to_string(),
+            short_name: None,
+            cluster: Cluster(0),
+            size: 0,
+            attributes: Attributes(ATTR_ARCHIVE),
+            created: Timestamp::default(),
+            accessed: Date::default(),
+            last_modified: Timestamp::default(),
+        }
+    }
+
+    /// Uses `short_name` as the 11-byte, space-padded 8.3 name instead of
+    /// generating one. Generated aliases always end in `~1`, so this is
+    /// needed when another entry in the directory already has that alias.
+    pub fn short_name(mut self, short_name: [u8; 11]) -> DirEntryBuilder {
+        self.short_name = Some(short_name);
+        self
+    }
+
+    /// Sets the first cluster of the entry's data.
+    pub fn cluster(mut self, cluster: u32) -> DirEntryBuilder {
+        self.cluster = Cluster::from(cluster);
+        self
+    }
+
+    /// Sets the size of the entry's data in bytes.
+    pub fn size(mut self, size: u32) -> DirEntryBuilder {
+        self.size = size;
+        self
+    }
+
+    /// Sets the entry's attributes, e.g. `Attributes(consts::ATTR_DIRECTORY)` for a directory.
+    pub fn attributes(mut self, attributes: Attributes) -> DirEntryBuilder {
+        self.attributes = attributes;
+        self
+    }
+
+    /// Sets the entry's creation time, last access date, and modification
+    /// time.
+    pub fn timestamps(
+        mut self,
+        created: Timestamp,
+        accessed: Date,
+        last_modified: Timestamp,
+    ) -> DirEntryBuilder {
+        self.created = created;
+        self.accessed = accessed;
+        self.last_modified = last_modified;
+        self
+    }
+
+    /// Encodes the entries.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error kind of `InvalidInput` if the name cannot be stored
+    /// on FAT (see `validate_long_name()`).
+    pub fn build(&self) -> io::Result<Vec<[u8; 32]>> {
+        validate_long_name(&self.name, false)?;
+        let short = match self.short_name {
+            Some(short) => short,
+            None => short_name_for(&self.name, ShortNameStrategy::NumericTail, |_| false)?,
+        };
+
+        let mut entries = Vec::new();
+        if display_name(&short) != self.name {
+            entries = lfn_entries(&self.name, lfn_checksum(&short));
+        }
+        entries.push(self.short_entry(&short));
+        Ok(entries)
+    }
+
+    /// Encodes the short entry named `short`.
+    fn short_entry(&self, short: &[u8; 11]) -> [u8; 32] {
+        let mut entry = [0u8; 32];
+        entry[..11].copy_from_slice(short);
+        // A leading 0xE5 would mark the entry deleted; 0x05 stands in for it.
+        if entry[0] == DELETED_ENTRY {
+            entry[0] = ESCAPED_DELETED_ENTRY;
+        }
+        entry[11] = self.attributes.0;
+        LittleEndian::write_u16(&mut entry[14..16], self.created.time.raw());
+        LittleEndian::write_u16(&mut entry[16..18], self.created.date.raw());
+        LittleEndian::write_u16(&mut entry[18..20], self.accessed.raw());
+        LittleEndian::write_u16(&mut entry[20..22], (self.cluster.0 >> 16) as u16);
+        LittleEndian::write_u16(&mut entry[22..24], self.last_modified.time.raw());
+        LittleEndian::write_u16(&mut entry[24..26], self.last_modified.date.raw());
+        LittleEndian::write_u16(&mut entry[26..28], self.cluster.0 as u16);
+        LittleEndian::write_u32(&mut entry[28..32], self.size);
+        entry
+    }
+}
+
+/// Returns the name an 11-byte short name is displayed as, e.g. `A.TXT`.
+fn display_name(short: &[u8; 11]) -> String {
+    let base = String::from_utf8_lossy(&short[..8])
+        .trim_right()
+        .to_string();
+    let ext = String::from_utf8_lossy(&short[8..])
+        .trim_right()
+        .to_string();
+    if ext.is_empty() {
+        base
+    } else {
+        format!("{}.{}", base, ext)
+    }
+}
+
+/// Encodes the LFN entries for `name` in on-disk order, last part first.
+fn lfn_entries(name: &str, checksum: u8) -> Vec<[u8; 32]> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    let count = (units.len() + UNITS_PER_LFN_ENTRY - 1) / UNITS_PER_LFN_ENTRY;
+    // The name is NUL-terminated if it does not fill its last entry, and the
+    // rest of the entry is padded with 0xFFFF.
+    if units.len() < count * UNITS_PER_LFN_ENTRY {
+        units.push(0);
+    }
+    units.resize(count * UNITS_PER_LFN_ENTRY, 0xFFFF);
+
+    (0..count)
+        .rev()
+        .map(|i| {
+            let mut entry = [0u8; 32];
+            entry[0] = (i + 1) as u8 | if i + 1 == count { LAST_LFN_ENTRY } else { 0 };
+            entry[11] = ATTR_LFN;
+            entry[13] = checksum;
+            let part = &units[i * UNITS_PER_LFN_ENTRY..(i + 1) * UNITS_PER_LFN_ENTRY];
+            for (&offset, &unit) in LFN_UNIT_OFFSETS.iter().zip(part) {
+                LittleEndian::write_u16(&mut entry[offset..offset + 2], unit);
+            }
+            entry
+        })
+        .collect()
+}
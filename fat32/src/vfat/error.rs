@@ -1,13 +1,76 @@
-use std::io;
+use std::{error, fmt, io};
 
 use mbr;
+use vfat::{Cluster, EntryLocation};
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
     Mbr(mbr::Error),
     Io(io::Error),
     BadSignature,
     NotFound,
+    /// The entry behind a handle was removed or renamed on disk, or moved to
+    /// another slot, as by `Dir::compact()`.
+    StaleHandle,
+    /// The medium was removed or replaced after the handle was opened.
+    MediaChanged,
+    /// The device passed to `VFat::remount()` holds another volume than the
+    /// one mounted, for `reason`.
+    VolumeMismatch {
+        reason: &'static str,
+    },
+    /// The BPB declares more sectors than `source` (the partition entry or
+    /// the device) holds. Sector counts are in logical sectors.
+    GeometryMismatch {
+        source: &'static str,
+        expected: u64,
+        actual: u64,
+    },
+    /// The media descriptor in the first FAT entry, `fat`, differs from the
+    /// one the BPB declares, `bpb`.
+    MediaMismatch {
+        bpb: u8,
+        fat: u8,
+    },
+    /// A file's contents do not match its hash tree: cluster `cluster` of the
+    /// file failed verification, or, if `None`, the tree itself does not
+    /// match the file or the trusted root digest.
+    Integrity {
+        cluster: Option<u64>,
+    },
+    /// The directory entry at `location` is malformed. For a long file name
+    /// run, `location` is its first slot.
+    CorruptEntry {
+        location: EntryLocation,
+        reason: &'static str,
+    },
+    /// The cluster chain of the file beginning at `start` cannot hold the
+    /// file's data: the chain's entry for `cluster` is wrong for `reason`.
+    CorruptChain {
+        start: Cluster,
+        cluster: Cluster,
+        reason: &'static str,
+    },
+    /// A write would grow a file past `limit` bytes. See
+    /// `MountOptions::max_file_size`.
+    FileTooLarge {
+        limit: u32,
+    },
+    /// Cluster `cluster` read back from the device with contents other than
+    /// those it was first read with, though nothing was written to it; see
+    /// `MountOptions::cluster_checksums`. If it was read for a file, `file`
+    /// holds the first cluster of the file and the offset of the cluster's
+    /// data in the file.
+    BitRotDetected {
+        cluster: Cluster,
+        file: Option<(Cluster, u64)>,
+    },
+    /// The file at `path` is not as an update manifest expects. See
+    /// `apply_manifest()`.
+    ManifestMismatch {
+        path: String,
+    },
 }
 
 impl From<mbr::Error> for Error {
@@ -21,3 +84,95 @@ impl From<io::Error> for Error {
         Error::Io(error)
     }
 }
+
+impl From<Error> for io::Error {
+    fn from(error: Error) -> io::Error {
+        match error {
+            Error::Io(error) => error,
+            e @ Error::NotFound | e @ Error::StaleHandle | e @ Error::MediaChanged => {
+                io::Error::new(io::ErrorKind::NotFound, e)
+            }
+            e @ Error::FileTooLarge { .. } => io::Error::new(io::ErrorKind::InvalidInput, e),
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Mbr(error) => write!(f, "invalid master boot record: {}", error),
+            Error::Io(error) => write!(f, "I/O error: {}", error),
+            Error::BadSignature => write!(f, "invalid EBPB signature"),
+            Error::NotFound => write!(f, "no FAT32 partition found"),
+            Error::StaleHandle => write!(f, "stale handle: entry was removed, renamed, or moved"),
+            Error::MediaChanged => write!(f, "stale handle: the medium was changed"),
+            Error::VolumeMismatch { reason } => {
+                write!(f, "device holds another volume: {}", reason)
+            }
+            Error::GeometryMismatch {
+                source,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "geometry mismatch: BPB declares {} sectors but the {} holds {}",
+                expected, source, actual
+            ),
+            Error::MediaMismatch { bpb, fat } => write!(
+                f,
+                "media descriptor mismatch: BPB declares {:#04x} but the FAT records {:#04x}",
+                bpb, fat
+            ),
+            Error::Integrity {
+                cluster: Some(cluster),
+            } => write!(
+                f,
+                "integrity check failed for cluster {} of the file",
+                cluster
+            ),
+            Error::CorruptEntry { location, reason } => write!(
+                f,
+                "corrupt entry at slot {} of directory cluster {}: {}",
+                location.slot, location.dir_cluster.0, reason
+            ),
+            Error::CorruptChain {
+                start,
+                cluster,
+                reason,
+            } => write!(
+                f,
+                "corrupt chain starting at cluster {}: {} at cluster {}",
+                start.0, reason, cluster.0
+            ),
+            Error::FileTooLarge { limit } => {
+                write!(f, "file would grow past the limit of {} bytes", limit)
+            }
+            Error::BitRotDetected {
+                cluster,
+                file: Some((start, offset)),
+            } => write!(
+                f,
+                "cluster {}, at offset {} of the file at cluster {}, changed on the device",
+                cluster.0, offset, start.0
+            ),
+            Error::BitRotDetected {
+                cluster,
+                file: None,
+            } => write!(f, "cluster {} changed on the device", cluster.0),
+            Error::ManifestMismatch { path } => write!(
+                f,
+                "{} does not hold the contents the manifest expects",
+                path
+            ),
+            Error::Integrity { cluster: None } => {
+                write!(
+                    f,
+                    "integrity check failed: hash tree does not match the file"
+                )
+            }
+        }
+    }
+}
+
+impl error::Error for Error {}
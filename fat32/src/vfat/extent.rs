@@ -0,0 +1,58 @@
+use vfat::Cluster;
+
+/// A run of `len` consecutive clusters beginning at `start`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Extent {
+    pub start: Cluster,
+    pub len: u32,
+}
+
+impl Extent {
+    pub fn new(start: Cluster, len: u32) -> Extent {
+        Extent { start, len }
+    }
+
+    /// The cluster one past the end of the run.
+    pub fn end(&self) -> Cluster {
+        Cluster(self.start.0 + self.len)
+    }
+
+    /// Returns `true` if `cluster` is part of this run.
+    pub fn contains(&self, cluster: Cluster) -> bool {
+        cluster >= self.start && cluster < self.end()
+    }
+}
+
+/// The free space of a volume, as returned by `VFat::free_extents()`.
+#[derive(Debug, Clone, Default)]
+pub struct FreeSpace {
+    /// Every run of free clusters, in ascending cluster order.
+    pub extents: Vec<Extent>,
+    /// `histogram[i]` is the number of free runs whose length is in
+    /// `[2^i, 2^(i + 1))`.
+    pub histogram: [u32; 32],
+}
+
+impl FreeSpace {
+    pub(crate) fn push(&mut self, extent: Extent) {
+        let bucket = 31 - extent.len.leading_zeros();
+        self.histogram[bucket as usize] += 1;
+        self.extents.push(extent);
+    }
+
+    /// The total number of free clusters.
+    pub fn free_clusters(&self) -> u64 {
+        self.extents.iter().map(|e| e.len as u64).sum()
+    }
+
+    /// The longest run of free clusters, if any cluster is free.
+    pub fn largest(&self) -> Option<Extent> {
+        self.extents.iter().cloned().max_by_key(|e| e.len)
+    }
+
+    /// Returns `true` if a contiguous run of at least `clusters` clusters is
+    /// free.
+    pub fn fits_contiguous(&self, clusters: u32) -> bool {
+        self.extents.iter().any(|e| e.len >= clusters)
+    }
+}
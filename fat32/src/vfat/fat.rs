@@ -1,7 +1,14 @@
 use std::fmt;
+use std::io;
+
+use consts::{
+    BAD_CLUSTER, CLEAN_SHUTDOWN, FAT_ENTRY_MASK, MAX_DATA_CLUSTER, NO_HARD_ERROR, RESERVED_MAX,
+    RESERVED_MIN,
+};
 use vfat::*;
 
-#[derive(Debug, PartialEq)]
+/// The classification of a raw FAT entry.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Status {
     /// The FAT entry corresponds to an unused (free) cluster.
     Free,
@@ -17,28 +24,175 @@ pub enum Status {
     Eoc(u32),
 }
 
+impl Status {
+    /// Returns the raw FAT entry value that encodes `self`. `Reserved` is
+    /// encoded as the first value of the reserved range.
+    pub fn raw(&self) -> u32 {
+        match *self {
+            Status::Free => 0,
+            Status::Reserved => RESERVED_MIN,
+            Status::Data(cluster) => cluster.0,
+            Status::Bad => BAD_CLUSTER,
+            Status::Eoc(value) => value,
+        }
+    }
+}
+
+/// How strictly FAT entry values are checked while following chains.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FatMode {
+    /// Ignore the reserved high nibble of entries and accept any end-of-chain
+    /// marker, as most implementations do.
+    Tolerant,
+    /// Treat nonconforming entries (see `FatEntry::is_conforming()`) and links
+    /// to clusters outside the volume as corruption.
+    Strict,
+}
+
+impl Default for FatMode {
+    fn default() -> FatMode {
+        FatMode::Tolerant
+    }
+}
+
+/// A single raw 32-bit entry of the file allocation table.
 #[repr(C, packed)]
+#[derive(Copy, Clone, PartialEq, Eq)]
 pub struct FatEntry(pub u32);
 
 impl FatEntry {
+    /// Returns the raw value of the entry, including the reserved high
+    /// nibble.
+    pub fn raw(&self) -> u32 {
+        self.0
+    }
+
+    /// Returns `true` if the entry marks its cluster as free.
+    pub fn is_free(&self) -> bool {
+        self.status() == Status::Free
+    }
+
+    /// Returns `true` if the entry marks its cluster as bad.
+    pub fn is_bad(&self) -> bool {
+        self.status() == Status::Bad
+    }
+
+    /// Returns `true` if the entry marks its cluster as the last in a chain.
+    pub fn is_eoc(&self) -> bool {
+        match self.status() {
+            Status::Eoc(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns the next cluster in the chain if this entry links to one.
+    pub fn next(&self) -> Option<Cluster> {
+        match self.status() {
+            Status::Data(cluster) => Some(cluster),
+            _ => None,
+        }
+    }
+
     /// Returns the `Status` of the FAT entry `self`.
+    ///
+    /// Per the specification, the high 4 bits of the entry are reserved and
+    /// ignored. Any value in `EOC_MIN..=EOC_MARKER` marks the end of a
+    /// chain.
     pub fn status(&self) -> Status {
-        match self.0 & 0x0fffffff {
+        match self.0 & FAT_ENTRY_MASK {
             0 => Status::Free,
             0x1 => Status::Reserved,
-            0x2...0x0FFFFFEF => Status::Data(Cluster::from(self.0)),
-            0xFFFFFF0...0xFFFFFF6 => Status::Reserved,
-            0xFFFFFF7 => Status::Bad,
-            _ => Status::Eoc(self.0),
+            0x2...MAX_DATA_CLUSTER => Status::Data(Cluster::from(self.0)),
+            RESERVED_MIN...RESERVED_MAX => Status::Reserved,
+            BAD_CLUSTER => Status::Bad,
+            eoc => Status::Eoc(eoc),
         }
     }
+
+    /// Returns `true` if this entry is one a conforming formatter would
+    /// write: the reserved high nibble is clear and the value is not one of
+    /// the reserved values.
+    pub fn is_conforming(&self) -> bool {
+        self.0 & !FAT_ENTRY_MASK == 0 && self.status() != Status::Reserved
+    }
 }
 
 impl fmt::Debug for FatEntry {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("FatEntry")
-            .field("value", &self.0)
+            .field("value", &{ self.0 })
             .field("status", &self.status())
             .finish()
     }
 }
+
+impl fmt::Display for FatEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.status() {
+            Status::Free => write!(f, "free"),
+            Status::Reserved => write!(f, "reserved ({:#010x})", self.raw()),
+            Status::Data(cluster) => write!(f, "next {}", cluster.0),
+            Status::Bad => write!(f, "bad"),
+            Status::Eoc(_) => write!(f, "end of chain"),
+        }
+    }
+}
+
+/// The two reserved entries at the start of the FAT, which describe the
+/// volume rather than clusters. Returned by `VFat::fat_header()`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FatHeader {
+    /// The raw first entry: the media descriptor in the low byte, every
+    /// other bit of the low 28 set.
+    pub media_entry: u32,
+    /// The raw second entry: an end-of-chain marker whose high bits record
+    /// whether the volume was cleanly unmounted and whether disk errors
+    /// occurred.
+    pub state_entry: u32,
+}
+
+impl FatHeader {
+    /// The media descriptor recorded in the FAT, which should equal the
+    /// BPB's.
+    pub fn media_descriptor(&self) -> u8 {
+        self.media_entry as u8
+    }
+
+    /// Returns `true` if the volume was cleanly unmounted, or has been synced
+    /// since it was last changed; see `MountOptions::mark_dirty`.
+    pub fn clean_shutdown(&self) -> bool {
+        self.state_entry & CLEAN_SHUTDOWN != 0
+    }
+
+    /// Returns `true` if a disk I/O error has been recorded on the volume.
+    pub fn hard_error(&self) -> bool {
+        self.state_entry & NO_HARD_ERROR == 0
+    }
+}
+
+/// An iterator over every entry of the FAT, including the two reserved
+/// entries at the start of the table. Created by `VFat::fat_entries()`.
+pub struct FatEntries<'a> {
+    vfat: &'a mut VFat,
+    next: u32,
+}
+
+impl<'a> FatEntries<'a> {
+    pub(crate) fn new(vfat: &'a mut VFat) -> FatEntries<'a> {
+        FatEntries { vfat, next: 0 }
+    }
+}
+
+impl<'a> Iterator for FatEntries<'a> {
+    type Item = io::Result<(Cluster, FatEntry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next >= self.vfat.num_clusters() + 2 {
+            return None;
+        }
+
+        let cluster = Cluster(self.next);
+        self.next += 1;
+        Some(self.vfat.fat_entry(cluster).map(|entry| (cluster, entry)))
+    }
+}
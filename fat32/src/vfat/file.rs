@@ -1,41 +1,731 @@
 use std::cmp::min;
+use std::hash::{Hash, Hasher};
 use std::io::{self, SeekFrom};
 
 use traits;
-use vfat::{Cluster, Metadata, Shared, VFat};
+use vfat::dir::{entry_at, parent_dir};
+use vfat::lock::would_block;
+use vfat::vfat::notify_at;
+use vfat::{
+    Cluster, Digest, Entry, EntryId, EntryLocation, Error, Event, HashTree, Metadata, Shared, VFat,
+};
+use vfat::{FileLock, LockKind, HASH_TREE_SUFFIX};
+
+/// The number of clusters read at once for `Advice::Sequential`.
+const SEQUENTIAL_READ_AHEAD: usize = 8;
+
+/// How a file is expected to be read, set with `File::advise()`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Advice {
+    /// Load the whole file on the first read and keep it loaded.
+    Normal,
+    /// Read ahead several clusters at a time and drop clusters from the
+    /// sector cache once they have been read past.
+    Sequential,
+    /// Read only the clusters a read covers, with no read-ahead.
+    Random,
+}
+
+impl Default for Advice {
+    fn default() -> Advice {
+        Advice::Normal
+    }
+}
+
+/// How a file is read whose entry records a nonzero size but no first
+/// cluster, as some tools leave behind when interrupted between writing an
+/// entry and allocating its data. Entries recording a first cluster but a
+/// size of zero always read as empty, without their chain being walked.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ClusterlessFile {
+    /// The file reads as empty. Its recorded size is still reported.
+    Empty,
+    /// Reads of the file fail with an error of kind `InvalidData`.
+    Fail,
+}
+
+impl Default for ClusterlessFile {
+    fn default() -> ClusterlessFile {
+        ClusterlessFile::Empty
+    }
+}
 
 #[derive(Debug)]
 pub struct File {
-    pub metadata: Metadata,
-    pub start_cluster: Cluster,
-    pub vfat: Shared<VFat>,
-    pub offset: u32,
-    data: Option<Vec<u8>>,
+    pub(crate) metadata: Metadata,
+    pub(crate) start_cluster: Cluster,
+    pub(crate) vfat: Shared<VFat>,
+    pub(crate) offset: u32,
+    pub(crate) location: Option<EntryLocation>,
+    /// The layout version of the entry's directory when the entry was read.
+    /// The entry is only updated while the directory is still at it.
+    layout: u64,
+    advice: Advice,
+    /// The clusters of the file, once needed by a non-`Normal` read.
+    chain: Option<Vec<Cluster>>,
+    /// Loaded file data beginning at byte `data_start` of the file. Data
+    /// loaded whole is shared with the other handles of the file.
+    data: Option<Shared<Vec<u8>>>,
+    data_start: u32,
+    /// The hash tree loaded data is verified against, if integrity checking
+    /// is enabled.
+    integrity: Option<HashTree>,
+    /// Appended bytes not yet written to the volume.
+    appended: Vec<u8>,
+    /// Whether appends are gathered in `appended` until a cluster's worth
+    /// is ready. See `set_append_buffering()`.
+    buffer_appends: bool,
+    /// The media generation the handle was opened under.
+    pub(crate) generation: u64,
 }
 
 impl File {
-    pub fn new(metadata: Metadata, start_cluster: Cluster, vfat: Shared<VFat>) -> File {
+    /// Creates a handle to the file whose entry is at `location`, read under
+    /// layout version `layout` of its directory, of media generation
+    /// `generation`. Does not borrow `vfat`.
+    pub(crate) fn with_generation(
+        metadata: Metadata,
+        start_cluster: Cluster,
+        vfat: Shared<VFat>,
+        location: Option<EntryLocation>,
+        layout: u64,
+        generation: u64,
+    ) -> File {
         File {
             metadata,
             start_cluster,
             vfat,
             offset: 0u32,
+            location,
+            layout,
+            advice: Advice::default(),
+            chain: None,
             data: None,
+            data_start: 0,
+            integrity: None,
+            appended: Vec::new(),
+            buffer_appends: true,
+            generation,
+        }
+    }
+
+    /// Returns a new handle to the same file.
+    ///
+    /// The new handle starts at the current offset of `self` but seeks
+    /// independently of it. Data loaded whole is shared between the handles,
+    /// as for any two handles of the same file. Appends buffered by `self`
+    /// are not seen by the new handle until they are flushed.
+    pub fn try_clone(&self) -> io::Result<File> {
+        Ok(File {
+            metadata: self.metadata.clone(),
+            start_cluster: self.start_cluster,
+            vfat: self.vfat.clone(),
+            offset: self.offset,
+            location: self.location,
+            layout: self.layout,
+            advice: self.advice,
+            chain: None,
+            data: None,
+            data_start: 0,
+            integrity: self.integrity.clone(),
+            appended: Vec::new(),
+            buffer_appends: self.buffer_appends,
+            generation: self.generation,
+        })
+    }
+
+    /// Re-reads this file's entry from disk, updating `self.metadata`. If the
+    /// file's size changed, any loaded data is discarded and the offset is
+    /// clamped to the new size.
+    ///
+    /// # Errors
+    ///
+    /// If the entry has since been removed or renamed, a `StaleHandle` error
+    /// of kind `NotFound` is returned, and if the medium has since changed,
+    /// a `MediaChanged` error of kind `NotFound`.
+    pub fn refresh_metadata(&mut self) -> io::Result<()> {
+        self.flush_appends()?;
+        let location = match self.location {
+            Some(location) => location,
+            None => return self.vfat.borrow_mut().check_generation(self.generation),
+        };
+
+        let (metadata, layout) = match entry_at(&self.vfat, location, self.generation)? {
+            Some(Entry::File(ref file))
+                if file.start_cluster == self.start_cluster
+                    && file.metadata.name == self.metadata.name =>
+            {
+                (file.metadata.clone(), file.layout)
+            }
+            _ => return Err(Error::StaleHandle.into()),
+        };
+
+        if metadata.size != self.metadata.size {
+            self.data = None;
+            self.chain = None;
+            self.offset = min(self.offset, metadata.size);
+        }
+
+        self.metadata = metadata;
+        self.layout = layout;
+        Ok(())
+    }
+
+    /// The metadata of this file, as of opening it or the last
+    /// `refresh_metadata()`.
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// The first cluster of this file, or `Cluster(0)` if it is empty.
+    pub fn start_cluster(&self) -> Cluster {
+        self.start_cluster
+    }
+
+    /// The location of this file's directory entry.
+    pub fn location(&self) -> Option<EntryLocation> {
+        self.location
+    }
+
+    /// The number of bytes of this file held in clusters: its size, unless
+    /// it has no first cluster, in which case `0` or an error according to
+    /// the mount's `MountOptions::clusterless_files`.
+    pub(crate) fn data_len(&self) -> io::Result<u32> {
+        if self.start_cluster.0 >= 2 || self.metadata.size == 0 {
+            return Ok(self.metadata.size);
+        }
+
+        match self.vfat.borrow().options().clusterless_files {
+            ClusterlessFile::Empty => Ok(0),
+            ClusterlessFile::Fail => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "file has a size but no clusters",
+            )),
+        }
+    }
+
+    /// Reads the whole file into a vector allocated to its size, without
+    /// moving this handle's offset. See `read_into()`.
+    pub fn read_to_vec(&self) -> io::Result<Vec<u8>> {
+        let mut data = vec![0; self.data_len()? as usize];
+        let read = self.read_into(0, &mut data)?;
+        data.truncate(read);
+        Ok(data)
+    }
+
+    /// Reads the bytes of the file beginning at byte `offset` into `buf`, as
+    /// `pread()` does, without moving this handle's offset. Returns the
+    /// number of bytes read, which is less than `buf.len()` only at the end
+    /// of the file.
+    ///
+    /// Since it takes `&self`, one handle may be read from by several
+    /// threads at once, as for disjoint ranges of the file; the reads are
+    /// serialized by the volume's lock. Appends buffered by this handle are
+    /// not seen until they are flushed.
+    pub fn read_into(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        if offset >= self.data_len()? as u64 {
+            return Ok(0);
+        }
+
+        let mut handle = self.try_clone()?;
+        handle.offset = offset as u32;
+        let mut read = 0;
+        while read < buf.len() {
+            match io::Read::read(&mut handle, &mut buf[read..])? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        Ok(read)
+    }
+
+    /// Writes `buf` into the file beginning at byte `offset`, as `pwrite()`
+    /// does, without moving this handle's offset. Bytes within the file are
+    /// overwritten in place; only the sectors partially covered by the write
+    /// are read first. Bytes past the end of the file are appended to it.
+    /// Buffered appends are written out first. Changes reach the disk on the
+    /// next `VFat::sync()`.
+    ///
+    /// Other handles of the file that have already loaded its data do not
+    /// see the write until they reopen the file.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidInput` if `offset` is past the end of
+    /// the file, since files with holes are not supported, and the errors of
+    /// `Write::write()` otherwise. Nothing is written on error, unless
+    /// writing to the volume itself fails.
+    pub fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<usize> {
+        self.flush_appends()?;
+        let size = self.metadata.size as u64;
+        if offset > size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "writes past the end of the file are not supported",
+            ));
+        }
+        self.check_size(offset + buf.len() as u64)?;
+        let location = match self.location {
+            Some(location) => location,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "file has no directory entry",
+                ))
+            }
+        };
+
+        let in_place = min(buf.len() as u64, size - offset) as usize;
+        {
+            let mut vfat = self.vfat.borrow_mut();
+            vfat.check_generation(self.generation)?;
+            vfat.check_layout(location.dir_cluster, self.layout)?;
+            vfat.check_modifiable(Some(location))?;
+            vfat.check_reserve(size, offset + buf.len() as u64)?;
+            if in_place > 0 {
+                let chain = vfat.chain(self.start_cluster)?;
+                let cluster_size = vfat.cluster_size();
+                if (chain.len() as u64) * (cluster_size as u64) < size {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "cluster chain is shorter than the file",
+                    ));
+                }
+
+                // Allocate the clusters of the part appended before writing
+                // in place, so that a full volume fails the write unchanged.
+                let end = offset + buf.len() as u64;
+                let needed = ((end + cluster_size as u64 - 1) / cluster_size as u64) as usize;
+                if chain.len() < needed {
+                    vfat.allocate((needed - chain.len()) as u32, chain.last().cloned())?;
+                    self.chain = None;
+                }
+
+                let mut position = offset as usize;
+                let mut written = 0;
+                while written < in_place {
+                    let cluster = chain[position / cluster_size];
+                    let chunk = &buf[written..in_place];
+                    let n = vfat.write_cluster(cluster, position % cluster_size, chunk)?;
+                    position += n;
+                    written += n;
+                }
+            }
+        }
+        self.data = None;
+
+        if in_place < buf.len() {
+            self.appended.extend_from_slice(&buf[in_place..]);
+            if let Err(e) = self.flush_appends() {
+                self.appended.clear();
+                return Err(e);
+            }
+        } else if in_place > 0 {
+            notify_at(&self.vfat, location, Event::Modify);
+        }
+        Ok(buf.len())
+    }
+
+    /// Returns the identity of this file. See `EntryId`.
+    pub fn id(&self) -> EntryId {
+        EntryId {
+            volume_id: self.vfat.borrow().volume_id(),
+            start_cluster: self.start_cluster,
+            location: self.location,
+        }
+    }
+
+    /// Declares how this handle will be read, tuning read-ahead and caching.
+    /// Any data loaded under the previous advice is dropped.
+    pub fn advise(&mut self, advice: Advice) {
+        if advice != self.advice {
+            self.advice = advice;
+            self.data = None;
+        }
+    }
+
+    /// Takes an advisory lock of `kind` on the whole file. See
+    /// `try_lock_range()`.
+    pub fn try_lock(&self, kind: LockKind) -> io::Result<FileLock> {
+        self.try_lock_range(0, ::std::u64::MAX, kind)
+    }
+
+    /// Takes an advisory lock of `kind` on the `len` bytes of the file
+    /// beginning at byte `start`, which may extend past the end of the file.
+    /// The lock is held until the returned `FileLock` is dropped.
+    ///
+    /// Locks are shared by every handle of the volume: a lock conflicts with
+    /// any overlapping lock on the same file unless both are
+    /// `LockKind::Shared`, even if both were taken through one handle.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `WouldBlock` if the lock conflicts with one
+    /// already held. Nothing waits for a lock to be released: a caller that
+    /// needs the lock retries once the conflicting `FileLock` is dropped.
+    ///
+    /// Locks follow their files' entries when `Dir::compact()` moves them,
+    /// and handles opened before the move fail with `Error::StaleHandle`.
+    pub fn try_lock_range(&self, start: u64, len: u64, kind: LockKind) -> io::Result<FileLock> {
+        let location = match self.location {
+            Some(location) => location,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "file has no directory entry",
+                ))
+            }
+        };
+
+        let end = start.saturating_add(len);
+        let mut vfat = self.vfat.borrow_mut();
+        vfat.check_generation(self.generation)?;
+        vfat.check_layout(location.dir_cluster, self.layout)?;
+        match vfat.try_lock(location, start, end, kind) {
+            Some(id) => Ok(FileLock {
+                vfat: self.vfat.clone(),
+                id,
+                kind,
+            }),
+            None => Err(would_block()),
         }
     }
 
+    /// Enables integrity checking: from now on, every cluster read through
+    /// this handle is verified against the file's hash tree sidecar (see
+    /// `HashTree`), and reads of clusters that fail verification return an
+    /// `Error::Integrity` of kind `InvalidData`.
+    ///
+    /// If `trusted_root` is given, the sidecar's root digest must match it,
+    /// which also detects tampering with the sidecar.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `NotFound` if the file has no sidecar, and an
+    /// `Error::Integrity` of kind `InvalidData` if the sidecar does not
+    /// describe the file or does not match `trusted_root`.
+    pub fn enable_integrity(&mut self, trusted_root: Option<&Digest>) -> io::Result<()> {
+        let location = match self.location {
+            Some(location) => location,
+            None => return Err(Error::NotFound.into()),
+        };
+
+        let name = format!("{}{}", self.metadata.name, HASH_TREE_SUFFIX);
+        let mut sidecar = match parent_dir(&self.vfat, location, self.generation).find(name)? {
+            Entry::File(file) => file,
+            Entry::Dir(_) => return Err(Error::Integrity { cluster: None }.into()),
+        };
+        let mut bytes = Vec::new();
+        io::Read::read_to_end(&mut sidecar, &mut bytes)?;
+
+        let tree = HashTree::from_bytes(&bytes)?;
+        let cluster_size = self.vfat.borrow().cluster_size();
+        tree.check_shape(cluster_size, self.metadata.size as u64)?;
+        if trusted_root.map_or(false, |root| *root != tree.root()) {
+            return Err(Error::Integrity { cluster: None }.into());
+        }
+
+        self.integrity = Some(tree);
+        self.data = None;
+        Ok(())
+    }
+
+    /// Verifies the loaded data against the hash tree, if integrity checking
+    /// is enabled. The data is dropped if verification fails.
+    fn verify_loaded(&mut self) -> io::Result<()> {
+        let result = match (self.integrity.as_ref(), self.data.as_ref()) {
+            (Some(tree), Some(data)) => {
+                let cluster_size = self.vfat.borrow().cluster_size();
+                let first = self.data_start as usize / cluster_size;
+                let data = data.borrow();
+                data.chunks(cluster_size)
+                    .enumerate()
+                    .map(|(i, cluster)| tree.verify(first + i, cluster))
+                    .collect::<Result<(), Error>>()
+            }
+            _ => Ok(()),
+        };
+
+        if let Err(e) = result {
+            self.data = None;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// Sets whether small appends are buffered. While buffering, which is
+    /// the default, appended bytes are held by the handle until a cluster's
+    /// worth has gathered, and are written together on `flush()`, `sync()`,
+    /// any read or seek, or when the handle is dropped. Turn buffering off
+    /// for files whose every append must reach the volume's cache at once;
+    /// doing so writes out any bytes already buffered.
+    pub fn set_append_buffering(&mut self, enabled: bool) -> io::Result<()> {
+        self.buffer_appends = enabled;
+        if !enabled {
+            self.flush_appends()?;
+        }
+        Ok(())
+    }
+
+    /// Returns `Error::FileTooLarge` if `size` exceeds the mount's
+    /// `MountOptions::max_file_size`.
+    fn check_size(&self, size: u64) -> io::Result<()> {
+        let limit = self.vfat.borrow().options().max_file_size;
+        if size > limit as u64 {
+            return Err(Error::FileTooLarge { limit }.into());
+        }
+        Ok(())
+    }
+
+    /// Writes the buffered appends to the end of the file, extending its
+    /// chain as needed, and updates the directory entry to the new size.
+    /// Changes reach the disk on the next `VFat::sync()`.
+    fn flush_appends(&mut self) -> io::Result<()> {
+        if self.appended.is_empty() {
+            return Ok(());
+        }
+
+        let location = match self.location {
+            Some(location) => location,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "file has no directory entry",
+                ))
+            }
+        };
+        if self.start_cluster.0 < 2 && self.metadata.size > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "file has a size but no clusters",
+            ));
+        }
+
+        let size = self.metadata.size + self.appended.len() as u32;
+        {
+            let mut vfat = self.vfat.borrow_mut();
+            vfat.check_generation(self.generation)?;
+            vfat.check_layout(location.dir_cluster, self.layout)?;
+            vfat.check_reserve(self.metadata.size as u64, size as u64)?;
+            self.start_cluster =
+                vfat.append_chain(self.start_cluster, self.metadata.size, &self.appended)?;
+            vfat.set_entry_extent(location, self.start_cluster, size)?;
+            vfat.dir_entries_changed(location.dir_cluster, false)?;
+        }
+
+        self.appended.clear();
+        self.metadata.size = size;
+        self.chain = None;
+        self.data = None;
+        notify_at(&self.vfat, location, Event::Modify);
+        Ok(())
+    }
+
+    /// Replaces the contents of this file with `data`, reusing the file's
+    /// clusters where possible, and rewinds the handle. The directory entry
+    /// is updated to match; changes reach the disk on the next `VFat::sync()`.
+    /// Buffered appends are discarded.
+    pub(crate) fn replace_contents(&mut self, data: &[u8]) -> io::Result<()> {
+        self.appended.clear();
+        self.check_size(data.len() as u64)?;
+        let location = match self.location {
+            Some(location) => location,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "file has no directory entry",
+                ))
+            }
+        };
+
+        {
+            let mut vfat = self.vfat.borrow_mut();
+            vfat.check_generation(self.generation)?;
+            vfat.check_layout(location.dir_cluster, self.layout)?;
+            vfat.check_modifiable(Some(location))?;
+            vfat.check_reserve(self.metadata.size as u64, data.len() as u64)?;
+            self.start_cluster = vfat.write_chain(self.start_cluster, data)?;
+            vfat.set_entry_extent(location, self.start_cluster, data.len() as u32)?;
+            vfat.dir_entries_changed(location.dir_cluster, false)?;
+        }
+
+        self.metadata.size = data.len() as u32;
+        self.offset = 0;
+        self.chain = None;
+        self.data = None;
+        notify_at(&self.vfat, location, Event::Modify);
+        Ok(())
+    }
+
+    /// Like `replace_contents()`, but with the `size` bytes of new contents
+    /// read from `reader` a `buf` at a time, so that at most `buf.len()`
+    /// bytes of the contents are held in memory. `buf` must hold a whole
+    /// number of clusters.
+    pub(crate) fn replace_contents_from<R: io::Read>(
+        &mut self,
+        reader: &mut R,
+        size: u32,
+        buf: &mut [u8],
+    ) -> io::Result<()> {
+        self.appended.clear();
+        self.check_size(size as u64)?;
+        let location = match self.location {
+            Some(location) => location,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "file has no directory entry",
+                ))
+            }
+        };
+
+        {
+            let mut vfat = self.vfat.borrow_mut();
+            vfat.check_generation(self.generation)?;
+            vfat.check_layout(location.dir_cluster, self.layout)?;
+            vfat.check_modifiable(Some(location))?;
+            vfat.check_reserve(self.metadata.size as u64, size as u64)?;
+            let cluster_size = vfat.cluster_size();
+            let chain = vfat.resize_chain(self.start_cluster, size as u64)?;
+
+            let mut remaining = size as usize;
+            for clusters in chain.chunks(buf.len() / cluster_size) {
+                let n = ::std::cmp::min(remaining, clusters.len() * cluster_size);
+                reader.read_exact(&mut buf[..n])?;
+                for (cluster, data) in clusters.iter().zip(buf[..n].chunks(cluster_size)) {
+                    vfat.write_cluster(*cluster, 0, data)?;
+                }
+                remaining -= n;
+            }
+
+            self.start_cluster = chain.first().cloned().unwrap_or(Cluster(0));
+            vfat.set_entry_extent(location, self.start_cluster, size)?;
+            vfat.dir_entries_changed(location.dir_cluster, false)?;
+        }
+
+        self.metadata.size = size;
+        self.offset = 0;
+        self.chain = None;
+        self.data = None;
+        notify_at(&self.vfat, location, Event::Modify);
+        Ok(())
+    }
+
+    /// Points this file's entry at the `size` bytes held by the chain
+    /// beginning at `start`, rewinds the handle, and returns the file's
+    /// previous first cluster. The previous chain is left allocated, for the
+    /// caller to free or to restore. Changes reach the disk on the next
+    /// `VFat::sync()`. Buffered appends are discarded.
+    pub(crate) fn set_extent(&mut self, start: Cluster, size: u32) -> io::Result<Cluster> {
+        self.appended.clear();
+        let location = match self.location {
+            Some(location) => location,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "file has no directory entry",
+                ))
+            }
+        };
+
+        {
+            let mut vfat = self.vfat.borrow_mut();
+            vfat.check_generation(self.generation)?;
+            vfat.check_layout(location.dir_cluster, self.layout)?;
+            vfat.check_modifiable(Some(location))?;
+            vfat.set_entry_extent(location, start, size)?;
+            vfat.dir_entries_changed(location.dir_cluster, false)?;
+        }
+
+        let previous = self.start_cluster;
+        self.start_cluster = start;
+        self.metadata.size = size;
+        self.offset = 0;
+        self.chain = None;
+        self.data = None;
+        notify_at(&self.vfat, location, Event::Modify);
+        Ok(previous)
+    }
+
     pub fn initialize(&mut self) -> io::Result<()> {
         match self.data {
             Some(_) => Ok(()),
             None => {
-                let mut tmp_buf = Vec::new();
-                self.vfat
+                let data = self
+                    .vfat
                     .borrow_mut()
-                    .read_chain(self.start_cluster, &mut tmp_buf)?;
-                self.data = Some(tmp_buf);
-                Ok(())
+                    .file_data(self.start_cluster, self.metadata.size)?;
+                self.data = Some(data);
+                self.data_start = 0;
+                self.verify_loaded()
+            }
+        }
+    }
+
+    /// Returns `true` if the loaded data covers the byte at `self.offset`.
+    fn loaded(&self) -> bool {
+        match self.data {
+            Some(ref data) => {
+                self.offset >= self.data_start
+                    && ((self.offset - self.data_start) as usize) < data.borrow().len()
+            }
+            None => false,
+        }
+    }
+
+    /// Loads the clusters beginning with the one holding `self.offset`: one
+    /// cluster for `Random`, `SEQUENTIAL_READ_AHEAD` for `Sequential`. For
+    /// `Sequential`, the previously loaded clusters are evicted from the
+    /// sector cache.
+    fn load_window(&mut self) -> io::Result<()> {
+        let mut vfat = self.vfat.borrow_mut();
+        if self.chain.is_none() {
+            self.chain = Some(vfat.chain(self.start_cluster)?);
+        }
+
+        let chain = self.chain.as_ref().unwrap();
+        let cluster_size = vfat.cluster_size();
+        if let (Advice::Sequential, Some(ref data)) = (self.advice, self.data.as_ref()) {
+            let first = self.data_start as usize / cluster_size;
+            let count = (data.borrow().len() + cluster_size - 1) / cluster_size;
+            for &cluster in chain.iter().skip(first).take(count) {
+                vfat.evict_cluster(cluster);
             }
         }
+
+        let first = self.offset as usize / cluster_size;
+        let count = match self.advice {
+            Advice::Sequential => SEQUENTIAL_READ_AHEAD,
+            _ => 1,
+        };
+
+        let mut data = Vec::new();
+        for (i, &cluster) in chain.iter().enumerate().skip(first).take(count) {
+            let start = data.len();
+            data.resize(start + cluster_size, 0);
+            let file = Some((self.start_cluster, (i * cluster_size) as u64));
+            vfat.read_file_cluster(cluster, file, &mut data[start..])?;
+        }
+
+        let data_start = (first * cluster_size) as u32;
+        data.truncate(self.metadata.size.saturating_sub(data_start) as usize);
+        self.data = Some(Shared::new(data));
+        self.data_start = data_start;
+        Ok(())
+    }
+}
+
+impl PartialEq for File {
+    fn eq(&self, other: &File) -> bool {
+        self.id() == other.id()
+    }
+}
+
+impl Eq for File {}
+
+impl Hash for File {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id().hash(state)
     }
 }
 
@@ -54,57 +744,131 @@ impl io::Seek for File {
     /// Seeking before the start of a file or beyond the end of the file results
     /// in an `InvalidInput` error.
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        let new_offset: i64 = match pos {
-            SeekFrom::Start(offset) => offset as i64,
-            SeekFrom::End(offset) => self.metadata.size as i64 - offset,
-            SeekFrom::Current(offset) => self.offset as i64 + offset,
+        self.flush_appends()?;
+        // Offsets are computed in `i64`, which holds every `u32` size; an
+        // overflow is as invalid as any other offset out of range.
+        let new_offset = match pos {
+            SeekFrom::Start(offset) if offset <= i64::max_value() as u64 => Some(offset as i64),
+            SeekFrom::Start(_) => None,
+            SeekFrom::End(offset) => (self.metadata.size as i64).checked_add(offset),
+            SeekFrom::Current(offset) => (self.offset as i64).checked_add(offset),
         };
 
-        if new_offset < 0 || new_offset > self.metadata.size as i64 {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "seek is invalid",
-            ));
+        match new_offset {
+            Some(offset) if offset >= 0 && offset <= self.metadata.size as i64 => {
+                self.offset = offset as u32
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "seek is invalid",
+                ))
+            }
         }
 
-        self.offset = new_offset as u32;
-
         Ok(self.offset as u64)
     }
 }
 
 impl traits::File for File {
     fn sync(&mut self) -> io::Result<()> {
-        unimplemented!()
+        self.flush_appends()?;
+        self.vfat.borrow_mut().sync()
     }
 
+    /// The size of the file, including buffered appends.
     fn size(&self) -> u64 {
-        self.metadata.size as u64
+        self.metadata.size as u64 + self.appended.len() as u64
     }
 }
 
 impl io::Write for File {
-    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
-        unimplemented!()
+    /// Appends `buf` to the file. See `set_append_buffering()`.
+    ///
+    /// # Errors
+    ///
+    /// Only appends are supported: if the handle is not at the end of the
+    /// file, an error of kind `Other` is returned. Use `write_at()` to write
+    /// elsewhere in the file. If the file would grow
+    /// past `MountOptions::max_file_size`, `Error::FileTooLarge` is returned
+    /// as an error of kind `InvalidInput`, and nothing is written. On a
+    /// read-only volume, or for a file beneath
+    /// `MountOptions::protected_paths`, an error of kind `PermissionDenied` is
+    /// returned.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.vfat.borrow_mut().check_generation(self.generation)?;
+        self.vfat.borrow_mut().check_modifiable(self.location)?;
+        let size = traits::File::size(self);
+        if self.offset as u64 != size {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "only writes at the end of the file are supported",
+            ));
+        }
+        self.check_size(size + buf.len() as u64)?;
+
+        let (buffered, offset) = (self.appended.len(), self.offset);
+        self.appended.extend_from_slice(buf);
+        self.offset += buf.len() as u32;
+        let cluster_size = self.vfat.borrow().cluster_size();
+        if !self.buffer_appends || self.appended.len() >= cluster_size {
+            if let Err(e) = self.flush_appends() {
+                // Take back what this call buffered, so that a later flush
+                // does not write bytes the caller was told were not.
+                self.appended.truncate(buffered);
+                self.offset = offset;
+                return Err(e);
+            }
+        }
+        Ok(buf.len())
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        unimplemented!()
+        self.flush_appends()
+    }
+}
+
+impl Drop for File {
+    /// Writes out buffered appends. Errors are ignored; call `flush()` first
+    /// to see them.
+    fn drop(&mut self) {
+        let _ = self.flush_appends();
     }
 }
 
 impl io::Read for File {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        if self.data.is_none() {
-            self.initialize()?;
+        self.flush_appends()?;
+        self.vfat.borrow_mut().check_generation(self.generation)?;
+        if self.offset >= self.data_len()? {
+            return Ok(0);
         }
 
-        let num_bytes_to_read = min(buf.len(), (self.metadata.size - self.offset) as usize);
+        if !self.loaded() {
+            match self.advice {
+                Advice::Normal => self.initialize()?,
+                _ => {
+                    self.load_window()?;
+                    self.verify_loaded()?;
+                }
+            }
+
+            if !self.loaded() {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "cluster chain is shorter than the file",
+                ));
+            }
+        }
+
+        let num_bytes_to_read = {
+            let data = self.data.as_ref().unwrap().borrow();
+            let start = (self.offset - self.data_start) as usize;
+            let num_bytes_to_read = min(buf.len(), data.len() - start);
 
-        &buf[..num_bytes_to_read].copy_from_slice(
-            &self.data.as_ref().unwrap()
-                [self.offset as usize..self.offset as usize + num_bytes_to_read],
-        );
+            &buf[..num_bytes_to_read].copy_from_slice(&data[start..start + num_bytes_to_read]);
+            num_bytes_to_read
+        };
 
         io::Seek::seek(self, SeekFrom::Current(num_bytes_to_read as i64))?;
         Ok(num_bytes_to_read)
@@ -0,0 +1,177 @@
+//! Hash trees for verifying file contents as they are read.
+//!
+//! A file's hash tree holds the SHA-256 digest of each of its clusters. It is
+//! stored in a sidecar file in the same directory, named after the file with
+//! `HASH_TREE_SUFFIX` appended, e.g. `kernel8.img.hashtree`. The sidecar is
+//! the magic `FHT1`, the cluster size as a little-endian `u32`, the file size
+//! as a little-endian `u64`, the number of clusters as a little-endian `u32`,
+//! and then each cluster's digest.
+//!
+//! The root digest of the tree covers the cluster size, the file size, and
+//! every cluster. Comparing it against a trusted value, as
+//! `File::enable_integrity()` does when given one, detects tampering with the
+//! file or the sidecar; without one, only corruption is detected.
+
+use std::io;
+
+use byteorder::{ByteOrder, LittleEndian};
+use vfat::sha256::{sha256, Sha256};
+use vfat::{Error, File};
+
+/// The suffix appended to a file's name to name its hash tree sidecar.
+pub const HASH_TREE_SUFFIX: &str = ".hashtree";
+
+const MAGIC: &[u8; 4] = b"FHT1";
+const HEADER_SIZE: usize = 20;
+
+/// A SHA-256 digest.
+pub type Digest = [u8; 32];
+
+/// The per-cluster digests of a file. See the module documentation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashTree {
+    cluster_size: u32,
+    size: u64,
+    leaves: Vec<Digest>,
+}
+
+impl HashTree {
+    /// Builds the hash tree of `file` by reading each of its clusters.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error kind of `UnexpectedEof` if the file's cluster chain
+    /// is shorter than the file. Errors reading the volume are returned as
+    /// they occur.
+    pub fn build(file: &File) -> io::Result<HashTree> {
+        let size = file.data_len()? as u64;
+        let mut vfat = file.vfat.borrow_mut();
+        let cluster_size = vfat.cluster_size();
+        let mut buf = vec![0; cluster_size];
+        let mut leaves = Vec::new();
+        let mut cluster = Some(file.start_cluster);
+
+        let mut hashed = 0u64;
+        while hashed < size {
+            let current = match cluster {
+                Some(current) => current,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "cluster chain is shorter than the file",
+                    ))
+                }
+            };
+
+            vfat.read_cluster(current, &mut buf)?;
+            let n = ::std::cmp::min(cluster_size as u64, size - hashed) as usize;
+            leaves.push(sha256(&buf[..n]));
+            hashed += n as u64;
+            cluster = vfat.next_in_chain(current)?;
+        }
+
+        Ok(HashTree {
+            cluster_size: cluster_size as u32,
+            size,
+            leaves,
+        })
+    }
+
+    /// Parses a hash tree sidecar.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error kind of `InvalidData` if `bytes` is not a well-formed
+    /// hash tree.
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<HashTree> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed hash tree");
+        if bytes.len() < HEADER_SIZE || &bytes[..4] != MAGIC {
+            return Err(invalid());
+        }
+
+        let count = LittleEndian::read_u32(&bytes[16..20]) as usize;
+        let digests = &bytes[HEADER_SIZE..];
+        if digests.len() != count * 32 {
+            return Err(invalid());
+        }
+
+        Ok(HashTree {
+            cluster_size: LittleEndian::read_u32(&bytes[4..8]),
+            size: LittleEndian::read_u64(&bytes[8..16]),
+            leaves: digests
+                .chunks(32)
+                .map(|chunk| {
+                    let mut digest = [0; 32];
+                    digest.copy_from_slice(chunk);
+                    digest
+                })
+                .collect(),
+        })
+    }
+
+    /// Serializes the tree into the sidecar format.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0; HEADER_SIZE];
+        bytes[..4].copy_from_slice(MAGIC);
+        LittleEndian::write_u32(&mut bytes[4..8], self.cluster_size);
+        LittleEndian::write_u64(&mut bytes[8..16], self.size);
+        LittleEndian::write_u32(&mut bytes[16..20], self.leaves.len() as u32);
+        for leaf in &self.leaves {
+            bytes.extend_from_slice(leaf);
+        }
+        bytes
+    }
+
+    /// The root digest of the tree. Pairs of digests are hashed together
+    /// level by level, an odd digest out being carried up unchanged, and the
+    /// top digest is hashed with the cluster size and file size.
+    pub fn root(&self) -> Digest {
+        let mut level = self.leaves.clone();
+        while level.len() > 1 {
+            level = level
+                .chunks(2)
+                .map(|pair| match pair.len() {
+                    2 => {
+                        let mut hasher = Sha256::new();
+                        hasher.update(&pair[0]);
+                        hasher.update(&pair[1]);
+                        hasher.finish()
+                    }
+                    _ => pair[0],
+                })
+                .collect();
+        }
+
+        let mut header = [0; 12];
+        LittleEndian::write_u32(&mut header[..4], self.cluster_size);
+        LittleEndian::write_u64(&mut header[4..], self.size);
+        let mut hasher = Sha256::new();
+        hasher.update(MAGIC);
+        hasher.update(&header);
+        hasher.update(level.first().map_or(&[][..], |top| &top[..]));
+        hasher.finish()
+    }
+
+    /// Checks that the tree describes a file of `size` bytes stored in
+    /// clusters of `cluster_size` bytes.
+    pub(crate) fn check_shape(&self, cluster_size: usize, size: u64) -> Result<(), Error> {
+        let clusters = (size + cluster_size as u64 - 1) / cluster_size as u64;
+        if self.cluster_size as usize != cluster_size
+            || self.size != size
+            || self.leaves.len() as u64 != clusters
+        {
+            return Err(Error::Integrity { cluster: None });
+        }
+        Ok(())
+    }
+
+    /// Checks `data`, the contents of cluster `index` of the file.
+    pub(crate) fn verify(&self, index: usize, data: &[u8]) -> Result<(), Error> {
+        match self.leaves.get(index) {
+            Some(leaf) if *leaf == sha256(data) => Ok(()),
+            _ => Err(Error::Integrity {
+                cluster: Some(index as u64),
+            }),
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use std::fmt;
+use std::io;
+
+use vfat::{Cluster, EntryLocation, Shared, VFat};
+
+/// The kind of an advisory lock taken with `File::try_lock()` and friends.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LockKind {
+    /// May overlap other shared locks.
+    Shared,
+    /// May not overlap any other lock.
+    Exclusive,
+}
+
+#[derive(Debug)]
+struct Lock {
+    id: u64,
+    /// The directory entry of the locked file. Entries only move when their
+    /// directory is compacted, which re-keys the lock; see `relocate()`.
+    target: EntryLocation,
+    start: u64,
+    end: u64,
+    kind: LockKind,
+}
+
+/// The advisory locks held on a volume's files. Locks exist only in memory
+/// and are released when their `FileLock` is dropped.
+#[derive(Debug, Default)]
+pub(crate) struct LockTable {
+    next_id: u64,
+    locks: Vec<Lock>,
+}
+
+impl LockTable {
+    /// Takes a lock of `kind` on bytes `start..end` of the file whose entry
+    /// is at `target`, returning its id, or `None` if it conflicts with a
+    /// lock already held.
+    pub(crate) fn try_acquire(
+        &mut self,
+        target: EntryLocation,
+        start: u64,
+        end: u64,
+        kind: LockKind,
+    ) -> Option<u64> {
+        let conflicts = self.locks.iter().any(|lock| {
+            lock.target == target
+                && lock.start < end
+                && start < lock.end
+                && (kind == LockKind::Exclusive || lock.kind == LockKind::Exclusive)
+        });
+        if conflicts {
+            return None;
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.locks.push(Lock {
+            id,
+            target,
+            start,
+            end,
+            kind,
+        });
+        Some(id)
+    }
+
+    pub(crate) fn release(&mut self, id: u64) {
+        self.locks.retain(|lock| lock.id != id);
+    }
+
+    /// Moves the locks on entries of the directory at `dir_cluster` to follow
+    /// their entries, given `moves` of `(old slot, new slot)`.
+    pub(crate) fn relocate(&mut self, dir_cluster: Cluster, moves: &[(u32, u32)]) {
+        for lock in self.locks.iter_mut() {
+            if lock.target.dir_cluster != dir_cluster {
+                continue;
+            }
+            if let Some(&(_, new)) = moves.iter().find(|&&(old, _)| old == lock.target.slot) {
+                lock.target.slot = new;
+            }
+        }
+    }
+}
+
+/// An advisory lock on a file or a byte range of it, released when dropped.
+///
+/// Locks are tracked by the `VFat` and only constrain other callers that
+/// take locks; reads and writes are never blocked by them.
+pub struct FileLock {
+    pub(crate) vfat: Shared<VFat>,
+    pub(crate) id: u64,
+    pub(crate) kind: LockKind,
+}
+
+impl FileLock {
+    /// The kind of this lock.
+    pub fn kind(&self) -> LockKind {
+        self.kind
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        self.vfat.borrow_mut().release_lock(self.id);
+    }
+}
+
+impl fmt::Debug for FileLock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FileLock")
+            .field("id", &self.id)
+            .field("kind", &self.kind)
+            .finish()
+    }
+}
+
+/// The error returned when a lock conflicts with one already held.
+pub(crate) fn would_block() -> io::Error {
+    io::Error::new(io::ErrorKind::WouldBlock, "conflicting lock is held")
+}
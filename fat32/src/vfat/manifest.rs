@@ -0,0 +1,333 @@
+//! Applying update manifests to a mounted volume.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::io::{self, Read};
+
+use traits::FileSystem;
+use vfat::sha256::{sha256, Sha256};
+use vfat::vfat::{create_in, new_file_parent};
+use vfat::{Cluster, Digest, Dir, Error, File, Shared, VFat};
+
+/// A change to one file listed in a `Manifest`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ManifestChange {
+    /// The file is added, holding `data`. It must not exist, or be empty as
+    /// an interrupted addition leaves it.
+    Add { data: Vec<u8> },
+    /// The file, whose contents hash to `expected`, is replaced with `data`.
+    Replace { expected: Digest, data: Vec<u8> },
+}
+
+/// A file of a `Manifest` and the change to make to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// The file's absolute path, with `/` separators.
+    pub path: String,
+    pub change: ManifestChange,
+}
+
+/// The changes `apply_manifest()` makes to a volume. Digests are SHA-256
+/// digests of whole files.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    /// Returns an empty manifest.
+    pub fn new() -> Manifest {
+        Manifest::default()
+    }
+
+    /// Lists the addition of the file at `path`, holding `data`.
+    pub fn add(&mut self, path: &str, data: Vec<u8>) {
+        self.push(path, ManifestChange::Add { data })
+    }
+
+    /// Lists the replacement of the file at `path`, whose contents hash to
+    /// `expected`, with `data`.
+    pub fn replace(&mut self, path: &str, expected: Digest, data: Vec<u8>) {
+        self.push(path, ManifestChange::Replace { expected, data })
+    }
+
+    fn push(&mut self, path: &str, change: ManifestChange) {
+        self.entries.push(ManifestEntry {
+            path: path.to_string(),
+            change,
+        })
+    }
+}
+
+/// A file changed by `apply_manifest()`, with its size and digest before
+/// and after the change. `before` is `None` for a file that was added.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileDiff {
+    pub path: String,
+    pub before: Option<(u32, Digest)>,
+    pub after: (u32, Digest),
+}
+
+/// The result of `apply_manifest()`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ManifestDiff {
+    /// The files changed, in manifest order.
+    pub changed: Vec<FileDiff>,
+    /// The paths of the files already as the manifest lists them, which were
+    /// left untouched.
+    pub unchanged: Vec<String>,
+}
+
+impl fmt::Display for ManifestDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for file in &self.changed {
+            let (new, _) = file.after;
+            match file.before {
+                Some((old, _)) => writeln!(f, "~ {}: {} -> {} bytes", file.path, old, new)?,
+                None => writeln!(f, "+ {}: {} bytes", file.path, new)?,
+            }
+        }
+        for path in &self.unchanged {
+            writeln!(f, "  {}: unchanged", path)?;
+        }
+        Ok(())
+    }
+}
+
+/// A change verified against the volume, waiting to be applied.
+struct Update<'a> {
+    path: &'a str,
+    /// The file to write, or `None` for a file to add until it is created.
+    file: Option<File>,
+    /// For a file to add, the directory to create it in and its name.
+    new: Option<(Dir, &'a str)>,
+    data: &'a [u8],
+    before: Option<(u32, Digest)>,
+}
+
+/// Returns the digest of `file`'s contents, read from its start.
+fn digest(file: &mut File) -> io::Result<Digest> {
+    let mut hasher = Sha256::new();
+    let mut buf = [0; 4096];
+    loop {
+        match file.read(&mut buf)? {
+            0 => return Ok(hasher.finish()),
+            n => hasher.update(&buf[..n]),
+        }
+    }
+}
+
+/// Frees the chains beginning at `starts`, undoing a failed update. Errors
+/// are ignored, so that the error that failed the update is the one
+/// returned; chains left allocated are reclaimed by `VFat::repair()`.
+fn release(volume: &Shared<VFat>, starts: &[Cluster]) {
+    let mut vfat = volume.borrow_mut();
+    for &start in starts {
+        if start.0 >= 2 {
+            let _ = vfat.truncate_chain(start, 0);
+        }
+    }
+}
+
+/// Replaces the contents of each file in `files` with its data,
+/// copy-on-write, in the three synced steps `apply_manifest()` describes.
+/// The files must have been checked with `VFat::check_modifiable()`.
+///
+/// # Errors
+///
+/// Returns an error kind of `Other` if the volume lacks the free clusters,
+/// beyond `MountOptions::reserved_clusters`, to hold the new contents
+/// alongside the old. If writing fails, the changes made so far are undone
+/// where possible and the error is returned.
+pub(crate) fn replace_files(
+    volume: &Shared<VFat>,
+    files: &mut [(&mut File, &[u8])],
+) -> io::Result<()> {
+    {
+        let mut vfat = volume.borrow_mut();
+        let cluster_size = vfat.cluster_size_bytes();
+        let needed: u64 = files
+            .iter()
+            .map(|&(_, data)| (data.len() as u64 + cluster_size - 1) / cluster_size)
+            .sum();
+        let reserved = vfat.options().reserved_clusters as u64;
+        if needed > 0 && vfat.free_clusters()? < needed + reserved {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "not enough free clusters to stage the update",
+            ));
+        }
+    }
+
+    // Write the new contents beside the old.
+    let mut staged = Vec::with_capacity(files.len());
+    for &(_, data) in files.iter() {
+        let result = volume.borrow_mut().write_chain(Cluster(0), data);
+        match result {
+            Ok(start) => staged.push(start),
+            Err(e) => {
+                release(volume, &staged);
+                return Err(e);
+            }
+        }
+    }
+    let result = volume.borrow_mut().sync();
+    if let Err(e) = result {
+        release(volume, &staged);
+        return Err(e);
+    }
+
+    // Switch the entries to the new contents.
+    let mut previous = Vec::with_capacity(files.len());
+    for i in 0..files.len() {
+        let size = files[i].1.len() as u32;
+        let old_size = files[i].0.metadata.size;
+        match files[i].0.set_extent(staged[i], size) {
+            Ok(old) => previous.push((old, old_size)),
+            Err(e) => {
+                for (&mut (ref mut file, _), &(old, old_size)) in files.iter_mut().zip(&previous) {
+                    let _ = file.set_extent(old, old_size);
+                }
+                release(volume, &staged);
+                return Err(e);
+            }
+        }
+    }
+    volume.borrow_mut().sync()?;
+
+    // Only now that no entry refers to them, free the old contents.
+    let mut vfat = volume.borrow_mut();
+    for &(old, _) in &previous {
+        if old.0 >= 2 {
+            vfat.truncate_chain(old, 0)?;
+        }
+    }
+    vfat.sync()
+}
+
+/// Applies `manifest` to `volume` and returns the files it changed.
+///
+/// Every entry is verified before anything is written: a file to replace
+/// must hash to its expected digest, and a file to add must not exist and
+/// must be creatable as `VFat::create_file()` would create it. Entries the
+/// volume already reflects, such as a file that already holds its new
+/// contents, are left alone and reported as unchanged, so an interrupted
+/// update can be applied again.
+///
+/// This crate has no journal. Instead, files to add are first created
+/// empty, and then all contents are written copy-on-write in three steps,
+/// each ending in `VFat::sync()`: the new contents are written to newly
+/// allocated clusters, the directory entries are pointed at them, and the
+/// old clusters are freed. A power loss before the second step completes
+/// leaves every file with its old contents, or empty if it was being added,
+/// and one during it may leave some files updated and others not, but each
+/// file whole. An empty file is taken as an interrupted addition when the
+/// manifest is applied again. A power loss at any point leaves at worst
+/// clusters allocated to no file, which `VFat::repair()` reclaims.
+///
+/// # Errors
+///
+/// Returns `Error::ManifestMismatch` as an error kind of `InvalidData` if a
+/// file is not as the manifest expects, and an error kind of `InvalidInput`
+/// if the manifest lists a path twice. A file that cannot be added gives the
+/// errors of `VFat::create_file()`. Returns `Error::FileTooLarge` if new
+/// contents exceed `MountOptions::max_file_size`, and an error kind of
+/// `Other` if the volume lacks the free clusters, beyond
+/// `MountOptions::reserved_clusters`, to hold the new contents alongside the
+/// old.
+///
+/// These errors, and errors opening the files, are returned before anything
+/// is written, except that a directory that cannot grow to hold an added
+/// file, or too few free clusters, are only found once the files to add
+/// have been created. If writing fails, the changes made so far are undone
+/// where possible, files created for additions are left empty, and the
+/// error is returned.
+pub fn apply_manifest(volume: &Shared<VFat>, manifest: &Manifest) -> io::Result<ManifestDiff> {
+    let mut diff = ManifestDiff::default();
+    let mut paths = HashSet::new();
+    let mut updates = Vec::new();
+    let limit = volume.borrow().options().max_file_size;
+    for entry in &manifest.entries {
+        if !paths.insert(entry.path.to_ascii_lowercase()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "manifest lists a path twice",
+            ));
+        }
+
+        let mut file = match volume.open_file(&entry.path) {
+            Ok(file) => Some(file),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        let current = match file {
+            Some(ref mut file) => Some((file.metadata.size, digest(file)?)),
+            None => None,
+        };
+        let mismatch = || {
+            let path = entry.path.clone();
+            io::Error::from(Error::ManifestMismatch { path })
+        };
+
+        let data = match (&entry.change, current) {
+            (&ManifestChange::Add { ref data }, Some((_, digest)))
+            | (&ManifestChange::Replace { ref data, .. }, Some((_, digest)))
+                if digest == sha256(data) =>
+            {
+                diff.unchanged.push(entry.path.clone());
+                continue;
+            }
+            (&ManifestChange::Add { ref data }, Some((0, _))) => data,
+            (&ManifestChange::Add { .. }, Some(_)) => return Err(mismatch()),
+            (&ManifestChange::Add { ref data }, None) => data,
+            (&ManifestChange::Replace { expected, ref data }, Some((_, digest)))
+                if digest == expected =>
+            {
+                data
+            }
+            (&ManifestChange::Replace { .. }, _) => return Err(mismatch()),
+        };
+        if data.len() as u64 > limit as u64 {
+            return Err(Error::FileTooLarge { limit }.into());
+        }
+        let new = match file {
+            Some(ref file) => {
+                volume.borrow_mut().check_modifiable(file.location)?;
+                None
+            }
+            None => Some(new_file_parent(volume, entry.path.as_ref())?),
+        };
+        updates.push(Update {
+            path: &entry.path,
+            file,
+            new,
+            data,
+            before: current,
+        });
+    }
+
+    for update in &mut updates {
+        if let Some((dir, name)) = update.new.take() {
+            update.file = Some(create_in(volume, &dir, name)?);
+        }
+    }
+    {
+        let mut files: Vec<_> = updates
+            .iter_mut()
+            .map(|update| {
+                let file = update.file.as_mut().expect("files to add were created");
+                (file, update.data)
+            })
+            .collect();
+        replace_files(volume, &mut files)?;
+    }
+
+    for update in updates {
+        diff.changed.push(FileDiff {
+            path: update.path.to_string(),
+            before: update.before,
+            after: (update.data.len() as u32, sha256(update.data)),
+        });
+    }
+    Ok(diff)
+}
@@ -0,0 +1,84 @@
+//! Accounting of the memory held by a volume's caches.
+
+/// The bytes charged to the directory cache for each directory it holds, an
+/// upper bound on the size of a cached path and its metadata. A fixed charge
+/// keeps the cache's share of a budget independent of path lengths.
+pub const DIR_CACHE_ENTRY_BYTES: usize = 256;
+
+/// A cap on the memory a volume's caches hold, divided between them by
+/// weight; see `MountOptions::memory_budget`.
+///
+/// Each cache is given `cap * weight / total weight` bytes. The sector cache
+/// always holds at least one sector, and data in use by open `File` handles
+/// occupies memory whether or not its cache has room for it, so the cap
+/// bounds what the caches keep, not what handles hold.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MemoryBudget {
+    /// The most bytes held by all the caches together. Defaults to 2 MiB.
+    pub cap: usize,
+    /// The weight of the sector cache. Defaults to `4`.
+    pub sector_weight: u32,
+    /// The weight of the whole-file data shared between `File` handles.
+    /// Defaults to `4`.
+    pub file_data_weight: u32,
+    /// The weight of the cache of directories resolved by path, charged
+    /// `DIR_CACHE_ENTRY_BYTES` per directory. Defaults to `1`.
+    pub dir_weight: u32,
+}
+
+impl MemoryBudget {
+    /// Returns the bytes given to a cache of weight `weight`.
+    fn share(&self, weight: u32) -> usize {
+        let total =
+            self.sector_weight as u64 + self.file_data_weight as u64 + self.dir_weight as u64;
+        match total {
+            0 => 0,
+            total => (self.cap as u64 * weight as u64 / total) as usize,
+        }
+    }
+
+    /// The bytes given to the sector cache.
+    pub fn sector_bytes(&self) -> usize {
+        self.share(self.sector_weight)
+    }
+
+    /// The bytes given to whole-file data.
+    pub fn file_data_bytes(&self) -> usize {
+        self.share(self.file_data_weight)
+    }
+
+    /// The bytes given to the directory cache.
+    pub fn dir_bytes(&self) -> usize {
+        self.share(self.dir_weight)
+    }
+}
+
+impl Default for MemoryBudget {
+    fn default() -> MemoryBudget {
+        MemoryBudget {
+            cap: 2 << 20,
+            sector_weight: 4,
+            file_data_weight: 4,
+            dir_weight: 1,
+        }
+    }
+}
+
+/// The bytes held by each of a volume's caches, as returned by
+/// `VFat::memory_usage()`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// The bytes of sectors cached.
+    pub sectors: usize,
+    /// The bytes of whole-file data cached, including data in use by handles.
+    pub file_data: usize,
+    /// The bytes charged for cached directories.
+    pub dirs: usize,
+}
+
+impl MemoryUsage {
+    /// The bytes held by all the caches together.
+    pub fn total(&self) -> usize {
+        self.sectors + self.file_data + self.dirs
+    }
+}
@@ -1,13 +1,21 @@
+use std::cmp::{max, min, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use consts::{ATTR_HIDDEN, ATTR_READ_ONLY};
 use traits;
+use traits::days_from_civil;
+use traits::Timestamp as TimestampTrait;
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
 
 /// A date as represented in FAT32 on-disk structures.
 #[repr(C, packed)]
-#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Date(u16);
 
 /// Time as represented in FAT32 on-disk structures.
 #[repr(C, packed)]
-#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Time(u16);
 
 /// File attributes as represented in FAT32 on-disk structures.
@@ -25,8 +33,13 @@ pub struct Timestamp {
 
 /// Metadata for a directory entry.
 #[derive(Default, Debug, Clone)]
+#[non_exhaustive]
 pub struct Metadata {
     pub name: String,
+    /// The entry's long file name exactly as stored, in UCS-2. Empty if the
+    /// entry has no long file name. Unlike `name`, unpaired surrogates are
+    /// preserved.
+    pub long_name: Vec<u16>,
     pub size: u32,
     pub attributes: Attributes,
     pub created: Timestamp,
@@ -34,6 +47,96 @@ pub struct Metadata {
     pub last_modified: Timestamp,
 }
 
+/// The inverse of `days_from_civil`: returns the `(year, month, day)` that is
+/// `days` days after 1970-01-01.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = if days >= 0 { days } else { days - 146096 } / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+impl Date {
+    /// The date stored on disk as `raw`.
+    pub(crate) fn from_raw(raw: u16) -> Date {
+        Date(raw)
+    }
+
+    /// The date as stored on disk.
+    pub(crate) fn raw(&self) -> u16 {
+        self.0
+    }
+}
+
+impl Time {
+    /// The time stored on disk as `raw`.
+    pub(crate) fn from_raw(raw: u16) -> Time {
+        Time(raw)
+    }
+
+    /// The time as stored on disk.
+    pub(crate) fn raw(&self) -> u16 {
+        self.0
+    }
+}
+
+impl Timestamp {
+    /// Converts this timestamp to a `SystemTime`, interpreting it as local time
+    /// `offset` minutes ahead of UTC.
+    pub fn to_system_time(&self, offset: i16) -> SystemTime {
+        let utc = self.to_unix_epoch(offset);
+        UNIX_EPOCH + Duration::from_secs(max(utc, 0) as u64)
+    }
+
+    /// Converts `time` to a local timestamp `offset` minutes ahead of UTC.
+    ///
+    /// Times outside of the range representable by FAT (1980 through 2107) are
+    /// clamped to that range. Seconds are rounded down to an even number.
+    pub fn from_system_time(time: SystemTime, offset: i16) -> Timestamp {
+        let utc = match time.duration_since(UNIX_EPOCH) {
+            Ok(duration) => duration.as_secs() as i64,
+            Err(_) => 0,
+        };
+
+        let earliest = days_from_civil(1980, 1, 1) * SECONDS_PER_DAY;
+        let latest = days_from_civil(2108, 1, 1) * SECONDS_PER_DAY - 1;
+        let local = min(max(utc + offset as i64 * 60, earliest), latest);
+
+        let (year, month, day) = civil_from_days(local / SECONDS_PER_DAY);
+        let seconds = local % SECONDS_PER_DAY;
+        let (hour, minute, second) = (seconds / 3600, (seconds / 60) % 60, seconds % 60);
+
+        Timestamp {
+            date: Date((((year - 1980) << 9) | (month << 5) | day) as u16),
+            time: Time(((hour << 11) | (minute << 5) | (second / 2)) as u16),
+        }
+    }
+}
+
+/// Timestamps are ordered chronologically. Timestamps of the same instant
+/// that differ on disk, e.g. in a zeroed day field, are ordered by their raw
+/// fields.
+impl Ord for Timestamp {
+    fn cmp(&self, other: &Timestamp) -> Ordering {
+        self.to_unix_epoch(0)
+            .cmp(&other.to_unix_epoch(0))
+            .then((self.date, self.time).cmp(&(other.date, other.time)))
+    }
+}
+
+impl PartialOrd for Timestamp {
+    fn partial_cmp(&self, other: &Timestamp) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 impl traits::Timestamp for Timestamp {
     fn year(&self) -> usize {
         1980 + ((self.date.0 >> 9) & 0b1111111) as usize
@@ -63,11 +166,11 @@ impl traits::Metadata for Metadata {
     type Timestamp = Timestamp;
 
     fn read_only(&self) -> bool {
-        self.attributes.0 & 0x01 != 0
+        self.attributes.0 & ATTR_READ_ONLY != 0
     }
 
     fn hidden(&self) -> bool {
-        self.attributes.0 & 0x02 != 0
+        self.attributes.0 & ATTR_HIDDEN != 0
     }
 
     fn created(&self) -> Self::Timestamp {
@@ -0,0 +1,70 @@
+use std::time::Duration;
+
+/// The kind of an operation on the underlying block device.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum IoOp {
+    Read,
+    Write,
+}
+
+/// A histogram of operation latencies. Bucket `i` counts operations that took
+/// `[2^i, 2^(i + 1))` microseconds; bucket `0` also counts operations faster
+/// than a microsecond.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    pub buckets: [u64; 32],
+    /// The slowest operation recorded.
+    pub max: Duration,
+}
+
+impl LatencyHistogram {
+    pub(crate) fn record(&mut self, latency: Duration) {
+        let micros = latency.as_secs() * 1_000_000 + latency.subsec_nanos() as u64 / 1_000;
+        let bucket = match micros {
+            0 => 0,
+            micros => ::std::cmp::min(63 - micros.leading_zeros(), 31),
+        };
+        self.buckets[bucket as usize] += 1;
+        self.max = ::std::cmp::max(self.max, latency);
+    }
+
+    /// The number of operations recorded.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+}
+
+/// Latency histograms of the device operations issued since the metrics were
+/// last taken with `VFat::take_io_metrics()`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IoMetrics {
+    pub reads: LatencyHistogram,
+    pub writes: LatencyHistogram,
+    /// The number of sector reads that failed their checksum, including
+    /// those that succeeded on a retry. See `MountOptions::checksum_retries`.
+    pub checksum_failures: u64,
+    /// The number of sectors that still failed their checksum after every
+    /// retry.
+    pub unrecovered_reads: u64,
+    /// The number of flushes that wrote at least one sector.
+    pub flushes: u64,
+    /// The number of dirty sectors written back by flushes.
+    pub flushed_sectors: u64,
+    /// The number of device writes flushes issued. Dirty sectors are written
+    /// in order of sector number, each run of consecutive sectors as a single
+    /// write, so `flushed_sectors / flush_writes` is the average run length.
+    pub flush_writes: u64,
+}
+
+impl IoMetrics {
+    pub(crate) fn record(&mut self, op: IoOp, latency: Duration) {
+        match op {
+            IoOp::Read => self.reads.record(latency),
+            IoOp::Write => self.writes.record(latency),
+        }
+    }
+}
+
+/// A callback invoked with the operation, the sector, and the latency of every
+/// device operation slower than a threshold. See `VFat::set_slow_io_hook()`.
+pub type SlowIoHook = Box<FnMut(IoOp, u64, Duration) + Send>;
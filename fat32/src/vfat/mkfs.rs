@@ -0,0 +1,760 @@
+//! Building disk images from scratch: a partition table and freshly
+//! formatted FAT32 partitions filled with trees of files.
+
+use std::cmp;
+use std::collections::{BTreeMap, HashSet};
+use std::io;
+
+use byteorder::{ByteOrder, LittleEndian};
+use consts::{
+    ATTR_DIRECTORY, ATTR_VOLUME_ID, BOOT_SIGNATURE, DIR_ENTRY_SIZE, EOC_MARKER,
+    FAT32_CHS_PARTITION, FAT32_LBA_PARTITION, FAT_ENTRY_SIZE, FSINFO_LEAD_SIGNATURE,
+    FSINFO_STRUCT_SIGNATURE, FSINFO_TRAIL_SIGNATURE, MAX_CLUSTERS, MEDIA_ENTRY_HIGH_BITS,
+};
+use traits::BlockDevice;
+use vfat::clone::encode_label;
+use vfat::collisions::fold_case;
+use vfat::repair::dot_entry;
+use vfat::tar::{components, read_member};
+use vfat::{
+    short_name_for, validate_long_name, Attributes, Cluster, DirEntryBuilder, ShortNameStrategy,
+    Timestamp,
+};
+
+const RESERVED_SECTORS: u64 = 32;
+const NUM_FATS: u64 = 2;
+const FS_INFO_SECTOR: u64 = 1;
+const BACKUP_BOOT_SECTOR: u64 = 6;
+const ROOT_CLUSTER: u32 = 2;
+const MEDIA_DESCRIPTOR: u8 = 0xF8;
+const MAX_CLUSTER_BYTES: u64 = 32 << 10;
+/// Partitions begin on 1 MiB boundaries, as current partitioning tools
+/// place them, so they line up with the erase blocks of SD cards.
+const PARTITION_ALIGNMENT: u64 = 1 << 20;
+/// The most zeroed sectors written at once.
+const ZERO_CHUNK_SECTORS: u64 = 64;
+
+fn invalid_input(reason: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, reason)
+}
+
+/// A file or directory of a `Tree`.
+#[derive(Debug, Clone)]
+enum Node {
+    File(Vec<u8>),
+    Dir(Tree),
+}
+
+/// How a `Tree` treats a name added beside one that differs from it only in
+/// case.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CaseCollisions {
+    /// Names that differ only in ASCII case name the same entry, as on FAT.
+    /// Names that differ in the case of other letters name distinct entries,
+    /// which Windows, folding all letters, cannot tell apart.
+    Merge,
+    /// Adding a name that differs only in case, of any letter, from an
+    /// entry's name fails, as with a tree copied from a case-sensitive file
+    /// system that would confuse Windows. See `VFat::name_collisions()` to
+    /// check a volume.
+    Reject,
+}
+
+impl Default for CaseCollisions {
+    fn default() -> CaseCollisions {
+        CaseCollisions::Merge
+    }
+}
+
+/// A tree of files and directories to write onto a new volume with
+/// `format()`. As on FAT, names that differ only in ASCII case name the same
+/// entry, unless the tree rejects them; see `CaseCollisions`.
+#[derive(Debug, Clone, Default)]
+pub struct Tree {
+    entries: BTreeMap<String, Node>,
+    case_collisions: CaseCollisions,
+}
+
+impl Tree {
+    /// Returns an empty tree.
+    pub fn new() -> Tree {
+        Tree::default()
+    }
+
+    /// Sets how names that differ only in case are treated when added to
+    /// this tree, and to the directories in it. Names already added are not
+    /// checked again.
+    pub fn set_case_collisions(&mut self, case_collisions: CaseCollisions) {
+        self.case_collisions = case_collisions;
+        for node in self.entries.values_mut() {
+            if let Node::Dir(ref mut dir) = *node {
+                dir.set_case_collisions(case_collisions);
+            }
+        }
+    }
+
+    /// Adds a file holding `data` at the `/`-separated `path`, creating any
+    /// missing parent directories. A file already at `path` is replaced.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error kind of `InvalidInput` if `path` is empty, leaves
+    /// the root, names a directory, or passes through a file, if one of its
+    /// names cannot be stored on FAT (see `validate_long_name()`), if the
+    /// tree rejects case collisions and one of its names differs only in
+    /// case from an entry's, or if `data` is too large for a FAT file.
+    pub fn add_file(&mut self, path: &str, data: Vec<u8>) -> io::Result<()> {
+        self.add_file_at(&split_path(path)?, data)
+    }
+
+    /// Adds a directory at the `/`-separated `path`, creating any missing
+    /// parent directories. Adding a directory that exists does nothing.
+    ///
+    /// # Errors
+    ///
+    /// As `add_file()`, except that `path` may name a directory but not a
+    /// file.
+    pub fn add_dir(&mut self, path: &str) -> io::Result<()> {
+        let names = split_path(path)?;
+        if names.is_empty() {
+            return Err(invalid_input("path is empty"));
+        }
+        self.dir_mut(&names).map(|_| ())
+    }
+
+    /// Reads the tar archive read from `reader` into a tree. Regular files
+    /// and directories are added; members of other types are skipped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error kind of `InvalidData` if the archive is malformed or
+    /// a member's path is not UTF-8 or leaves the root, an error kind of
+    /// `InvalidInput` as `add_file()`, and any I/O error reading the archive.
+    pub fn from_tar<R: io::Read>(reader: &mut R) -> io::Result<Tree> {
+        let mut tree = Tree::new();
+        tree.add_tar(reader)?;
+        Ok(tree)
+    }
+
+    /// Adds the members of the tar archive read from `reader` to this tree,
+    /// as `from_tar()` does. Members replace files of the same name already
+    /// in the tree.
+    ///
+    /// # Errors
+    ///
+    /// As `from_tar()`. Members added before an error stay in the tree.
+    pub fn add_tar<R: io::Read>(&mut self, reader: &mut R) -> io::Result<()> {
+        while let Some(member) = read_member(reader)? {
+            let names = components(&member.path)?;
+            if names.is_empty() {
+                continue;
+            }
+            match member.kind {
+                b'0' | 0 => self.add_file_at(&names, member.data)?,
+                b'5' => self.dir_mut(&names).map(|_| ())?,
+                _ => continue,
+            }
+        }
+        Ok(())
+    }
+
+    fn add_file_at(&mut self, names: &[&str], data: Vec<u8>) -> io::Result<()> {
+        let (name, parents) = match names.split_last() {
+            Some(split) => split,
+            None => return Err(invalid_input("path is empty")),
+        };
+        if data.len() as u64 > ::std::u32::MAX as u64 {
+            return Err(invalid_input("file is too large for FAT"));
+        }
+
+        let dir = self.dir_mut(parents)?;
+        let key = dir.key_for(name)?;
+        if let Some(&Node::Dir(_)) = dir.entries.get(&key) {
+            return Err(invalid_input("path names a directory"));
+        }
+        dir.entries.insert(key, Node::File(data));
+        Ok(())
+    }
+
+    /// Returns the directory at `names`, creating it and its parents if
+    /// missing.
+    fn dir_mut(&mut self, names: &[&str]) -> io::Result<&mut Tree> {
+        let (name, rest) = match names.split_first() {
+            Some(split) => split,
+            None => return Ok(self),
+        };
+        let key = self.key_for(name)?;
+        let case_collisions = self.case_collisions;
+        match *self.entries.entry(key).or_insert_with(|| {
+            Node::Dir(Tree {
+                entries: BTreeMap::new(),
+                case_collisions,
+            })
+        }) {
+            Node::Dir(ref mut dir) => dir.dir_mut(rest),
+            Node::File(_) => Err(invalid_input("path passes through a file")),
+        }
+    }
+
+    /// Returns the key of the entry named `name`, ignoring case, or `name`
+    /// itself if there is none.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error kind of `InvalidInput` if the tree rejects case
+    /// collisions and an entry's name differs from `name` only in case.
+    fn key_for(&self, name: &str) -> io::Result<String> {
+        validate_long_name(name, false)?;
+        if self.case_collisions == CaseCollisions::Reject {
+            let folded = fold_case(name);
+            if self
+                .entries
+                .keys()
+                .any(|key| key != name && fold_case(key) == folded)
+            {
+                return Err(invalid_input("name differs only in case from another"));
+            }
+        }
+        Ok(self
+            .entries
+            .keys()
+            .find(|key| key.eq_ignore_ascii_case(name))
+            .cloned()
+            .unwrap_or_else(|| name.to_string()))
+    }
+}
+
+/// Splits a `/`-separated path into names, dropping empty and `.` names.
+fn split_path(path: &str) -> io::Result<Vec<&str>> {
+    let mut names = Vec::new();
+    for name in path.split('/') {
+        match name {
+            "" | "." => continue,
+            ".." => return Err(invalid_input("path leaves the root")),
+            name => names.push(name),
+        }
+    }
+    Ok(names)
+}
+
+/// Options for formatting a FAT32 volume with `format()`.
+#[derive(Debug, Clone, Default)]
+pub struct FormatOptions {
+    /// The volume label, 1 to 11 printable ASCII characters, or `None` for
+    /// a volume without one. Defaults to `None`.
+    pub label: Option<String>,
+    /// The volume serial number. Defaults to `0`.
+    pub volume_id: u32,
+    /// The sectors per cluster, a power of two, or `None` to choose by the
+    /// volume's size as Windows does. Defaults to `None`.
+    pub sectors_per_cluster: Option<u8>,
+    /// The creation and modification time of every entry written. Defaults
+    /// to `Timestamp::default()`.
+    pub timestamp: Timestamp,
+}
+
+/// Returns the cluster size Windows formats a FAT32 volume of `bytes`
+/// bytes with.
+fn default_cluster_bytes(bytes: u64) -> u64 {
+    const MIB: u64 = 1 << 20;
+    match bytes {
+        bytes if bytes <= 260 * MIB => 512,
+        bytes if bytes <= 8192 * MIB => 4096,
+        bytes if bytes <= 16384 * MIB => 8192,
+        bytes if bytes <= 32768 * MIB => 16384,
+        _ => MAX_CLUSTER_BYTES,
+    }
+}
+
+/// The placement of a volume's regions, in sectors from its start.
+struct Geometry {
+    sector_size: u64,
+    sectors_per_cluster: u64,
+    sectors_per_fat: u64,
+    clusters: u64,
+}
+
+impl Geometry {
+    /// Lays out a volume of `sectors` sectors of `sector_size` bytes.
+    fn new(sector_size: u64, sectors: u64, options: &FormatOptions) -> io::Result<Geometry> {
+        if !sector_size.is_power_of_two() || sector_size < 512 || sector_size > 4096 {
+            return Err(invalid_input("sector size must be 512 to 4096 bytes"));
+        }
+        if sectors > ::std::u32::MAX as u64 {
+            return Err(invalid_input("volume is too large for the BPB"));
+        }
+
+        let sectors_per_cluster = match options.sectors_per_cluster {
+            Some(n) => n as u64,
+            None => cmp::max(
+                1,
+                default_cluster_bytes(sectors * sector_size) / sector_size,
+            ),
+        };
+        if !sectors_per_cluster.is_power_of_two()
+            || sectors_per_cluster * sector_size > MAX_CLUSTER_BYTES
+        {
+            return Err(invalid_input(
+                "sectors per cluster must be a power of two, up to 32 KiB per cluster",
+            ));
+        }
+
+        // Grow the FAT until it covers every cluster that fits after it.
+        let mut sectors_per_fat = 1;
+        let clusters = loop {
+            let data_start = RESERVED_SECTORS + NUM_FATS * sectors_per_fat;
+            if data_start >= sectors {
+                return Err(invalid_input("volume is too small for FAT32"));
+            }
+
+            let clusters = (sectors - data_start) / sectors_per_cluster;
+            let needed = ((clusters + 2) * FAT_ENTRY_SIZE as u64 + sector_size - 1) / sector_size;
+            if needed <= sectors_per_fat {
+                break clusters;
+            }
+            sectors_per_fat = needed;
+        };
+
+        if clusters == 0 {
+            return Err(invalid_input("volume is too small for FAT32"));
+        }
+        if clusters > MAX_CLUSTERS as u64 {
+            return Err(invalid_input("volume has too many clusters for FAT32"));
+        }
+
+        Ok(Geometry {
+            sector_size,
+            sectors_per_cluster,
+            sectors_per_fat,
+            clusters,
+        })
+    }
+
+    fn cluster_bytes(&self) -> u64 {
+        self.sector_size * self.sectors_per_cluster
+    }
+
+    fn data_start(&self) -> u64 {
+        RESERVED_SECTORS + NUM_FATS * self.sectors_per_fat
+    }
+}
+
+/// Chooses the short names of `tree`'s entries, in the tree's order.
+fn short_names(tree: &Tree) -> io::Result<Vec<[u8; 11]>> {
+    let mut used: HashSet<[u8; 11]> = HashSet::new();
+    let mut names = Vec::with_capacity(tree.entries.len());
+    for name in tree.entries.keys() {
+        let short = short_name_for(name, ShortNameStrategy::NumericTail, |candidate| {
+            used.contains(candidate)
+        })?;
+        used.insert(short);
+        names.push(short);
+    }
+    Ok(names)
+}
+
+/// Returns the bytes the entries of the directory holding `tree` take,
+/// with its dot entries or, for the root, its label.
+fn dir_bytes(tree: &Tree, root: bool, label: bool) -> io::Result<u64> {
+    let mut slots = if !root {
+        2
+    } else if label {
+        1
+    } else {
+        0
+    };
+    for (name, short) in tree.entries.keys().zip(short_names(tree)?) {
+        slots += DirEntryBuilder::new(name).short_name(short).build()?.len();
+    }
+    // Every directory takes a cluster, even an empty root.
+    Ok(cmp::max(slots, 1) as u64 * DIR_ENTRY_SIZE as u64)
+}
+
+/// Writes a freshly laid out volume: its directories and files in cluster
+/// order, recording their chains in an in-memory FAT.
+struct Writer<'a, T: BlockDevice + 'a> {
+    device: &'a mut T,
+    start: u64,
+    geometry: &'a Geometry,
+    timestamp: Timestamp,
+    /// The FAT entries from cluster 0 up to `fat.len()`, the next free
+    /// cluster. Every cluster past it is free.
+    fat: Vec<u32>,
+}
+
+impl<'a, T: BlockDevice> Writer<'a, T> {
+    /// Allocates a contiguous chain of clusters for `bytes` bytes and
+    /// returns its first cluster, or cluster 0 if `bytes` is 0.
+    fn allocate(&mut self, bytes: u64) -> io::Result<Cluster> {
+        let count = (bytes + self.geometry.cluster_bytes() - 1) / self.geometry.cluster_bytes();
+        if count == 0 {
+            return Ok(Cluster(0));
+        }
+
+        let first = self.fat.len() as u64;
+        if first - 2 + count > self.geometry.clusters {
+            return Err(invalid_input("files do not fit on the volume"));
+        }
+        for cluster in first..first + count - 1 {
+            self.fat.push(cluster as u32 + 1);
+        }
+        self.fat.push(EOC_MARKER);
+        Ok(Cluster(first as u32))
+    }
+
+    /// Writes `data` from the start of the chain at `first`, zeroing the
+    /// rest of its last sector.
+    fn write_data(&mut self, first: Cluster, data: &[u8]) -> io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let sector_size = self.geometry.sector_size as usize;
+        let sector = self.start
+            + self.geometry.data_start()
+            + (first.0 - 2) as u64 * self.geometry.sectors_per_cluster;
+        let whole = data.len() / sector_size * sector_size;
+        self.device.write_sectors(sector, &data[..whole])?;
+        if whole < data.len() {
+            let mut last = vec![0; sector_size];
+            last[..data.len() - whole].copy_from_slice(&data[whole..]);
+            self.device
+                .write_sector(sector + (whole / sector_size) as u64, &last)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the directory holding `tree`, whose chain begins at `cluster`
+    /// and whose parent's begins at `parent`, then everything beneath it.
+    /// The root directory is written with `label`, if given, and without
+    /// dot entries.
+    fn write_dir(
+        &mut self,
+        tree: &Tree,
+        cluster: Cluster,
+        parent: Cluster,
+        label: Option<[u8; 11]>,
+    ) -> io::Result<()> {
+        let root = cluster.0 == ROOT_CLUSTER;
+        let timestamp = self.timestamp;
+        let mut slots: Vec<[u8; 32]> = Vec::new();
+        if root {
+            if let Some(label) = label {
+                let mut entry = [0u8; 32];
+                entry[..11].copy_from_slice(&label);
+                entry[11] = ATTR_VOLUME_ID;
+                LittleEndian::write_u16(&mut entry[22..24], timestamp.time.raw());
+                LittleEndian::write_u16(&mut entry[24..26], timestamp.date.raw());
+                slots.push(entry);
+            }
+        } else {
+            let parent = if parent.0 == ROOT_CLUSTER {
+                Cluster(0)
+            } else {
+                parent
+            };
+            slots.push(dot_entry(b".          ", cluster));
+            slots.push(dot_entry(b"..         ", parent));
+        }
+
+        // Place every entry before writing any of them, so that each
+        // directory precedes everything beneath it.
+        let mut subdirs = Vec::new();
+        let shorts = short_names(tree)?;
+        for ((name, node), short) in tree.entries.iter().zip(shorts) {
+            let builder = DirEntryBuilder::new(name).short_name(short).timestamps(
+                timestamp,
+                timestamp.date,
+                timestamp,
+            );
+            let builder = match *node {
+                Node::File(ref data) => {
+                    let first = self.allocate(data.len() as u64)?;
+                    self.write_data(first, data)?;
+                    builder.cluster(first.0).size(data.len() as u32)
+                }
+                Node::Dir(ref dir) => {
+                    let first = self.allocate(dir_bytes(dir, false, false)?)?;
+                    subdirs.push((dir, first));
+                    builder
+                        .cluster(first.0)
+                        .attributes(Attributes(ATTR_DIRECTORY))
+                }
+            };
+            slots.extend(builder.build()?);
+        }
+
+        let size = dir_bytes(tree, root, label.is_some())?;
+        let cluster_bytes = self.geometry.cluster_bytes();
+        let mut data =
+            vec![0; ((size + cluster_bytes - 1) / cluster_bytes * cluster_bytes) as usize];
+        for (i, slot) in slots.iter().enumerate() {
+            data[i * DIR_ENTRY_SIZE..(i + 1) * DIR_ENTRY_SIZE].copy_from_slice(slot);
+        }
+        self.write_data(cluster, &data)?;
+
+        for (dir, first) in subdirs {
+            self.write_dir(dir, first, cluster, None)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes `count` zeroed sectors to `device` from `sector`.
+fn zero_sectors<T: BlockDevice>(device: &mut T, sector: u64, count: u64) -> io::Result<()> {
+    let sector_size = device.sector_size();
+    let zeroes = vec![0; (cmp::min(count, ZERO_CHUNK_SECTORS) * sector_size) as usize];
+    let mut done = 0;
+    while done < count {
+        let chunk = cmp::min(count - done, ZERO_CHUNK_SECTORS);
+        device.write_sectors(sector + done, &zeroes[..(chunk * sector_size) as usize])?;
+        done += chunk;
+    }
+    Ok(())
+}
+
+/// Formats the `sectors` sectors of `device` beginning at `start` as a
+/// FAT32 volume holding `tree`, and returns the volume's number of data
+/// clusters. The volume's logical sectors are the device's sectors. The
+/// partition table is not touched; see `build_image()` to write one too.
+///
+/// The volume has 32 reserved sectors, two FATs, a backup boot sector, and
+/// an FSInfo sector recording its free space. Directories and files are
+/// written contiguously from the start of the data region, each directory
+/// before the entries beneath it. Names that do not fit in 8.3 get long
+/// names and numeric-tail aliases.
+///
+/// # Errors
+///
+/// Returns an error kind of `InvalidInput` if the device's sector size is
+/// not 512 to 4096 bytes, if `options` asks for an invalid cluster size or
+/// label, if the volume is too small or too large for FAT32, or if `tree`
+/// does not fit on it. Returns any I/O error writing to `device`.
+pub fn format<T: BlockDevice>(
+    device: &mut T,
+    start: u64,
+    sectors: u64,
+    options: &FormatOptions,
+    tree: &Tree,
+) -> io::Result<u32> {
+    let geometry = Geometry::new(device.sector_size(), sectors, options)?;
+    if let Some(device_sectors) = device.num_sectors() {
+        if start + sectors > device_sectors {
+            return Err(invalid_input("volume does not fit on the device"));
+        }
+    }
+    let label = match options.label {
+        Some(ref label) => Some(encode_label(label)?),
+        None => None,
+    };
+
+    // Lay out and write the directories and files, then the FATs and the
+    // reserved sectors, so that a volume whose writes fail part way is not
+    // mistaken for a formatted one.
+    let fat = {
+        let mut writer = Writer {
+            device: &mut *device,
+            start,
+            geometry: &geometry,
+            timestamp: options.timestamp,
+            fat: vec![MEDIA_ENTRY_HIGH_BITS | MEDIA_DESCRIPTOR as u32, EOC_MARKER],
+        };
+        let root = writer.allocate(dir_bytes(tree, true, label.is_some())?)?;
+        writer.write_dir(tree, root, root, label)?;
+        writer.fat
+    };
+
+    let sector_size = geometry.sector_size as usize;
+    let entries_per_sector = sector_size / FAT_ENTRY_SIZE;
+    for copy in 0..NUM_FATS {
+        let fat_start = start + RESERVED_SECTORS + copy * geometry.sectors_per_fat;
+        let mut written = 0;
+        for entries in fat.chunks(entries_per_sector) {
+            let mut sector = vec![0; sector_size];
+            LittleEndian::write_u32_into(entries, &mut sector[..entries.len() * 4]);
+            device.write_sector(fat_start + written, &sector)?;
+            written += 1;
+        }
+        zero_sectors(
+            device,
+            fat_start + written,
+            geometry.sectors_per_fat - written,
+        )?;
+    }
+
+    let used = fat.len() as u64 - 2;
+    let mut info = vec![0; sector_size];
+    LittleEndian::write_u32(&mut info[0..4], FSINFO_LEAD_SIGNATURE);
+    LittleEndian::write_u32(&mut info[484..488], FSINFO_STRUCT_SIGNATURE);
+    LittleEndian::write_u32(&mut info[488..492], (geometry.clusters - used) as u32);
+    let next_free = if used < geometry.clusters {
+        fat.len() as u32
+    } else {
+        ::std::u32::MAX
+    };
+    LittleEndian::write_u32(&mut info[492..496], next_free);
+    LittleEndian::write_u32(&mut info[508..512], FSINFO_TRAIL_SIGNATURE);
+
+    let boot = boot_sector(&geometry, start, sectors, options.volume_id, label);
+    zero_sectors(device, start, RESERVED_SECTORS)?;
+    for &base in &[0, BACKUP_BOOT_SECTOR] {
+        device.write_sector(start + base + FS_INFO_SECTOR, &info)?;
+        device.write_sector(start + base, &boot)?;
+    }
+
+    Ok(geometry.clusters as u32)
+}
+
+/// Encodes the boot sector of a volume laid out as `geometry`.
+fn boot_sector(
+    geometry: &Geometry,
+    start: u64,
+    sectors: u64,
+    volume_id: u32,
+    label: Option<[u8; 11]>,
+) -> Vec<u8> {
+    let mut boot = vec![0; geometry.sector_size as usize];
+    boot[0..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+    boot[3..11].copy_from_slice(b"MSWIN4.1");
+    LittleEndian::write_u16(&mut boot[11..13], geometry.sector_size as u16);
+    boot[13] = geometry.sectors_per_cluster as u8;
+    LittleEndian::write_u16(&mut boot[14..16], RESERVED_SECTORS as u16);
+    boot[16] = NUM_FATS as u8;
+    boot[21] = MEDIA_DESCRIPTOR;
+    LittleEndian::write_u16(&mut boot[24..26], 63);
+    LittleEndian::write_u16(&mut boot[26..28], 255);
+    LittleEndian::write_u32(&mut boot[28..32], start as u32);
+    LittleEndian::write_u32(&mut boot[32..36], sectors as u32);
+    LittleEndian::write_u32(&mut boot[36..40], geometry.sectors_per_fat as u32);
+    LittleEndian::write_u32(&mut boot[44..48], ROOT_CLUSTER);
+    LittleEndian::write_u16(&mut boot[48..50], FS_INFO_SECTOR as u16);
+    LittleEndian::write_u16(&mut boot[50..52], BACKUP_BOOT_SECTOR as u16);
+    boot[64] = 0x80;
+    boot[66] = 0x29;
+    LittleEndian::write_u32(&mut boot[67..71], volume_id);
+    boot[71..82].copy_from_slice(&label.unwrap_or(*b"NO NAME    "));
+    boot[82..90].copy_from_slice(b"FAT32   ");
+    boot[510..512].copy_from_slice(&BOOT_SIGNATURE);
+    boot
+}
+
+/// A partition of an image built by `build_image()`.
+#[derive(Debug, Clone)]
+pub struct PartitionLayout {
+    /// The size of the partition in sectors, or `None` for the rest of the
+    /// device, which only the last partition may take.
+    pub sectors: Option<u64>,
+    /// The MBR partition type. Partitions of type `FAT32_LBA_PARTITION` or
+    /// `FAT32_CHS_PARTITION` are formatted and filled with `tree`; others
+    /// are only entered in the partition table.
+    pub partition_type: u8,
+    /// Whether the partition is marked active.
+    pub bootable: bool,
+    /// The options the partition is formatted with.
+    pub format: FormatOptions,
+    /// The files and directories written onto the partition.
+    pub tree: Tree,
+}
+
+impl PartitionLayout {
+    /// Returns the layout of a FAT32 partition of `sectors` sectors, or the
+    /// rest of the device, holding `tree`.
+    pub fn fat32(sectors: Option<u64>, tree: Tree) -> PartitionLayout {
+        PartitionLayout {
+            sectors,
+            partition_type: FAT32_LBA_PARTITION,
+            bootable: false,
+            format: FormatOptions::default(),
+            tree,
+        }
+    }
+
+    fn is_fat32(&self) -> bool {
+        self.partition_type == FAT32_LBA_PARTITION || self.partition_type == FAT32_CHS_PARTITION
+    }
+}
+
+/// The layout of an image built by `build_image()`.
+#[derive(Debug, Clone, Default)]
+pub struct ImageLayout {
+    /// The disk signature recorded in the MBR. Defaults to `0`.
+    pub disk_id: u32,
+    /// Up to four primary partitions, placed in order on 1 MiB boundaries.
+    pub partitions: Vec<PartitionLayout>,
+}
+
+/// Writes a complete image onto `device`: every FAT32 partition of `layout`
+/// formatted with `format()` and filled with its tree, then an MBR whose
+/// partition table describes them all. Returns the first sector and the
+/// number of sectors of each partition, in order.
+///
+/// The MBR is written last, so a device whose build fails part way does not
+/// look partitioned. Its bootstrap area is zeroed; see
+/// `MasterBootRecord::install_bootstrap()`.
+///
+/// # Errors
+///
+/// Returns an error kind of `InvalidInput` if `layout` has more than four
+/// partitions, if a partition is empty, if a partition other than the last
+/// has no size, if the partitions do not fit on `device`, or as `format()`.
+/// Returns any I/O error writing to `device`.
+pub fn build_image<T: BlockDevice>(
+    device: &mut T,
+    layout: &ImageLayout,
+) -> io::Result<Vec<(u64, u64)>> {
+    if layout.partitions.len() > 4 {
+        return Err(invalid_input("an MBR holds at most four partitions"));
+    }
+
+    let sector_size = device.sector_size();
+    let alignment = cmp::max(1, PARTITION_ALIGNMENT / sector_size);
+    let device_sectors = device.num_sectors();
+    let mut placed = Vec::with_capacity(layout.partitions.len());
+    let mut start = alignment;
+    for (i, partition) in layout.partitions.iter().enumerate() {
+        let sectors = match (partition.sectors, device_sectors) {
+            (Some(sectors), _) => sectors,
+            (None, Some(total)) if i + 1 == layout.partitions.len() => total.saturating_sub(start),
+            (None, _) => {
+                return Err(invalid_input(
+                    "only the last partition of a device of known size may fill it",
+                ))
+            }
+        };
+        if sectors == 0 {
+            return Err(invalid_input("partition is empty"));
+        }
+        if device_sectors.map_or(false, |total| start + sectors > total)
+            || start + sectors > ::std::u32::MAX as u64
+        {
+            return Err(invalid_input("partitions do not fit on the device"));
+        }
+        placed.push((start, sectors));
+        start = (start + sectors + alignment - 1) / alignment * alignment;
+    }
+
+    for (partition, &(start, sectors)) in layout.partitions.iter().zip(&placed) {
+        if partition.is_fat32() {
+            format(device, start, sectors, &partition.format, &partition.tree)?;
+        }
+    }
+
+    let mut mbr = vec![0; sector_size as usize];
+    LittleEndian::write_u32(&mut mbr[440..444], layout.disk_id);
+    for (i, (partition, &(start, sectors))) in layout.partitions.iter().zip(&placed).enumerate() {
+        let entry = &mut mbr[446 + i * 16..446 + (i + 1) * 16];
+        entry[0] = if partition.bootable { 0x80 } else { 0x00 };
+        // CHS addresses past the first 8 GiB are given as the maximum, and
+        // every reader of these images uses the LBA fields.
+        entry[1..4].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+        entry[4] = partition.partition_type;
+        entry[5..8].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+        LittleEndian::write_u32(&mut entry[8..12], start as u32);
+        LittleEndian::write_u32(&mut entry[12..16], sectors as u32);
+    }
+    let end = sector_size as usize;
+    mbr[end - 2..].copy_from_slice(&BOOT_SIGNATURE);
+    device.write_sector(0, &mbr)?;
+
+    Ok(placed)
+}
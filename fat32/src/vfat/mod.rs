@@ -1,24 +1,83 @@
-pub(crate) mod cache;
+pub(crate) mod bitmap;
+pub(crate) mod clone;
 pub(crate) mod cluster;
+pub(crate) mod collisions;
+pub(crate) mod copy;
+pub(crate) mod crc32;
+pub(crate) mod data_cache;
 pub(crate) mod dir;
+pub(crate) mod dir_cache;
+#[cfg(any(test, feature = "fuzz"))]
+pub mod dir_corpus;
+pub(crate) mod dir_index;
+pub(crate) mod dir_source;
 pub(crate) mod ebpb;
 pub(crate) mod entry;
+pub(crate) mod entry_builder;
 pub(crate) mod error;
+pub(crate) mod extent;
 pub(crate) mod fat;
 pub(crate) mod file;
+pub(crate) mod integrity;
+pub(crate) mod lock;
+pub(crate) mod manifest;
+pub(crate) mod memory;
 pub(crate) mod metadata;
+pub(crate) mod metrics;
+pub mod mkfs;
+pub(crate) mod mount_table;
+pub(crate) mod options;
+pub(crate) mod protect;
+pub(crate) mod repair;
+pub(crate) mod report;
+pub mod resize;
+pub mod search;
+pub(crate) mod sha256;
 pub(crate) mod shared;
+pub(crate) mod short_name;
+pub(crate) mod sparse;
+pub(crate) mod tar;
 pub(crate) mod vfat;
+pub(crate) mod walk;
+pub(crate) mod watch;
+pub mod xattr;
 
-pub use self::dir::Dir;
+pub use self::bitmap::SectorBitmap;
+pub use self::clone::clone_volume;
+pub use self::cluster::Cluster;
+pub use self::collisions::NameCollision;
+pub use self::copy::{extract, CancelToken};
+pub use self::dir::{
+    Dir, DirIter, EntryMode, IterOptions, LfnProblem, OrphanedLfn, UnpairedSurrogates,
+};
+pub use self::dir_index::INDEX_NAME;
+#[cfg(feature = "raw")]
 pub use self::ebpb::BiosParameterBlock;
-pub use self::entry::Entry;
+pub use self::ebpb::BOOT_CODE_SIZE;
+pub use self::entry::{Entry, EntryId, EntryKind, EntryLocation};
+pub use self::entry_builder::DirEntryBuilder;
 pub use self::error::Error;
-pub use self::file::File;
+pub use self::extent::{Extent, FreeSpace};
+pub use self::fat::{FatEntries, FatEntry, FatHeader, FatMode, Status};
+pub use self::file::{Advice, ClusterlessFile, File};
+pub use self::integrity::{Digest, HashTree, HASH_TREE_SUFFIX};
+pub use self::lock::{FileLock, LockKind};
+pub use self::manifest::{
+    apply_manifest, FileDiff, Manifest, ManifestChange, ManifestDiff, ManifestEntry,
+};
+pub use self::memory::{MemoryBudget, MemoryUsage, DIR_CACHE_ENTRY_BYTES};
 pub use self::metadata::{Attributes, Date, Metadata, Time, Timestamp};
+pub use self::metrics::{IoMetrics, IoOp, LatencyHistogram, SlowIoHook};
+pub use self::mount_table::{
+    MountEntry, MountError, MountFailure, PartitionSelector, VolumeManager,
+};
+pub use self::options::{DeterministicOptions, DirTimestamps, GeometryCheck, MountOptions};
+pub use self::repair::{RepairAction, RepairOptions};
+pub use self::report::MountReport;
 pub use self::shared::Shared;
+pub use self::short_name::{lfn_checksum, short_name_for, validate_long_name, ShortNameStrategy};
+pub use self::sparse::restore_sparse;
+pub use self::tar::apply_tar;
 pub use self::vfat::VFat;
-
-pub(crate) use self::cache::{CachedDevice, Partition};
-pub(crate) use self::cluster::Cluster;
-pub(crate) use self::fat::{FatEntry, Status};
+pub use self::walk::{AllFiles, FileExtents};
+pub use self::watch::{Event, WatchCallback, WatchId};
@@ -0,0 +1,263 @@
+//! Mounting volumes from a declarative table, as `/etc/fstab` does.
+
+use std::{error, fmt, io};
+
+#[cfg(feature = "no_std")]
+use path::{Path, PathBuf};
+#[cfg(not(feature = "no_std"))]
+use std::path::{Path, PathBuf};
+
+use traits::BlockDevice;
+use vfat::vfat::normal_components;
+use vfat::{Error, MountOptions, Shared, VFat};
+
+/// Which volume on a device a `MountEntry` mounts.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PartitionSelector {
+    /// The first FAT32 partition, as `VFat::from()` mounts.
+    FirstFat,
+    /// The volume in this entry (from 0 to 3) of the partition table,
+    /// whatever the entry's partition type.
+    Index(usize),
+    /// The first partition whose volume has this volume id (serial number).
+    /// Each non-empty entry of the partition table is tried in turn, opening
+    /// the device once per attempt.
+    VolumeId(u32),
+}
+
+/// A volume to mount: one line of a mount table.
+#[derive(Debug, Clone)]
+pub struct MountEntry {
+    /// The device holding the volume, as understood by the opener passed to
+    /// `VolumeManager::from_table()`.
+    pub device: String,
+    pub partition: PartitionSelector,
+    /// The options the volume is mounted with. `MountOptions::partition` is
+    /// set from `partition`.
+    pub options: MountOptions,
+    /// The absolute path the volume's root directory appears at.
+    pub prefix: PathBuf,
+}
+
+/// Why an entry of a mount table was not mounted.
+#[derive(Debug)]
+pub enum MountError {
+    /// The entry's mount point is not absolute.
+    BadPrefix,
+    /// An earlier entry is already mounted at the entry's mount point.
+    PrefixInUse,
+    /// The entry's device could not be opened.
+    Device(io::Error),
+    /// No volume on the device matches the entry's `PartitionSelector`.
+    NoPartition,
+    /// The volume was found but failed to mount.
+    Volume(Error),
+}
+
+impl fmt::Display for MountError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MountError::BadPrefix => write!(f, "mount point is not absolute"),
+            MountError::PrefixInUse => write!(f, "mount point is already in use"),
+            MountError::Device(error) => write!(f, "cannot open device: {}", error),
+            MountError::NoPartition => write!(f, "no partition matches"),
+            MountError::Volume(error) => write!(f, "cannot mount volume: {}", error),
+        }
+    }
+}
+
+impl error::Error for MountError {}
+
+/// An entry of a mount table that was not mounted, as returned by
+/// `VolumeManager::from_table()`.
+#[derive(Debug)]
+pub struct MountFailure {
+    /// The entry's index in the table.
+    pub index: usize,
+    pub device: String,
+    pub prefix: PathBuf,
+    pub error: MountError,
+}
+
+impl fmt::Display for MountFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "mount table entry {} ({} at {:?}): {}",
+            self.index, self.device, self.prefix, self.error
+        )
+    }
+}
+
+impl error::Error for MountFailure {}
+
+/// The volumes mounted at each mount point of a single directory tree.
+#[derive(Debug, Default)]
+pub struct VolumeManager {
+    /// The mounted volumes and their mount points, in mount order.
+    mounts: Vec<(PathBuf, Shared<VFat>)>,
+}
+
+impl VolumeManager {
+    /// Returns a manager with no volumes mounted.
+    pub fn new() -> VolumeManager {
+        VolumeManager::default()
+    }
+
+    /// Mounts every entry of `table`, in order, and returns the manager
+    /// holding the volumes mounted along with the entries that were not.
+    /// A failed entry does not stop the entries after it.
+    ///
+    /// `open` is called with an entry's `MountEntry::device` to open the
+    /// device holding it, once per volume tried.
+    pub fn from_table<T, F>(table: &[MountEntry], mut open: F) -> (VolumeManager, Vec<MountFailure>)
+    where
+        T: BlockDevice + 'static,
+        F: FnMut(&str) -> io::Result<T>,
+    {
+        let mut manager = VolumeManager::new();
+        let mut failures = Vec::new();
+        for (index, entry) in table.iter().enumerate() {
+            let result = manager
+                .check_prefix(&entry.prefix)
+                .and_then(|_| mount_entry(entry, &mut open))
+                .and_then(|volume| manager.mount(&entry.prefix, volume));
+            if let Err(error) = result {
+                failures.push(MountFailure {
+                    index,
+                    device: entry.device.clone(),
+                    prefix: entry.prefix.clone(),
+                    error,
+                });
+            }
+        }
+        (manager, failures)
+    }
+
+    /// Mounts `volume` at `prefix`, which must be absolute and not already
+    /// in use. Mount points may nest: a path is resolved on the volume with
+    /// the deepest mount point above it.
+    ///
+    /// # Errors
+    ///
+    /// Returns `MountError::BadPrefix` if `prefix` is not absolute and
+    /// `MountError::PrefixInUse` if a volume is already mounted at it.
+    pub fn mount<P: AsRef<Path>>(
+        &mut self,
+        prefix: P,
+        volume: Shared<VFat>,
+    ) -> Result<(), MountError> {
+        let prefix = prefix.as_ref();
+        self.check_prefix(prefix)?;
+        self.mounts.push((prefix.to_path_buf(), volume));
+        Ok(())
+    }
+
+    /// Unmounts and returns the volume mounted at `prefix`, if any. The
+    /// volume is not synced.
+    pub fn unmount<P: AsRef<Path>>(&mut self, prefix: P) -> Option<Shared<VFat>> {
+        let names = normal_components(prefix.as_ref());
+        let position = self
+            .mounts
+            .iter()
+            .position(|&(ref mounted, _)| normal_components(mounted) == names)?;
+        Some(self.mounts.remove(position).1)
+    }
+
+    /// Returns the volume holding `path` and the absolute path on that
+    /// volume it refers to, or `None` if no mount point lies above `path`.
+    pub fn resolve<P: AsRef<Path>>(&self, path: P) -> Option<(&Shared<VFat>, PathBuf)> {
+        let names = normal_components(path.as_ref());
+        let mut best: Option<(usize, &Shared<VFat>)> = None;
+        for &(ref prefix, ref volume) in &self.mounts {
+            let prefix = normal_components(prefix);
+            let deeper = best.map_or(true, |(depth, _)| prefix.len() > depth);
+            if deeper && names.starts_with(&prefix) {
+                best = Some((prefix.len(), volume));
+            }
+        }
+
+        let (depth, volume) = best?;
+        let mut rest = PathBuf::from("/");
+        for name in &names[depth..] {
+            rest.push(name);
+        }
+        Some((volume, rest))
+    }
+
+    /// Returns the mounted volumes and their mount points, in mount order.
+    pub fn mounts(&self) -> impl Iterator<Item = (&Path, &Shared<VFat>)> {
+        self.mounts
+            .iter()
+            .map(|&(ref prefix, ref volume)| (prefix.as_ref(), volume))
+    }
+
+    /// Syncs every mounted volume, returning the first error after trying
+    /// them all.
+    pub fn sync_all(&self) -> io::Result<()> {
+        let mut result = Ok(());
+        for &(_, ref volume) in &self.mounts {
+            let synced = volume.borrow_mut().sync();
+            if result.is_ok() {
+                result = synced;
+            }
+        }
+        result
+    }
+
+    fn check_prefix(&self, prefix: &Path) -> Result<(), MountError> {
+        if !prefix.is_absolute() {
+            return Err(MountError::BadPrefix);
+        }
+        let names = normal_components(prefix);
+        if self
+            .mounts
+            .iter()
+            .any(|&(ref mounted, _)| normal_components(mounted) == names)
+        {
+            return Err(MountError::PrefixInUse);
+        }
+        Ok(())
+    }
+}
+
+/// Opens `entry`'s device with `open` and mounts the volume its selector
+/// picks.
+fn mount_entry<T, F>(entry: &MountEntry, open: &mut F) -> Result<Shared<VFat>, MountError>
+where
+    T: BlockDevice + 'static,
+    F: FnMut(&str) -> io::Result<T>,
+{
+    let attempt = |open: &mut F, partition: Option<usize>| {
+        let device = open(&entry.device).map_err(MountError::Device)?;
+        let mut options = entry.options.clone();
+        options.partition = partition;
+        match VFat::with_options(device, options) {
+            Ok(volume) => Ok(volume),
+            Err(Error::NotFound) => Err(MountError::NoPartition),
+            Err(error) => Err(MountError::Volume(error)),
+        }
+    };
+
+    match entry.partition {
+        PartitionSelector::FirstFat => attempt(open, None),
+        PartitionSelector::Index(index) => attempt(open, Some(index)),
+        PartitionSelector::VolumeId(id) => {
+            for index in 0..4 {
+                match attempt(open, Some(index)) {
+                    Ok(volume) => {
+                        if volume.borrow().volume_id() == id {
+                            return Ok(volume);
+                        }
+                    }
+                    // Failing to open the device fails every attempt.
+                    Err(error @ MountError::Device(_)) => return Err(error),
+                    // Entries that are empty or hold no FAT volume are
+                    // skipped.
+                    Err(_) => {}
+                }
+            }
+            Err(MountError::NoPartition)
+        }
+    }
+}
@@ -0,0 +1,236 @@
+#[cfg(feature = "no_std")]
+use path::PathBuf;
+#[cfg(not(feature = "no_std"))]
+use std::path::PathBuf;
+
+use vfat::{
+    ClusterlessFile, EntryMode, FatMode, MemoryBudget, ShortNameStrategy, Timestamp,
+    UnpairedSurrogates,
+};
+
+/// Options controlling how a volume is interpreted once mounted.
+#[derive(Debug, Clone)]
+pub struct MountOptions {
+    /// If `Some(n)`, the volume in entry `n` (from 0 to 3) of the partition
+    /// table is mounted, whatever the entry's partition type, rather than the
+    /// first FAT32 partition. Mounting fails with `Error::NotFound` if the
+    /// entry does not exist or is empty. Defaults to `None`.
+    pub partition: Option<usize>,
+    /// The offset of the volume's local time from UTC, in minutes.
+    ///
+    /// FAT timestamps are recorded in local time with no zone. This offset is
+    /// applied when converting timestamps to `SystemTime` and when stamping
+    /// new entries. For example, UTC-8 is `-480`. Defaults to `0`.
+    pub timezone_offset: i16,
+    /// How 8.3 aliases are generated for new entries whose names do not fit
+    /// in 8.3. Defaults to `ShortNameStrategy::NumericTail`.
+    pub short_name_strategy: ShortNameStrategy,
+    /// If `Some(n)`, a directory holding at least `n` deleted entry slots is
+    /// compacted with `Dir::compact()` before a file is created in it. This
+    /// crate removes no entries itself, so the deleted slots are those left
+    /// by other FAT implementations. See `Dir::compact_if_needed()`. Defaults
+    /// to `None`.
+    pub auto_compact_threshold: Option<usize>,
+    /// How strictly FAT entries are checked while following cluster chains.
+    /// In strict mode, mounting also fails with `Error::MediaMismatch` if the
+    /// FAT's media descriptor differs from the BPB's; see
+    /// `VFat::media_warning()`. Defaults to `FatMode::Tolerant`.
+    pub fat_mode: FatMode,
+    /// How strictly directory entries are checked while listing directories.
+    /// Defaults to `EntryMode::Tolerant`.
+    pub entry_mode: EntryMode,
+    /// How unpaired UTF-16 surrogates in long names are decoded. Defaults to
+    /// `UnpairedSurrogates::Underscore`.
+    pub unpaired_surrogates: UnpairedSurrogates,
+    /// If `true`, the clean shutdown bit of the second FAT entry is cleared on
+    /// disk before the first change after mounting or after a `sync()`, and
+    /// restored once `sync()` has written every change, so that a crash in
+    /// between leaves the volume marked for checking by chkdsk or fsck. A
+    /// failed write to the device clears the entry's no hard error bit. Costs
+    /// two extra writes of the first FAT sector each time changes are
+    /// synced. Defaults to `false`.
+    pub mark_dirty: bool,
+    /// If `true`, the sectors before the data region (the partition table,
+    /// the reserved sectors, and the FATs) are guarded in the sector cache so
+    /// that only the code maintaining them can modify them. A write to them
+    /// by any other path, such as `VFat::write_raw_sector()` or a cluster
+    /// write sent astray by a bug, fails with an error of kind
+    /// `PermissionDenied`, and panics in debug builds. See
+    /// `CachedDevice::guard()`. Defaults to `false`.
+    pub guard_metadata: bool,
+    /// How files recording a size but no first cluster are read. Defaults to
+    /// `ClusterlessFile::Empty`.
+    pub clusterless_files: ClusterlessFile,
+    /// If `true`, `open_file()` walks a file's cluster chain before
+    /// returning it, failing with `Error::CorruptChain` if the chain is too
+    /// short for the file's size or runs through a free, reserved, or bad
+    /// cluster. Defaults to `false`.
+    pub validate_on_open: bool,
+    /// The largest size, in bytes, that writes may grow a file to; writes
+    /// past it fail with `Error::FileTooLarge`. Defaults to, and cannot
+    /// usefully exceed, `u32::max_value()`, 4 GiB less one byte, the largest
+    /// size a directory entry can record.
+    pub max_file_size: u32,
+    /// The free clusters kept back from file writes: a write that would
+    /// leave fewer free fails with an error of kind `Other`, and nothing is
+    /// written. Directory upkeep and `VFat::repair()` may still use them.
+    /// Keeps runaway logging from filling a volume that must stay bootable.
+    /// Defaults to `0`.
+    pub reserved_clusters: u32,
+    /// Paths whose entries, and everything beneath them, cannot be changed:
+    /// writes to their files, changes to their extended attributes, and
+    /// compaction of their directories fail with an error of kind
+    /// `PermissionDenied`. Paths that do not exist protect nothing.
+    /// For example, `/boot`. Defaults to none.
+    pub protected_paths: Vec<PathBuf>,
+    /// If `true`, the volume is mounted read-only even if the device is
+    /// writable: every change fails with an error of kind `PermissionDenied`.
+    /// Devices that are not `BlockDevice::writable()` are always mounted
+    /// read-only. Defaults to `false`.
+    pub read_only: bool,
+    /// What to do when the BPB declares more sectors than the partition or
+    /// device holds. Defaults to `GeometryCheck::Clamp`.
+    pub geometry_check: GeometryCheck,
+    /// If `true`, new entries are only created with names Windows accepts.
+    /// See `validate_long_name()`. Defaults to `true`.
+    pub windows_compat: bool,
+    /// The most bytes of whole-file data kept loaded for sharing between
+    /// `File` handles of the same file. Data still in use by a handle is kept
+    /// regardless. `0` disables sharing. Defaults to 1 MiB.
+    pub file_data_cache_bytes: usize,
+    /// The most directories remembered by path to speed up opening entries
+    /// beneath them. `0` disables the cache. Defaults to `64`.
+    pub dir_cache_entries: usize,
+    /// If `Some`, the sector cache, whole-file data, and directory cache are
+    /// sized from one cap on their memory, overriding
+    /// `file_data_cache_bytes` and `dir_cache_entries`; see `MemoryBudget`.
+    /// If `None`, the sector cache is unbounded. Can be changed after
+    /// mounting with `VFat::set_memory_budget()`. Defaults to `None`.
+    pub memory_budget: Option<MemoryBudget>,
+    /// The most bytes of data bulk transfers, `extract()`, `apply_tar()`,
+    /// and `VFat::clone_to()`, hold in memory at once, rounded down to whole
+    /// clusters and at least one cluster. `None` for one cluster. Together
+    /// with `memory_budget`, bounds the memory a transfer takes regardless
+    /// of the size of the data. Defaults to `None`.
+    pub transfer_chunk_size: Option<usize>,
+    /// If `Some(n)`, sectors are read with `BlockDevice::read_sector_checked()`
+    /// and reads failing their checksum are retried up to `n` times; see
+    /// `IoMetrics::checksum_failures`. For devices on unreliable links.
+    /// Defaults to `None`.
+    pub checksum_retries: Option<u32>,
+    /// If `true`, the CRC-32 of each data cluster is recorded the first time
+    /// the cluster is read, and checked whenever the cluster is read from
+    /// the device again after leaving the sector cache. A mismatch, with no
+    /// write to the cluster in between, fails the read with
+    /// `Error::BitRotDetected` as an error of kind `InvalidData`, catching
+    /// silent corruption on flash media. Checksums last until the cluster is
+    /// written or the medium changes, and cost about 24 bytes of memory per
+    /// cluster read. Defaults to `false`.
+    pub cluster_checksums: bool,
+    /// If `true`, directories holding a `DIRINDEX.SYS` sidecar are looked up
+    /// through the name index it holds, which is rebuilt when found stale.
+    /// For directories of many thousands of entries. Defaults to `false`.
+    pub dir_index: bool,
+    /// If `Some`, writes that would otherwise depend on the wall clock or on
+    /// earlier allocations are made reproducible, so that the same operations
+    /// on the same image always produce the same bytes. Defaults to `None`.
+    pub deterministic: Option<DeterministicOptions>,
+    /// When a directory's last modification time, recorded in the entry
+    /// naming it, is updated for changes to its entries. Defaults to
+    /// `DirTimestamps::OnCreateDelete`.
+    pub dir_timestamps: DirTimestamps,
+}
+
+/// Settings for reproducible image writes; see `MountOptions::deterministic`.
+///
+/// While set, clusters are allocated first-fit from the start of the data
+/// region rather than after the last allocation, and entries stamped by
+/// `apply_tar()` are given `timestamp` rather than their archived times.
+/// The volume id is left as found on the image.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DeterministicOptions {
+    /// The timestamp written to every entry stamped while set.
+    pub timestamp: Timestamp,
+}
+
+/// When a directory is stamped as modified for changes to its entries; see
+/// `MountOptions::dir_timestamps`. FAT implementations differ here: Windows
+/// and Linux update a directory's modification time when entries are created
+/// in or removed from it, while some embedded implementations never do.
+///
+/// The stamp is the current time, or the fixed timestamp of
+/// `MountOptions::deterministic` if set. Only the entry naming the directory
+/// in its parent is stamped, not its `.` entry, and the root directory,
+/// which has no entry, is never stamped. The directory's archive attribute
+/// is left as it is, as Windows does, so backup tools that select entries
+/// by archive bit are not sent to directories whose files have not changed.
+/// Directories protected by `MountOptions::protected_paths` are not stamped.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DirTimestamps {
+    /// Directories' modification times are never updated.
+    Never,
+    /// A directory is stamped when an entry is created in or removed from
+    /// it, such as by `FileSystem::create_file()`.
+    OnCreateDelete,
+    /// A directory is also stamped whenever one of its files' entries
+    /// changes, such as when a file is written to. Costs a read of the
+    /// directory and of its parent each time a file's size or first cluster
+    /// is updated.
+    Always,
+}
+
+impl Default for DirTimestamps {
+    fn default() -> DirTimestamps {
+        DirTimestamps::OnCreateDelete
+    }
+}
+
+/// How a mismatch between the BPB's sector count and the actual size of the
+/// partition or device is handled at mount.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum GeometryCheck {
+    /// Mount using the smaller size. The mismatch is available from
+    /// `VFat::geometry_warning()`.
+    Clamp,
+    /// Fail the mount with `Error::GeometryMismatch`.
+    Fail,
+}
+
+impl Default for GeometryCheck {
+    fn default() -> GeometryCheck {
+        GeometryCheck::Clamp
+    }
+}
+
+impl Default for MountOptions {
+    fn default() -> MountOptions {
+        MountOptions {
+            partition: None,
+            timezone_offset: 0,
+            short_name_strategy: ShortNameStrategy::default(),
+            auto_compact_threshold: None,
+            fat_mode: FatMode::default(),
+            entry_mode: EntryMode::default(),
+            unpaired_surrogates: UnpairedSurrogates::default(),
+            mark_dirty: false,
+            guard_metadata: false,
+            clusterless_files: ClusterlessFile::default(),
+            validate_on_open: false,
+            max_file_size: u32::max_value(),
+            reserved_clusters: 0,
+            protected_paths: Vec::new(),
+            read_only: false,
+            geometry_check: GeometryCheck::default(),
+            windows_compat: true,
+            file_data_cache_bytes: 1 << 20,
+            dir_cache_entries: 64,
+            memory_budget: None,
+            transfer_chunk_size: None,
+            checksum_retries: None,
+            cluster_checksums: false,
+            dir_index: false,
+            deterministic: None,
+            dir_timestamps: DirTimestamps::default(),
+        }
+    }
+}
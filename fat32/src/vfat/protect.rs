@@ -0,0 +1,100 @@
+//! The entries shielded from modification by `MountOptions::protected_paths`.
+
+use std::collections::HashSet;
+use std::io;
+
+#[cfg(feature = "no_std")]
+use path::{Path, PathBuf};
+#[cfg(not(feature = "no_std"))]
+use std::path::{Path, PathBuf};
+
+use vfat::dir::{lookup, ucs2_name, RawEntries};
+use vfat::vfat::normal_components;
+use vfat::{Cluster, EntryLocation, VFat};
+
+/// The entries at and beneath a volume's protected paths, resolved when
+/// first needed.
+#[derive(Debug, Default)]
+pub(crate) struct Protected {
+    /// The directories at or beneath a protected path, by first cluster.
+    /// None of their entries may change.
+    dirs: HashSet<Cluster>,
+    /// The entries naming the protected paths themselves.
+    entries: HashSet<EntryLocation>,
+}
+
+impl Protected {
+    /// Resolves `paths` on `vfat`, walking every directory beneath them.
+    /// Paths that do not exist protect nothing.
+    pub(crate) fn resolve(vfat: &mut VFat, paths: &[PathBuf]) -> io::Result<Protected> {
+        let mut protected = Protected::default();
+        for path in paths {
+            if let Some((location, dir)) = find(vfat, path)? {
+                protected.entries.extend(location);
+                if let Some(dir) = dir {
+                    protected.add_tree(vfat, dir)?;
+                }
+            }
+        }
+        Ok(protected)
+    }
+
+    /// Adds the directory at `dir_cluster` and every directory beneath it.
+    fn add_tree(&mut self, vfat: &mut VFat, dir_cluster: Cluster) -> io::Result<()> {
+        let mut pending = vec![dir_cluster];
+        self.dirs.insert(dir_cluster);
+        while let Some(dir_cluster) = pending.pop() {
+            let mut entries = RawEntries::read(vfat, dir_cluster)?;
+            while let Some(entry) = entries.next_entry() {
+                let name = &entry.metadata.name;
+                if !entry.is_dir() || name == "." || name == ".." {
+                    continue;
+                }
+                // A directory reached twice, through a loop, is walked once.
+                if entry.start_cluster.0 >= 2 && self.dirs.insert(entry.start_cluster) {
+                    pending.push(entry.start_cluster);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns `true` if the entry at `location` is protected, or lies in a
+    /// protected directory.
+    pub(crate) fn covers(&self, location: EntryLocation) -> bool {
+        self.entries.contains(&location) || self.dirs.contains(&location.dir_cluster)
+    }
+
+    /// Returns `true` if the directory at `dir_cluster` is protected.
+    pub(crate) fn covers_dir(&self, dir_cluster: Cluster) -> bool {
+        self.dirs.contains(&dir_cluster)
+    }
+}
+
+/// Finds the entry at `path`, returning its location, `None` for the root
+/// directory, and its first cluster if it is a directory. Returns `None` if
+/// there is no such entry.
+fn find(
+    vfat: &mut VFat,
+    path: &Path,
+) -> io::Result<Option<(Option<EntryLocation>, Option<Cluster>)>> {
+    let mut location = None;
+    let mut dir = Some(vfat.root_dir_cluster());
+    for name in normal_components(path) {
+        let (dir_cluster, ucs2) = match (dir, ucs2_name(name.as_ref())) {
+            (Some(dir_cluster), Some(ucs2)) => (dir_cluster, ucs2),
+            _ => return Ok(None),
+        };
+        let entry = match lookup(vfat, dir_cluster, &ucs2)? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        location = Some(entry.location);
+        dir = if entry.is_dir() {
+            Some(entry.start_cluster)
+        } else {
+            None
+        };
+    }
+    Ok(Some((location, dir)))
+}
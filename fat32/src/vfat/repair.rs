@@ -0,0 +1,518 @@
+//! Repair of common damage to a volume. See `VFat::repair()`.
+
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::io;
+
+use byteorder::{ByteOrder, LittleEndian};
+use consts::{ATTR_DIRECTORY, DELETED_ENTRY, DIR_ENTRY_SIZE, END_OF_ENTRIES, EOC_MARKER};
+use vfat::dir::{orphans_in, RawEntries};
+use vfat::{Attributes, Cluster, DirEntryBuilder, EntryLocation, OrphanedLfn, Status, VFat};
+
+/// The most lost chains saved into one `FOUND.nnn` directory.
+const MAX_RECLAIMED: usize = 10000;
+
+/// Which repairs `VFat::repair()` makes. Every repair is enabled by default.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RepairOptions {
+    /// End cluster chains that loop, run into another chain, or run into a
+    /// cluster that is free, reserved, bad, or outside of the volume at their
+    /// last sound cluster.
+    pub truncate_chains: bool,
+    /// Make each file's size agree with its cluster chain, freeing clusters
+    /// past the end of the size or shrinking the size to fit the chain.
+    pub fix_sizes: bool,
+    /// Save chains of clusters in use by no entry as `FILEnnnn.CHK` files in
+    /// a new `FOUND.nnn` directory in the root directory.
+    pub reclaim_lost_chains: bool,
+    /// Mark deleted the long file name entries that name no short entry. See
+    /// `Dir::orphaned_lfns()`.
+    pub remove_orphaned_lfns: bool,
+    /// Rewrite the free cluster count and next free cluster hint in the
+    /// FSInfo sector to match the FAT.
+    pub rebuild_fs_info: bool,
+}
+
+impl Default for RepairOptions {
+    fn default() -> RepairOptions {
+        RepairOptions {
+            truncate_chains: true,
+            fix_sizes: true,
+            reclaim_lost_chains: true,
+            remove_orphaned_lfns: true,
+            rebuild_fs_info: true,
+        }
+    }
+}
+
+/// A change made by `VFat::repair()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RepairAction {
+    /// The chain of the entry at `location`, or of the root directory if
+    /// `None`, was cut to its first `clusters` clusters.
+    TruncatedChain {
+        location: Option<EntryLocation>,
+        clusters: u32,
+        reason: &'static str,
+    },
+    /// The size of the file at `location` was changed to fit its chain.
+    FixedSize {
+        location: EntryLocation,
+        old: u32,
+        new: u32,
+    },
+    /// The lost chain beginning at `start` was saved as the file at `path`.
+    ReclaimedChain {
+        start: Cluster,
+        clusters: u32,
+        path: String,
+    },
+    /// The entries of an orphaned LFN run in the directory at `dir_cluster`
+    /// were marked deleted.
+    RemovedOrphanedLfn {
+        dir_cluster: Cluster,
+        orphan: OrphanedLfn,
+    },
+    /// The FSInfo sector was rewritten with these values.
+    RebuiltFsInfo {
+        free_clusters: u32,
+        next_free: Cluster,
+    },
+}
+
+impl fmt::Display for RepairAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RepairAction::TruncatedChain {
+                location,
+                clusters,
+                reason,
+            } => match location {
+                Some(location) => write!(
+                    f,
+                    "entry {}:{}: chain {}, cut to {} clusters",
+                    (location.dir_cluster).0,
+                    location.slot,
+                    reason,
+                    clusters
+                ),
+                None => write!(
+                    f,
+                    "root directory: chain {}, cut to {} clusters",
+                    reason, clusters
+                ),
+            },
+            RepairAction::FixedSize { location, old, new } => write!(
+                f,
+                "entry {}:{}: size changed from {} to {} bytes",
+                (location.dir_cluster).0,
+                location.slot,
+                old,
+                new
+            ),
+            RepairAction::ReclaimedChain {
+                start,
+                clusters,
+                ref path,
+            } => write!(
+                f,
+                "lost chain at cluster {} ({} clusters) saved as {}",
+                start.0, clusters, path
+            ),
+            RepairAction::RemovedOrphanedLfn {
+                dir_cluster,
+                ref orphan,
+            } => write!(
+                f,
+                "directory {}: removed slots {}..{} ({:?})",
+                dir_cluster.0, orphan.slots.start, orphan.slots.end, orphan.problem
+            ),
+            RepairAction::RebuiltFsInfo {
+                free_clusters,
+                next_free,
+            } => write!(
+                f,
+                "FSInfo: {} free clusters, next free {:#X}",
+                free_clusters, next_free.0
+            ),
+        }
+    }
+}
+
+/// The state of one repair pass.
+struct Repair<'a> {
+    vfat: &'a mut VFat,
+    options: RepairOptions,
+    /// Clusters belonging to a chain that has been followed.
+    owned: HashSet<Cluster>,
+    /// The names in the root directory, upper-cased.
+    root_names: HashSet<String>,
+    actions: Vec<RepairAction>,
+}
+
+impl<'a> Repair<'a> {
+    /// Returns `true` if `cluster` lies in the volume, is in use, and belongs
+    /// to no chain followed so far.
+    fn usable(&mut self, cluster: Cluster) -> io::Result<bool> {
+        if cluster.0 < 2 || cluster.0 >= self.vfat.num_clusters() + 2 {
+            return Ok(false);
+        }
+        if self.owned.contains(&cluster) {
+            return Ok(false);
+        }
+        Ok(match self.vfat.fat_entry(cluster)?.status() {
+            Status::Data(_) | Status::Eoc(_) => true,
+            _ => false,
+        })
+    }
+
+    /// Follows the chain beginning at `start`, a usable cluster, claiming
+    /// its clusters. If the chain does not end properly, it is cut at its
+    /// last sound cluster when the options allow, and the reason is
+    /// returned.
+    fn follow(&mut self, start: Cluster) -> io::Result<(Vec<Cluster>, Option<&'static str>)> {
+        let mut chain = vec![start];
+        self.owned.insert(start);
+        loop {
+            let last = chain[chain.len() - 1];
+            let next = match self.vfat.fat_entry(last)?.status() {
+                Status::Data(next) => next,
+                _ => return Ok((chain, None)),
+            };
+
+            let reason = if self.owned.contains(&next) {
+                if chain.contains(&next) {
+                    "loops"
+                } else {
+                    "runs into another chain"
+                }
+            } else if !self.usable(next)? {
+                "runs into an unusable cluster"
+            } else {
+                chain.push(next);
+                self.owned.insert(next);
+                continue;
+            };
+
+            if self.options.truncate_chains {
+                self.vfat.set_fat_entry(last, Status::Eoc(EOC_MARKER))?;
+            }
+            return Ok((chain, Some(reason)));
+        }
+    }
+
+    /// Reads the clusters of `chain`.
+    fn read(&mut self, chain: &[Cluster]) -> io::Result<Vec<u8>> {
+        let cluster_size = self.vfat.cluster_size();
+        let mut buf = vec![0; chain.len() * cluster_size];
+        for (cluster, chunk) in chain.iter().zip(buf.chunks_mut(cluster_size)) {
+            self.vfat.read_cluster(*cluster, chunk)?;
+        }
+        Ok(buf)
+    }
+
+    /// Writes `data` at byte `offset` of the directory whose clusters are
+    /// `chain`.
+    fn write_slot(&mut self, chain: &[Cluster], offset: usize, data: &[u8]) -> io::Result<()> {
+        let cluster_size = self.vfat.cluster_size();
+        self.vfat
+            .write_cluster(chain[offset / cluster_size], offset % cluster_size, data)?;
+        Ok(())
+    }
+
+    /// Repairs the directory whose clusters are `chain`, returning the
+    /// chains of its subdirectories.
+    fn repair_dir(&mut self, chain: &[Cluster], is_root: bool) -> io::Result<Vec<Vec<Cluster>>> {
+        let dir_cluster = chain[0];
+        let buf = self.read(chain)?;
+        if self.options.remove_orphaned_lfns {
+            for orphan in orphans_in(&buf) {
+                for slot in orphan.slots.clone() {
+                    self.write_slot(chain, slot as usize * DIR_ENTRY_SIZE, &[DELETED_ENTRY])?;
+                }
+                self.actions.push(RepairAction::RemovedOrphanedLfn {
+                    dir_cluster,
+                    orphan,
+                });
+            }
+        }
+
+        let mut subdirs = Vec::new();
+        let mut entries = RawEntries::from_slots(self.vfat, dir_cluster, &buf, 0);
+        while let Some(entry) = entries.next_entry() {
+            let name = entry.metadata.name.clone();
+            if name == "." || name == ".." {
+                continue;
+            }
+            if is_root {
+                self.root_names.insert(name.to_ascii_uppercase());
+            }
+
+            let location = entry.location;
+            let start = entry.start_cluster;
+            if entry.is_dir() {
+                if self.usable(start)? {
+                    let (chain, reason) = self.follow(start)?;
+                    self.report_cut(Some(location), &chain, reason);
+                    subdirs.push(chain);
+                }
+                continue;
+            }
+
+            self.repair_file(location, start, entry.metadata.size)?;
+        }
+        Ok(subdirs)
+    }
+
+    /// Records that the chain `chain` of the entry at `location` was cut, if
+    /// `reason` is given and the options allow cutting.
+    fn report_cut(
+        &mut self,
+        location: Option<EntryLocation>,
+        chain: &[Cluster],
+        reason: Option<&'static str>,
+    ) {
+        if let (Some(reason), true) = (reason, self.options.truncate_chains) {
+            self.actions.push(RepairAction::TruncatedChain {
+                location,
+                clusters: chain.len() as u32,
+                reason,
+            });
+        }
+    }
+
+    /// Repairs the chain and size of the file at `location`.
+    fn repair_file(
+        &mut self,
+        location: EntryLocation,
+        start: Cluster,
+        size: u32,
+    ) -> io::Result<()> {
+        let chain = if start.0 == 0 {
+            Vec::new()
+        } else if self.usable(start)? {
+            let (chain, reason) = self.follow(start)?;
+            self.report_cut(Some(location), &chain, reason);
+            chain
+        } else {
+            if self.options.truncate_chains {
+                self.vfat.set_entry_extent(location, Cluster(0), 0)?;
+                self.actions.push(RepairAction::TruncatedChain {
+                    location: Some(location),
+                    clusters: 0,
+                    reason: "begins at an unusable cluster",
+                });
+            }
+            return Ok(());
+        };
+        if !self.options.fix_sizes {
+            return Ok(());
+        }
+
+        let cluster_size = self.vfat.cluster_size();
+        let needed = ((size as u64 + cluster_size as u64 - 1) / cluster_size as u64) as usize;
+        if chain.len() > needed {
+            for &cluster in &chain[needed..] {
+                self.vfat.set_fat_entry(cluster, Status::Free)?;
+                self.owned.remove(&cluster);
+            }
+            if needed == 0 {
+                self.vfat.set_entry_extent(location, Cluster(0), 0)?;
+            } else {
+                self.vfat
+                    .set_fat_entry(chain[needed - 1], Status::Eoc(EOC_MARKER))?;
+            }
+            self.actions.push(RepairAction::TruncatedChain {
+                location: Some(location),
+                clusters: needed as u32,
+                reason: "is longer than the file",
+            });
+        } else if chain.len() < needed {
+            let new = (chain.len() * cluster_size) as u32;
+            let start = chain.first().cloned().unwrap_or(Cluster(0));
+            self.vfat.set_entry_extent(location, start, new)?;
+            self.actions.push(RepairAction::FixedSize {
+                location,
+                old: size,
+                new,
+            });
+        }
+        Ok(())
+    }
+
+    /// Follows the chains of clusters in use by no entry, returning them.
+    fn lost_chains(&mut self) -> io::Result<Vec<Vec<Cluster>>> {
+        let mut lost = Vec::new();
+        let mut linked = HashSet::new();
+        for n in 2..self.vfat.num_clusters() + 2 {
+            let cluster = Cluster(n);
+            if !self.usable(cluster)? {
+                continue;
+            }
+            lost.push(cluster);
+            if let Status::Data(next) = self.vfat.fat_entry(cluster)?.status() {
+                linked.insert(next);
+            }
+        }
+
+        // Chains are followed from their heads, then from any cluster left,
+        // which lies on a chain that loops without a head.
+        let mut chains = Vec::new();
+        let heads: Vec<Cluster> = lost
+            .iter()
+            .filter(|cluster| !linked.contains(cluster))
+            .chain(lost.iter())
+            .cloned()
+            .collect();
+        for head in heads {
+            if self.usable(head)? {
+                let (chain, _) = self.follow(head)?;
+                chains.push(chain);
+            }
+        }
+        Ok(chains)
+    }
+
+    /// Saves `chains` as files in a new `FOUND.nnn` directory in the root
+    /// directory, whose clusters are `root`.
+    fn reclaim(&mut self, root: &[Cluster], chains: &[Vec<Cluster>]) -> io::Result<()> {
+        let found = match (0..1000)
+            .map(|n| format!("FOUND.{:03}", n))
+            .find(|name| !self.root_names.contains(name))
+        {
+            Some(found) => found,
+            None => return Ok(()),
+        };
+        let chains = &chains[..::std::cmp::min(chains.len(), MAX_RECLAIMED)];
+
+        let cluster_size = self.vfat.cluster_size();
+        let len = (2 + chains.len()) * DIR_ENTRY_SIZE;
+        let clusters = (len + cluster_size - 1) / cluster_size;
+        let first = self.vfat.allocate(clusters as u32, None)?;
+
+        let mut data = Vec::with_capacity(clusters * cluster_size);
+        data.extend_from_slice(&dot_entry(b".          ", first));
+        data.extend_from_slice(&dot_entry(b"..         ", Cluster(0)));
+        for (i, chain) in chains.iter().enumerate() {
+            let name = format!("FILE{:04}.CHK", i);
+            let size = chain.len() * cluster_size;
+            for entry in DirEntryBuilder::new(&name)
+                .cluster(chain[0].0)
+                .size(size as u32)
+                .build()?
+            {
+                data.extend_from_slice(&entry);
+            }
+            self.actions.push(RepairAction::ReclaimedChain {
+                start: chain[0],
+                clusters: chain.len() as u32,
+                path: format!("/{}/{}", found, name),
+            });
+        }
+        data.resize(clusters * cluster_size, 0);
+        self.vfat.write_chain(first, &data)?;
+
+        let entry = DirEntryBuilder::new(&found)
+            .attributes(Attributes(ATTR_DIRECTORY))
+            .cluster(first.0)
+            .build()?;
+        self.add_root_entry(root, &entry[0])
+    }
+
+    /// Writes `entry` into the first free slot of the root directory, whose
+    /// clusters are `root`, growing the directory if it is full.
+    fn add_root_entry(&mut self, root: &[Cluster], entry: &[u8; 32]) -> io::Result<()> {
+        let mut chain = root.to_vec();
+        let buf = self.read(&chain)?;
+        let slots = buf.len() / DIR_ENTRY_SIZE;
+        let free = buf
+            .chunks(DIR_ENTRY_SIZE)
+            .position(|slot| slot[0] == END_OF_ENTRIES || slot[0] == DELETED_ENTRY);
+
+        let slot = match free {
+            Some(slot) => {
+                let end = buf[slot * DIR_ENTRY_SIZE] == END_OF_ENTRIES;
+                if end && slot + 1 < slots {
+                    let next = (slot + 1) * DIR_ENTRY_SIZE;
+                    self.write_slot(&chain, next, &[END_OF_ENTRIES])?;
+                }
+                slot
+            }
+            None => {
+                let cluster_size = self.vfat.cluster_size();
+                let last = chain[chain.len() - 1];
+                let grown = self.vfat.allocate(1, Some(last))?;
+                self.vfat.write_cluster(grown, 0, &vec![0; cluster_size])?;
+                chain.push(grown);
+                slots
+            }
+        };
+        self.write_slot(&chain, slot * DIR_ENTRY_SIZE, entry)
+    }
+}
+
+/// Encodes a `.` or `..` entry, named `name`, for the directory at
+/// `cluster`.
+pub(crate) fn dot_entry(name: &[u8; 11], cluster: Cluster) -> [u8; 32] {
+    let mut entry = [0u8; 32];
+    entry[..11].copy_from_slice(name);
+    entry[11] = ATTR_DIRECTORY;
+    LittleEndian::write_u16(&mut entry[20..22], (cluster.0 >> 16) as u16);
+    LittleEndian::write_u16(&mut entry[26..28], cluster.0 as u16);
+    entry
+}
+
+/// Makes the repairs `options` allow to the volume whose root directory
+/// begins at `root`. See `VFat::repair()`.
+pub(crate) fn repair(
+    vfat: &mut VFat,
+    root: Cluster,
+    options: RepairOptions,
+) -> io::Result<Vec<RepairAction>> {
+    let mut repair = Repair {
+        vfat,
+        options,
+        owned: HashSet::new(),
+        root_names: HashSet::new(),
+        actions: Vec::new(),
+    };
+
+    if !repair.usable(root)? {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "root directory begins at an unusable cluster",
+        ));
+    }
+    let (root_chain, reason) = repair.follow(root)?;
+    repair.report_cut(None, &root_chain, reason);
+
+    let mut dirs = VecDeque::new();
+    dirs.push_back((root_chain.clone(), true));
+    while let Some((chain, is_root)) = dirs.pop_front() {
+        for subdir in repair.repair_dir(&chain, is_root)? {
+            dirs.push_back((subdir, false));
+        }
+    }
+
+    if repair.options.reclaim_lost_chains {
+        let chains = repair.lost_chains()?;
+        if !chains.is_empty() {
+            repair.reclaim(&root_chain, &chains)?;
+        }
+    }
+
+    if repair.options.rebuild_fs_info {
+        if let Some((free_clusters, next_free)) = repair.vfat.rebuild_fs_info()? {
+            repair.actions.push(RepairAction::RebuiltFsInfo {
+                free_clusters,
+                next_free,
+            });
+        }
+    }
+
+    // Entries may have changed or moved.
+    repair.vfat.forget_dirs();
+    repair.vfat.dir_indexes().clear();
+    Ok(repair.actions)
+}
@@ -0,0 +1,96 @@
+use std::fmt;
+
+/// A summary of a mounted volume, as returned by `VFat::describe()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct MountReport {
+    /// The file system type recorded in the EBPB, e.g. `FAT32`.
+    pub fat_type: String,
+    /// The volume label recorded in the EBPB, without trailing spaces.
+    pub label: String,
+    pub serial: u32,
+    pub bytes_per_sector: u16,
+    pub sectors_per_cluster: u8,
+    pub total_sectors: u64,
+    pub num_fats: u8,
+    pub sectors_per_fat: u32,
+    pub num_clusters: u32,
+    pub free_clusters: u64,
+    /// The only FAT in use, or `None` if every FAT mirrors the first.
+    pub active_fat: Option<u8>,
+    /// The media descriptor recorded in the first FAT entry.
+    pub media_descriptor: u8,
+    /// `true` if the volume was not cleanly unmounted, per the flag in the
+    /// second FAT entry.
+    pub dirty: bool,
+    /// `true` if a disk I/O error has been recorded, per the flag in the
+    /// second FAT entry.
+    pub hard_error: bool,
+    /// `true` if the primary boot sector was invalid and the volume was
+    /// mounted from the backup boot sector.
+    pub used_backup_boot_sector: bool,
+    /// The sector where the partition begins, per the partition table.
+    pub partition_start: u64,
+    /// The number of sectors preceding the partition, per the BPB.
+    pub hidden_sectors: u32,
+}
+
+impl fmt::Display for MountReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let cluster_size = self.bytes_per_sector as u64 * self.sectors_per_cluster as u64;
+        writeln!(
+            f,
+            "{} volume \"{}\" (serial {:04X}-{:04X})",
+            self.fat_type,
+            self.label,
+            self.serial >> 16,
+            self.serial & 0xFFFF
+        )?;
+        writeln!(
+            f,
+            "  {} sectors of {} bytes, {} clusters of {} bytes",
+            self.total_sectors, self.bytes_per_sector, self.num_clusters, cluster_size
+        )?;
+        writeln!(
+            f,
+            "  {} free clusters ({} bytes)",
+            self.free_clusters,
+            self.free_clusters * cluster_size
+        )?;
+        match self.active_fat {
+            None => writeln!(
+                f,
+                "  {} mirrored FATs of {} sectors",
+                self.num_fats, self.sectors_per_fat
+            )?,
+            Some(active) => writeln!(
+                f,
+                "  {} FATs of {} sectors, mirroring disabled (FAT {} active)",
+                self.num_fats, self.sectors_per_fat, active
+            )?,
+        }
+        write!(
+            f,
+            "  {}{}{}",
+            if self.dirty { "dirty" } else { "clean" },
+            if self.hard_error {
+                ", disk errors recorded"
+            } else {
+                ""
+            },
+            if self.used_backup_boot_sector {
+                ", mounted from backup boot sector"
+            } else {
+                ""
+            }
+        )?;
+        if self.hidden_sectors as u64 != self.partition_start {
+            write!(
+                f,
+                "\n  BPB hidden sectors ({}) disagree with partition start ({})",
+                self.hidden_sectors, self.partition_start
+            )?;
+        }
+        Ok(())
+    }
+}
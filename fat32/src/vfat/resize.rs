@@ -0,0 +1,246 @@
+//! Offline resizing of FAT32 volumes.
+
+use std::io;
+
+use byteorder::{ByteOrder, LittleEndian};
+use consts::{
+    FAT32_CHS_PARTITION, FAT32_LBA_PARTITION, FAT_ENTRY_SIZE, FAT_MIRRORING_DISABLED,
+    FSINFO_LEAD_SIGNATURE, FSINFO_STRUCT_SIGNATURE, MAX_CLUSTERS,
+};
+use mbr::MasterBootRecord;
+use traits::BlockDevice;
+use vfat::ebpb::BiosParameterBlock;
+use vfat::{FatEntry, Status};
+
+/// Logical-sector access to the sectors of a single partition.
+struct Volume<'a, T: BlockDevice + 'a> {
+    device: &'a mut T,
+    start: u64,
+    factor: u64,
+}
+
+impl<'a, T: BlockDevice> Volume<'a, T> {
+    fn read(&mut self, sector: u64) -> io::Result<Vec<u8>> {
+        let device_sector_size = self.device.sector_size() as usize;
+        let mut data = vec![0; device_sector_size * self.factor as usize];
+        for (i, chunk) in data.chunks_mut(device_sector_size).enumerate() {
+            let physical = self.start + sector * self.factor + i as u64;
+            self.device.read_sector(physical, chunk)?;
+        }
+        Ok(data)
+    }
+
+    fn write(&mut self, sector: u64, data: &[u8]) -> io::Result<()> {
+        let device_sector_size = self.device.sector_size() as usize;
+        for (i, chunk) in data.chunks(device_sector_size).enumerate() {
+            let physical = self.start + sector * self.factor + i as u64;
+            self.device.write_sector(physical, chunk)?;
+        }
+        Ok(())
+    }
+}
+
+/// Grows the FAT32 volume on `device` to `new_total_sectors` logical sectors
+/// and returns the new number of data clusters. The volume must not be
+/// mounted.
+///
+/// If the larger volume needs a larger FAT, the FATs are extended and every
+/// allocated data cluster is moved up by the number of clusters the FATs
+/// grew into, so cluster numbers (and so every directory entry and chain)
+/// stay valid. The BPB, its backup, and the FSInfo free count are updated.
+/// If the partition entry is smaller than the new size, it is extended as
+/// well, provided no other partition lies in the way.
+///
+/// # Errors
+///
+/// Returns an error kind of `InvalidInput` if `new_total_sectors` is smaller
+/// than the current size, if the device or partition table cannot hold the
+/// new size, or if the new size would not add clusters. Returns an error
+/// kind of `InvalidData` if the volume is not a valid FAT32 volume.
+pub fn grow<T: BlockDevice>(device: &mut T, new_total_sectors: u64) -> io::Result<u32> {
+    let invalid_input = |reason| io::Error::new(io::ErrorKind::InvalidInput, reason);
+
+    let mbr = MasterBootRecord::from(device).map_err(::vfat::Error::from)?;
+    let (index, partition) = match mbr
+        .partition_table_entries
+        .iter()
+        .enumerate()
+        .find(|&(_, p)| {
+            p.partition_type == FAT32_CHS_PARTITION || p.partition_type == FAT32_LBA_PARTITION
+        }) {
+        Some((index, partition)) => (index, *partition),
+        None => return Err(::vfat::Error::NotFound.into()),
+    };
+
+    let start = partition.relative_sector as u64;
+    let bpb = BiosParameterBlock::from(device, start)?;
+    let bytes_per_sector = bpb.bytes_per_sector as u64;
+    let device_sector_size = device.sector_size();
+    if bytes_per_sector < device_sector_size || bytes_per_sector % device_sector_size != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "logical sector size is not a multiple of the device's",
+        ));
+    }
+
+    let factor = bytes_per_sector / device_sector_size;
+    let reserved = bpb.reserved_sectors as u64;
+    let num_fats = bpb.num_fats as u64;
+    let sectors_per_cluster = bpb.sectors_per_cluster as u64;
+    let old_sectors_per_fat = bpb.sectors_per_fat as u64;
+    let old_total = bpb.total_sectors();
+
+    if new_total_sectors < old_total {
+        return Err(invalid_input("cannot shrink a volume"));
+    }
+    if new_total_sectors > ::std::u32::MAX as u64 {
+        return Err(invalid_input("new size does not fit in the BPB"));
+    }
+
+    let old_data_start = reserved + num_fats * old_sectors_per_fat;
+    let old_clusters = ::std::cmp::min(
+        old_total.saturating_sub(old_data_start) / sectors_per_cluster,
+        (old_sectors_per_fat * bytes_per_sector / FAT_ENTRY_SIZE as u64).saturating_sub(2),
+    );
+
+    // Grow the FAT until it covers every cluster that fits after it. The FATs
+    // may only grow by whole clusters so that data clusters can be moved up
+    // without renumbering them.
+    let mut sectors_per_fat = old_sectors_per_fat;
+    let new_clusters = loop {
+        let data_start = reserved + num_fats * sectors_per_fat;
+        if data_start >= new_total_sectors {
+            return Err(invalid_input("new size leaves no room for data"));
+        }
+
+        let clusters = ::std::cmp::min(
+            (new_total_sectors - data_start) / sectors_per_cluster,
+            MAX_CLUSTERS as u64,
+        );
+        let fits = (clusters + 2) * FAT_ENTRY_SIZE as u64 <= sectors_per_fat * bytes_per_sector;
+        let aligned = (sectors_per_fat - old_sectors_per_fat) * num_fats % sectors_per_cluster == 0;
+        if fits && aligned {
+            break clusters;
+        }
+
+        sectors_per_fat += 1;
+    };
+
+    if new_clusters <= old_clusters {
+        return Err(invalid_input("new size does not add any clusters"));
+    }
+
+    // Check that the partition can be extended before writing anything.
+    let needed = new_total_sectors * factor;
+    let extend_partition = (partition.total_sectors as u64) < needed;
+    if let Some(sectors) = device.num_sectors() {
+        if start + needed > sectors {
+            return Err(invalid_input("device is smaller than the new size"));
+        }
+    }
+    if extend_partition {
+        let in_the_way = mbr
+            .partition_table_entries
+            .iter()
+            .enumerate()
+            .any(|(i, p)| {
+                i != index
+                    && p.partition_type != 0
+                    && p.relative_sector as u64 > start
+                    && (p.relative_sector as u64) < start + needed
+            });
+        if in_the_way {
+            return Err(invalid_input("another partition follows this one"));
+        }
+        if start + needed > ::std::u32::MAX as u64 {
+            return Err(invalid_input(
+                "new size does not fit in the partition table",
+            ));
+        }
+    }
+
+    let mut volume = Volume {
+        device,
+        start,
+        factor,
+    };
+
+    // Read the whole FAT before any of it is overwritten.
+    let entries_per_sector = bytes_per_sector / FAT_ENTRY_SIZE as u64;
+    let active_fat = match bpb.flags & FAT_MIRRORING_DISABLED {
+        0 => 0,
+        _ => (bpb.flags & 0xF) as u64,
+    };
+    let mut entries = Vec::with_capacity(new_clusters as usize + 2);
+    for index in 0..old_sectors_per_fat {
+        let sector = volume.read(reserved + active_fat * old_sectors_per_fat + index)?;
+        for raw in sector.chunks(FAT_ENTRY_SIZE) {
+            entries.push(LittleEndian::read_u32(raw));
+        }
+    }
+    entries.truncate(old_clusters as usize + 2);
+    entries.resize(new_clusters as usize + 2, 0);
+
+    // Move allocated clusters up, last first, so that no cluster is
+    // overwritten before it has been moved.
+    let shift = (sectors_per_fat - old_sectors_per_fat) * num_fats;
+    if shift > 0 {
+        for cluster in (2..old_clusters + 2).rev() {
+            if FatEntry(entries[cluster as usize]).status() == Status::Free {
+                continue;
+            }
+
+            let first = old_data_start + (cluster - 2) * sectors_per_cluster;
+            for sector in first..first + sectors_per_cluster {
+                let data = volume.read(sector)?;
+                volume.write(sector + shift, &data)?;
+            }
+        }
+    }
+
+    let mut sector = vec![0; bytes_per_sector as usize];
+    for index in 0..sectors_per_fat {
+        let first = (index * entries_per_sector) as usize;
+        for (i, raw) in sector.chunks_mut(FAT_ENTRY_SIZE).enumerate() {
+            LittleEndian::write_u32(raw, entries.get(first + i).cloned().unwrap_or(0));
+        }
+        for copy in 0..num_fats {
+            volume.write(reserved + copy * sectors_per_fat + index, &sector)?;
+        }
+    }
+
+    let mut boot = volume.read(0)?;
+    LittleEndian::write_u16(&mut boot[19..21], 0);
+    LittleEndian::write_u32(&mut boot[32..36], new_total_sectors as u32);
+    LittleEndian::write_u32(&mut boot[36..40], sectors_per_fat as u32);
+    volume.write(0, &boot)?;
+    let backup = bpb.backup_boot_sector_num;
+    if backup != 0 && (backup as u64) < reserved {
+        volume.write(backup as u64, &boot)?;
+    }
+
+    let fs_info = bpb.fs_info_sector_num;
+    if fs_info != 0 && (fs_info as u64) < reserved {
+        let mut info = volume.read(fs_info as u64)?;
+        if LittleEndian::read_u32(&info[0..4]) == FSINFO_LEAD_SIGNATURE
+            && LittleEndian::read_u32(&info[484..488]) == FSINFO_STRUCT_SIGNATURE
+        {
+            let free = entries[2..]
+                .iter()
+                .filter(|&&raw| FatEntry(raw).status() == Status::Free)
+                .count();
+            LittleEndian::write_u32(&mut info[488..492], free as u32);
+            volume.write(fs_info as u64, &info)?;
+        }
+    }
+
+    if extend_partition {
+        let mut mbr_sector = vec![0; device_sector_size as usize];
+        volume.device.read_sector(0, &mut mbr_sector)?;
+        let offset = 446 + index * 16;
+        LittleEndian::write_u32(&mut mbr_sector[offset + 12..offset + 16], needed as u32);
+        volume.device.write_sector(0, &mbr_sector)?;
+    }
+
+    Ok(new_clusters as u32)
+}
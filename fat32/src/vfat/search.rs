@@ -0,0 +1,137 @@
+//! Finding the entries below a directory by name, size, and modification
+//! time.
+
+use std::io;
+
+use vfat::dir::{ucs2_matches, with_ucs2_name};
+use vfat::walk::Walk;
+use vfat::{Dir, Entry, EntryKind, Timestamp};
+
+/// The conditions an entry must meet to be returned by `find()`. Every
+/// condition that is set must hold; the default query matches every entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Query {
+    /// A pattern the whole of the entry's name must match, in which `*`
+    /// matches any run of characters and `?` matches any single character.
+    /// Names are compared as by `Dir::find_all_matching()`.
+    pub name: Option<String>,
+    /// The kind of entry to match.
+    pub kind: Option<EntryKind>,
+    /// The smallest size, in bytes, of matching files. Directories never
+    /// match a query with a size bound.
+    pub min_size: Option<u32>,
+    /// The largest size, in bytes, of matching files.
+    pub max_size: Option<u32>,
+    /// Matching entries were last modified after this time.
+    pub modified_after: Option<Timestamp>,
+    /// The deepest level searched, where the entries of the root of the
+    /// search are at depth `1`. Directories at this depth are not descended
+    /// into.
+    pub max_depth: Option<usize>,
+}
+
+impl Default for Query {
+    fn default() -> Query {
+        Query {
+            name: None,
+            kind: None,
+            min_size: None,
+            max_size: None,
+            modified_after: None,
+            max_depth: None,
+        }
+    }
+}
+
+/// An iterator over the entries matching a query, returned by `find()`.
+pub struct Matches {
+    walk: Walk,
+    query: Query,
+    /// `query.name` in UCS-2.
+    pattern: Option<Vec<u16>>,
+}
+
+impl Matches {
+    /// Whether `entry` meets every condition of the query.
+    fn matches(&self, entry: &Entry) -> bool {
+        let metadata = match *entry {
+            Entry::File(ref file) => &file.metadata,
+            Entry::Dir(ref dir) => &dir.metadata,
+        };
+        let is_dir = match *entry {
+            Entry::File(_) => false,
+            Entry::Dir(_) => true,
+        };
+
+        if let Some(kind) = self.query.kind {
+            if kind
+                != if is_dir {
+                    EntryKind::Dir
+                } else {
+                    EntryKind::File
+                }
+            {
+                return false;
+            }
+        }
+        if is_dir && (self.query.min_size.is_some() || self.query.max_size.is_some()) {
+            return false;
+        }
+        if self.query.min_size.map_or(false, |min| metadata.size < min)
+            || self.query.max_size.map_or(false, |max| metadata.size > max)
+        {
+            return false;
+        }
+        if let Some(after) = self.query.modified_after {
+            if metadata.last_modified <= after {
+                return false;
+            }
+        }
+        match self.pattern {
+            Some(ref pattern) => with_ucs2_name(metadata, |name| ucs2_matches(name, pattern)),
+            None => true,
+        }
+    }
+}
+
+impl Iterator for Matches {
+    /// A matching entry and its path relative to the root of the search.
+    type Item = io::Result<(String, Entry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (path, entry, depth) = match self.walk.next()? {
+                Ok(found) => found,
+                Err(e) => return Some(Err(e)),
+            };
+            if self.query.max_depth.map_or(false, |max| depth >= max) {
+                self.walk.prune();
+            }
+            if self.matches(&entry) {
+                return Some(Ok((path, entry)));
+            }
+        }
+    }
+}
+
+/// Returns an iterator over the entries below `root`, at any depth, that
+/// match `query`, yielding each with its path relative to `root`. Hidden
+/// and system entries are searched; `.` and `..` are not.
+///
+/// Conditions are checked as the tree is walked, depth first, and
+/// directories past `query.max_depth` are never read. An error reading a
+/// directory is yielded in place of its entries, and the search continues.
+///
+/// # Errors
+///
+/// Returns the error reading `root` fails with, if it does.
+pub fn find(root: &Dir, query: Query) -> io::Result<Matches> {
+    Ok(Matches {
+        walk: Walk::new(root, String::new())?,
+        pattern: query
+            .name
+            .as_ref()
+            .map(|name| name.encode_utf16().collect()),
+        query,
+    })
+}
@@ -5,10 +5,16 @@ use std::ops::{Deref, DerefMut};
 /// The inner `T` can be borrowed immutably with `.borrow()` and mutably with
 /// `.borrow_mut()`. The implementation guarantees the usual reference
 /// guarantees.
+///
+/// A `Shared<T>` is `Send` and `Sync` when `T` is `Send`. In particular, the
+/// handles holding a `Shared<VFat>` (`File`, `Dir`, `DirIter`, and `Entry`)
+/// can be moved between threads; their operations on the volume are
+/// serialized by the inner lock.
 #[derive(Debug)]
 pub struct Shared<T>(imp::Inner<T>);
 
 #[cfg(target_os = "ros")]
+#[allow(unsafe_code)]
 mod imp {
     use super::Shared;
     use std::rc::Rc;
@@ -20,6 +26,10 @@ mod imp {
         Rc::new(Mutex::new(val))
     }
 
+    pub fn ref_count<T>(inner: &Inner<T>) -> usize {
+        Rc::strong_count(inner)
+    }
+
     // Without an enabled MMU/cache, the processor faults on atomic accesses.
     // As such, use an `Rc` instead of an `Arc` when running on ROS until
     // multithreading, the MMU, and caches are enabled.
@@ -36,6 +46,10 @@ mod imp {
     pub fn new<T>(val: T) -> Inner<T> {
         Arc::new(Mutex::new(val))
     }
+
+    pub fn ref_count<T>(inner: &Inner<T>) -> usize {
+        Arc::strong_count(inner)
+    }
 }
 
 impl<T> Shared<T> {
@@ -59,6 +73,12 @@ impl<T> Shared<T> {
     pub fn borrow_mut<'a>(&'a self) -> impl DerefMut<Target = T> + 'a {
         self.0.lock().expect("all okay")
     }
+
+    /// Returns the number of `Shared<T>` pointers to the inner value,
+    /// including `self`.
+    pub(crate) fn ref_count(&self) -> usize {
+        imp::ref_count(&self.0)
+    }
 }
 
 impl<T> Clone for Shared<T> {
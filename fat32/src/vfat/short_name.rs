@@ -0,0 +1,240 @@
+use std::io;
+
+/// Characters allowed in an 8.3 name besides `A-Z` and `0-9`.
+const SPECIAL_CHARS: &[u8] = b"!#$%&'()-@^_`{}~";
+
+/// Characters Windows forbids in long file names.
+const FORBIDDEN_CHARS: &str = "\\/:*?\"<>|";
+
+/// DOS device names Windows refuses as file names, with or without an
+/// extension.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// The last numeric tail tried before falling back to hashed aliases.
+const MAX_NUMERIC_TAIL: u8 = 4;
+
+/// The maximum length of a long file name in UCS-2 units.
+const MAX_LFN_UNITS: usize = 255;
+
+/// Checks that `name` can be stored as the long name of a new entry.
+///
+/// Every name must be non-empty, must not be `.` or `..`, must not contain
+/// `/` or NUL, and must fit in 255 UCS-2 units. With `windows_compat`, names
+/// containing `\:*?"<>|` or control characters, names ending in a dot or
+/// space, and reserved DOS device names such as `CON` or `lpt1.txt` are also
+/// rejected.
+///
+/// # Errors
+///
+/// Returns an error kind of `InvalidInput` describing the first rule `name`
+/// breaks.
+pub fn validate_long_name(name: &str, windows_compat: bool) -> io::Result<()> {
+    let invalid = |reason| Err(io::Error::new(io::ErrorKind::InvalidInput, reason));
+
+    if name.is_empty() || name == "." || name == ".." {
+        return invalid("name is empty or a dot entry");
+    }
+
+    if name.contains('/') || name.contains('\0') {
+        return invalid("name contains a path separator or NUL");
+    }
+
+    if name.encode_utf16().count() > MAX_LFN_UNITS {
+        return invalid("name is longer than 255 UCS-2 units");
+    }
+
+    if !windows_compat {
+        return Ok(());
+    }
+
+    if name.chars().any(|c| c < ' ' || FORBIDDEN_CHARS.contains(c)) {
+        return invalid("name contains a character forbidden on Windows");
+    }
+
+    if name.ends_with('.') || name.ends_with(' ') {
+        return invalid("name ends with a dot or space");
+    }
+
+    let stem = name
+        .split('.')
+        .next()
+        .unwrap_or(name)
+        .trim_right_matches(' ');
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+    {
+        return invalid("name is a reserved DOS device name");
+    }
+
+    Ok(())
+}
+
+/// How an 8.3 alias is chosen for a long name that does not fit in 8.3.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ShortNameStrategy {
+    /// Try `BASIS~1` through `BASIS~4`, then fall back to names of the form
+    /// `BAxxxx~1` through `BAxxxx~9`, where `xxxx` is a hash of the long name.
+    /// This is the sequence Windows follows, but Windows' hash is
+    /// undocumented, so the hashed aliases differ from those Windows would
+    /// pick. Either is an ordinary 8.3 name to readers that only know short
+    /// names.
+    NumericTail,
+    /// Never generate an alias: names that do not fit in 8.3 are rejected.
+    Fail,
+}
+
+impl Default for ShortNameStrategy {
+    fn default() -> ShortNameStrategy {
+        ShortNameStrategy::NumericTail
+    }
+}
+
+/// Returns the on-disk 11-byte short name for `name`, generating an alias with
+/// `strategy` if `name` does not fit in 8.3. `exists` is called with candidate
+/// short names and must return `true` for those already used in the target
+/// directory.
+///
+/// # Errors
+///
+/// If `strategy` is `Fail` and `name` does not fit in 8.3, an error kind of
+/// `InvalidInput` is returned. If `name` fits in 8.3 but is already used, or
+/// every candidate alias is used, an error kind of `AlreadyExists` is
+/// returned.
+pub fn short_name_for<F>(
+    name: &str,
+    strategy: ShortNameStrategy,
+    mut exists: F,
+) -> io::Result<[u8; 11]>
+where
+    F: FnMut(&[u8; 11]) -> bool,
+{
+    let (base, ext, lossy) = basis_name(name);
+
+    if !lossy && !base.is_empty() {
+        let short = pack(&base, &ext);
+        if exists(&short) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "short name already exists",
+            ));
+        }
+
+        return Ok(short);
+    }
+
+    if strategy == ShortNameStrategy::Fail {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "name does not fit in 8.3",
+        ));
+    }
+
+    let base = if base.is_empty() { b"_".to_vec() } else { base };
+    for n in 1..=MAX_NUMERIC_TAIL {
+        let mut candidate = base[..::std::cmp::min(base.len(), 6)].to_vec();
+        candidate.extend_from_slice(&[b'~', b'0' + n]);
+        let short = pack(&candidate, &ext);
+        if !exists(&short) {
+            return Ok(short);
+        }
+    }
+
+    let hash = name_hash(name);
+    for n in 1..10u8 {
+        let mut candidate = base[..::std::cmp::min(base.len(), 2)].to_vec();
+        for shift in [12u16, 8, 4, 0].iter() {
+            let digit = ((hash >> *shift) & 0xF) as u8;
+            candidate.push(if digit < 10 {
+                b'0' + digit
+            } else {
+                b'A' + digit - 10
+            });
+        }
+        candidate.extend_from_slice(&[b'~', b'0' + n]);
+
+        let short = pack(&candidate, &ext);
+        if !exists(&short) {
+            return Ok(short);
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        "no free short name alias",
+    ))
+}
+
+/// Returns the checksum of an 11-byte short name stored in each of the LFN
+/// entries that precede it.
+pub fn lfn_checksum(short: &[u8; 11]) -> u8 {
+    short.iter().fold(0u8, |sum, &b| {
+        ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(b)
+    })
+}
+
+/// Splits `name` into an uppercased, sanitized 8.3 base and extension.
+/// Returns `(base, extension, lossy)` where `lossy` is `true` if anything
+/// other than case was lost in the conversion.
+fn basis_name(name: &str) -> (Vec<u8>, Vec<u8>, bool) {
+    let mut lossy = false;
+
+    let trimmed = name.trim_left_matches('.');
+    if trimmed.len() != name.len() {
+        lossy = true;
+    }
+
+    let (base, ext) = match trimmed.rfind('.') {
+        Some(i) => (&trimmed[..i], &trimmed[i + 1..]),
+        None => (trimmed, ""),
+    };
+
+    let mut sanitize = |part: &str, max: usize| {
+        let mut out = Vec::new();
+        for c in part.chars() {
+            if c == ' ' || c == '.' {
+                lossy = true;
+                continue;
+            }
+
+            let upper = c.to_ascii_uppercase();
+            let byte = if upper.is_ascii_alphanumeric() || SPECIAL_CHARS.contains(&(upper as u8)) {
+                upper as u8
+            } else {
+                lossy = true;
+                b'_'
+            };
+
+            if out.len() == max {
+                lossy = true;
+                break;
+            }
+            out.push(byte);
+        }
+        out
+    };
+
+    let base = sanitize(base, 8);
+    let ext = sanitize(ext, 3);
+    (base, ext, lossy)
+}
+
+/// Packs a base and extension into the space-padded on-disk layout.
+fn pack(base: &[u8], ext: &[u8]) -> [u8; 11] {
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base);
+    short[8..8 + ext.len()].copy_from_slice(ext);
+    short
+}
+
+/// A deterministic 16-bit hash of a long name, used for hashed aliases: the
+/// FNV-1a hash of its UCS-2 units, folded to 16 bits.
+fn name_hash(name: &str) -> u16 {
+    let hash = name.encode_utf16().fold(0x811c9dc5u32, |hash, unit| {
+        (hash ^ unit as u32).wrapping_mul(0x01000193)
+    });
+    (hash ^ (hash >> 16)) as u16
+}
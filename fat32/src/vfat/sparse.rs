@@ -0,0 +1,71 @@
+//! A compact image format holding only the used sectors of a disk.
+//!
+//! A sparse image is the 8-byte magic `FATSPARS`, the number of runs as a
+//! little-endian `u64`, and then each run: its byte offset on the disk and its
+//! length in bytes, both little-endian `u64`s, followed by the run's data.
+//! Images are written by `VFat::dump_sparse()` and read back by
+//! `restore_sparse()`.
+
+use std::io::{self, Read, Write};
+
+use byteorder::{ByteOrder, LittleEndian};
+use traits::BlockDevice;
+
+const MAGIC: &[u8; 8] = b"FATSPARS";
+
+/// Writes the header of a sparse image holding `runs` runs.
+pub(crate) fn write_header<W: Write>(writer: &mut W, runs: u64) -> io::Result<()> {
+    let mut count = [0; 8];
+    LittleEndian::write_u64(&mut count, runs);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&count)
+}
+
+/// Writes the header of a run of `len` bytes at byte `offset` of the disk. The
+/// caller writes the run's data next.
+pub(crate) fn write_run_header<W: Write>(writer: &mut W, offset: u64, len: u64) -> io::Result<()> {
+    let mut header = [0; 16];
+    LittleEndian::write_u64(&mut header[..8], offset);
+    LittleEndian::write_u64(&mut header[8..], len);
+    writer.write_all(&header)
+}
+
+/// Writes the sparse image read from `reader` to `device` and returns the
+/// number of bytes written. Sectors not covered by the image are left
+/// untouched.
+///
+/// # Errors
+///
+/// Returns an error kind of `InvalidData` if `reader` does not hold a sparse
+/// image or if a run is not aligned to `device`'s sector size. Errors reading
+/// from `reader` or writing to `device` are returned as they occur.
+pub fn restore_sparse<R: Read, T: BlockDevice>(reader: &mut R, device: &mut T) -> io::Result<u64> {
+    let invalid = |reason| io::Error::new(io::ErrorKind::InvalidData, reason);
+
+    let mut header = [0; 16];
+    reader.read_exact(&mut header)?;
+    if &header[..8] != MAGIC {
+        return Err(invalid("not a sparse image"));
+    }
+
+    let sector_size = device.sector_size();
+    let mut sector = vec![0; sector_size as usize];
+    let mut written = 0;
+    for _ in 0..LittleEndian::read_u64(&header[8..]) {
+        let mut run = [0; 16];
+        reader.read_exact(&mut run)?;
+        let offset = LittleEndian::read_u64(&run[..8]);
+        let len = LittleEndian::read_u64(&run[8..]);
+        if offset % sector_size != 0 || len % sector_size != 0 {
+            return Err(invalid("sparse image run is not sector aligned"));
+        }
+
+        for n in 0..len / sector_size {
+            reader.read_exact(&mut sector)?;
+            device.write_sector(offset / sector_size + n, &sector)?;
+        }
+        written += len;
+    }
+
+    Ok(written)
+}
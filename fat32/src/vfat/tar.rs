@@ -0,0 +1,262 @@
+//! Reading tar archives and applying them to a volume.
+
+use std::io;
+use std::str;
+use std::time::{Duration, UNIX_EPOCH};
+
+use vfat::vfat::create_in;
+use vfat::{Dir, Entry};
+
+const BLOCK_SIZE: usize = 512;
+
+fn invalid_data(reason: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// Returns the NUL-terminated string at the start of `field`.
+fn field_bytes(field: &[u8]) -> &[u8] {
+    match field.iter().position(|&b| b == 0) {
+        Some(end) => &field[..end],
+        None => field,
+    }
+}
+
+/// Parses an octal number field of a tar header.
+fn parse_octal(field: &[u8]) -> io::Result<u64> {
+    let digits = str::from_utf8(field_bytes(field))
+        .map_err(|_| invalid_data("malformed tar header"))?
+        .trim_matches(' ');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8).map_err(|_| invalid_data("malformed tar header"))
+}
+
+/// Verifies the checksum of tar header `block`, computed with the checksum
+/// field itself taken as spaces.
+fn verify_checksum(block: &[u8]) -> io::Result<()> {
+    let sum: u64 = block
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| {
+            if i >= 148 && i < 156 {
+                b' ' as u64
+            } else {
+                b as u64
+            }
+        })
+        .sum();
+    if parse_octal(&block[148..156])? != sum {
+        return Err(invalid_data("bad tar header checksum"));
+    }
+    Ok(())
+}
+
+/// Returns the number of padding bytes after `size` bytes of member data.
+fn padding(size: u64) -> usize {
+    ((BLOCK_SIZE as u64 - size % BLOCK_SIZE as u64) % BLOCK_SIZE as u64) as usize
+}
+
+/// Reads the `size` bytes of a member's data and its padding.
+fn read_data<R: io::Read>(reader: &mut R, size: u64) -> io::Result<Vec<u8>> {
+    if size > ::std::u32::MAX as u64 {
+        return Err(invalid_data("tar member is too large for FAT"));
+    }
+
+    let mut data = vec![0; size as usize];
+    reader.read_exact(&mut data)?;
+    reader.read_exact(&mut [0; BLOCK_SIZE][..padding(size)])?;
+    Ok(data)
+}
+
+/// Reads and discards the `size` bytes of a member's data and its padding,
+/// `buf` at a time.
+fn skip_data<R: io::Read>(reader: &mut R, size: u64, buf: &mut [u8]) -> io::Result<()> {
+    let mut remaining = size + padding(size) as u64;
+    while remaining > 0 {
+        let n = ::std::cmp::min(remaining, buf.len() as u64) as usize;
+        reader.read_exact(&mut buf[..n])?;
+        remaining -= n as u64;
+    }
+    Ok(())
+}
+
+/// The header of a member of a tar archive, as read by `read_header()`.
+pub(crate) struct Header {
+    /// The member's path, with any ustar prefix or GNU long name applied.
+    pub(crate) path: Vec<u8>,
+    /// The member's type flag: `b'0'` or `0` for a regular file, `b'5'` for a
+    /// directory.
+    pub(crate) kind: u8,
+    /// The member's modification time, in seconds since the Unix epoch.
+    pub(crate) mtime: u64,
+    /// The size of the member's data, which follows the header.
+    pub(crate) size: u64,
+}
+
+/// A member of a tar archive, as read by `read_member()`.
+pub(crate) struct Member {
+    /// The member's path, with any ustar prefix or GNU long name applied.
+    pub(crate) path: Vec<u8>,
+    /// The member's type flag: `b'0'` or `0` for a regular file, `b'5'` for a
+    /// directory.
+    pub(crate) kind: u8,
+    pub(crate) data: Vec<u8>,
+}
+
+/// Reads the header of the next member of the tar archive read from
+/// `reader`, or `None` at the end of the archive, leaving `reader` at the
+/// member's data. GNU long name members are applied to the member they name
+/// rather than returned.
+pub(crate) fn read_header<R: io::Read>(reader: &mut R) -> io::Result<Option<Header>> {
+    let mut long_name: Option<Vec<u8>> = None;
+    let mut block = [0; BLOCK_SIZE];
+
+    loop {
+        reader.read_exact(&mut block)?;
+        if block.iter().all(|&b| b == 0) {
+            return Ok(None);
+        }
+        verify_checksum(&block)?;
+
+        let size = parse_octal(&block[124..136])?;
+        if block[156] == b'L' {
+            long_name = Some(field_bytes(&read_data(reader, size)?).to_vec());
+            continue;
+        }
+
+        let path = match long_name.take() {
+            Some(name) => name,
+            None if &block[257..262] == b"ustar" && block[345] != 0 => {
+                let mut path = field_bytes(&block[345..500]).to_vec();
+                path.push(b'/');
+                path.extend_from_slice(field_bytes(&block[..100]));
+                path
+            }
+            None => field_bytes(&block[..100]).to_vec(),
+        };
+        return Ok(Some(Header {
+            path,
+            kind: block[156],
+            mtime: parse_octal(&block[136..148])?,
+            size,
+        }));
+    }
+}
+
+/// Reads the next member of the tar archive read from `reader`, data and
+/// all, or `None` at the end of the archive. See `read_header()`.
+pub(crate) fn read_member<R: io::Read>(reader: &mut R) -> io::Result<Option<Member>> {
+    match read_header(reader)? {
+        Some(header) => Ok(Some(Member {
+            data: read_data(reader, header.size)?,
+            path: header.path,
+            kind: header.kind,
+        })),
+        None => Ok(None),
+    }
+}
+
+/// Splits a member's path into components, dropping empty and `.`
+/// components.
+pub(crate) fn components(path: &[u8]) -> io::Result<Vec<&str>> {
+    let path = str::from_utf8(path).map_err(|_| invalid_data("tar member path is not UTF-8"))?;
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => continue,
+            ".." => return Err(invalid_data("tar member path leaves the destination")),
+            component => components.push(component),
+        }
+    }
+    Ok(components)
+}
+
+/// Finds the directory at `path` beneath `dest`.
+fn find_dir(dest: &Dir, path: &[&str]) -> io::Result<Dir> {
+    let mut dir = dest.clone();
+    for component in path {
+        dir = match dir.find(component)? {
+            Entry::Dir(dir) => dir,
+            Entry::File(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "tar member path passes through a file",
+                ))
+            }
+        };
+    }
+    Ok(dir)
+}
+
+/// Applies the tar archive read from `reader` to the tree beneath `dest` as
+/// it streams in, and returns the number of files written. File data is
+/// copied `MountOptions::transfer_chunk_size` bytes at a time.
+///
+/// Regular file members replace the contents of the file at the same path
+/// beneath `dest`, which is created with `VFat::create_file()` if missing;
+/// each file's clusters are allocated at once, so that they are contiguous
+/// where the free space allows. Directories are not created, so directory
+/// members, and the parents of file members, must name existing
+/// directories. Members of other types are skipped. Both ustar and GNU long
+/// names are understood. Each written file's modification time is set to
+/// the member's, or to the fixed timestamp of `MountOptions::deterministic`
+/// if set. Changes reach the disk on the next `VFat::sync()`.
+///
+/// # Errors
+///
+/// Returns an error kind of `NotFound` for a member beneath a missing
+/// directory, and of `InvalidData` if the archive is malformed or if a
+/// member's path leaves `dest`. Errors creating files, reading from `reader`
+/// or writing to the volume are returned as they occur. On error, the
+/// members before the failing one have already been applied.
+pub fn apply_tar<R: io::Read>(reader: &mut R, dest: &Dir) -> io::Result<u64> {
+    let mut buf = vec![0; dest.vfat.borrow().transfer_chunk()];
+    let mut applied = 0;
+    while let Some(header) = read_header(reader)? {
+        match header.kind {
+            b'0' | 0 => {
+                if header.size > ::std::u32::MAX as u64 {
+                    return Err(invalid_data("tar member is too large for FAT"));
+                }
+                let components = components(&header.path)?;
+                let (name, parents) = match components.split_last() {
+                    Some(split) => split,
+                    None => return Err(invalid_data("tar member has an empty path")),
+                };
+
+                let dir = find_dir(dest, parents)?;
+                let mut file = match dir.find(name) {
+                    Ok(Entry::File(file)) => file,
+                    Ok(Entry::Dir(_)) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "tar file member names a directory",
+                        ))
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                        create_in(&dest.vfat, &dir, name)?
+                    }
+                    Err(e) => return Err(e),
+                };
+                file.replace_contents_from(reader, header.size as u32, &mut buf)?;
+                reader.read_exact(&mut buf[..padding(header.size)])?;
+                if let Some(location) = file.location {
+                    let mut vfat = file.vfat.borrow_mut();
+                    let stamp = match vfat.options().deterministic {
+                        Some(deterministic) => deterministic.timestamp,
+                        None => vfat.timestamp_from(UNIX_EPOCH + Duration::from_secs(header.mtime)),
+                    };
+                    vfat.set_entry_modified(location, stamp)?;
+                }
+                applied += 1;
+            }
+            b'5' => {
+                skip_data(reader, header.size, &mut buf)?;
+                find_dir(dest, &components(&header.path)?)?;
+            }
+            _ => skip_data(reader, header.size, &mut buf)?,
+        }
+    }
+    Ok(applied)
+}
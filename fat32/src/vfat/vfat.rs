@@ -1,14 +1,103 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::io;
-use std::path::{Component, Path};
+use std::time::{Duration, SystemTime};
+
+#[cfg(feature = "no_std")]
+use path::{Component, Path, PathBuf};
+#[cfg(not(feature = "no_std"))]
+use std::path::{Component, Path, PathBuf};
 
 use byteorder::{ByteOrder, LittleEndian};
+use cache::{CacheOptions, CachedDevice, Partition};
+use consts::{
+    ATTR_LFN, ATTR_VOLUME_ID, CLEAN_SHUTDOWN, DELETED_ENTRY, DIR_ENTRY_SIZE, END_OF_ENTRIES,
+    EOC_MARKER, FAT_ENTRY_MASK, FAT_ENTRY_SIZE, FAT_MIRRORING_DISABLED, FSINFO_LEAD_SIGNATURE,
+    FSINFO_STRUCT_SIGNATURE, MAX_DIR_ENTRIES, NO_HARD_ERROR,
+};
 use mbr::MasterBootRecord;
-use traits;
-use traits::{BlockDevice, FileSystem};
-use vfat::{BiosParameterBlock, CachedDevice, Partition};
-use vfat::{Cluster, Dir, Entry, Error, FatEntry, File, Shared, Status};
+use traits::{BlockDevice, FileStat, FileSystem, OpenOptions, OpenTrace, TraceStep};
+use traits::{Entry as EntryTrait, Metadata as MetadataTrait, Timestamp as TimestampTrait};
+use vfat::clone;
+use vfat::collisions;
+use vfat::crc32::crc32;
+use vfat::data_cache::FileDataCache;
+use vfat::dir::{entry_at, is_named, lookup, lookup_counted, ucs2_name, LookupStats, RawEntries};
+use vfat::dir_cache::DirCache;
+use vfat::dir_index::DirIndexes;
+use vfat::dir_source::DirSource;
+use vfat::ebpb::BiosParameterBlock;
+use vfat::lock::LockTable;
+use vfat::manifest::replace_files;
+use vfat::protect::Protected;
+use vfat::repair::{self, RepairAction, RepairOptions};
+use vfat::sparse;
+use vfat::watch::Watchers;
+use vfat::xattr;
+use vfat::{
+    short_name_for, validate_long_name, Cluster, Dir, DirEntryBuilder, Entry, EntryLocation, Error,
+    FatEntries, FatEntry, FatHeader, FatMode, File, Shared, Status, Timestamp,
+};
+use vfat::{AllFiles, Event, LockKind, WatchCallback, WatchId, BOOT_CODE_SIZE};
+use vfat::{
+    DirTimestamps, Extent, FreeSpace, GeometryCheck, IoMetrics, MemoryBudget, MemoryUsage,
+    MountOptions, MountReport, NameCollision, SectorBitmap, SlowIoHook, DIR_CACHE_ENTRY_BYTES,
+};
+
+/// Where the backup boot sector conventionally lives, relative to the start
+/// of the partition.
+const BACKUP_BOOT_SECTOR: u64 = 6;
+/// The FSInfo next free hint meaning no free cluster is known.
+const NO_FREE_HINT: u32 = 0xFFFFFFFF;
+
+/// The error returned for changes to entries beneath
+/// `MountOptions::protected_paths`.
+fn protected_entry() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "entry is protected")
+}
+
+/// Returns `1` if a FAT entry changing from `old` to `new` frees its
+/// cluster, `-1` if it allocates it, and `0` otherwise.
+fn freed(old: u32, new: u32) -> i64 {
+    match (old & FAT_ENTRY_MASK, new & FAT_ENTRY_MASK) {
+        (0, 0) => 0,
+        (_, 0) => 1,
+        (0, _) => -1,
+        _ => 0,
+    }
+}
+
+/// The error returned by the `FileSystem` methods that would change a
+/// read-only volume.
+fn read_only_file_system() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "read only file system")
+}
+
+/// The error returned by the `FileSystem` methods this crate does not
+/// implement: `PermissionDenied` on a read-only volume, as for any change,
+/// and `Other` otherwise.
+fn unsupported(vfat: &Shared<VFat>) -> io::Error {
+    if vfat.borrow().read_only() {
+        return read_only_file_system();
+    }
+    io::Error::new(io::ErrorKind::Other, "operation not supported")
+}
 
-const FAT_ENTRY_SIZE: u16 = 4;
+/// The sizes of a volume's caches under `options`: the most sectors cached,
+/// the bytes of whole-file data, and the most directories.
+fn cache_sizes(options: &MountOptions, sector_size: usize) -> (Option<usize>, usize, usize) {
+    match options.memory_budget {
+        Some(budget) => (
+            Some(budget.sector_bytes() / sector_size),
+            budget.file_data_bytes(),
+            budget.dir_bytes() / DIR_CACHE_ENTRY_BYTES,
+        ),
+        None => (
+            None,
+            options.file_data_cache_bytes,
+            options.dir_cache_entries,
+        ),
+    }
+}
 
 #[derive(Debug)]
 pub struct VFat {
@@ -16,168 +105,2664 @@ pub struct VFat {
     bytes_per_sector: u16,
     sectors_per_cluster: u8,
     sectors_per_fat: u32,
+    /// The physical sector where the partition begins, per the MBR.
+    partition_start: u64,
+    /// The BPB's count of sectors preceding the partition, which should
+    /// equal `partition_start` but is not trusted.
+    hidden_sectors: u32,
     fat_start_sector: u64,
+    num_fats: u8,
+    /// The only FAT in use when mirroring is disabled, `None` when every FAT
+    /// is a mirror of the first.
+    active_fat: Option<u8>,
+    /// Sectors (relative to the start of a FAT) of the primary FAT that have
+    /// been modified but not yet copied to the mirrors.
+    dirty_fat_sectors: BTreeSet<u32>,
+    /// Where the allocator begins its search for free clusters.
+    next_free: Cluster,
+    /// The number of free clusters, once counted. See `free_clusters()`.
+    free_clusters: Option<u64>,
     data_start_sector: u64,
+    num_clusters: u32,
     root_dir_cluster: Cluster,
+    /// The fixed region holding the root directory on FAT12 and FAT16. Always
+    /// `None` on FAT32, whose root directory is the chain at
+    /// `root_dir_cluster`.
+    root_dir_region: Option<DirSource>,
+    volume_id: u32,
+    label: [u8; 11],
+    system_id: [u8; 8],
+    /// The number of logical sectors in the volume, after any clamping.
+    total_sectors: u64,
+    used_backup_boot_sector: bool,
+    /// The sector of the backup boot sector relative to the start of the
+    /// partition, or `0` if there is none.
+    backup_boot_sector: u16,
+    /// The sector of the FSInfo structure relative to the start of the
+    /// partition, or `0` if there is none.
+    fs_info_sector: u16,
+    /// The geometry mismatch that was clamped at mount, if any.
+    geometry_warning: Option<Error>,
+    /// The media descriptor mismatch tolerated at mount, if any.
+    media_warning: Option<Error>,
+    /// The second FAT entry as the volume is left once every change is
+    /// synced: as read at mount, less the no hard error bit after a failed
+    /// write. See `MountOptions::mark_dirty`.
+    fat_state: u32,
+    /// Whether the clean shutdown bit has been cleared on disk for changes
+    /// not yet synced.
+    marked_dirty: bool,
+    watchers: Watchers,
+    /// Whole-file data shared between `File` handles.
+    file_data: FileDataCache,
+    /// Advisory locks held on files.
+    locks: LockTable,
+    /// Directories resolved by path.
+    dir_cache: DirCache,
+    /// The name indexes of large directories.
+    dir_indexes: DirIndexes,
+    /// The entries beneath `MountOptions::protected_paths`, once resolved.
+    protected: Option<Protected>,
+    /// The number of corrupt directory entries skipped since mounting.
+    corrupt_entries: u64,
+    /// The device generation the caches above were filled under. See
+    /// `media_generation()`.
+    generation: u64,
+    /// The layout version of each directory whose entries have moved since
+    /// mounting. See `layout_version()`.
+    layouts: HashMap<Cluster, u64>,
+    /// The CRC-32 of each data cluster as first read, while
+    /// `MountOptions::cluster_checksums` is set.
+    checksums: HashMap<Cluster, u32>,
+    /// The media generations under which the device held this volume with
+    /// nothing lost, as of mounting or the last `remount()`. Handles opened
+    /// under any of them are valid while the current generation is one too.
+    bound_generations: Vec<u64>,
+    /// The device's count of discarded writes as of the last bound
+    /// generation.
+    bound_discarded: u64,
+    options: MountOptions,
+}
+
+/// Finds the partition `options` selects on `device` and reads its BPB.
+/// Returns the partition's first sector and length, the BPB, and whether the
+/// BPB was read from the backup boot sector.
+fn read_boot_sector<T: BlockDevice>(
+    device: &mut T,
+    options: &MountOptions,
+) -> Result<(u32, u32, BiosParameterBlock, bool), Error> {
+    let mbr = MasterBootRecord::from(&mut *device)?;
+
+    let partition = match options.partition {
+        Some(index) => mbr
+            .partition_table_entries
+            .get(index)
+            .filter(|partition| partition.total_sectors != 0),
+        None => mbr.get_fat_partition(),
+    };
+    let (bpb_offset, partition_sectors) = match partition {
+        None => {
+            return Err(Error::NotFound);
+        }
+        Some(partition) => (partition.relative_sector, partition.total_sectors),
+    };
+
+    match BiosParameterBlock::from(&mut *device, bpb_offset as u64) {
+        Err(Error::BadSignature) => {
+            let backup = bpb_offset as u64 + BACKUP_BOOT_SECTOR;
+            let bpb = BiosParameterBlock::from(device, backup)?;
+            Ok((bpb_offset, partition_sectors, bpb, true))
+        }
+        result => Ok((bpb_offset, partition_sectors, result?, false)),
+    }
 }
 
 impl VFat {
-    pub fn from<T>(mut device: T) -> Result<Shared<VFat>, Error>
+    pub fn from<T>(device: T) -> Result<Shared<VFat>, Error>
     where
         T: BlockDevice + 'static,
     {
-        let mbr = MasterBootRecord::from(&mut device)?;
+        VFat::with_options(device, MountOptions::default())
+    }
 
-        let bpb_offset = match mbr.get_fat_partition_offset() {
-            None => {
-                return Err(Error::NotFound);
+    /// Mounts the FAT32 partition on `device`, interpreting it according to
+    /// `options`.
+    pub fn with_options<T>(mut device: T, options: MountOptions) -> Result<Shared<VFat>, Error>
+    where
+        T: BlockDevice + 'static,
+    {
+        let (bpb_offset, partition_sectors, bpb, used_backup_boot_sector) =
+            read_boot_sector(&mut device, &options)?;
+
+        // Compare the BPB's size against what actually backs the volume, all
+        // in logical sectors.
+        let device_sector_size = device.sector_size();
+        let to_logical =
+            |physical: u64| physical * device_sector_size / bpb.bytes_per_sector as u64;
+        let mut total_sectors = bpb.total_sectors();
+        let mut geometry_warning = None;
+        let mut bounds = vec![("partition entry", to_logical(partition_sectors as u64))];
+        if let Some(sectors) = device.num_sectors() {
+            bounds.push((
+                "device",
+                to_logical(sectors.saturating_sub(bpb_offset as u64)),
+            ));
+        }
+        for (source, actual) in bounds {
+            if actual >= total_sectors {
+                continue;
             }
-            Some(offset) => offset,
-        };
 
-        let bpb = BiosParameterBlock::from(&mut device, bpb_offset as u64)?;
+            let mismatch = Error::GeometryMismatch {
+                source,
+                expected: bpb.total_sectors(),
+                actual,
+            };
+            match options.geometry_check {
+                GeometryCheck::Fail => return Err(mismatch),
+                GeometryCheck::Clamp => {
+                    total_sectors = actual;
+                    geometry_warning = Some(mismatch);
+                }
+            }
+        }
 
         let fat_start_sector = bpb_offset as u64 + bpb.reserved_sectors as u64;
 
-        let data_start_sector =
+        // FAT12 and FAT16 keep the root directory in a region of
+        // `max_dir_entries` slots between the FATs and the data region. FAT32
+        // declares no such region.
+        let root_dir_start =
             fat_start_sector + (bpb.sectors_per_fat as u64) * (bpb.num_fats as u64);
+        let root_dir_sectors =
+            (bpb.max_dir_entries as u64 * DIR_ENTRY_SIZE as u64 + bpb.bytes_per_sector as u64 - 1)
+                / bpb.bytes_per_sector as u64;
+        let root_dir_region = match root_dir_sectors {
+            0 => None,
+            sectors => Some(DirSource::Region {
+                start: root_dir_start,
+                sectors: sectors as u32,
+            }),
+        };
+        let data_start_sector = root_dir_start + root_dir_sectors;
+
+        // The number of data clusters is bounded both by the sectors after the
+        // FATs and by the number of entries a FAT can hold.
+        let data_sectors = (bpb_offset as u64 + total_sectors).saturating_sub(data_start_sector);
+        let fat_entries =
+            bpb.sectors_per_fat as u64 * bpb.bytes_per_sector as u64 / FAT_ENTRY_SIZE as u64;
+        let num_clusters = ::std::cmp::min(
+            data_sectors / bpb.sectors_per_cluster as u64,
+            fat_entries.saturating_sub(2),
+        ) as u32;
 
-        Ok(Shared::new(VFat {
-            device: CachedDevice::new(
+        let (sector_capacity, file_data_bytes, dir_entries) =
+            cache_sizes(&options, bpb.bytes_per_sector as usize);
+        let mut vfat = VFat {
+            device: CachedDevice::with_options(
                 device,
                 Partition {
                     start: bpb_offset as u64,
                     sector_size: bpb.bytes_per_sector as u64,
                 },
+                CacheOptions {
+                    capacity: sector_capacity,
+                    checksum_retries: options.checksum_retries,
+                    read_only: options.read_only,
+                },
             ),
             bytes_per_sector: bpb.bytes_per_sector as u16,
             sectors_per_cluster: bpb.sectors_per_cluster,
             sectors_per_fat: bpb.sectors_per_fat as u32,
+            partition_start: bpb_offset as u64,
+            hidden_sectors: bpb.num_hidden_sectors,
             fat_start_sector,
+            num_fats: bpb.num_fats,
+            active_fat: match bpb.flags & FAT_MIRRORING_DISABLED {
+                0 => None,
+                _ => Some((bpb.flags & 0xF) as u8),
+            },
+            dirty_fat_sectors: BTreeSet::new(),
+            next_free: Cluster(2),
+            free_clusters: None,
             data_start_sector,
+            num_clusters,
             root_dir_cluster: Cluster::from(bpb.root_cluster_num),
-        }))
+            root_dir_region,
+            volume_id: bpb.volume_id,
+            label: bpb.volume_label_string,
+            system_id: bpb.system_id_string,
+            total_sectors,
+            used_backup_boot_sector,
+            backup_boot_sector: bpb.backup_boot_sector_num,
+            fs_info_sector: bpb.fs_info_sector_num,
+            geometry_warning,
+            media_warning: None,
+            fat_state: 0,
+            marked_dirty: false,
+            watchers: Watchers::default(),
+            file_data: FileDataCache::new(file_data_bytes),
+            locks: LockTable::default(),
+            dir_cache: DirCache::new(dir_entries),
+            dir_indexes: DirIndexes::default(),
+            protected: None,
+            corrupt_entries: 0,
+            generation: 0,
+            layouts: HashMap::new(),
+            checksums: HashMap::new(),
+            bound_generations: vec![0],
+            bound_discarded: 0,
+            options,
+        };
+        if vfat.options.guard_metadata {
+            let metadata_end = vfat.fat_sector(vfat.num_fats, 0);
+            vfat.device.guard(0..metadata_end);
+        }
+        vfat.check_fat_header(bpb.fat_id)?;
+        Ok(Shared::new(vfat))
     }
 
-    /// A method to read from an offset of a cluster into a buffer
-    fn read_cluster(
-        &mut self,
-        cluster: Cluster,
-        // offset: usize, TODO: WAT?
-        buf: &mut [u8],
-    ) -> io::Result<usize> {
-        let start_read_sector = self.data_start_sector as u64
-            + (cluster.0.saturating_sub(2)) as u64 * self.sectors_per_cluster as u64;
-        let mut bytes_read = 0;
-        for i in 0..self.sectors_per_cluster {
-            let start_byte = (i as u16 * self.bytes_per_sector) as usize;
+    /// Reads the reserved entries at the start of the FAT, remembering the
+    /// volume's state, and compares the FAT's media descriptor against the
+    /// BPB's, `media`.
+    fn check_fat_header(&mut self, media: u8) -> Result<(), Error> {
+        let header = self.fat_header()?;
+        self.fat_state = header.state_entry;
+        if header.media_descriptor() != media {
+            let mismatch = Error::MediaMismatch {
+                bpb: media,
+                fat: header.media_descriptor(),
+            };
+            match self.options.fat_mode {
+                FatMode::Strict => return Err(mismatch),
+                FatMode::Tolerant => self.media_warning = Some(mismatch),
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns an iterator over every file on the volume, hidden and system
+    /// files included, yielding each file's path, metadata, and extents.
+    /// Directories are read lazily as the iterator advances. See `AllFiles`.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error reading the root directory fails with, if it does.
+    pub fn iter_all_files(vfat: &Shared<VFat>) -> io::Result<AllFiles> {
+        AllFiles::new(vfat)
+    }
+
+    /// Runs `op`, which should operate on `vfat`, with the device time it may
+    /// take limited to `budget`. Once device reads and writes have taken
+    /// `budget` in total, further accesses that miss the sector cache fail
+    /// with an error of kind `TimedOut`, so that a failing card that takes
+    /// seconds per sector cannot stall the caller indefinitely. A budget set
+    /// within `op` is limited to what remains of `budget`.
+    ///
+    /// An operation that times out may have made some of its changes, in the
+    /// cache, and not others.
+    pub fn with_io_budget<T, F>(vfat: &Shared<VFat>, budget: Duration, op: F) -> io::Result<T>
+    where
+        F: FnOnce() -> io::Result<T>,
+    {
+        let (outer, budget) = {
+            let mut volume = vfat.borrow_mut();
+            let outer = volume.device.io_budget_remaining();
+            let budget = outer.map_or(budget, |outer| ::std::cmp::min(outer, budget));
+            volume.device.set_io_budget(Some(budget));
+            (outer, budget)
+        };
+
+        let result = op();
+
+        let mut volume = vfat.borrow_mut();
+        let left = volume.device.io_budget_remaining().unwrap_or(budget);
+        let spent = budget - left;
+        let outer = outer.map(|outer| outer.checked_sub(spent).unwrap_or(Duration::from_secs(0)));
+        volume.device.set_io_budget(outer);
+        result
+    }
+
+    /// Returns a summary of the volume's geometry, identity, free space, and
+    /// state. Counting free clusters scans the whole FAT.
+    pub fn describe(&mut self) -> io::Result<MountReport> {
+        let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).trim_right().to_string();
+        let header = self.fat_header()?;
+
+        Ok(MountReport {
+            fat_type: text(&self.system_id),
+            label: text(&self.label),
+            serial: self.volume_id,
+            bytes_per_sector: self.bytes_per_sector,
+            sectors_per_cluster: self.sectors_per_cluster,
+            total_sectors: self.total_sectors,
+            num_fats: self.num_fats,
+            sectors_per_fat: self.sectors_per_fat,
+            num_clusters: self.num_clusters,
+            free_clusters: self.free_extents()?.free_clusters(),
+            active_fat: self.active_fat,
+            media_descriptor: header.media_descriptor(),
+            dirty: !header.clean_shutdown(),
+            hard_error: header.hard_error(),
+            used_backup_boot_sector: self.used_backup_boot_sector,
+            partition_start: self.partition_start,
+            hidden_sectors: self.hidden_sectors,
+        })
+    }
+
+    /// The first cluster of the root directory.
+    pub(crate) fn root_dir_cluster(&self) -> Cluster {
+        self.root_dir_cluster
+    }
 
-            bytes_read += self.device.read_sector(
-                start_read_sector + i as u64,
-                &mut buf[start_byte..start_byte + self.bytes_per_sector as usize],
-            )?;
+    /// The sector where the partition begins, per the partition table. This
+    /// is what the volume is mounted from.
+    pub fn partition_start(&self) -> u64 {
+        self.partition_start
+    }
+
+    /// The number of sectors preceding the partition, per the BPB. Some
+    /// tools write a value that disagrees with `partition_start()`; it is
+    /// ignored when mounting.
+    pub fn hidden_sectors(&self) -> u32 {
+        self.hidden_sectors
+    }
+
+    /// Rewrites the BPB's hidden sectors field, in the boot sector and its
+    /// backup, to match `partition_start()`. Returns `false` if the two
+    /// already agreed. Changes reach the disk on the next `sync()`.
+    pub fn repair_hidden_sectors(&mut self) -> io::Result<bool> {
+        if self.hidden_sectors as u64 == self.partition_start {
+            return Ok(false);
         }
-        Ok(bytes_read)
+
+        let hidden_sectors = self.partition_start as u32;
+        for sector in self.boot_sectors() {
+            let bpb = self.metadata_sector_mut(sector)?;
+            LittleEndian::write_u32(&mut bpb[28..32], hidden_sectors);
+        }
+
+        self.hidden_sectors = hidden_sectors;
+        Ok(true)
     }
 
-    ///  * A method to read all of the clusters chained from a starting cluster
-    ///    into a vector.
+    /// Repairs damage left by crashes and faulty writers, making the repairs
+    /// `options` allow, and returns every change made, in order:
     ///
-    pub fn read_chain(&mut self, start: Cluster, buf: &mut Vec<u8>) -> io::Result<usize> {
-        let mut cluster_cursor = start;
-        let mut bytes_read = 0usize;
+    ///  * Chains that loop, run into another chain, or run into an unusable
+    ///    cluster are cut at their last sound cluster, directories first.
+    ///  * File sizes are made to agree with their chains.
+    ///  * Orphaned long file name entries are marked deleted.
+    ///  * Chains in use by no entry are saved as `FILEnnnn.CHK` files in a
+    ///    new `FOUND.nnn` directory in the root directory.
+    ///  * The FSInfo free cluster count and hint are rebuilt.
+    ///
+    /// Handles opened before the repair may be stale afterwards. Changes
+    /// reach the disk on the next `sync()`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidData` if the root directory begins at an
+    /// unusable cluster, and an error of `Other` if the volume is too full
+    /// to hold a `FOUND.nnn` directory.
+    pub fn repair(&mut self, options: RepairOptions) -> io::Result<Vec<RepairAction>> {
+        let root = self.root_dir_cluster;
+        repair::repair(self, root, options)
+    }
 
-        loop {
-            let fat_entry = self.fat_entry(cluster_cursor)?;
-            cluster_cursor = match fat_entry.status() {
-                Status::Data(next) => {
-                    buf.resize_default(
-                        buf.len()
-                            + self.bytes_per_sector as usize * self.sectors_per_cluster as usize,
-                    );
-                    bytes_read += self.read_cluster(cluster_cursor, &mut buf[bytes_read..])?;
-                    next
-                }
-                Status::Eoc(_) => {
-                    buf.resize_default(
-                        buf.len()
-                            + self.bytes_per_sector as usize * self.sectors_per_cluster as usize,
-                    );
-                    bytes_read += self.read_cluster(cluster_cursor, &mut buf[bytes_read..])?;
-
-                    return Ok(bytes_read);
-                }
-                _ => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        "Fat entry is Free/Reserved/Bad",
-                    ));
-                }
+    /// Finds the names in each directory of the volume that differ only in
+    /// case. This driver, like Windows, compares names without regard to
+    /// case (though it folds only ASCII letters), so only one name of each
+    /// collision can be opened. Directories are checked breadth first from
+    /// the root directory; hidden and system entries are included.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first error reading a directory.
+    pub fn name_collisions(&mut self) -> io::Result<Vec<NameCollision>> {
+        let root = self.root_dir_cluster;
+        collisions::find(self, root)
+    }
+
+    /// Rewrites the FSInfo sector's free cluster count and next free cluster
+    /// hint to match the FAT. Returns the new values, or `None` if they
+    /// already matched or the volume has no valid FSInfo sector. Counting
+    /// free clusters scans the whole FAT.
+    pub(crate) fn rebuild_fs_info(&mut self) -> io::Result<Option<(u32, Cluster)>> {
+        let reserved = self.fat_start_sector - self.partition_start;
+        if self.fs_info_sector == 0 || self.fs_info_sector as u64 >= reserved {
+            return Ok(None);
+        }
+
+        let space = self.free_extents()?;
+        let free = space.free_clusters() as u32;
+        let next_free = space
+            .extents
+            .first()
+            .map_or(NO_FREE_HINT, |extent| extent.start.0);
+
+        let info = self
+            .device
+            .get_mut_guarded(self.partition_start + self.fs_info_sector as u64)?;
+        if LittleEndian::read_u32(&info[0..4]) != FSINFO_LEAD_SIGNATURE
+            || LittleEndian::read_u32(&info[484..488]) != FSINFO_STRUCT_SIGNATURE
+        {
+            return Ok(None);
+        }
+        if LittleEndian::read_u32(&info[488..492]) == free
+            && LittleEndian::read_u32(&info[492..496]) == next_free
+        {
+            return Ok(None);
+        }
+
+        LittleEndian::write_u32(&mut info[488..492], free);
+        LittleEndian::write_u32(&mut info[492..496], next_free);
+        Ok(Some((free, Cluster(next_free))))
+    }
+
+    /// The boot sector and, if it lies within the reserved sectors, its
+    /// backup.
+    fn boot_sectors(&self) -> Vec<u64> {
+        let reserved = self.fat_start_sector - self.partition_start;
+        let mut sectors = vec![self.partition_start];
+        if self.backup_boot_sector != 0 && (self.backup_boot_sector as u64) < reserved {
+            sectors.push(self.partition_start + self.backup_boot_sector as u64);
+        }
+        sectors
+    }
+
+    /// Installs `code` into the boot code area of the boot sector and of its
+    /// backup, zeroing the rest of the area. The BPB and the boot signature
+    /// are left untouched. Changes reach the disk on the next `sync()`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error kind of `InvalidInput` if `code` is longer than
+    /// `BOOT_CODE_SIZE` bytes.
+    pub fn install_boot_code(&mut self, code: &[u8]) -> io::Result<()> {
+        if code.len() > BOOT_CODE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "boot code does not fit in the boot sector",
+            ));
+        }
+
+        for sector in self.boot_sectors() {
+            let area = &mut self.metadata_sector_mut(sector)?[90..90 + BOOT_CODE_SIZE];
+            for byte in area.iter_mut() {
+                *byte = 0;
             }
+            area[..code.len()].copy_from_slice(code);
         }
+
+        Ok(())
     }
 
-    /// A method to return a reference to a `FatEntry` for a cluster where the
-    /// reference points directly into a cached sector.
-    fn fat_entry(&mut self, cluster: Cluster) -> io::Result<FatEntry> {
-        let entries_per_sector = (self.bytes_per_sector / FAT_ENTRY_SIZE) as u32;
-        // index of the sector that contains this cluster. e.g. if there are
-        // 10 fat entries per sector and we want sector 12, this should be 1
-        let fat_sector_index = cluster.0 / entries_per_sector;
-        // index of the entry within the given sector, e.g. if we have the
-        // sector with entries 10-20 and we want sectore 12, this should be 2
-        let fat_entry_index = cluster.0 % entries_per_sector;
+    /// Returns the sector of the volume `sector` sectors past the boot
+    /// sector, checking that it lies within the partition.
+    fn raw_sector(&self, sector: u64) -> io::Result<u64> {
+        if sector >= self.total_sectors {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "sector lies outside of the partition",
+            ));
+        }
+        Ok(self.partition_start + sector)
+    }
 
-        let fat_entries = self
-            .device
-            .get(self.fat_start_sector + fat_sector_index as u64)?;
+    /// Reads logical sector `sector` of the partition, counting from the boot
+    /// sector, into `buf`, and returns the number of bytes read. The read is
+    /// served from the sector cache, so it sees writes not yet synced.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error kind of `InvalidInput` if `sector` lies outside of
+    /// the partition, and of `UnexpectedEof` if `buf` is smaller than a
+    /// sector.
+    pub fn read_raw_sector(&mut self, sector: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let sector = self.raw_sector(sector)?;
+        let sector_size = self.sector_size();
+        if buf.len() < sector_size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "buffer smaller than a sector",
+            ));
+        }
+        self.device.read_sector(sector, &mut buf[..sector_size])
+    }
 
-        let idx = (fat_entry_index * FAT_ENTRY_SIZE as u32) as usize;
+    /// Writes the first sector's worth of `buf` to logical sector `sector` of
+    /// the partition, counting from the boot sector, and returns the number
+    /// of bytes written. The write goes through the sector cache, as the
+    /// file system's own writes do, and reaches the disk on the next
+    /// `sync()`.
+    ///
+    /// Writes to the primary FAT are copied to its mirrors on `sync()`, and
+    /// cached file data, directories, and directory indexes are dropped. Fields read from the
+    /// boot sector at mount, such as the volume label, are not re-read.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error kind of `InvalidInput` if `sector` lies outside of
+    /// the partition, and of `UnexpectedEof` if `buf` is smaller than a
+    /// sector. If `MountOptions::guard_metadata` is set, writes to the
+    /// reserved sectors and the FATs fail with an error kind of
+    /// `PermissionDenied`, and panic in debug builds.
+    pub fn write_raw_sector(&mut self, sector: u64, buf: &[u8]) -> io::Result<usize> {
+        let sector = self.raw_sector(sector)?;
+        let sector_size = self.sector_size();
+        if buf.len() < sector_size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "buffer smaller than a sector",
+            ));
+        }
 
-        let raw_fat_entry = LittleEndian::read_u32(&fat_entries[idx..idx + 4]);
-        Ok(FatEntry(raw_fat_entry))
+        self.write_sectors(sector, 0, &buf[..sector_size])?;
+        let primary = self.fat_sector(self.primary_fat(), 0);
+        if sector >= primary && sector < primary + self.sectors_per_fat as u64 {
+            self.dirty_fat_sectors.insert((sector - primary) as u32);
+            self.free_clusters = None;
+        }
+        self.protected = None;
+        self.forget_dirs();
+        self.dir_indexes.clear();
+        Ok(sector_size)
     }
-}
 
-impl<'a> FileSystem for &'a Shared<VFat> {
-    type File = File;
-    type Dir = Dir;
-    type Entry = Entry;
+    /// Returns the `Error::GeometryMismatch` that was clamped at mount, if the
+    /// BPB declared more sectors than the partition or device holds.
+    pub fn geometry_warning(&self) -> Option<&Error> {
+        self.geometry_warning.as_ref()
+    }
 
-    fn open<P: AsRef<Path>>(&self, path: P) -> io::Result<Self::Entry> {
-        let mut current_dir = Entry::Dir(Dir {
-            start_cluster: self.borrow().root_dir_cluster,
-            vfat: (*self).clone(),
-            metadata: Default::default(),
-        });
+    /// Returns the `Error::MediaMismatch` that was tolerated at mount, if the
+    /// FAT's media descriptor differed from the BPB's.
+    pub fn media_warning(&self) -> Option<&Error> {
+        self.media_warning.as_ref()
+    }
 
-        for file_component in path.as_ref().components() {
-            if let Component::Normal(name) = file_component {
-                match traits::Entry::as_dir(&current_dir) {
-                    Some(ref dir) => {
-                        current_dir = dir.find(name)?;
-                    }
-                    None => {
-                        return Err(io::Error::new(
-                            io::ErrorKind::InvalidInput,
-                            "tried to traverse through file.",
-                        ));
+    /// Reads the two reserved entries at the start of the primary FAT. They
+    /// reflect changes not yet synced.
+    pub fn fat_header(&mut self) -> io::Result<FatHeader> {
+        Ok(FatHeader {
+            media_entry: self.fat_entry(Cluster(0))?.raw(),
+            state_entry: self.fat_entry(Cluster(1))?.raw(),
+        })
+    }
+
+    /// Sets the clean shutdown bit and clears the hard error bit of the
+    /// second FAT entry, as a checker does once the volume is consistent.
+    /// Changes reach the disk on the next `sync()`.
+    pub fn mark_clean(&mut self) -> io::Result<()> {
+        self.fat_state |= CLEAN_SHUTDOWN | NO_HARD_ERROR;
+        let state = self.fat_state;
+        self.write_fat_state(state)
+    }
+
+    /// Writes `state` as the second entry of every FAT in use.
+    fn write_fat_state(&mut self, state: u32) -> io::Result<()> {
+        let copies = match self.active_fat {
+            Some(active) => active..active + 1,
+            None => 0..self.num_fats,
+        };
+        for copy in copies {
+            let sector = self.device.get_mut_guarded(self.fat_sector(copy, 0))?;
+            LittleEndian::write_u32(&mut sector[4..8], state);
+        }
+        Ok(())
+    }
+
+    /// Clears the clean shutdown bit on disk ahead of the first change since
+    /// mounting or the last `sync()`, if `MountOptions::mark_dirty` is set.
+    fn mark_dirty(&mut self) -> io::Result<()> {
+        if !self.options.mark_dirty || self.marked_dirty {
+            return Ok(());
+        }
+
+        let state = self.fat_state & !CLEAN_SHUTDOWN;
+        self.write_fat_state(state)?;
+        self.device.flush()?;
+        self.marked_dirty = true;
+        Ok(())
+    }
+
+    /// Records in the second FAT entry that writing to the device failed
+    /// with `error`, if `MountOptions::mark_dirty` is set. Errors that do not
+    /// come from the device, such as an exhausted I/O budget, are ignored.
+    fn record_hard_error(&mut self, error: &io::Error) {
+        match error.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::PermissionDenied => return,
+            _ if !self.options.mark_dirty => return,
+            _ => {}
+        }
+
+        self.fat_state &= !NO_HARD_ERROR;
+        let state = self.fat_state & !CLEAN_SHUTDOWN;
+        // The entry reaches the disk with the next successful flush.
+        let _ = self.write_fat_state(state);
+    }
+
+    /// Returns sector `sector` for modification, marking the volume dirty
+    /// first. See `mark_dirty()`.
+    fn sector_mut(&mut self, sector: u64) -> io::Result<&mut [u8]> {
+        self.mark_dirty()?;
+        self.forget_checksum(sector);
+        self.device.get_mut(sector)
+    }
+
+    /// Replaces the contents of sector `sector` with `data`, marking the
+    /// volume dirty first. See `mark_dirty()`.
+    fn overwrite_sector(&mut self, sector: u64, data: &[u8]) -> io::Result<()> {
+        self.mark_dirty()?;
+        self.forget_checksum(sector);
+        self.device.overwrite(sector, data)
+    }
+
+    /// Drops the recorded checksum of the cluster holding sector `sector`,
+    /// which is about to be written. See `MountOptions::cluster_checksums`.
+    fn forget_checksum(&mut self, sector: u64) {
+        if self.checksums.is_empty() || sector < self.data_start_sector {
+            return;
+        }
+        let index = (sector - self.data_start_sector) / self.sectors_per_cluster as u64;
+        self.checksums.remove(&Cluster(index as u32 + 2));
+    }
+
+    /// Returns sector `sector` of the reserved region or the FATs for
+    /// modification, as `sector_mut()` does, even if it is guarded by
+    /// `MountOptions::guard_metadata`.
+    fn metadata_sector_mut(&mut self, sector: u64) -> io::Result<&mut [u8]> {
+        self.mark_dirty()?;
+        self.device.get_mut_guarded(sector)
+    }
+
+    /// Replaces the contents of sector `sector` of the reserved region or the
+    /// FATs, as `overwrite_sector()` does, even if it is guarded by
+    /// `MountOptions::guard_metadata`.
+    fn overwrite_metadata_sector(&mut self, sector: u64, data: &[u8]) -> io::Result<()> {
+        self.mark_dirty()?;
+        self.device.overwrite_guarded(sector, data)
+    }
+
+    /// Checks that `name` can be given to a new entry on this volume,
+    /// honoring the mount's `windows_compat` option. See
+    /// `validate_long_name()`.
+    pub fn validate_name(&self, name: &str) -> io::Result<()> {
+        validate_long_name(name, self.options.windows_compat)
+    }
+
+    /// Returns the volume serial number recorded in the EBPB.
+    pub fn volume_id(&self) -> u32 {
+        self.volume_id
+    }
+
+    /// Returns the number of data clusters in the volume. Valid clusters are
+    /// numbered `2` through `num_clusters() + 1`.
+    pub fn num_clusters(&self) -> u32 {
+        self.num_clusters
+    }
+
+    /// Returns a bitmap of the sectors of the volume that hold live data: the
+    /// reserved area, the FATs, and every allocated (not free and not bad)
+    /// cluster. Sectors are logical sectors counted from the start of the
+    /// partition; the bitmap ends with the last data cluster.
+    pub fn used_sector_bitmap(&mut self) -> io::Result<SectorBitmap> {
+        let spc = self.sectors_per_cluster as u64;
+        let data_start = self.data_start_sector - self.partition_start;
+        let mut bitmap = SectorBitmap::new(data_start + self.num_clusters as u64 * spc);
+        bitmap.mark(0, data_start);
+
+        for n in 2..self.num_clusters + 2 {
+            match self.fat_entry(Cluster(n))?.status() {
+                Status::Free | Status::Bad => {}
+                _ => bitmap.mark(data_start + (n - 2) as u64 * spc, spc),
+            }
+        }
+
+        Ok(bitmap)
+    }
+
+    /// Writes a sparse image of the disk holding this volume to `writer` and
+    /// returns the number of bytes of disk data it holds. The image contains
+    /// every sector before the partition (e.g. the MBR) and the sectors marked
+    /// in `used_sector_bitmap()`; free clusters are skipped. Modified sectors
+    /// that have not been synced are dumped as modified. See
+    /// `restore_sparse()`.
+    pub fn dump_sparse<W: io::Write>(&mut self, writer: &mut W) -> io::Result<u64> {
+        let runs = self.used_sector_bitmap()?.used_runs();
+        let sector_size = self.bytes_per_sector as u64;
+        let base = self.partition_start * self.device.sector_len(0) as u64;
+
+        sparse::write_header(writer, runs.len() as u64 + 1)?;
+        sparse::write_run_header(writer, 0, base)?;
+        for sector in 0..self.partition_start {
+            writer.write_all(&self.device.read_through(sector)?)?;
+        }
+
+        let mut dumped = base;
+        for (start, count) in runs {
+            sparse::write_run_header(writer, base + start * sector_size, count * sector_size)?;
+            for sector in start..start + count {
+                let data = self.device.read_through(self.partition_start + sector)?;
+                writer.write_all(&data)?;
+            }
+            dumped += count * sector_size;
+        }
+
+        Ok(dumped)
+    }
+
+    /// Copies the disk holding this volume to `dst` and returns the number of
+    /// bytes copied. As with `dump_sparse()`, every sector before the
+    /// partition and the sectors marked in `used_sector_bitmap()` are copied,
+    /// and modified sectors that have not been synced are copied as
+    /// modified; free clusters are left as they were on `dst`. If `label` is
+    /// given, the copy's volume label is set to it in the boot sector, its
+    /// backup, and the root directory's label entry, if there is one.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error kind of `InvalidInput` if `dst` is smaller than the
+    /// disk up to the end of the partition, if `dst`'s sector size does not
+    /// divide this volume's sector sizes, or if `label` is not a valid volume
+    /// label. Errors reading this volume or writing `dst` are returned as
+    /// they occur.
+    ///
+    /// Sectors are copied `MountOptions::transfer_chunk_size` bytes at a
+    /// time, read past the sector cache.
+    pub fn clone_to<D: BlockDevice>(
+        &mut self,
+        dst: &mut D,
+        label: Option<&str>,
+    ) -> io::Result<u64> {
+        let device_sector_size = self.device.sector_len(0) as u64;
+        let sector_size = self.bytes_per_sector as u64;
+        let dst_sector_size = dst.sector_size();
+        if device_sector_size % dst_sector_size != 0 || sector_size % dst_sector_size != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "destination sector size does not divide the volume's",
+            ));
+        }
+
+        let base = self.partition_start * device_sector_size;
+        let end = base + self.total_sectors * sector_size;
+        if let Some(sectors) = dst.num_sectors() {
+            if sectors * dst_sector_size < end {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "destination is smaller than the volume",
+                ));
+            }
+        }
+
+        // Label bytes to patch, as (sector, offset within the sector, label).
+        let mut patches = Vec::new();
+        if let Some(label) = label {
+            let label = clone::encode_label(label)?;
+            for sector in self.boot_sectors() {
+                patches.push((sector, 71, label));
+            }
+            if let Some((sector, offset)) = self.label_entry()? {
+                patches.push((sector, offset, label));
+            }
+        }
+
+        let mut runs = vec![(0, self.partition_start)];
+        for (start, count) in self.used_sector_bitmap()?.used_runs() {
+            runs.push((self.partition_start + start, count));
+        }
+
+        // Each run is copied a chunk of consecutive sectors at a time.
+        let chunk = self.transfer_chunk();
+        let mut data = Vec::with_capacity(chunk);
+        let mut copied = 0;
+        for (start, count) in runs {
+            let mut sector = start;
+            while sector < start + count {
+                let first = sector;
+                data.clear();
+                while sector < start + count
+                    && (data.is_empty() || data.len() + self.device.sector_len(sector) <= chunk)
+                {
+                    let at = data.len();
+                    data.extend_from_slice(&self.device.read_through(sector)?);
+                    for &(patched, offset, ref label) in &patches {
+                        if patched == sector {
+                            data[at + offset..at + offset + 11].copy_from_slice(label);
+                        }
                     }
+                    sector += 1;
+                }
+
+                let offset = if first < self.partition_start {
+                    first * device_sector_size
+                } else {
+                    base + (first - self.partition_start) * sector_size
+                };
+                for (i, piece) in data.chunks(dst_sector_size as usize).enumerate() {
+                    dst.write_sector(offset / dst_sector_size + i as u64, piece)?;
+                }
+                copied += data.len() as u64;
+            }
+        }
+
+        Ok(copied)
+    }
+
+    /// Returns the sector holding the root directory's volume label entry, if
+    /// there is one, and the entry's byte offset within that sector.
+    fn label_entry(&mut self) -> io::Result<Option<(u64, usize)>> {
+        let mut buf = Vec::new();
+        let root = self.dir_source(self.root_dir_cluster);
+        root.read(self, &mut buf)?;
+
+        let slot = buf
+            .chunks(DIR_ENTRY_SIZE)
+            .take_while(|entry| entry[0] != END_OF_ENTRIES)
+            .position(|entry| {
+                entry[0] != DELETED_ENTRY
+                    && entry[11] != ATTR_LFN
+                    && entry[11] & ATTR_VOLUME_ID != 0
+            });
+        let slot = match slot {
+            Some(slot) => slot,
+            None => return Ok(None),
+        };
+
+        root.position(self, slot * DIR_ENTRY_SIZE).map(Some)
+    }
+
+    /// Scans the FAT and returns every run of free clusters along with a
+    /// histogram of run lengths.
+    pub fn free_extents(&mut self) -> io::Result<FreeSpace> {
+        let mut space = FreeSpace::default();
+        let mut run: Option<Extent> = None;
+
+        for n in 2..self.num_clusters + 2 {
+            if self.fat_entry(Cluster(n))?.status() == Status::Free {
+                match run {
+                    Some(ref mut extent) => extent.len += 1,
+                    None => run = Some(Extent::new(Cluster(n), 1)),
                 }
+            } else if let Some(extent) = run.take() {
+                space.push(extent);
             }
         }
-        Ok(current_dir)
+
+        if let Some(extent) = run {
+            space.push(extent);
+        }
+
+        Ok(space)
+    }
+
+    /// Returns `true` if the volume is mounted read-only, because the device
+    /// is not writable or `MountOptions::read_only` is set. Changes to a
+    /// read-only volume fail with an error of kind `PermissionDenied`.
+    pub fn read_only(&self) -> bool {
+        !self.device.writable()
+    }
+
+    /// Returns the options this volume was mounted with.
+    pub fn options(&self) -> &MountOptions {
+        &self.options
+    }
+
+    /// Returns the cap on the memory held by the volume's caches, if any. See
+    /// `MountOptions::memory_budget`.
+    pub fn memory_budget(&self) -> Option<MemoryBudget> {
+        self.options.memory_budget
+    }
+
+    /// Replaces the cap on the memory held by the volume's caches, resizing
+    /// them and evicting whatever no longer fits. With `None`, the caches are
+    /// sized by `MountOptions::file_data_cache_bytes` and `dir_cache_entries`
+    /// again and the sector cache is unbounded.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if dirty sectors must be written to shrink the sector
+    /// cache and writing fails. The new budget is in effect regardless.
+    pub fn set_memory_budget(&mut self, budget: Option<MemoryBudget>) -> io::Result<()> {
+        self.options.memory_budget = budget;
+        let (sector_capacity, file_data_bytes, dir_entries) =
+            cache_sizes(&self.options, self.sector_size());
+        self.file_data.set_budget(file_data_bytes);
+        self.dir_cache.set_capacity(dir_entries);
+        self.device.set_capacity(sector_capacity)
+    }
+
+    /// Returns the bytes currently held by each of the volume's caches.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            sectors: self.device.cached_bytes(),
+            file_data: self.file_data.bytes(),
+            dirs: self.dir_cache.len() * DIR_CACHE_ENTRY_BYTES,
+        }
+    }
+
+    /// Converts the on-disk local timestamp `ts` to a `SystemTime` using the
+    /// mount's timezone offset.
+    pub fn to_system_time(&self, ts: Timestamp) -> SystemTime {
+        ts.to_system_time(self.options.timezone_offset)
+    }
+
+    /// Converts `time` to an on-disk local timestamp using the mount's
+    /// timezone offset.
+    pub fn timestamp_from(&self, time: SystemTime) -> Timestamp {
+        Timestamp::from_system_time(time, self.options.timezone_offset)
+    }
+
+    /// The first sector of `cluster`.
+    pub(crate) fn cluster_start_sector(&self, cluster: Cluster) -> u64 {
+        self.data_start_sector as u64
+            + (cluster.0.saturating_sub(2)) as u64 * self.sectors_per_cluster as u64
+    }
+
+    /// The number of device sectors per logical sector of the volume.
+    fn sector_factor(&self) -> u64 {
+        self.bytes_per_sector as u64 / self.device.device_sector_size()
+    }
+
+    /// The size in bytes of a cluster.
+    pub fn cluster_size_bytes(&self) -> u64 {
+        self.cluster_size() as u64
+    }
+
+    /// Returns the sector of the underlying device where `cluster` begins, as
+    /// addressed by `BlockDevice::read_sector()` on that device. A cluster
+    /// spans `cluster_size_bytes()` bytes of consecutive device sectors.
+    /// Returns `None` if `cluster` is not a data cluster of the volume.
+    pub fn cluster_to_sector(&self, cluster: Cluster) -> Option<u64> {
+        if cluster.0 < 2 || cluster.0 >= self.num_clusters + 2 {
+            return None;
+        }
+
+        let logical = self.cluster_start_sector(cluster) - self.partition_start;
+        Some(self.partition_start + logical * self.sector_factor())
+    }
+
+    /// Returns the data cluster holding sector `sector` of the underlying
+    /// device, or `None` if the sector lies outside the volume's data region.
+    pub fn sector_to_cluster(&self, sector: u64) -> Option<Cluster> {
+        let data_start = self.cluster_to_sector(Cluster(2))?;
+        if sector < data_start {
+            return None;
+        }
+
+        let per_cluster = self.sectors_per_cluster as u64 * self.sector_factor();
+        let index = (sector - data_start) / per_cluster;
+        if index >= self.num_clusters as u64 {
+            return None;
+        }
+        Some(Cluster(index as u32 + 2))
+    }
+
+    /// Returns the latency histograms of the device reads and writes issued
+    /// since the last call, and starts recording afresh.
+    pub fn take_io_metrics(&mut self) -> IoMetrics {
+        self.device.take_metrics()
+    }
+
+    /// Calls `hook` with the operation, sector, and latency of every device
+    /// read or write that takes longer than `threshold`.
+    pub fn set_slow_io_hook(&mut self, threshold: Duration, hook: SlowIoHook) {
+        self.device.set_slow_io_hook(threshold, hook)
     }
 
-    fn create_file<P: AsRef<Path>>(self, _path: P) -> io::Result<Self::File> {
-        unimplemented!("read only file system")
+    /// Calls `callback` with every file created or written through this
+    /// `VFat` at or beneath `prefix`, e.g. `/config`. Prefix components are
+    /// matched case-insensitively. Appends are reported when they are
+    /// flushed, not as each `write()` buffers them.
+    pub fn watch(&mut self, prefix: &str, callback: WatchCallback) -> WatchId {
+        self.watchers.add(prefix, callback)
+    }
+
+    /// Removes the watch `id`. Returns `false` if it was already removed.
+    pub fn unwatch(&mut self, id: WatchId) -> bool {
+        self.watchers.remove(id)
+    }
+
+    /// Polls the device for a media change and returns the number of changes
+    /// seen since mounting. On a change, every cache is dropped, including
+    /// writes not yet synced, and handles opened before it fail with
+    /// `Error::MediaChanged`. The volume is assumed to be the same; if another
+    /// card was inserted, it must be mounted anew. See `remount()` to bring
+    /// back the same card through a new device.
+    pub fn media_generation(&mut self) -> u64 {
+        let generation = self.device.generation();
+        if generation != self.generation {
+            self.file_data.clear();
+            self.dir_cache.clear();
+            self.dir_indexes.clear();
+            self.dirty_fat_sectors.clear();
+            self.marked_dirty = false;
+            self.next_free = Cluster(2);
+            self.free_clusters = None;
+            self.protected = None;
+            self.checksums.clear();
+            self.generation = generation;
+        }
+        self.generation
+    }
+
+    /// Returns an `Error::MediaChanged` if the medium has changed since
+    /// `generation`.
+    pub(crate) fn check_generation(&mut self, generation: u64) -> io::Result<()> {
+        let current = self.media_generation();
+        let bound = |g| self.bound_generations.contains(&g);
+        if current != generation && !(bound(current) && bound(generation)) {
+            return Err(Error::MediaChanged.into());
+        }
+        Ok(())
+    }
+
+    /// Replaces the device the volume is read from with `device`, as after
+    /// the card was removed and inserted again, perhaps under another device
+    /// handle. The new device must hold the same volume: the partition
+    /// `MountOptions::partition` selects must have the volume id (serial
+    /// number) and geometry read at mount.
+    ///
+    /// Every cache is dropped, as on a media change. If no writes were lost,
+    /// because every change had been synced, the handles opened before the
+    /// card was removed, and before any earlier `remount()`, are rebound to
+    /// the new device and keep working. Otherwise writes not yet synced are
+    /// lost with the old device, and handles opened before the remount fail
+    /// with `Error::MediaChanged`, since their view of the volume may not
+    /// match the card.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::VolumeMismatch` if the new device holds another
+    /// volume, and the errors of `with_options()` if its boot sector cannot
+    /// be read. The current device is kept on error.
+    pub fn remount<T>(&mut self, mut device: T) -> Result<(), Error>
+    where
+        T: BlockDevice + 'static,
+    {
+        let (bpb_offset, _, bpb, _) = read_boot_sector(&mut device, &self.options)?;
+        if bpb.volume_id != self.volume_id {
+            return Err(Error::VolumeMismatch {
+                reason: "volume id differs",
+            });
+        }
+        if bpb_offset as u64 != self.partition_start
+            || bpb.bytes_per_sector != self.bytes_per_sector
+            || bpb.sectors_per_cluster != self.sectors_per_cluster
+            || bpb.sectors_per_fat != self.sectors_per_fat
+            || bpb.num_fats != self.num_fats
+            || Cluster::from(bpb.root_cluster_num) != self.root_dir_cluster
+        {
+            return Err(Error::VolumeMismatch {
+                reason: "geometry differs",
+            });
+        }
+
+        let lost = self.device.dirty_sectors() > 0
+            || self.device.discarded_writes() != self.bound_discarded;
+        self.device.replace_device(device);
+        let generation = self.media_generation();
+        if lost {
+            self.bound_generations.clear();
+        }
+        self.bound_generations.push(generation);
+        self.bound_discarded = self.device.discarded_writes();
+        self.check_fat_header(bpb.fat_id)
+    }
+
+    /// The layout version of the directory at `dir_cluster`, which advances
+    /// whenever its entries move to other slots, as in `Dir::compact()`.
+    ///
+    /// Directory listings are snapshots: a `DirIter` decodes the slots it
+    /// read when created, so changes made while it is in use, by any handle,
+    /// never disturb it. A handle made from an entry remembers the layout
+    /// version its slots were read under, and only updates its entry while
+    /// the directory is still at that version; once entries have moved, the
+    /// slot it remembers may hold another entry.
+    pub(crate) fn layout_version(&self, dir_cluster: Cluster) -> u64 {
+        self.layouts.get(&dir_cluster).cloned().unwrap_or(0)
+    }
+
+    /// Advances the layout version of the directory at `dir_cluster` after
+    /// its entries have moved, given as `(old slot, new slot)` pairs, and
+    /// moves the advisory locks on them along.
+    pub(crate) fn entries_moved(&mut self, dir_cluster: Cluster, moves: &[(u32, u32)]) {
+        *self.layouts.entry(dir_cluster).or_insert(0) += 1;
+        self.protected = None;
+        self.locks.relocate(dir_cluster, moves);
+    }
+
+    /// Returns an error of kind `PermissionDenied` if the entry at
+    /// `location` may not be changed: the volume is read-only, or the entry
+    /// is protected by `MountOptions::protected_paths`.
+    pub(crate) fn check_modifiable(&mut self, location: Option<EntryLocation>) -> io::Result<()> {
+        if self.read_only() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "volume is read-only",
+            ));
+        }
+        match location {
+            Some(location) if self.protected()?.covers(location) => Err(protected_entry()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns an error of kind `PermissionDenied` if the entries of the
+    /// directory at `dir_cluster` are protected by
+    /// `MountOptions::protected_paths`.
+    pub(crate) fn check_dir_modifiable(&mut self, dir_cluster: Cluster) -> io::Result<()> {
+        if self.protected()?.covers_dir(dir_cluster) {
+            return Err(protected_entry());
+        }
+        Ok(())
+    }
+
+    /// The entries beneath `MountOptions::protected_paths`, resolved when
+    /// first needed and again after entries move.
+    fn protected(&mut self) -> io::Result<&Protected> {
+        if self.protected.is_none() {
+            let paths = self.options.protected_paths.clone();
+            self.protected = Some(Protected::resolve(self, &paths)?);
+        }
+        Ok(self.protected.as_ref().unwrap())
+    }
+
+    /// Returns an error of kind `Other` if growing a file from `old_size` to
+    /// `new_size` bytes would leave fewer free clusters than
+    /// `MountOptions::reserved_clusters`.
+    pub(crate) fn check_reserve(&mut self, old_size: u64, new_size: u64) -> io::Result<()> {
+        let reserved = self.options.reserved_clusters as u64;
+        if reserved == 0 {
+            return Ok(());
+        }
+
+        let cluster_size = self.cluster_size() as u64;
+        let clusters = |size: u64| (size + cluster_size - 1) / cluster_size;
+        let needed = clusters(new_size).saturating_sub(clusters(old_size));
+        if needed > 0 && self.free_clusters()? < needed + reserved {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "write would use the reserved free clusters",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the number of free clusters, counted by scanning the FAT when
+    /// first needed and kept up to date as clusters are allocated and freed.
+    pub fn free_clusters(&mut self) -> io::Result<u64> {
+        if let Some(free) = self.free_clusters {
+            return Ok(free);
+        }
+
+        let mut free = 0;
+        for entry in self.fat_entries().skip(2) {
+            if entry?.1.is_free() {
+                free += 1;
+            }
+        }
+        self.free_clusters = Some(free);
+        Ok(free)
+    }
+
+    /// Adjusts the count of free clusters by `delta`, if it has been counted.
+    fn count_freed(&mut self, delta: i64) {
+        if let Some(ref mut free) = self.free_clusters {
+            *free = (*free as i64 + delta) as u64;
+        }
+    }
+
+    /// Returns an `Error::StaleHandle` if the entries of the directory at
+    /// `dir_cluster` have moved since layout version `layout`.
+    pub(crate) fn check_layout(&self, dir_cluster: Cluster, layout: u64) -> io::Result<()> {
+        if self.layout_version(dir_cluster) != layout {
+            return Err(Error::StaleHandle.into());
+        }
+        Ok(())
+    }
+
+    /// The number of corrupt directory entries skipped while listing
+    /// directories since mounting, counting an entry again each time it is
+    /// listed. Nonzero means the volume needs checking. See `EntryMode`.
+    pub fn corrupt_entries(&self) -> u64 {
+        self.corrupt_entries
+    }
+
+    /// Counts `count` corrupt directory entries that were skipped.
+    pub(crate) fn note_corrupt_entries(&mut self, count: usize) {
+        self.corrupt_entries += count as u64;
+    }
+
+    /// The number of bytes of whole-file data held for sharing between
+    /// `File` handles. See `MountOptions::file_data_cache_bytes`.
+    pub fn cached_file_bytes(&self) -> usize {
+        self.file_data.bytes()
+    }
+
+    /// Returns the shared data of the file beginning at `start` of `size`
+    /// bytes, reading it from the volume if it is not cached.
+    pub(crate) fn file_data(&mut self, start: Cluster, size: u32) -> io::Result<Shared<Vec<u8>>> {
+        self.media_generation();
+        if let Some(data) = self.file_data.get(start, size) {
+            return Ok(data);
+        }
+
+        let mut data = Vec::new();
+        self.read_chain_of(start, size as usize, true, &mut data)?;
+        Ok(self.file_data.insert(start, size, data))
+    }
+
+    /// Forgets the directories cached by path, e.g. because entries have
+    /// moved on disk.
+    pub(crate) fn forget_dirs(&mut self) {
+        self.dir_cache.clear()
+    }
+
+    /// The name indexes of the directories looked up since mounting.
+    pub(crate) fn dir_indexes(&mut self) -> &mut DirIndexes {
+        &mut self.dir_indexes
+    }
+
+    /// Takes an advisory lock of `kind` on bytes `start..end` of the file
+    /// whose entry is at `target`. Returns the lock's id, or `None` if it
+    /// conflicts with a held lock.
+    pub(crate) fn try_lock(
+        &mut self,
+        target: EntryLocation,
+        start: u64,
+        end: u64,
+        kind: LockKind,
+    ) -> Option<u64> {
+        self.locks.try_acquire(target, start, end, kind)
+    }
+
+    /// Releases the advisory lock `id`.
+    pub(crate) fn release_lock(&mut self, id: u64) {
+        self.locks.release(id)
+    }
+
+    /// Drops the clean sectors of `cluster` from the sector cache.
+    pub(crate) fn evict_cluster(&mut self, cluster: Cluster) {
+        let start = self.cluster_start_sector(cluster);
+        for sector in start..start + self.sectors_per_cluster as u64 {
+            self.device.evict(sector);
+        }
+    }
+
+    /// A method to read from an offset of a cluster into a buffer
+    pub(crate) fn read_cluster(
+        &mut self,
+        cluster: Cluster,
+        // offset: usize, TODO: WAT?
+        buf: &mut [u8],
+    ) -> io::Result<usize> {
+        self.read_file_cluster(cluster, None, buf)
+    }
+
+    /// Reads `cluster` into `buf` as `read_cluster()` does. If the cluster
+    /// belongs to a file, `file` holds the file's first cluster and the
+    /// offset of the cluster's data in the file, for `Error::BitRotDetected`.
+    pub(crate) fn read_file_cluster(
+        &mut self,
+        cluster: Cluster,
+        file: Option<(Cluster, u64)>,
+        buf: &mut [u8],
+    ) -> io::Result<usize> {
+        let start_read_sector = self.cluster_start_sector(cluster);
+        let cluster_size = self.cluster_size();
+        if !self.options.cluster_checksums {
+            return self.read_sectors(start_read_sector, &mut buf[..cluster_size]);
+        }
+
+        let device_reads = self.device.metrics().reads.count();
+        let read = self.read_sectors(start_read_sector, &mut buf[..cluster_size])?;
+        let refilled = self.device.metrics().reads.count() != device_reads;
+        match self.checksums.get(&cluster).cloned() {
+            Some(expected) if refilled && crc32(&buf[..cluster_size]) != expected => {
+                // Let the next read go back to the device, in case the
+                // corruption was in transfer rather than on the medium.
+                self.evict_cluster(cluster);
+                return Err(Error::BitRotDetected { cluster, file }.into());
+            }
+            Some(_) => {}
+            None => {
+                let checksum = crc32(&buf[..cluster_size]);
+                self.checksums.insert(cluster, checksum);
+            }
+        }
+        Ok(read)
+    }
+
+    /// Reads the whole sectors of the volume beginning at `first_sector` into
+    /// `buf`, whose length is a multiple of the sector size.
+    pub(crate) fn read_sectors(&mut self, first_sector: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let mut bytes_read = 0;
+        for (i, chunk) in buf.chunks_mut(self.bytes_per_sector as usize).enumerate() {
+            bytes_read += self.device.read_sector(first_sector + i as u64, chunk)?;
+        }
+        Ok(bytes_read)
+    }
+
+    /// Writes `buf` into `cluster` beginning `offset` bytes into the cluster.
+    /// Returns the number of bytes written, which is less than `buf.len()` if
+    /// `buf` extends past the end of the cluster.
+    ///
+    /// Sectors entirely covered by the write are replaced in the cache without
+    /// first being read from the disk, so cluster-aligned writes never read
+    /// the old contents. Only partially covered sectors are read, modified,
+    /// and written. Data reaches the disk on `sync()`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading a partially covered sector fails.
+    pub fn write_cluster(
+        &mut self,
+        cluster: Cluster,
+        offset: usize,
+        buf: &[u8],
+    ) -> io::Result<usize> {
+        let sector_size = self.bytes_per_sector as usize;
+        let cluster_size = sector_size * self.sectors_per_cluster as usize;
+        if offset >= cluster_size {
+            return Ok(0);
+        }
+
+        let len = ::std::cmp::min(buf.len(), cluster_size - offset);
+        let first_sector = self.cluster_start_sector(cluster);
+        self.write_sectors(first_sector, offset, &buf[..len])?;
+        Ok(len)
+    }
+
+    /// Writes `buf` into the sectors of the volume beginning `offset` bytes
+    /// into sector `first_sector`, as `write_cluster()` does.
+    pub(crate) fn write_sectors(
+        &mut self,
+        first_sector: u64,
+        offset: usize,
+        buf: &[u8],
+    ) -> io::Result<()> {
+        // The sectors may belong to any file whose data is shared.
+        self.file_data.clear();
+
+        let sector_size = self.bytes_per_sector as usize;
+        let len = buf.len();
+        let mut written = 0;
+        while written < len {
+            let position = offset + written;
+            let sector = first_sector + (position / sector_size) as u64;
+            let sector_offset = position % sector_size;
+            let n = ::std::cmp::min(sector_size - sector_offset, len - written);
+
+            let src = &buf[written..written + n];
+            if n == sector_size {
+                self.overwrite_sector(sector, src)?;
+            } else {
+                self.sector_mut(sector)?[sector_offset..sector_offset + n].copy_from_slice(src);
+            }
+
+            written += n;
+        }
+
+        Ok(())
+    }
+
+    /// Writes all modified sectors back to the underlying device, first
+    /// copying modified FAT sectors to every mirrored FAT. With
+    /// `MountOptions::mark_dirty`, the clean shutdown bit is then restored.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.mirror_fats()?;
+        if let Err(e) = self.device.flush() {
+            self.record_hard_error(&e);
+            return Err(e);
+        }
+
+        if self.marked_dirty {
+            // Every change is on disk, so the volume is as clean as it was
+            // before the first of them.
+            let state = self.fat_state;
+            self.write_fat_state(state)?;
+            self.device.flush()?;
+            self.marked_dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Copies the primary FAT's modified sectors into the other FATs. Does
+    /// nothing when mirroring is disabled.
+    fn mirror_fats(&mut self) -> io::Result<()> {
+        let dirty = ::std::mem::replace(&mut self.dirty_fat_sectors, BTreeSet::new());
+        if self.active_fat.is_some() {
+            return Ok(());
+        }
+
+        for index in dirty {
+            let data = self.device.get(self.fat_sector(0, index))?.to_vec();
+            for copy in 1..self.num_fats {
+                let sector = self.fat_sector(copy, index);
+                self.overwrite_metadata_sector(sector, &data)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The absolute sector holding sector `index` of FAT number `copy`.
+    fn fat_sector(&self, copy: u8, index: u32) -> u64 {
+        self.fat_start_sector + copy as u64 * self.sectors_per_fat as u64 + index as u64
+    }
+
+    /// The FAT that reads are served from and that batched updates modify.
+    fn primary_fat(&self) -> u8 {
+        self.active_fat.unwrap_or(0)
+    }
+
+    /// Sets the FAT entry for `cluster` to encode `status` in every FAT copy,
+    /// or only in the active FAT when mirroring is disabled. The reserved
+    /// high nibble of each entry is preserved.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error kind of `InvalidInput` if `cluster` lies outside of
+    /// the FAT.
+    pub fn set_fat_entry(&mut self, cluster: Cluster, status: Status) -> io::Result<()> {
+        if cluster.0 >= self.num_clusters + 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cluster lies outside of the volume",
+            ));
+        }
+
+        let entries_per_sector = (self.bytes_per_sector as usize / FAT_ENTRY_SIZE) as u32;
+        let index = cluster.0 / entries_per_sector;
+        let idx = ((cluster.0 % entries_per_sector) * FAT_ENTRY_SIZE as u32) as usize;
+        let copies = match self.active_fat {
+            Some(active) => active..active + 1,
+            None => 0..self.num_fats,
+        };
+
+        let primary = self.primary_fat();
+        let mut delta = 0;
+        for copy in copies {
+            let fat_sector = self.fat_sector(copy, index);
+            let sector = self.metadata_sector_mut(fat_sector)?;
+            let old = LittleEndian::read_u32(&sector[idx..idx + 4]);
+            let new = (old & !FAT_ENTRY_MASK) | (status.raw() & FAT_ENTRY_MASK);
+            LittleEndian::write_u32(&mut sector[idx..idx + 4], new);
+            if copy == primary {
+                delta = freed(old, new);
+            }
+        }
+
+        self.count_freed(delta);
+        Ok(())
+    }
+
+    /// Sets the FAT entries for many clusters at once. The low 28 bits of each
+    /// raw value are stored; the reserved high nibble of each entry is
+    /// preserved.
+    ///
+    /// Updates are grouped by FAT sector so that each affected sector is
+    /// fetched and modified once. Only the primary FAT is modified here; the
+    /// mirrors are brought up to date by `sync()`.
+    pub(crate) fn set_fat_entries(&mut self, updates: &mut [(Cluster, u32)]) -> io::Result<()> {
+        let entries_per_sector = (self.bytes_per_sector as usize / FAT_ENTRY_SIZE) as u32;
+        updates.sort_by_key(|&(cluster, _)| cluster);
+
+        let primary = self.primary_fat();
+        let mut delta = 0;
+        let mut i = 0;
+        while i < updates.len() {
+            let index = (updates[i].0).0 / entries_per_sector;
+            let fat_sector = self.fat_sector(primary, index);
+            let sector = self.metadata_sector_mut(fat_sector)?;
+            while i < updates.len() && (updates[i].0).0 / entries_per_sector == index {
+                let (cluster, value) = updates[i];
+                let idx = ((cluster.0 % entries_per_sector) * FAT_ENTRY_SIZE as u32) as usize;
+                let old = LittleEndian::read_u32(&sector[idx..idx + 4]);
+                let new = (old & !FAT_ENTRY_MASK) | (value & FAT_ENTRY_MASK);
+                LittleEndian::write_u32(&mut sector[idx..idx + 4], new);
+                delta += freed(old, new);
+                i += 1;
+            }
+
+            self.dirty_fat_sectors.insert(index);
+        }
+
+        self.count_freed(delta);
+        Ok(())
+    }
+
+    /// Allocates a chain of `count` free clusters and returns its first
+    /// cluster. If `after` is `Some`, the new chain is linked to the end of
+    /// the chain whose last cluster is `after`.
+    ///
+    /// All FAT entries for the allocation are written in a single batch. See
+    /// `set_fat_entries()`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error kind of `InvalidInput` if `count` is zero.
+    ///
+    /// Returns an error kind of `Other` if fewer than `count` clusters are
+    /// free, in which case nothing is allocated.
+    pub fn allocate(&mut self, count: u32, after: Option<Cluster>) -> io::Result<Cluster> {
+        if count == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot allocate an empty chain",
+            ));
+        }
+
+        let first = 2;
+        let end = self.num_clusters + 2;
+        let hint = match self.options.deterministic {
+            Some(_) => first,
+            None => ::std::cmp::max(self.next_free.0, first),
+        };
+
+        let mut clusters = Vec::with_capacity(count as usize);
+        let mut candidate = hint;
+        loop {
+            if self.fat_entry(Cluster(candidate))?.status() == Status::Free {
+                clusters.push(Cluster(candidate));
+                if clusters.len() == count as usize {
+                    break;
+                }
+            }
+
+            candidate = if candidate + 1 == end {
+                first
+            } else {
+                candidate + 1
+            };
+            if candidate == hint {
+                return Err(io::Error::new(io::ErrorKind::Other, "volume is full"));
+            }
+        }
+
+        let mut updates: Vec<(Cluster, u32)> = clusters
+            .windows(2)
+            .map(|pair| (pair[0], (pair[1]).0))
+            .collect();
+        updates.push((clusters[clusters.len() - 1], EOC_MARKER));
+        if let Some(previous) = after {
+            updates.push((previous, clusters[0].0));
+        }
+
+        self.set_fat_entries(&mut updates)?;
+        self.next_free = clusters[clusters.len() - 1];
+        Ok(clusters[0])
+    }
+
+    /// Returns the clusters of the chain beginning at `start`, in order.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidData` if the chain runs into a free,
+    /// reserved, or bad cluster, or if it is longer than the volume has
+    /// clusters, as a chain that loops back on itself is.
+    pub fn chain(&mut self, start: Cluster) -> io::Result<Vec<Cluster>> {
+        let mut clusters = vec![start];
+        while let Some(next) = self.next_in_chain(clusters[clusters.len() - 1])? {
+            if clusters.len() >= self.num_clusters as usize {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "cluster chain loops",
+                ));
+            }
+            clusters.push(next);
+        }
+        Ok(clusters)
+    }
+
+    /// Returns the cluster following `cluster` in its chain, or `None` if
+    /// `cluster` is the last in its chain. Entries are checked according to
+    /// the mount's `FatMode`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidData` if `cluster`'s entry is free,
+    /// reserved, or bad, or, in strict mode, if the entry is nonconforming or
+    /// links outside of the volume.
+    pub(crate) fn next_in_chain(&mut self, cluster: Cluster) -> io::Result<Option<Cluster>> {
+        let entry = self.fat_entry(cluster)?;
+        if self.options.fat_mode == FatMode::Strict && !entry.is_conforming() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "nonconforming FAT entry",
+            ));
+        }
+
+        match entry.status() {
+            Status::Data(next) => {
+                if self.options.fat_mode == FatMode::Strict
+                    && (next.0 < 2 || next.0 >= self.num_clusters + 2)
+                {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "FAT entry links outside of the volume",
+                    ));
+                }
+                Ok(Some(next))
+            }
+            Status::Eoc(_) => Ok(None),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Fat entry is Free/Reserved/Bad",
+            )),
+        }
+    }
+
+    /// Checks that the chain beginning at `start` can hold `len` bytes: that
+    /// its clusters up to the `len`th byte are in the volume and are linked
+    /// by data entries, the last ending the chain or linking onwards.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::CorruptChain` naming the first cluster whose entry is
+    /// free, reserved, bad, ends the chain early, or lies outside of the
+    /// volume.
+    pub(crate) fn validate_chain(&mut self, start: Cluster, len: u32) -> Result<(), Error> {
+        let cluster_size = self.cluster_size() as u64;
+        let needed = (len as u64 + cluster_size - 1) / cluster_size;
+        let corrupt = |cluster, reason| Error::CorruptChain {
+            start,
+            cluster,
+            reason,
+        };
+
+        let mut cluster = start;
+        for i in 0..needed {
+            if cluster.0 < 2 || cluster.0 >= self.num_clusters + 2 {
+                return Err(corrupt(cluster, "cluster lies outside of the volume"));
+            }
+            match self.fat_entry(cluster)?.status() {
+                Status::Data(next) => cluster = next,
+                Status::Eoc(_) if i + 1 == needed => {}
+                Status::Eoc(_) => return Err(corrupt(cluster, "chain ends before the file")),
+                Status::Free => return Err(corrupt(cluster, "cluster is free")),
+                Status::Reserved => return Err(corrupt(cluster, "cluster is reserved")),
+                Status::Bad => return Err(corrupt(cluster, "cluster is bad")),
+            }
+        }
+        Ok(())
+    }
+
+    /// Shortens the chain beginning at `start` to its first `keep` clusters,
+    /// marking the rest free. If `keep` is `0`, the whole chain is freed.
+    pub(crate) fn truncate_chain(&mut self, start: Cluster, keep: usize) -> io::Result<()> {
+        let chain = self.chain(start)?;
+        if keep >= chain.len() {
+            return Ok(());
+        }
+
+        let mut updates: Vec<(Cluster, u32)> = chain[keep..].iter().map(|&c| (c, 0)).collect();
+        if keep > 0 {
+            updates.push((chain[keep - 1], EOC_MARKER));
+        }
+
+        self.set_fat_entries(&mut updates)
+    }
+
+    /// Replaces the contents of the chain beginning at `start` with `data`,
+    /// growing or shrinking the chain to exactly fit `data`. A `start` below
+    /// cluster 2 denotes an empty chain. Returns the first cluster of the new
+    /// chain, or `Cluster(0)` if `data` is empty.
+    pub(crate) fn write_chain(&mut self, start: Cluster, data: &[u8]) -> io::Result<Cluster> {
+        let cluster_size = self.cluster_size();
+        let chain = self.resize_chain(start, data.len() as u64)?;
+        for (cluster, chunk) in chain.iter().zip(data.chunks(cluster_size)) {
+            self.write_cluster(*cluster, 0, chunk)?;
+        }
+
+        Ok(chain.first().cloned().unwrap_or(Cluster(0)))
+    }
+
+    /// Grows or shrinks the chain beginning at `start` to exactly fit `len`
+    /// bytes and returns its clusters, which are empty if `len` is `0`. A
+    /// `start` below cluster 2 denotes an empty chain. The data in the
+    /// chain's clusters is left as it was.
+    pub(crate) fn resize_chain(&mut self, start: Cluster, len: u64) -> io::Result<Vec<Cluster>> {
+        let cluster_size = self.cluster_size() as u64;
+        let needed = ((len + cluster_size - 1) / cluster_size) as usize;
+        let mut chain = match start.0 {
+            0 | 1 => Vec::new(),
+            _ => self.chain(start)?,
+        };
+
+        if needed == 0 {
+            if !chain.is_empty() {
+                self.truncate_chain(chain[0], 0)?;
+            }
+            return Ok(Vec::new());
+        }
+
+        if chain.len() < needed {
+            let first = self.allocate((needed - chain.len()) as u32, chain.last().cloned())?;
+            chain = self.chain(if chain.is_empty() { first } else { chain[0] })?;
+        } else if chain.len() > needed {
+            self.truncate_chain(chain[0], needed)?;
+            chain.truncate(needed);
+        }
+        Ok(chain)
+    }
+
+    /// The bytes a bulk transfer holds in memory at once. See
+    /// `MountOptions::transfer_chunk_size`.
+    pub(crate) fn transfer_chunk(&self) -> usize {
+        let cluster_size = self.cluster_size();
+        match self.options.transfer_chunk_size {
+            Some(bytes) => ::std::cmp::max(bytes / cluster_size, 1) * cluster_size,
+            None => cluster_size,
+        }
+    }
+
+    /// Writes `data` after the first `size` bytes of the chain beginning at
+    /// `start`, allocating clusters onto its end as needed, and returns the
+    /// chain's first cluster, which is newly allocated if `start` is not a
+    /// data cluster. Data reaches the disk on `sync()`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `UnexpectedEof` if the chain is too short to
+    /// hold `size` bytes, and of kind `Other` if the volume is full.
+    pub(crate) fn append_chain(
+        &mut self,
+        start: Cluster,
+        size: u32,
+        data: &[u8],
+    ) -> io::Result<Cluster> {
+        let cluster_size = self.cluster_size();
+        let mut chain = match start.0 {
+            0 | 1 => Vec::new(),
+            _ => self.chain(start)?,
+        };
+        if (chain.len() as u64) * (cluster_size as u64) < size as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "cluster chain is shorter than the file",
+            ));
+        }
+
+        let needed = ((size as u64 + data.len() as u64 + cluster_size as u64 - 1)
+            / cluster_size as u64) as usize;
+        if chain.len() < needed {
+            let first = self.allocate((needed - chain.len()) as u32, chain.last().cloned())?;
+            chain = self.chain(if chain.is_empty() { first } else { chain[0] })?;
+        }
+
+        let mut position = size as usize;
+        let mut written = 0;
+        while written < data.len() {
+            let cluster = chain[position / cluster_size];
+            let n = self.write_cluster(cluster, position % cluster_size, &data[written..])?;
+            position += n;
+            written += n;
+        }
+        Ok(chain[0])
+    }
+
+    /// Sets the first cluster and size recorded in the directory entry at
+    /// `location`.
+    pub(crate) fn set_entry_extent(
+        &mut self,
+        location: EntryLocation,
+        start: Cluster,
+        size: u32,
+    ) -> io::Result<()> {
+        let source = self.dir_source(location.dir_cluster);
+        let offset = location.slot as usize * DIR_ENTRY_SIZE;
+        let mut raw = [0; 2];
+        LittleEndian::write_u16(&mut raw, (start.0 >> 16) as u16);
+        source.write(self, offset + 20, &raw)?;
+
+        let mut raw = [0; 6];
+        LittleEndian::write_u16(&mut raw[..2], start.0 as u16);
+        LittleEndian::write_u32(&mut raw[2..], size);
+        source.write(self, offset + 26, &raw)
+    }
+
+    /// Sets the last modification and last access times recorded in the
+    /// directory entry at `location` to `ts`.
+    pub(crate) fn set_entry_modified(
+        &mut self,
+        location: EntryLocation,
+        ts: Timestamp,
+    ) -> io::Result<()> {
+        let source = self.dir_source(location.dir_cluster);
+        let offset = location.slot as usize * DIR_ENTRY_SIZE;
+        let mut raw = [0; 2];
+        LittleEndian::write_u16(&mut raw, ts.date.raw());
+        source.write(self, offset + 18, &raw)?;
+
+        let mut raw = [0; 4];
+        LittleEndian::write_u16(&mut raw[..2], ts.time.raw());
+        LittleEndian::write_u16(&mut raw[2..], ts.date.raw());
+        source.write(self, offset + 22, &raw)
+    }
+
+    /// Writes the raw entries `slots`, in on-disk order, into the first run
+    /// of free slots in the directory at `dir_cluster` that can hold them,
+    /// growing the directory by zeroed clusters if none can, and returns the
+    /// location of the last of them. Slots of deleted entries are free, as
+    /// is every slot from the end marker on; if the entries take the end
+    /// marker's place, it is written again after them.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error kind of `Other` if the directory cannot grow: it is
+    /// the fixed root directory of FAT12 or FAT16, it would pass
+    /// `MAX_DIR_ENTRIES` slots, or the volume is full.
+    pub(crate) fn insert_entries(
+        &mut self,
+        dir_cluster: Cluster,
+        slots: &[[u8; DIR_ENTRY_SIZE]],
+    ) -> io::Result<EntryLocation> {
+        let source = self.dir_source(dir_cluster);
+        let mut buf = Vec::new();
+        source.read(self, &mut buf)?;
+        let total = buf.len() / DIR_ENTRY_SIZE;
+
+        // The current run of deleted slots, and where it ends if it is long
+        // enough or reaches the end marker.
+        let (mut run_start, mut run_len) = (0, 0);
+        let mut end = total;
+        for (i, slot) in buf.chunks(DIR_ENTRY_SIZE).enumerate() {
+            match slot[0] {
+                END_OF_ENTRIES => {
+                    end = i;
+                    break;
+                }
+                DELETED_ENTRY => {
+                    if run_len == 0 {
+                        run_start = i;
+                    }
+                    run_len += 1;
+                    if run_len == slots.len() {
+                        end = i + 1;
+                        break;
+                    }
+                }
+                _ => run_len = 0,
+            }
+        }
+        let start = if run_len > 0 { run_start } else { end };
+        let past = start + slots.len();
+
+        if past > total {
+            let full = || io::Error::new(io::ErrorKind::Other, "directory is full");
+            let start_cluster = match source {
+                DirSource::Chain(start_cluster) if past <= MAX_DIR_ENTRIES => start_cluster,
+                _ => return Err(full()),
+            };
+            let cluster_size = self.cluster_size();
+            let needed = (past - total) * DIR_ENTRY_SIZE;
+            let count = (needed + cluster_size - 1) / cluster_size;
+            let chain = self.chain(start_cluster)?;
+            let first = self.allocate(count as u32, chain.last().cloned())?;
+            let zeroes = vec![0; cluster_size];
+            for cluster in self.chain(first)? {
+                self.write_cluster(cluster, 0, &zeroes)?;
+            }
+        }
+
+        let mut raw = Vec::with_capacity(slots.len() * DIR_ENTRY_SIZE);
+        for slot in slots {
+            raw.extend_from_slice(slot);
+        }
+        source.write(self, start * DIR_ENTRY_SIZE, &raw)?;
+        // Slots past the end marker may hold stale entries.
+        if past > end && past < total {
+            source.write(self, past * DIR_ENTRY_SIZE, &[END_OF_ENTRIES])?;
+        }
+
+        // The new entry may be the directory's index, or be protected.
+        self.dir_indexes.forget(dir_cluster);
+        self.protected = None;
+        Ok(EntryLocation {
+            dir_cluster,
+            slot: (past - 1) as u32,
+        })
+    }
+
+    /// Stamps the directory at `dir_cluster` as modified if
+    /// `MountOptions::dir_timestamps` calls for it after one of its entries
+    /// was changed, or, if `membership` is `true`, created or removed.
+    pub(crate) fn dir_entries_changed(
+        &mut self,
+        dir_cluster: Cluster,
+        membership: bool,
+    ) -> io::Result<()> {
+        let stamp = match self.options.dir_timestamps {
+            DirTimestamps::Never => false,
+            DirTimestamps::OnCreateDelete => membership,
+            DirTimestamps::Always => true,
+        };
+        if !stamp {
+            return Ok(());
+        }
+
+        let location = match self.dir_entry_location(dir_cluster)? {
+            Some(location) => location,
+            None => return Ok(()),
+        };
+        if self.protected()?.covers(location) {
+            return Ok(());
+        }
+        let ts = match self.options.deterministic {
+            Some(deterministic) => deterministic.timestamp,
+            None => self.timestamp_from(SystemTime::now()),
+        };
+        self.set_entry_modified(location, ts)
+    }
+
+    /// Checks that a file named `name` may be created in `dir`: that the
+    /// volume is writable, `dir`'s entries are not protected, and `name` can
+    /// be stored on FAT. Whether an entry already has the name is not
+    /// checked.
+    pub(crate) fn check_creatable(&mut self, dir: &Dir, name: &str) -> io::Result<()> {
+        self.check_generation(dir.generation)?;
+        self.check_modifiable(None)?;
+        self.check_dir_modifiable(dir.start_cluster)?;
+        validate_long_name(name, self.options.windows_compat)
+    }
+
+    /// Whether `dir_cluster` denotes the root directory. A cluster below 2
+    /// does in the `..` entries of the root's children.
+    fn is_root(&self, dir_cluster: Cluster) -> bool {
+        dir_cluster.0 < 2 || dir_cluster == self.root_dir_cluster
+    }
+
+    /// Returns the location of the entry naming the directory at
+    /// `dir_cluster` in its parent, found through the directory's `..`
+    /// entry, or `None` for the root directory or if there is no such entry.
+    fn dir_entry_location(&mut self, dir_cluster: Cluster) -> io::Result<Option<EntryLocation>> {
+        if self.is_root(dir_cluster) {
+            return Ok(None);
+        }
+
+        let mut parent = None;
+        let mut entries = RawEntries::read(self, dir_cluster)?;
+        while let Some(entry) = entries.next_entry() {
+            if entry.metadata.name == ".." {
+                parent = Some(entry.start_cluster);
+                break;
+            }
+        }
+        let parent = match parent {
+            // A `..` entry records the root directory as cluster 0.
+            Some(parent) if parent.0 < 2 => self.root_dir_cluster,
+            Some(parent) => parent,
+            None => return Ok(None),
+        };
+
+        let mut entries = RawEntries::read(self, parent)?;
+        while let Some(entry) = entries.next_entry() {
+            let name = &entry.metadata.name;
+            if entry.is_dir() && entry.start_cluster == dir_cluster && name != "." && name != ".." {
+                return Ok(Some(entry.location));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the absolute path of the entry at `location`, found by
+    /// climbing the `..` entries of the directories above it, or `None` if a
+    /// directory on the way cannot be placed in its parent.
+    pub(crate) fn entry_path(&mut self, location: EntryLocation) -> io::Result<Option<String>> {
+        let mut names = Vec::new();
+        let mut location = location;
+        loop {
+            let mut name = None;
+            let mut entries = RawEntries::read(self, location.dir_cluster)?;
+            while let Some(entry) = entries.next_entry() {
+                if entry.location == location {
+                    name = Some(entry.metadata.name);
+                    break;
+                }
+            }
+            match name {
+                Some(name) => names.push(name),
+                None => return Ok(None),
+            }
+
+            // Each directory takes a cluster, so a deeper path loops.
+            if names.len() > self.num_clusters as usize {
+                return Ok(None);
+            }
+            location = match self.dir_entry_location(location.dir_cluster)? {
+                Some(parent) => parent,
+                None if self.is_root(location.dir_cluster) => break,
+                None => return Ok(None),
+            };
+        }
+
+        let mut path = String::new();
+        for name in names.iter().rev() {
+            path.push('/');
+            path.push_str(name);
+        }
+        Ok(Some(path))
+    }
+
+    /// The size in bytes of a cluster.
+    pub(crate) fn cluster_size(&self) -> usize {
+        self.bytes_per_sector as usize * self.sectors_per_cluster as usize
+    }
+
+    /// The size in bytes of a logical sector.
+    pub(crate) fn sector_size(&self) -> usize {
+        self.bytes_per_sector as usize
+    }
+
+    /// Where the slots of the directory at `dir_cluster` are stored. A
+    /// `dir_cluster` below 2 denotes the root directory of a volume with a
+    /// fixed root directory region, as in the `..` entries of its children.
+    pub(crate) fn dir_source(&self, dir_cluster: Cluster) -> DirSource {
+        match self.root_dir_region {
+            Some(region) if dir_cluster.0 < 2 || dir_cluster == self.root_dir_cluster => region,
+            _ => DirSource::Chain(dir_cluster),
+        }
+    }
+
+    ///  * A method to read all of the clusters chained from a starting cluster
+    ///    into a vector.
+    ///
+    pub fn read_chain(&mut self, start: Cluster, buf: &mut Vec<u8>) -> io::Result<usize> {
+        self.read_chain_upto(start, ::std::usize::MAX, buf)
+    }
+
+    /// Reads at most `max_bytes` bytes of the cluster chain beginning at
+    /// `start`, appending them to `buf`. The chain is walked only as far as
+    /// needed, and `buf` is not padded to a cluster boundary. Returns the
+    /// number of bytes appended.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidData` if the chain runs into a free,
+    /// reserved, or bad cluster before `max_bytes` bytes are read.
+    pub fn read_chain_upto(
+        &mut self,
+        start: Cluster,
+        max_bytes: usize,
+        buf: &mut Vec<u8>,
+    ) -> io::Result<usize> {
+        self.read_chain_of(start, max_bytes, false, buf)
+    }
+
+    /// Reads the chain as `read_chain_upto()` does. If `of_file` is set, the
+    /// chain holds a file's data, and bit rot found in it is reported against
+    /// the file.
+    fn read_chain_of(
+        &mut self,
+        start: Cluster,
+        max_bytes: usize,
+        of_file: bool,
+        buf: &mut Vec<u8>,
+    ) -> io::Result<usize> {
+        let cluster_size = self.bytes_per_sector as usize * self.sectors_per_cluster as usize;
+        let start_len = buf.len();
+        let mut cluster_cursor = start;
+        let mut bytes_read = 0usize;
+
+        while bytes_read < max_bytes {
+            let next = self.next_in_chain(cluster_cursor)?;
+
+            buf.resize_default(start_len + bytes_read + cluster_size);
+            let file = if of_file {
+                Some((start, bytes_read as u64))
+            } else {
+                None
+            };
+            let chunk = &mut buf[start_len + bytes_read..];
+            bytes_read += self.read_file_cluster(cluster_cursor, file, chunk)?;
+
+            match next {
+                Some(next) => cluster_cursor = next,
+                None => break,
+            }
+        }
+
+        let bytes_read = ::std::cmp::min(bytes_read, max_bytes);
+        buf.truncate(start_len + bytes_read);
+        Ok(bytes_read)
+    }
+
+    /// Returns an iterator over every entry of the primary FAT, from cluster 0
+    /// through the last data cluster of the volume.
+    pub fn fat_entries(&mut self) -> FatEntries {
+        FatEntries::new(self)
+    }
+
+    /// Returns the `FatEntry` for `cluster`, read from the primary FAT.
+    pub fn fat_entry(&mut self, cluster: Cluster) -> io::Result<FatEntry> {
+        let entries_per_sector = (self.bytes_per_sector as usize / FAT_ENTRY_SIZE) as u32;
+        // index of the sector that contains this cluster. e.g. if there are
+        // 10 fat entries per sector and we want sector 12, this should be 1
+        let fat_sector_index = cluster.0 / entries_per_sector;
+        // index of the entry within the given sector, e.g. if we have the
+        // sector with entries 10-20 and we want sectore 12, this should be 2
+        let fat_entry_index = cluster.0 % entries_per_sector;
+
+        let primary = self.primary_fat();
+        let fat_entries = self
+            .device
+            .get(self.fat_sector(primary, fat_sector_index))?;
+
+        let idx = (fat_entry_index * FAT_ENTRY_SIZE as u32) as usize;
+
+        let raw_fat_entry = LittleEndian::read_u32(&fat_entries[idx..idx + 4]);
+        Ok(FatEntry(raw_fat_entry))
+    }
+}
+
+/// A normal component of a path.
+#[cfg(feature = "no_std")]
+pub(crate) type Name<'a> = &'a str;
+#[cfg(not(feature = "no_std"))]
+pub(crate) type Name<'a> = &'a ::std::ffi::OsStr;
+
+/// Returns the names along `path`, applying `.` and `..` components.
+pub(crate) fn normal_components(path: &Path) -> Vec<Name> {
+    let mut names = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => names.push(name),
+            Component::ParentDir => {
+                names.pop();
+            }
+            _ => {}
+        }
+    }
+    names
+}
+
+/// Reports `event` to the watchers of `vfat` whose prefix covers it. The
+/// callbacks run without `vfat` locked; see `WatchCallback`.
+pub(crate) fn notify(vfat: &Shared<VFat>, event: Event) {
+    let mut taken = vfat.borrow_mut().watchers.take_matching(&event);
+    for &mut (_, ref mut callback) in taken.iter_mut() {
+        callback(&event);
+    }
+    vfat.borrow_mut().watchers.restore(taken);
+}
+
+/// Returns the directory in which `VFat::create_file()` would create the
+/// file at `path`, and the file's name, checked as it would check them
+/// except that an entry may already have the name.
+pub(crate) fn new_file_parent<'a>(
+    vfat: &Shared<VFat>,
+    path: &'a Path,
+) -> io::Result<(Dir, &'a str)> {
+    let names = normal_components(path);
+    let (name, parent) = match names.split_last() {
+        Some((&name, parent)) if path.is_absolute() => (name, parent),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "path is not absolute or names no file",
+            ))
+        }
+    };
+    let name = match name_str(name) {
+        Some(name) => name,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "name is not valid Unicode",
+            ))
+        }
+    };
+    let not_a_dir = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "parent is not an existing directory",
+        )
+    };
+    let dir = match resolve(vfat, parent) {
+        Ok(Entry::Dir(dir)) => dir,
+        Ok(Entry::File(_)) => return Err(not_a_dir()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Err(not_a_dir()),
+        Err(e) => return Err(e),
+    };
+    vfat.borrow_mut().check_creatable(&dir, name)?;
+    Ok((dir, name))
+}
+
+/// Creates an empty file named `name` in `dir`, as `VFat::create_file()`
+/// does once it has found the parent directory.
+pub(crate) fn create_in(vfat: &Shared<VFat>, dir: &Dir, name: &str) -> io::Result<File> {
+    dir.compact_if_needed()?;
+    let location = {
+        let mut vfat = vfat.borrow_mut();
+        vfat.check_creatable(dir, name)?;
+        let ucs2: Vec<u16> = name.encode_utf16().collect();
+        if lookup(&mut vfat, dir.start_cluster, &ucs2)?.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "an entry with this name exists",
+            ));
+        }
+
+        let mut used = HashSet::new();
+        let mut entries = RawEntries::read(&mut vfat, dir.start_cluster)?;
+        while let Some(entry) = entries.next_entry() {
+            used.insert(entry.short_name);
+        }
+        let strategy = vfat.options.short_name_strategy;
+        let short = short_name_for(name, strategy, |candidate| used.contains(candidate))?;
+        let now = match vfat.options.deterministic {
+            Some(deterministic) => deterministic.timestamp,
+            None => vfat.timestamp_from(SystemTime::now()),
+        };
+        let slots = DirEntryBuilder::new(name)
+            .short_name(short)
+            .timestamps(now, now.date, now)
+            .build()?;
+
+        let location = vfat.insert_entries(dir.start_cluster, &slots)?;
+        vfat.dir_entries_changed(dir.start_cluster, true)?;
+        location
+    };
+
+    xattr::forget(dir, name)?;
+    notify_at(vfat, location, Event::Create);
+
+    match entry_at(vfat, location, dir.generation)? {
+        Some(Entry::File(file)) => Ok(file),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "created entry cannot be read back",
+        )),
+    }
+}
+
+/// Reports `event` for the file whose entry is at `location`, if anything is
+/// watching. The file's path is recovered from the directories' `..`
+/// entries; no event is reported if it cannot be.
+pub(crate) fn notify_at(vfat: &Shared<VFat>, location: EntryLocation, event: fn(String) -> Event) {
+    let path = {
+        let mut vfat = vfat.borrow_mut();
+        if vfat.watchers.is_empty() {
+            return;
+        }
+        vfat.entry_path(location)
+    };
+    if let Ok(Some(path)) = path {
+        notify(vfat, event(path));
+    }
+}
+
+/// Returns `name` as a string, or `None` if it is not valid Unicode.
+#[cfg(feature = "no_std")]
+fn name_str(name: Name) -> Option<&str> {
+    Some(name)
+}
+#[cfg(not(feature = "no_std"))]
+fn name_str(name: Name) -> Option<&str> {
+    name.to_str()
+}
+
+/// Returns the deepest directory along `names` in the directory cache, and
+/// the number of names it covers. The root directory covers none.
+fn resolve_cached(vfat: &Shared<VFat>, names: &[Name]) -> (usize, Dir) {
+    let generation = vfat.borrow_mut().media_generation();
+    let cached = vfat.borrow_mut().dir_cache.deepest(names, vfat, generation);
+    cached.unwrap_or_else(|| {
+        (
+            0,
+            Dir {
+                start_cluster: vfat.borrow().root_dir_cluster,
+                vfat: vfat.clone(),
+                metadata: Default::default(),
+                location: None,
+                generation,
+            },
+        )
+    })
+}
+
+/// Opens the entry at the path made of `names`, beginning from the deepest
+/// directory along it in the directory cache.
+fn resolve(vfat: &Shared<VFat>, names: &[Name]) -> io::Result<Entry> {
+    resolve_traced(vfat, names, None)
+}
+
+/// Resolves `names` as `resolve()` does, recording each step in `trace` if
+/// given.
+fn resolve_traced(
+    vfat: &Shared<VFat>,
+    names: &[Name],
+    mut trace: Option<&mut OpenTrace>,
+) -> io::Result<Entry> {
+    let (depth, dir) = resolve_cached(vfat, names);
+    let generation = dir.generation;
+    let mut current = Entry::Dir(dir);
+    if let Some(ref mut trace) = trace {
+        trace.cached_components = depth;
+    }
+
+    // Each component is looked up under a single borrow of the volume,
+    // with no handle alive inside the directory being searched.
+    for (i, name) in names.iter().enumerate().skip(depth) {
+        let dir_cluster = match current {
+            Entry::Dir(ref dir) => dir.start_cluster,
+            Entry::File(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "tried to traverse through file.",
+                ));
+            }
+        };
+        let ucs2 = match ucs2_name(name.as_ref()) {
+            Some(name) => name,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "name cannot be represented on FAT",
+                ))
+            }
+        };
+
+        let mut volume = vfat.borrow_mut();
+        volume.check_generation(generation)?;
+        let reads_before = volume.device.metrics().reads.count();
+        let mut stats = LookupStats::default();
+        let found = lookup_counted(&mut volume, dir_cluster, &ucs2, &mut stats);
+        if let Some(ref mut trace) = trace {
+            trace.steps.push(TraceStep {
+                component: String::from_utf16_lossy(&ucs2),
+                dir: dir_cluster.0 as u64,
+                indexed: stats.indexed,
+                entries_parsed: stats.entries_parsed,
+                device_reads: volume.device.metrics().reads.count() - reads_before,
+                found: match found {
+                    Ok(Some(_)) => true,
+                    _ => false,
+                },
+            });
+        }
+        current = match found? {
+            Some(entry) => entry.into_entry(vfat, generation),
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "Entry not found")),
+        };
+        if let Entry::Dir(ref dir) = current {
+            volume.dir_cache.insert(&names[..i + 1], dir);
+        }
+    }
+    Ok(current)
+}
+
+/// Returns a copy of `error`, for reporting one failure for several paths.
+fn copy_error(error: &io::Error) -> io::Error {
+    io::Error::new(error.kind(), error.to_string())
+}
+
+/// Looks up the entries named in `wanted` in the directory at the path made
+/// of `parent`, reading the directory once. For each `(i, name)` in `wanted`,
+/// stores the result in `results[i]`.
+fn stat_group(
+    vfat: &Shared<VFat>,
+    parent: &[Name],
+    wanted: &[(usize, Name)],
+    results: &mut [Option<io::Result<Entry>>],
+) {
+    let mut pending = Vec::new();
+    for &(i, name) in wanted {
+        match ucs2_name(name.as_ref()) {
+            Some(name) => pending.push((i, name)),
+            None => {
+                results[i] = Some(Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "name cannot be represented on FAT",
+                )))
+            }
+        }
+    }
+
+    let read = resolve(vfat, parent).and_then(|entry| {
+        let dir = match entry {
+            Entry::Dir(dir) => dir,
+            Entry::File(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "tried to traverse through file.",
+                ))
+            }
+        };
+        let mut volume = vfat.borrow_mut();
+        volume.check_generation(dir.generation)?;
+        let entries = RawEntries::read(&mut volume, dir.start_cluster)?;
+        Ok((dir.generation, entries))
+    });
+    let (generation, mut entries) = match read {
+        Ok(read) => read,
+        Err(e) => {
+            for (i, _) in pending {
+                results[i] = Some(Err(copy_error(&e)));
+            }
+            return;
+        }
+    };
+
+    while !pending.is_empty() {
+        let entry = match entries.next_entry() {
+            Some(entry) => entry,
+            None => break,
+        };
+        pending.retain(|&(i, ref name)| {
+            if is_named(&entry, name) {
+                results[i] = Some(Ok(entry.clone().into_entry(vfat, generation)));
+                return false;
+            }
+            true
+        });
+    }
+    vfat.borrow_mut().note_corrupt_entries(entries.skipped());
+
+    for (i, _) in pending {
+        results[i] = Some(Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "Entry not found",
+        )));
+    }
+}
+
+impl<'a> FileSystem for &'a Shared<VFat> {
+    type File = File;
+    type Dir = Dir;
+    type Entry = Entry;
+
+    fn open<P: AsRef<Path>>(&self, path: P) -> io::Result<Self::Entry> {
+        resolve(self, &normal_components(path.as_ref()))
+    }
+
+    /// Returns the `FileStat` of the entry at `path`, with its timestamps
+    /// read in the mount's `MountOptions::timezone_offset`, its FAT attribute
+    /// byte, and its first cluster.
+    fn stat<P: AsRef<Path>>(&self, path: P) -> io::Result<FileStat> {
+        let entry = self.open(path)?;
+        let offset = self.borrow().options.timezone_offset;
+        let metadata = entry.metadata();
+        Ok(FileStat {
+            size: match entry {
+                Entry::File(ref file) => file.metadata.size as u64,
+                Entry::Dir(_) => 0,
+            },
+            is_dir: entry.is_dir(),
+            attributes: metadata.attributes.0 as u32,
+            read_only: metadata.read_only(),
+            hidden: metadata.hidden(),
+            created: metadata.created().to_unix_epoch(offset),
+            accessed: metadata.accessed().to_unix_epoch(offset),
+            modified: metadata.modified().to_unix_epoch(offset),
+            start_cluster: entry.id().start_cluster.0 as u64,
+            nlink: 1,
+        })
+    }
+
+    /// Opens the file at `path` as `FileSystem::open_file()` does. If the
+    /// mount's `MountOptions::validate_on_open` is set, the file's chain is
+    /// first checked to hold its data, and `Error::CorruptChain` is returned
+    /// as an error of kind `InvalidData` if it does not.
+    fn open_file<P: AsRef<Path>>(&self, path: P) -> io::Result<Self::File> {
+        let file = match self.open(path)? {
+            Entry::File(file) => file,
+            Entry::Dir(_) => {
+                return Err(io::Error::new(io::ErrorKind::Other, "not a regular file"))
+            }
+        };
+
+        if self.borrow().options.validate_on_open {
+            let len = file.data_len()?;
+            self.borrow_mut().validate_chain(file.start_cluster, len)?;
+        }
+        Ok(file)
+    }
+
+    fn open_traced<P: AsRef<Path>>(&self, path: P) -> (io::Result<Self::Entry>, OpenTrace) {
+        let mut trace = OpenTrace::default();
+        let result = resolve_traced(self, &normal_components(path.as_ref()), Some(&mut trace));
+        (result, trace)
+    }
+
+    /// Groups `paths` by parent directory and reads each parent once for
+    /// all of the paths beneath it. Parents are resolved as by `open()`.
+    fn stat_many<P: AsRef<Path>>(&self, paths: &[P]) -> Vec<io::Result<Self::Entry>> {
+        let mut results: Vec<Option<io::Result<Entry>>> = paths.iter().map(|_| None).collect();
+        let mut groups: HashMap<Vec<Name>, Vec<(usize, Name)>> = HashMap::new();
+        for (i, path) in paths.iter().enumerate() {
+            let names = normal_components(path.as_ref());
+            match names.split_last() {
+                Some((&name, parent)) => groups
+                    .entry(parent.to_vec())
+                    .or_insert_with(Vec::new)
+                    .push((i, name)),
+                None => results[i] = Some(resolve(self, &names)),
+            }
+        }
+
+        for (parent, wanted) in groups {
+            stat_group(self, &parent, &wanted, &mut results);
+        }
+        results.into_iter().map(|result| result.unwrap()).collect()
+    }
+
+    /// Returns the deepest ancestor of `path` in the volume's directory
+    /// cache, which `open()` fills as it resolves paths.
+    fn resolve_prefix<P: AsRef<Path>>(&self, path: P) -> io::Result<(Self::Dir, PathBuf)> {
+        let names = normal_components(path.as_ref());
+        let (depth, dir) = resolve_cached(self, &names);
+
+        let mut rest = PathBuf::new();
+        for name in &names[depth..] {
+            rest.push(name);
+        }
+        Ok((dir, rest))
+    }
+
+    /// Opens the file at `path` as `FileSystem::open_with()` does, creating
+    /// it with `create_file()`. On a read-only volume, options that may
+    /// modify it fail with an error kind of `PermissionDenied` before
+    /// anything is opened.
+    fn open_with<P: AsRef<Path>>(self, path: P, options: &OpenOptions) -> io::Result<Self::File> {
+        options.validate()?;
+        if options.is_mutating() && self.borrow().read_only() {
+            return Err(read_only_file_system());
+        }
+        if options.create_new {
+            return self.create_file(path);
+        }
+
+        let mut file = match self.open_file(path.as_ref()) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound && options.create => {
+                return self.create_file(path);
+            }
+            Err(e) => return Err(e),
+        };
+
+        if options.truncate && file.metadata.size != 0 {
+            file.replace_contents(&[])?;
+        }
+
+        if options.append {
+            io::Seek::seek(&mut file, io::SeekFrom::End(0))?;
+        }
+
+        Ok(file)
+    }
+
+    /// Replaces the contents of the file at `path` with `data` as
+    /// `FileSystem::write_atomic()` does. Instead of a temporary file, `data`
+    /// is written to free clusters, and the file's entry, which lies in a
+    /// single sector, is then pointed at them, each step ending in a sync. A
+    /// power loss leaves at worst clusters allocated to no file, which
+    /// `VFat::repair()` reclaims. A missing file is first created empty with
+    /// `create_file()` and synced, so a power loss may leave it empty.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of `create_file()` if there is no file at `path`,
+    /// and `Error::FileTooLarge` if `data` is larger than
+    /// `MountOptions::max_file_size`. Returns an error kind of
+    /// `PermissionDenied` if the volume is read-only or the file is protected
+    /// by `MountOptions::protected_paths`, and of `Other` if the volume lacks
+    /// the free clusters, beyond `MountOptions::reserved_clusters`, to hold
+    /// `data` alongside the old contents.
+    fn write_atomic<P: AsRef<Path>>(self, path: P, data: &[u8]) -> io::Result<()> {
+        let limit = self.borrow().options.max_file_size;
+        if data.len() as u64 > limit as u64 {
+            return Err(Error::FileTooLarge { limit }.into());
+        }
+        let mut file = match self.open_file(path.as_ref()) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                let file = self.create_file(path)?;
+                self.borrow_mut().sync()?;
+                file
+            }
+            Err(e) => return Err(e),
+        };
+        self.borrow_mut().check_modifiable(file.location)?;
+        replace_files(self, &mut [(&mut file, data)])
+    }
+
+    /// Creates an empty file at `path` as `FileSystem::create_file()` does,
+    /// with its timestamps set to now, or to the fixed timestamp of
+    /// `MountOptions::deterministic` if set. A name that does not fit in 8.3
+    /// gets long file name entries and an alias made by the mount's
+    /// `MountOptions::short_name_strategy`. The entry takes the first free
+    /// slots in its directory that can hold it, and the directory grows if
+    /// none can. The directory is first compacted if it reaches
+    /// `MountOptions::auto_compact_threshold`. As FAT records empty files, the file owns no clusters until
+    /// it is first written. Changes reach the disk on the next
+    /// `VFat::sync()`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error kind of `InvalidInput` if `path` is not absolute, if
+    /// its parent is not an existing directory, or if its name cannot be
+    /// stored on FAT (see `validate_long_name()`, which is applied with
+    /// `MountOptions::windows_compat`). Returns an error kind of
+    /// `AlreadyExists` if an entry is named, or has the 8.3 name of, the new
+    /// name. Returns an error kind of `PermissionDenied` if the volume is
+    /// read-only or the parent's entries are protected by
+    /// `MountOptions::protected_paths`, and of `Other` if the directory
+    /// cannot grow to hold the entry.
+    fn create_file<P: AsRef<Path>>(self, path: P) -> io::Result<Self::File> {
+        let (dir, name) = new_file_parent(self, path.as_ref())?;
+        create_in(self, &dir, name)
     }
 
     fn create_dir<P>(self, _path: P, _parents: bool) -> io::Result<Self::Dir>
     where
         P: AsRef<Path>,
     {
-        unimplemented!("read only file system")
+        Err(unsupported(self))
     }
 
     fn rename<P, Q>(self, _from: P, _to: Q) -> io::Result<()>
@@ -185,10 +2770,10 @@ impl<'a> FileSystem for &'a Shared<VFat> {
         P: AsRef<Path>,
         Q: AsRef<Path>,
     {
-        unimplemented!("read only file system")
+        Err(unsupported(self))
     }
 
     fn remove<P: AsRef<Path>>(self, _path: P, _children: bool) -> io::Result<()> {
-        unimplemented!("read only file system")
+        Err(unsupported(self))
     }
 }
@@ -0,0 +1,187 @@
+//! Depth-first traversal of directory trees.
+
+use std::io;
+
+use traits::Entry as EntryTrait;
+use traits::FileSystem;
+use vfat::{Cluster, Dir, DirIter, Entry, Extent, File, IterOptions, Metadata, Shared, VFat};
+
+/// A file found by `VFat::iter_all_files()`.
+#[derive(Debug, Clone)]
+pub struct FileExtents {
+    /// The file's absolute path, with `/` separators.
+    pub path: String,
+    pub metadata: Metadata,
+    /// The runs of clusters holding the file's data, in file order. Empty for
+    /// empty files.
+    pub extents: Vec<Extent>,
+}
+
+/// A depth-first traversal of the entries below a directory.
+///
+/// Each directory is yielded before its entries, and `prune()` skips the
+/// entries of the directory just yielded. Only the directories along the
+/// path to the current entry are held in memory. An error reading a
+/// directory is yielded in place of its entries, and the traversal
+/// continues.
+pub(crate) struct Walk {
+    /// The directories being listed, deepest last, with their paths and
+    /// first clusters.
+    stack: Vec<(String, Cluster, DirIter)>,
+    /// Whether the last entry yielded was a directory whose listing is on top
+    /// of `stack`.
+    descended: bool,
+}
+
+impl Walk {
+    /// Starts a walk of the entries below `root`, whose path is `path`.
+    /// Entry paths are `path` joined with the names below it by `/`.
+    pub(crate) fn new(root: &Dir, path: String) -> io::Result<Walk> {
+        Ok(Walk {
+            stack: vec![(
+                path,
+                root.start_cluster,
+                root.entries_with(IterOptions::all())?,
+            )],
+            descended: false,
+        })
+    }
+
+    /// Skips the entries of the directory yielded last. Does nothing if the
+    /// last entry yielded was a file.
+    pub(crate) fn prune(&mut self) {
+        if self.descended {
+            self.stack.pop();
+            self.descended = false;
+        }
+    }
+}
+
+impl Iterator for Walk {
+    /// An entry, its path, and its depth below the root, which is `1` for the
+    /// root's own entries.
+    type Item = io::Result<(String, Entry, usize)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.descended = false;
+        loop {
+            let entry = match self.stack.last_mut() {
+                Some(&mut (_, _, ref mut entries)) => entries.next(),
+                None => return None,
+            };
+            let entry = match entry {
+                Some(entry) => entry,
+                None => {
+                    self.stack.pop();
+                    continue;
+                }
+            };
+            if entry.name() == "." || entry.name() == ".." {
+                continue;
+            }
+
+            let depth = self.stack.len();
+            let path = {
+                let parent = &self.stack[depth - 1].0;
+                if parent.is_empty() || parent.ends_with('/') {
+                    format!("{}{}", parent, entry.name())
+                } else {
+                    format!("{}/{}", parent, entry.name())
+                }
+            };
+            if let Entry::Dir(ref dir) = entry {
+                if self.stack.iter().any(|&(_, c, _)| c == dir.start_cluster) {
+                    return Some(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "directory contains one of its ancestors",
+                    )));
+                }
+                match dir.entries_with(IterOptions::all()) {
+                    Ok(entries) => {
+                        self.stack.push((path.clone(), dir.start_cluster, entries));
+                        self.descended = true;
+                    }
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+            return Some(Ok((path, entry, depth)));
+        }
+    }
+}
+
+/// An iterator over every file on a volume, returned by
+/// `VFat::iter_all_files()`.
+///
+/// Directories are listed depth first as the iterator advances, so only the
+/// directories along the path to the current file are held in memory. An
+/// error reading a directory or a file's chain is yielded in place of the
+/// directory's entries or the file, and the traversal continues.
+pub struct AllFiles {
+    walk: Walk,
+}
+
+impl AllFiles {
+    pub(crate) fn new(vfat: &Shared<VFat>) -> io::Result<AllFiles> {
+        let root = vfat.open_dir("/")?;
+        Ok(AllFiles {
+            walk: Walk::new(&root, "/".to_string())?,
+        })
+    }
+}
+
+/// Returns the runs of clusters holding `file`'s data.
+///
+/// # Errors
+///
+/// Returns an error of kind `UnexpectedEof` if the file's chain ends before
+/// its size is covered.
+fn extents(file: &File) -> io::Result<Vec<Extent>> {
+    let mut vfat = file.vfat.borrow_mut();
+    let size = file.data_len()? as u64;
+    let cluster_size = vfat.cluster_size() as u64;
+    let mut remaining = (size + cluster_size - 1) / cluster_size;
+
+    let mut extents: Vec<Extent> = Vec::new();
+    let mut cluster = file.start_cluster;
+    while remaining > 0 {
+        match extents.last_mut() {
+            Some(ref mut extent) if extent.end() == cluster => extent.len += 1,
+            _ => extents.push(Extent::new(cluster, 1)),
+        }
+        remaining -= 1;
+        if remaining == 0 {
+            break;
+        }
+
+        cluster = match vfat.next_in_chain(cluster)? {
+            Some(next) => next,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "cluster chain is shorter than the file",
+                ))
+            }
+        };
+    }
+    Ok(extents)
+}
+
+impl Iterator for AllFiles {
+    type Item = io::Result<FileExtents>;
+
+    fn next(&mut self) -> Option<io::Result<FileExtents>> {
+        loop {
+            let (path, entry, _) = match self.walk.next()? {
+                Ok(found) => found,
+                Err(e) => return Some(Err(e)),
+            };
+            if let Entry::File(file) = entry {
+                return Some(extents(&file).map(|extents| FileExtents {
+                    path,
+                    metadata: file.metadata.clone(),
+                    extents,
+                }));
+            }
+        }
+    }
+}
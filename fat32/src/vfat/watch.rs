@@ -0,0 +1,108 @@
+use std::fmt;
+
+/// A mutation made through a `VFat`, as reported to watchers registered with
+/// `VFat::watch()`. Paths are absolute, with `/` separators.
+///
+/// Files are only created and written here; there is no removal or rename to
+/// report.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Event {
+    /// A file was created.
+    Create(String),
+    /// A file's contents were written, replaced, or truncated.
+    Modify(String),
+}
+
+impl Event {
+    /// Returns `true` if the path named by the event lies at or beneath
+    /// `prefix`. Components are compared case-insensitively.
+    fn is_under(&self, prefix: &str) -> bool {
+        match *self {
+            Event::Create(ref path) | Event::Modify(ref path) => is_under(path, prefix),
+        }
+    }
+}
+
+fn is_under(path: &str, prefix: &str) -> bool {
+    let mut path = path.split('/').filter(|c| !c.is_empty());
+    for component in prefix.split('/').filter(|c| !c.is_empty()) {
+        match path.next() {
+            Some(c) if c.eq_ignore_ascii_case(component) => continue,
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// A callback invoked with every event beneath a watched prefix.
+///
+/// The callback runs after the change is made and without the `VFat` locked,
+/// so it may use the file system, e.g. to read a file that was just changed.
+/// Events caused by the callback's own changes are not reported back to it.
+pub type WatchCallback = Box<FnMut(&Event) + Send>;
+
+/// Identifies a watch registered with `VFat::watch()`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct WatchId(usize);
+
+/// The watches registered on a `VFat`. A watch's callback is `None` while it
+/// is running.
+#[derive(Default)]
+pub(crate) struct Watchers {
+    next_id: usize,
+    watches: Vec<(WatchId, String, Option<WatchCallback>)>,
+}
+
+impl Watchers {
+    pub(crate) fn add(&mut self, prefix: &str, callback: WatchCallback) -> WatchId {
+        let id = WatchId(self.next_id);
+        self.next_id += 1;
+        self.watches.push((id, prefix.to_string(), Some(callback)));
+        id
+    }
+
+    pub(crate) fn remove(&mut self, id: WatchId) -> bool {
+        let before = self.watches.len();
+        self.watches.retain(|&(watch, _, _)| watch != id);
+        self.watches.len() != before
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.watches.is_empty()
+    }
+
+    /// Takes out the callbacks of the watches whose prefix covers `event`, in
+    /// registration order, so that they can be called without the volume
+    /// locked. Callbacks already taken out, because they are running, are
+    /// skipped. Each must be handed back with `restore()`.
+    pub(crate) fn take_matching(&mut self, event: &Event) -> Vec<(WatchId, WatchCallback)> {
+        let mut taken = Vec::new();
+        for &mut (id, ref prefix, ref mut callback) in self.watches.iter_mut() {
+            if event.is_under(prefix) {
+                if let Some(callback) = callback.take() {
+                    taken.push((id, callback));
+                }
+            }
+        }
+        taken
+    }
+
+    /// Hands back callbacks taken out by `take_matching()`. Those whose watch
+    /// was removed in the meantime are dropped.
+    pub(crate) fn restore(&mut self, taken: Vec<(WatchId, WatchCallback)>) {
+        for (id, callback) in taken {
+            if let Some(watch) = self.watches.iter_mut().find(|watch| watch.0 == id) {
+                watch.2 = Some(callback);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Watchers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(self.watches.iter().map(|&(_, ref prefix, _)| prefix))
+            .finish()
+    }
+}
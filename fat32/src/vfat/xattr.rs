@@ -0,0 +1,244 @@
+//! Extended attributes stored in a per-directory sidecar file.
+//!
+//! FAT has no native extended attributes. Instead, the attributes of every
+//! entry in a directory are kept in a hidden file named `XATTRS.SYS` in that
+//! directory. The sidecar is a sequence of little-endian records following
+//! the magic `FXA1`:
+//!
+//! ```text
+//! key_len: u16, key: [u8; key_len]        // the entry's name, UTF-8
+//! count: u16                              // number of attributes
+//! count * {
+//!     name_len: u8, name: [u8; name_len], // the attribute's name, UTF-8
+//!     value_len: u16, value: [u8; value_len]
+//! }
+//! ```
+//!
+//! Keys are matched case-insensitively against entries' long names, as
+//! entry names are. The sidecar must already exist in the directory for
+//! attributes to be set.
+//!
+//! This crate keeps the sidecar in step with the changes it makes itself:
+//! `VFat::create_file()`, and everything that creates files through it, drops
+//! any record left under the new name. It has no way to remove or rename an
+//! entry, so those need no upkeep here. Entries removed or renamed by another
+//! FAT implementation leave their records behind; those are pruned the next
+//! time an attribute in the directory is set. An entry reached through its
+//! short alias is not matched to a record kept under its long name.
+
+use std::io;
+
+use byteorder::{ByteOrder, LittleEndian};
+use traits;
+use vfat::dir::parent_dir;
+use vfat::{Dir, Entry, File};
+
+/// The name of the sidecar file in each directory.
+pub const SIDECAR_NAME: &str = "XATTRS.SYS";
+
+const MAGIC: &[u8; 4] = b"FXA1";
+
+/// The attributes of one entry: `(name, value)` pairs.
+type Attrs = Vec<(String, Vec<u8>)>;
+
+fn invalid_data() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed xattr sidecar")
+}
+
+/// A cursor over a sidecar's contents.
+struct Input<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Input<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.pos + len > self.data.len() {
+            return Err(invalid_data());
+        }
+        self.pos += len;
+        Ok(&self.data[self.pos - len..self.pos])
+    }
+
+    fn string(&mut self, len: usize) -> io::Result<String> {
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| invalid_data())
+    }
+}
+
+/// Parses a sidecar's contents into `(key, attributes)` records.
+fn parse(data: &[u8]) -> io::Result<Vec<(String, Attrs)>> {
+    if data.is_empty() {
+        return Ok(Vec::new());
+    }
+    if data.len() < MAGIC.len() || &data[..MAGIC.len()] != MAGIC {
+        return Err(invalid_data());
+    }
+
+    let mut input = Input {
+        data,
+        pos: MAGIC.len(),
+    };
+
+    let mut records = Vec::new();
+    while input.pos < data.len() {
+        let key_len = LittleEndian::read_u16(input.take(2)?) as usize;
+        let key = input.string(key_len)?;
+        let count = LittleEndian::read_u16(input.take(2)?);
+
+        let mut attrs = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let name_len = input.take(1)?[0] as usize;
+            let name = input.string(name_len)?;
+            let value_len = LittleEndian::read_u16(input.take(2)?) as usize;
+            attrs.push((name, input.take(value_len)?.to_vec()));
+        }
+        records.push((key, attrs));
+    }
+
+    Ok(records)
+}
+
+/// Serializes `records` into a sidecar's contents.
+fn serialize(records: &[(String, Attrs)]) -> Vec<u8> {
+    let mut data = MAGIC.to_vec();
+    let mut raw = [0; 2];
+    for &(ref key, ref attrs) in records {
+        LittleEndian::write_u16(&mut raw, key.len() as u16);
+        data.extend_from_slice(&raw);
+        data.extend_from_slice(key.as_bytes());
+        LittleEndian::write_u16(&mut raw, attrs.len() as u16);
+        data.extend_from_slice(&raw);
+        for &(ref name, ref value) in attrs {
+            data.push(name.len() as u8);
+            data.extend_from_slice(name.as_bytes());
+            LittleEndian::write_u16(&mut raw, value.len() as u16);
+            data.extend_from_slice(&raw);
+            data.extend_from_slice(value);
+        }
+    }
+    data
+}
+
+/// Returns the directory containing `entry`.
+fn parent_of(entry: &Entry) -> io::Result<Dir> {
+    let (location, vfat, generation) = match entry {
+        Entry::File(file) => (file.location, &file.vfat, file.generation),
+        Entry::Dir(dir) => (dir.location, &dir.vfat, dir.generation),
+    };
+
+    match location {
+        Some(location) => Ok(parent_dir(vfat, location, generation)),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the root directory has no extended attributes",
+        )),
+    }
+}
+
+/// Reads the sidecar of `dir`, returning `None` if `dir` has no sidecar.
+fn load(dir: &Dir) -> io::Result<Option<(File, Vec<(String, Attrs)>)>> {
+    let mut file = match dir.find(SIDECAR_NAME) {
+        Ok(Entry::File(file)) => file,
+        Ok(Entry::Dir(_)) => return Err(invalid_data()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let mut data = Vec::new();
+    io::Read::read_to_end(&mut file, &mut data)?;
+    let records = parse(&data)?;
+    Ok(Some((file, records)))
+}
+
+/// Drops the record of the entry named `name` from the sidecar of `dir`, if
+/// there is one, so that an entry created under that name does not inherit
+/// the attributes of an earlier entry of the same name.
+pub(crate) fn forget(dir: &Dir, name: &str) -> io::Result<()> {
+    let (mut file, mut records) = match load(dir)? {
+        Some(loaded) => loaded,
+        None => return Ok(()),
+    };
+
+    let before = records.len();
+    records.retain(|&(ref k, _)| !k.eq_ignore_ascii_case(name));
+    if records.len() == before {
+        return Ok(());
+    }
+    file.replace_contents(&serialize(&records))
+}
+
+/// Returns the value of attribute `name` of `entry`, if set.
+pub(crate) fn get(entry: &Entry, name: &str) -> io::Result<Option<Vec<u8>>> {
+    let key = traits::Entry::name(entry);
+    let records = match load(&parent_of(entry)?)? {
+        Some((_, records)) => records,
+        None => return Ok(None),
+    };
+
+    Ok(records
+        .into_iter()
+        .find(|&(ref k, _)| k.eq_ignore_ascii_case(key))
+        .and_then(|(_, attrs)| attrs.into_iter().find(|&(ref n, _)| n == name))
+        .map(|(_, value)| value))
+}
+
+/// Sets attribute `name` of `entry` to `value`, or removes it if `value` is
+/// `None`.
+pub(crate) fn set(entry: &Entry, name: &str, value: Option<&[u8]>) -> io::Result<()> {
+    if name.is_empty() || name.len() > 255 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "xattr names must be 1 to 255 bytes",
+        ));
+    }
+    if value.map_or(false, |v| v.len() > 0xFFFF) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "xattr values must be at most 65535 bytes",
+        ));
+    }
+
+    let key = traits::Entry::name(entry).to_string();
+    let dir = parent_of(entry)?;
+    let (mut file, mut records) = match load(&dir)? {
+        Some(loaded) => loaded,
+        None if value.is_none() => return Ok(()),
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "directory has no xattr sidecar",
+            ))
+        }
+    };
+
+    // Drop the records of entries that no longer exist.
+    let mut live = Vec::new();
+    for entry in traits::Dir::entries(&dir)? {
+        live.push(traits::Entry::name(&entry).to_string());
+    }
+    records.retain(|&(ref k, _)| live.iter().any(|name| name.eq_ignore_ascii_case(k)));
+
+    let index = match records
+        .iter()
+        .position(|&(ref k, _)| k.eq_ignore_ascii_case(&key))
+    {
+        Some(index) => index,
+        None => {
+            records.push((key, Vec::new()));
+            records.len() - 1
+        }
+    };
+
+    {
+        let attrs = &mut records[index].1;
+        attrs.retain(|&(ref n, _)| n != name);
+        if let Some(value) = value {
+            attrs.push((name.to_string(), value.to_vec()));
+        }
+    }
+    if records[index].1.is_empty() {
+        records.remove(index);
+    }
+
+    file.replace_contents(&serialize(&records))
+}
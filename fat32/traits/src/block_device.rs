@@ -0,0 +1,221 @@
+use std::io;
+
+/// Trait implemented by devices that can be read/written in sector
+/// granularities.
+pub trait BlockDevice: Send {
+    /// Sector size in bytes. Must be a multiple of 512 >= 512. Defaults to 512.
+    fn sector_size(&self) -> u64 {
+        512
+    }
+
+    /// The total number of sectors on the device, if known. Defaults to
+    /// `None`.
+    fn num_sectors(&mut self) -> Option<u64> {
+        None
+    }
+
+    /// Returns `true` if sectors can be written. Devices that cannot, such as
+    /// ROMs or write-protected cards, return `false` and fail every
+    /// `write_sector()`; file systems then mount them read-only rather than
+    /// fail on their first write. Defaults to `true`.
+    fn writable(&self) -> bool {
+        true
+    }
+
+    /// Returns `true` if the medium has been removed or replaced since the
+    /// last call, e.g. because an SD card was ejected and reinserted. Callers
+    /// that cache sectors must then drop them, as they may describe another
+    /// medium. Called before every cached access, so it should be cheap.
+    /// Defaults to `false`.
+    fn media_changed(&mut self) -> bool {
+        false
+    }
+
+    /// Read sector number `n` into `buf`.
+    ///
+    /// `self.sector_size()` or `buf.len()` bytes, whichever is less, are read
+    /// into `buf`. The number of bytes read is returned.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if seeking or reading from `self` fails.
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Read sector number `n` into `buf` as `read_sector()` does, verifying
+    /// it against the checksum the transport sent with it, for devices
+    /// reached over unreliable links such as a network or serial line.
+    /// Defaults to `read_sector()`, for devices without checksums.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if the sector does not match
+    /// its checksum, and any error `read_sector()` would.
+    fn read_sector_checked(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_sector(n, buf)
+    }
+
+    /// Append sector number `n` into `vec`.
+    ///
+    /// `self.sector_size()` bytes are appended to `vec`. The number of bytes
+    /// read is returned.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if seeking or reading from `self` fails.
+    fn read_all_sector(&mut self, n: u64, vec: &mut Vec<u8>) -> io::Result<usize> {
+        let sector_size = self.sector_size() as usize;
+
+        let start = vec.len();
+        vec.resize(start + sector_size, 0);
+        match self.read_sector(n, &mut vec[start..]) {
+            Ok(read) => {
+                vec.truncate(start + read);
+                Ok(read)
+            }
+            Err(e) => {
+                vec.truncate(start);
+                Err(e)
+            }
+        }
+    }
+
+    /// Overwrites sector `n` with the contents of `buf`.
+    ///
+    /// `self.sector_size()` or `buf.len()` bytes, whichever is less, are written
+    /// to the sector. The number of byte written is returned.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if seeking or writing to `self` fails. Returns an
+    /// error of `UnexpectedEof` if the length of `buf` is less than
+    /// `self.sector_size()`. Devices that are not `writable()` return an error
+    /// of `PermissionDenied`.
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize>;
+
+    /// Overwrites the consecutive sectors beginning at sector `n` with the
+    /// contents of `buf`, whose length is a multiple of `self.sector_size()`.
+    /// The number of bytes written is returned.
+    ///
+    /// Devices that can write several sectors in one command, such as SD
+    /// cards with multiple block writes, should override this: a run written
+    /// at once wears flash far less than the same sectors written one by one.
+    /// Defaults to calling `write_sector()` for each sector.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if seeking or writing to `self` fails. Sectors before
+    /// the failing one may have been written.
+    fn write_sectors(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        let sector_size = self.sector_size() as usize;
+        let mut written = 0;
+        for (i, chunk) in buf.chunks(sector_size).enumerate() {
+            written += self.write_sector(n + i as u64, chunk)?;
+        }
+        Ok(written)
+    }
+}
+
+impl<'a, T: BlockDevice> BlockDevice for &'a mut T {
+    fn sector_size(&self) -> u64 {
+        (**self).sector_size()
+    }
+
+    fn num_sectors(&mut self) -> Option<u64> {
+        (*self).num_sectors()
+    }
+
+    fn writable(&self) -> bool {
+        (**self).writable()
+    }
+
+    fn media_changed(&mut self) -> bool {
+        (*self).media_changed()
+    }
+
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        (*self).read_sector(n, buf)
+    }
+
+    fn read_sector_checked(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        (*self).read_sector_checked(n, buf)
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        (*self).write_sector(n, buf)
+    }
+
+    fn write_sectors(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        (*self).write_sectors(n, buf)
+    }
+}
+
+macro impl_for_read_write_seek($(<$($gen:tt),*>)* $T:path) {
+    use std::io::{Read, Write, Seek};
+
+    impl $(<$($gen),*>)* BlockDevice for $T {
+        fn num_sectors(&mut self) -> Option<u64> {
+            let sector_size = self.sector_size();
+            let position = self.seek(io::SeekFrom::Current(0)).ok()?;
+            let len = self.seek(io::SeekFrom::End(0)).ok()?;
+            self.seek(io::SeekFrom::Start(position)).ok()?;
+            Some(len / sector_size)
+        }
+
+        fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+            let sector_size = self.sector_size();
+            let to_read = ::std::cmp::min(sector_size as usize, buf.len());
+            self.seek(io::SeekFrom::Start(n * sector_size))?;
+            self.read_exact(&mut buf[..to_read])?;
+            Ok(to_read)
+        }
+
+        fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+            let sector_size = self.sector_size();
+            let to_write = ::std::cmp::min(sector_size as usize, buf.len());
+            self.seek(io::SeekFrom::Start(n * sector_size))?;
+            self.write_all(&buf[..to_write])?;
+            Ok(to_write)
+        }
+
+        fn write_sectors(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+            self.seek(io::SeekFrom::Start(n * self.sector_size()))?;
+            self.write_all(buf)?;
+            Ok(buf.len())
+        }
+    }
+}
+
+/// A read-only device over an image in memory, e.g. one embedded with
+/// `include_bytes!()`.
+impl<'a> BlockDevice for ::std::io::Cursor<&'a [u8]> {
+    fn num_sectors(&mut self) -> Option<u64> {
+        Some(self.get_ref().len() as u64 / self.sector_size())
+    }
+
+    fn writable(&self) -> bool {
+        false
+    }
+
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        use std::io::{Read, Seek};
+
+        let sector_size = self.sector_size();
+        let to_read = ::std::cmp::min(sector_size as usize, buf.len());
+        self.seek(io::SeekFrom::Start(n * sector_size))?;
+        self.read_exact(&mut buf[..to_read])?;
+        Ok(to_read)
+    }
+
+    fn write_sector(&mut self, _n: u64, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "device is read-only",
+        ))
+    }
+}
+
+impl_for_read_write_seek!(<'a> ::std::io::Cursor<&'a mut [u8]>);
+impl_for_read_write_seek!(::std::io::Cursor<Vec<u8>>);
+impl_for_read_write_seek!(::std::io::Cursor<Box<[u8]>>);
+#[cfg(feature = "file_device")]
+impl_for_read_write_seek!(::std::fs::File);
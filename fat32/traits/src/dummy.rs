@@ -1,5 +1,5 @@
 use std::io;
-use traits::{Dir, Entry, File, Metadata, Timestamp};
+use {Dir, Entry, File, Metadata, Timestamp};
 
 /// A type that implements all of the file system traits.
 #[derive(Copy, Clone)]
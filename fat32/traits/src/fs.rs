@@ -0,0 +1,422 @@
+use std::io::{self, SeekFrom};
+
+#[cfg(feature = "no_std")]
+use path::{Component, Path, PathBuf};
+#[cfg(not(feature = "no_std"))]
+use std::path::{Component, Path, PathBuf};
+
+use {FileStat, Metadata, OpenOptions, OpenTrace, Timestamp};
+
+/// Trait implemented by files in the file system.
+pub trait File: io::Read + io::Write + io::Seek + Sized {
+    /// Writes any buffered data to disk.
+    fn sync(&mut self) -> io::Result<()>;
+
+    /// Returns the size of the file in bytes.
+    fn size(&self) -> u64;
+
+    /// Truncates or extends the file to `size` bytes.
+    ///
+    /// # Errors
+    ///
+    /// The default implementation returns an error kind of `Other` for file
+    /// systems that cannot resize files.
+    fn set_len(&mut self, _size: u64) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "resizing files is not supported",
+        ))
+    }
+}
+
+/// Trait implemented by directories in a file system.
+pub trait Dir: Sized {
+    /// The type of entry stored in this directory.
+    type Entry: Entry;
+
+    /// An type that is an iterator over the entries in this directory.
+    type Iter: Iterator<Item = Self::Entry>;
+
+    /// Returns an interator over the entries in this directory.
+    fn entries(&self) -> io::Result<Self::Iter>;
+
+    /// Returns `true` if `entry`, one of the entries of this directory, is
+    /// named `name`. This is the case-folding hook that `find()` compares
+    /// names with. It is given the entry rather than its name so that it can
+    /// compare names in the form the file system stores them.
+    ///
+    /// The default implementation compares `entry.name()` and `name` exactly,
+    /// as a case-sensitive file system does.
+    fn names_match(&self, entry: &Self::Entry, name: &str) -> bool {
+        entry.name() == name
+    }
+
+    /// Finds the entry named `name` in `self` and returns it, comparing names
+    /// with `names_match()`.
+    ///
+    /// The default implementation checks the entries `entries()` yields in
+    /// order and stops at the first match. How much of the directory that
+    /// reads is up to `entries()`: an iterator that reads the whole directory
+    /// when created, as the FAT one does, reads all of it however early the
+    /// match. Implementations may override it with a faster lookup, such as
+    /// an index, but must find the same entry.
+    ///
+    /// # Errors
+    ///
+    /// If no entry in `self` is named `name`, an error kind of `NotFound` is
+    /// returned. Errors from `entries()` are returned as is.
+    fn find<N: AsRef<str>>(&self, name: N) -> io::Result<Self::Entry> {
+        let name = name.as_ref();
+        for entry in self.entries()? {
+            if self.names_match(&entry, name) {
+                return Ok(entry);
+            }
+        }
+
+        Err(io::Error::new(io::ErrorKind::NotFound, "entry not found"))
+    }
+}
+
+/// Trait implemented by directory entries in a file system.
+///
+/// An entry is either a `File` or a `Directory` and is associated with both
+/// `Metadata` and a name.
+pub trait Entry: Sized {
+    type File: File;
+    type Dir: Dir;
+    type Metadata: Metadata;
+
+    /// The name of the file or directory corresponding to this entry.
+    fn name(&self) -> &str;
+
+    /// The metadata associated with the entry.
+    fn metadata(&self) -> &Self::Metadata;
+
+    /// If `self` is a file, returns `Some` of a reference to the file.
+    /// Otherwise returns `None`.
+    fn as_file(&self) -> Option<&Self::File>;
+
+    /// If `self` is a directory, returns `Some` of a reference to the
+    /// directory. Otherwise returns `None`.
+    fn as_dir(&self) -> Option<&Self::Dir>;
+
+    /// If `self` is a file, returns `Some` of the file. Otherwise returns
+    /// `None`.
+    fn into_file(self) -> Option<Self::File>;
+
+    /// If `self` is a directory, returns `Some` of the directory. Otherwise
+    /// returns `None`.
+    fn into_dir(self) -> Option<Self::Dir>;
+
+    /// Returns `true` if this entry is a file or `false` otherwise.
+    fn is_file(&self) -> bool {
+        self.as_file().is_some()
+    }
+
+    /// Returns `true` if this entry is a directory or `false` otherwise.
+    fn is_dir(&self) -> bool {
+        self.as_dir().is_some()
+    }
+}
+
+/// Trait implemented by file systems.
+pub trait FileSystem: Sized {
+    /// The type of files in this file system.
+    type File: File;
+
+    /// The type of directories in this file system.
+    type Dir: Dir<Entry = Self::Entry>;
+
+    /// The type of directory entries in this file system.
+    type Entry: Entry<File = Self::File, Dir = Self::Dir>;
+
+    /// Opens the entry at `path`. `path` must be absolute.
+    ///
+    /// # Errors
+    ///
+    /// If `path` is not absolute, an error kind of `InvalidInput` is returned.
+    ///
+    /// If any component but the last in `path` does not refer to an existing
+    /// directory, an error kind of `InvalidInput` is returned.
+    ///
+    /// If there is no entry at `path`, an error kind of `NotFound` is returned.
+    ///
+    /// All other error values are implementation defined.
+    fn open<P: AsRef<Path>>(&self, path: P) -> io::Result<Self::Entry>;
+
+    /// Opens the entry at `path` as `open()` does, also returning a record
+    /// of how each component of `path` was resolved: which directory was
+    /// searched, how many entries were parsed, and what was found in caches.
+    /// For diagnosing slow or failing opens.
+    ///
+    /// The default implementation opens `path` and returns an empty trace.
+    fn open_traced<P: AsRef<Path>>(&self, path: P) -> (io::Result<Self::Entry>, OpenTrace) {
+        (self.open(path), OpenTrace::default())
+    }
+
+    /// Resolves as much of `path` as is cheaply available, returning the
+    /// deepest known ancestor directory of `path` and the part of `path`
+    /// beneath it, as a relative path. `path` must be absolute; `.` and `..`
+    /// components are applied.
+    ///
+    /// Opening the remaining components from the returned directory yields
+    /// the same entry as `open(path)`, so callers opening many paths under a
+    /// deep common prefix can skip re-resolving it.
+    ///
+    /// The default implementation knows no directory but the root.
+    fn resolve_prefix<P: AsRef<Path>>(&self, path: P) -> io::Result<(Self::Dir, PathBuf)> {
+        let root = self.open_dir("/")?;
+        let mut rest = PathBuf::new();
+        for component in path.as_ref().components() {
+            match component {
+                Component::Normal(name) => rest.push(name),
+                Component::ParentDir => {
+                    rest.pop();
+                }
+                _ => {}
+            }
+        }
+        Ok((root, rest))
+    }
+
+    /// Opens every path in `paths`, returning the results in the same order.
+    /// Each result is as `open()` would return for its path.
+    ///
+    /// Implementations may share work between paths, e.g. reading a
+    /// directory once for all of the paths beneath it. The default
+    /// implementation opens each path in turn.
+    fn stat_many<P: AsRef<Path>>(&self, paths: &[P]) -> Vec<io::Result<Self::Entry>> {
+        paths.iter().map(|path| self.open(path)).collect()
+    }
+
+    /// Returns the `FileStat` of the entry at `path`. `path` must be
+    /// absolute.
+    ///
+    /// The default implementation opens the entry and fills in what the
+    /// traits expose: timestamps are read as UTC, and `attributes` and
+    /// `start_cluster` are `0`.
+    ///
+    /// # Errors
+    ///
+    /// The error conditions are those of `open()`.
+    fn stat<P: AsRef<Path>>(&self, path: P) -> io::Result<FileStat> {
+        let entry = self.open(path)?;
+        let metadata = entry.metadata();
+        Ok(FileStat {
+            size: entry.as_file().map_or(0, |file| file.size()),
+            is_dir: entry.is_dir(),
+            attributes: 0,
+            read_only: metadata.read_only(),
+            hidden: metadata.hidden(),
+            created: metadata.created().to_unix_epoch(0),
+            accessed: metadata.accessed().to_unix_epoch(0),
+            modified: metadata.modified().to_unix_epoch(0),
+            start_cluster: 0,
+            nlink: 1,
+        })
+    }
+
+    /// Opens the file at `path`. `path` must be absolute.
+    ///
+    /// # Errors
+    ///
+    /// In addition to the error conditions for `open()`, this method returns an
+    /// error kind of `Other` if the entry at `path` is not a regular file.
+    fn open_file<P: AsRef<Path>>(&self, path: P) -> io::Result<Self::File> {
+        self.open(path)?
+            .into_file()
+            .ok_or(io::Error::new(io::ErrorKind::Other, "not a regular file"))
+    }
+
+    /// Opens the directory at `path`. `path` must be absolute.
+    ///
+    /// # Errors
+    ///
+    /// In addition to the error conditions for `open()`, this method returns an
+    /// error kind of `Other` if the entry at `path` is not a directory.
+    fn open_dir<P: AsRef<Path>>(&self, path: P) -> io::Result<Self::Dir> {
+        self.open(path)?
+            .into_dir()
+            .ok_or(io::Error::new(io::ErrorKind::Other, "not a directory"))
+    }
+
+    /// Reads the text file at `path` and returns its lines, trimmed of
+    /// surrounding whitespace. As in `config.txt`-style files, blank lines
+    /// and comment lines, whose first non-blank character is `#`, are
+    /// skipped. Lines may end in `\n` or `\r\n`.
+    ///
+    /// # Errors
+    ///
+    /// In addition to the error conditions for `open_file()`, this method
+    /// returns an error kind of `InvalidData` if the file is not UTF-8.
+    fn read_lines<P: AsRef<Path>>(&self, path: P) -> io::Result<Vec<String>> {
+        let reader = io::BufReader::new(self.open_file(path)?);
+        let mut lines = Vec::new();
+        for line in io::BufRead::lines(reader) {
+            let line = line?;
+            let line = line.trim();
+            if !line.is_empty() && !line.starts_with('#') {
+                lines.push(line.to_string());
+            }
+        }
+        Ok(lines)
+    }
+
+    /// Reads the text file at `path` as `read_lines()` does and returns the
+    /// key-value pairs on its lines, in file order. Each line is split at its
+    /// first `delimiter` and both halves are trimmed. Lines without
+    /// `delimiter`, such as `[pi4]` section headers, are skipped. Keys may
+    /// repeat.
+    ///
+    /// # Errors
+    ///
+    /// As `read_lines()`.
+    fn read_key_values<P: AsRef<Path>>(
+        &self,
+        path: P,
+        delimiter: char,
+    ) -> io::Result<Vec<(String, String)>> {
+        Ok(self
+            .read_lines(path)?
+            .iter()
+            .filter_map(|line| {
+                let split = line.find(delimiter)?;
+                let (key, value) = (&line[..split], &line[split + delimiter.len_utf8()..]);
+                Some((key.trim_right().to_string(), value.trim_left().to_string()))
+            })
+            .collect())
+    }
+
+    /// Opens the file at `path` as directed by `options`. `path` must be
+    /// absolute.
+    ///
+    /// This is the canonical entry point for opening files that may be
+    /// modified: `create`, `create_new`, `truncate`, and `append` behave as
+    /// they do for `std::fs::OpenOptions`.
+    ///
+    /// # Errors
+    ///
+    /// If `options` is not a valid combination of flags, an error kind of
+    /// `InvalidInput` is returned.
+    ///
+    /// If `create_new` is set, the error conditions are those of
+    /// `create_file()`. Otherwise they are those of `open_file()`, except that
+    /// a missing entry is created if `create` is set.
+    fn open_with<P: AsRef<Path>>(self, path: P, options: &OpenOptions) -> io::Result<Self::File> {
+        options.validate()?;
+        if options.create_new {
+            return self.create_file(path);
+        }
+
+        let mut file = match self.open_file(path.as_ref()) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound && options.create => {
+                return self.create_file(path);
+            }
+            Err(e) => return Err(e),
+        };
+
+        if options.truncate && file.size() != 0 {
+            file.set_len(0)?;
+        }
+
+        if options.append {
+            io::Seek::seek(&mut file, SeekFrom::End(0))?;
+        }
+
+        Ok(file)
+    }
+
+    /// Replaces the contents of the file at `path` with `data`, creating the
+    /// file if it does not exist, such that a power loss leaves the file with
+    /// either its old contents or `data`, never a mix of the two, and never
+    /// leaves `path` missing if it existed. `path` must be absolute.
+    ///
+    /// There is no default implementation: `rename()` does not replace an
+    /// existing entry, so the usual write, sync, and rename-over sequence
+    /// would have to remove `path` first, and a power loss after the removal
+    /// would lose it. Implementations must switch from the old contents to
+    /// the new in a single step, such as renaming over the target or
+    /// rewriting an entry that lies within one sector.
+    ///
+    /// # Errors
+    ///
+    /// If `path` is not absolute or names no file, an error kind of
+    /// `InvalidInput` is returned. If any component but the last in `path`
+    /// does not refer to an existing directory, an error kind of
+    /// `InvalidInput` is returned.
+    ///
+    /// All other error values are implementation defined. On error, `path`
+    /// is left with its old contents.
+    fn write_atomic<P: AsRef<Path>>(self, path: P, data: &[u8]) -> io::Result<()>;
+
+    /// Creates a new file at `path`, opens it, and returns it.
+    ///
+    /// `path` must be absolute.
+    ///
+    /// # Errors
+    ///
+    /// If `path` is not absolute, an error kind of `InvalidInput` is returned.
+    ///
+    /// If any component but the last in `path` does not refer to an existing
+    /// directory, an error kind of `InvalidInput` is returned.
+    ///
+    /// If an entry at `path` already exists, an error kind of `AlreadyExists`
+    /// is returned.
+    ///
+    /// All other error values are implementation defined.
+    fn create_file<P: AsRef<Path>>(self, path: P) -> io::Result<Self::File>;
+
+    /// Creates a new directory at `path`, opens it, and returns it. If
+    /// `parents` is `true`, also creates all non-existent directories leading
+    /// up to the last component in `path`.
+    ///
+    /// `path` must be absolute.
+    ///
+    /// # Errors
+    ///
+    /// If `path` is not absolute, an error kind of `InvalidInput` is returned.
+    ///
+    /// If any component but the last in `path` does not refer to an existing
+    /// directory, or `parents` is `false` and there is no entry at that
+    /// component, an error kind of `InvalidInput` is returned.
+    ///
+    /// If an entry at `path` already exists, an error kind of `AlreadyExists`
+    /// is returned.
+    ///
+    /// All other error values are implementation defined.
+    fn create_dir<P: AsRef<Path>>(self, path: P, parents: bool) -> io::Result<Self::Dir>;
+
+    /// Renames the entry at path `from` to `to`. But `from` and `to` must be
+    /// absolute.
+    ///
+    /// # Errors
+    ///
+    /// If `from` or `to` are not absolute, an error kind of `InvalidInput` is
+    /// returned.
+    ///
+    /// If an entry at `to` already exists, an error kind of `AlreadyExists` is
+    /// returned.
+    ///
+    /// If there is no entry at `from`, an error kind of `NotFound` is returned.
+    ///
+    /// All other error values are implementation defined.
+    fn rename<P: AsRef<Path>, Q: AsRef<Path>>(self, from: P, to: Q) -> io::Result<()>;
+
+    /// Removes the entry at `path`. If `children` is `true` and `path` is a
+    /// directory, all files in that directory are recursively removed.
+    ///
+    /// `path` must be absolute.
+    ///
+    /// # Errors
+    ///
+    /// If `path` is not absolute, an error kind of `InvalidInput` is returned.
+    ///
+    /// If there is no entry at `path`, an error kind of `NotFound` is returned.
+    ///
+    /// If the entry at `path` is a directory and `children` is `false`, an
+    /// error kind of `Other` is returned.
+    ///
+    /// All other error values are implementation defined.
+    fn remove<P: AsRef<Path>>(self, path: P, children: bool) -> io::Result<()>;
+}
@@ -0,0 +1,33 @@
+//! The traits shared by file system drivers: `BlockDevice` for the storage
+//! beneath a file system, and `FileSystem`, `Dir`, `Entry`, `File`, and
+//! `Metadata` for the file system itself.
+//!
+//! These live apart from any one driver so that several drivers can
+//! implement them, and code written against them can use any of those
+//! drivers. The `fat32` crate re-exports this crate as `fat32::traits`.
+
+#![feature(decl_macro)]
+#![feature(nll)]
+// The only unsafe code is `Path::new()`'s cast, allowed where it is made.
+#![deny(unsafe_code)]
+
+mod block_device;
+mod dummy;
+mod fs;
+mod metadata;
+mod open_options;
+mod stat;
+mod trace;
+
+pub mod path;
+
+#[cfg(test)]
+mod path_tests;
+
+pub use block_device::BlockDevice;
+pub use dummy::Dummy;
+pub use fs::{Dir, Entry, File, FileSystem};
+pub use metadata::{days_from_civil, Metadata, Timestamp};
+pub use open_options::OpenOptions;
+pub use stat::FileStat;
+pub use trace::{OpenTrace, TraceStep};
@@ -0,0 +1,96 @@
+use std::cmp::max;
+use std::time::Duration;
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// Returns the number of days between 1970-01-01 and `year`-`month`-`day` in
+/// the proleptic Gregorian calendar.
+pub fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Trait for a timestamp (year, month, day, hour, minute, second).
+///
+/// Implementations that also implement `Ord` must order timestamps
+/// chronologically: `a < b` whenever `a.to_unix_epoch(0)` is less than
+/// `b.to_unix_epoch(0)`, so that sorting by timestamp sorts by age.
+pub trait Timestamp: Copy + Clone + Sized {
+    /// The calendar year.
+    ///
+    /// The year is not offset. 2009 is 2009.
+    fn year(&self) -> usize;
+
+    /// The calendar month, starting at 1 for January. Always in range [1, 12].
+    ///
+    /// January is 1, Feburary is 2, ..., December is 12.
+    fn month(&self) -> u8;
+
+    /// The calendar day, starting at 1. Always in range [1, 31].
+    fn day(&self) -> u8;
+
+    /// The 24-hour hour. Always in range [0, 24).
+    fn hour(&self) -> u8;
+
+    /// The minute. Always in range [0, 60).
+    fn minute(&self) -> u8;
+
+    /// The second. Always in range [0, 60).
+    fn second(&self) -> u8;
+
+    /// Seconds since the Unix epoch of this timestamp, interpreting it as
+    /// local time `offset` minutes ahead of UTC. Negative for timestamps
+    /// before 1970.
+    ///
+    /// Zeroed month and day fields, as written by some formatters, are treated
+    /// as January and the 1st respectively.
+    fn to_unix_epoch(&self, offset: i16) -> i64 {
+        let days = days_from_civil(
+            self.year() as i64,
+            max(self.month(), 1) as i64,
+            max(self.day(), 1) as i64,
+        );
+        days * SECONDS_PER_DAY
+            + self.hour() as i64 * 3600
+            + self.minute() as i64 * 60
+            + self.second() as i64
+            - offset as i64 * 60
+    }
+
+    /// The time elapsed from `earlier` to this timestamp, or `None` if
+    /// `earlier` is later than this timestamp. Both are read in the same
+    /// zone, so no offset is needed.
+    fn duration_since(&self, earlier: &Self) -> Option<Duration> {
+        let seconds = self.to_unix_epoch(0) - earlier.to_unix_epoch(0);
+        if seconds < 0 {
+            None
+        } else {
+            Some(Duration::from_secs(seconds as u64))
+        }
+    }
+}
+
+/// Trait for directory entry metadata.
+pub trait Metadata: Sized {
+    /// Type corresponding to a point in time.
+    type Timestamp: Timestamp;
+
+    /// Whether the associated entry is read only.
+    fn read_only(&self) -> bool;
+
+    /// Whether the entry should be "hidden" from directory traversals.
+    fn hidden(&self) -> bool;
+
+    /// The timestamp when the entry was created.
+    fn created(&self) -> Self::Timestamp;
+
+    /// The timestamp for the entry's last access.
+    fn accessed(&self) -> Self::Timestamp;
+
+    /// The timestamp for the entry's last modification.
+    fn modified(&self) -> Self::Timestamp;
+}
@@ -0,0 +1,96 @@
+use std::io;
+
+/// Options and flags which configure how a file is opened by
+/// `FileSystem::open_with()`, mirroring `std::fs::OpenOptions`.
+///
+/// All flags default to `false`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct OpenOptions {
+    /// Open the file for reading.
+    pub read: bool,
+    /// Open the file for writing.
+    pub write: bool,
+    /// Open the file for writing, with the cursor positioned at its end.
+    pub append: bool,
+    /// Create the file if it does not exist.
+    pub create: bool,
+    /// Create the file, failing if it already exists.
+    pub create_new: bool,
+    /// Truncate an existing file to zero length.
+    pub truncate: bool,
+}
+
+impl OpenOptions {
+    /// Creates a blank set of options with every flag set to `false`.
+    pub fn new() -> OpenOptions {
+        OpenOptions::default()
+    }
+
+    pub fn read(&mut self, read: bool) -> &mut OpenOptions {
+        self.read = read;
+        self
+    }
+
+    pub fn write(&mut self, write: bool) -> &mut OpenOptions {
+        self.write = write;
+        self
+    }
+
+    pub fn append(&mut self, append: bool) -> &mut OpenOptions {
+        self.append = append;
+        self
+    }
+
+    pub fn create(&mut self, create: bool) -> &mut OpenOptions {
+        self.create = create;
+        self
+    }
+
+    pub fn create_new(&mut self, create_new: bool) -> &mut OpenOptions {
+        self.create_new = create_new;
+        self
+    }
+
+    pub fn truncate(&mut self, truncate: bool) -> &mut OpenOptions {
+        self.truncate = truncate;
+        self
+    }
+
+    /// Returns `true` if these options may modify the file system.
+    pub fn is_mutating(&self) -> bool {
+        self.write || self.append || self.create || self.create_new || self.truncate
+    }
+
+    /// Checks that the combination of flags is meaningful.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error kind of `InvalidInput` if none of `read`, `write`, or
+    /// `append` is set, if `create`, `create_new`, or `truncate` is set
+    /// without `write` or `append`, or if both `append` and `truncate` are set.
+    pub fn validate(&self) -> io::Result<()> {
+        let writable = self.write || self.append;
+        if !self.read && !writable {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no access mode requested",
+            ));
+        }
+
+        if (self.create || self.create_new || self.truncate) && !writable {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "creating or truncating requires write access",
+            ));
+        }
+
+        if self.append && self.truncate {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot both append and truncate",
+            ));
+        }
+
+        Ok(())
+    }
+}
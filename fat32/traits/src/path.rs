@@ -0,0 +1,295 @@
+//! A minimal slash-separated path type.
+//!
+//! `std::path` is unavailable to `no_std` consumers, so when the `no_std`
+//! feature is enabled the file system traits take `fat32_traits::path::Path`
+//! instead.
+//! Paths are UTF-8 and always use `/` as the separator.
+
+use std::borrow::{Borrow, ToOwned};
+use std::fmt;
+use std::ops::Deref;
+
+const SEPARATOR: char = '/';
+
+/// A borrowed, slash-separated path. This is an unsized type, like `str`.
+#[repr(transparent)]
+pub struct Path {
+    inner: str,
+}
+
+/// An owned, mutable path. Derefs to `Path`.
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+pub struct PathBuf {
+    inner: String,
+}
+
+/// A single component of a `Path`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Component<'a> {
+    /// The leading `/` of an absolute path.
+    RootDir,
+    /// A `.` component.
+    CurDir,
+    /// A `..` component.
+    ParentDir,
+    /// Any other component.
+    Normal(&'a str),
+}
+
+impl<'a> Component<'a> {
+    /// Returns the component as a string slice.
+    pub fn as_str(&self) -> &'a str {
+        match *self {
+            Component::RootDir => "/",
+            Component::CurDir => ".",
+            Component::ParentDir => "..",
+            Component::Normal(name) => name,
+        }
+    }
+}
+
+/// An iterator over the components of a `Path`. Empty components (from
+/// repeated or trailing separators) are skipped.
+#[derive(Debug, Clone)]
+pub struct Components<'a> {
+    rest: &'a str,
+    has_root: bool,
+}
+
+impl<'a> Iterator for Components<'a> {
+    type Item = Component<'a>;
+
+    fn next(&mut self) -> Option<Component<'a>> {
+        if self.has_root {
+            self.has_root = false;
+            return Some(Component::RootDir);
+        }
+
+        loop {
+            if self.rest.is_empty() {
+                return None;
+            }
+
+            let (component, rest) = match self.rest.find(SEPARATOR) {
+                Some(i) => (&self.rest[..i], &self.rest[i + 1..]),
+                None => (self.rest, ""),
+            };
+            self.rest = rest;
+
+            match component {
+                "" => continue,
+                "." => return Some(Component::CurDir),
+                ".." => return Some(Component::ParentDir),
+                name => return Some(Component::Normal(name)),
+            }
+        }
+    }
+}
+
+impl Path {
+    /// Wraps a string slice as a `Path`.
+    #[allow(unsafe_code)]
+    pub fn new<S: AsRef<str> + ?Sized>(s: &S) -> &Path {
+        // `Path` is a `repr(transparent)` wrapper of `str`, so a `str` may be
+        // viewed as one, as `std::path::Path::new()` views an `OsStr`.
+        unsafe { &*(s.as_ref() as *const str as *const Path) }
+    }
+
+    /// Returns the path as a string slice.
+    pub fn as_str(&self) -> &str {
+        &self.inner
+    }
+
+    /// Returns `true` if the path starts with `/`.
+    pub fn is_absolute(&self) -> bool {
+        self.inner.starts_with(SEPARATOR)
+    }
+
+    /// Returns an iterator over the components of the path.
+    pub fn components(&self) -> Components {
+        Components {
+            rest: &self.inner,
+            has_root: self.is_absolute(),
+        }
+    }
+
+    /// Returns the final component of the path if it is a normal component.
+    pub fn file_name(&self) -> Option<&str> {
+        match self.components().last() {
+            Some(Component::Normal(name)) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Returns the path without its final component, or `None` if the path
+    /// is the root or empty.
+    pub fn parent(&self) -> Option<&Path> {
+        let trimmed = self.inner.trim_right_matches(SEPARATOR);
+        if trimmed.is_empty() {
+            return None;
+        }
+
+        match trimmed.rfind(SEPARATOR) {
+            Some(0) => Some(Path::new("/")),
+            Some(i) => Some(Path::new(trimmed[..i].trim_right_matches(SEPARATOR))),
+            None => Some(Path::new("")),
+        }
+    }
+
+    /// Returns a new `PathBuf` with `path` appended to `self`. If `path` is
+    /// absolute, it replaces `self`.
+    pub fn join<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        let mut buf = self.to_path_buf();
+        buf.push(path);
+        buf
+    }
+
+    /// Copies the path into a new `PathBuf`.
+    pub fn to_path_buf(&self) -> PathBuf {
+        PathBuf {
+            inner: self.inner.to_string(),
+        }
+    }
+
+    /// Converts a `std` path to a `Path` if it is valid UTF-8.
+    #[cfg(not(feature = "no_std"))]
+    pub fn from_std(path: &::std::path::Path) -> Option<&Path> {
+        path.to_str().map(Path::new)
+    }
+}
+
+impl PathBuf {
+    /// Creates an empty `PathBuf`.
+    pub fn new() -> PathBuf {
+        PathBuf::default()
+    }
+
+    /// Appends `path` to `self`, inserting a separator if needed. If `path`
+    /// is absolute, it replaces `self`.
+    pub fn push<P: AsRef<Path>>(&mut self, path: P) {
+        let path = path.as_ref();
+        if path.is_absolute() {
+            self.inner.clear();
+        } else if !self.inner.is_empty() && !self.inner.ends_with(SEPARATOR) {
+            self.inner.push(SEPARATOR);
+        }
+
+        self.inner.push_str(path.as_str());
+    }
+
+    /// Removes the final component of the path. Returns `false` if there was
+    /// no parent to truncate to.
+    pub fn pop(&mut self) -> bool {
+        let len = match self.parent() {
+            Some(parent) => parent.as_str().len(),
+            None => return false,
+        };
+
+        self.inner.truncate(len);
+        true
+    }
+
+    /// Consumes the `PathBuf`, returning the underlying `String`.
+    pub fn into_string(self) -> String {
+        self.inner
+    }
+}
+
+impl Deref for PathBuf {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        Path::new(&self.inner)
+    }
+}
+
+impl Borrow<Path> for PathBuf {
+    fn borrow(&self) -> &Path {
+        self
+    }
+}
+
+impl ToOwned for Path {
+    type Owned = PathBuf;
+
+    fn to_owned(&self) -> PathBuf {
+        self.to_path_buf()
+    }
+}
+
+impl AsRef<Path> for Path {
+    fn as_ref(&self) -> &Path {
+        self
+    }
+}
+
+impl AsRef<Path> for PathBuf {
+    fn as_ref(&self) -> &Path {
+        self
+    }
+}
+
+impl AsRef<Path> for str {
+    fn as_ref(&self) -> &Path {
+        Path::new(self)
+    }
+}
+
+impl AsRef<Path> for String {
+    fn as_ref(&self) -> &Path {
+        Path::new(self)
+    }
+}
+
+#[cfg(not(feature = "no_std"))]
+impl AsRef<::std::path::Path> for Path {
+    fn as_ref(&self) -> &::std::path::Path {
+        ::std::path::Path::new(&self.inner)
+    }
+}
+
+impl<'a> From<&'a str> for PathBuf {
+    fn from(s: &'a str) -> PathBuf {
+        PathBuf {
+            inner: s.to_string(),
+        }
+    }
+}
+
+impl From<String> for PathBuf {
+    fn from(inner: String) -> PathBuf {
+        PathBuf { inner }
+    }
+}
+
+impl PartialEq for Path {
+    fn eq(&self, other: &Path) -> bool {
+        self.components().eq(other.components())
+    }
+}
+
+impl Eq for Path {}
+
+impl fmt::Debug for Path {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self.inner, f)
+    }
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.inner, f)
+    }
+}
+
+impl fmt::Debug for PathBuf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl fmt::Display for PathBuf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
@@ -0,0 +1,48 @@
+use path::{Component, Path, PathBuf};
+
+#[test]
+fn test_path_components() {
+    let components: Vec<_> = Path::new("/a//b/./../c/").components().collect();
+    assert_eq!(
+        components,
+        vec![
+            Component::RootDir,
+            Component::Normal("a"),
+            Component::Normal("b"),
+            Component::CurDir,
+            Component::ParentDir,
+            Component::Normal("c"),
+        ]
+    );
+
+    let components: Vec<_> = Path::new("a/b").components().collect();
+    assert_eq!(
+        components,
+        vec![Component::Normal("a"), Component::Normal("b")]
+    );
+}
+
+#[test]
+fn test_path_parent_and_file_name() {
+    assert_eq!(Path::new("/a/b/c").parent(), Some(Path::new("/a/b")));
+    assert_eq!(Path::new("/a/").parent(), Some(Path::new("/")));
+    assert_eq!(Path::new("a").parent(), Some(Path::new("")));
+    assert_eq!(Path::new("/").parent(), None);
+
+    assert_eq!(Path::new("/a/b.txt").file_name(), Some("b.txt"));
+    assert_eq!(Path::new("/a/..").file_name(), None);
+}
+
+#[test]
+fn test_path_join_and_pop() {
+    assert_eq!(Path::new("/a").join("b").as_str(), "/a/b");
+    assert_eq!(Path::new("/a/").join("b").as_str(), "/a/b");
+    assert_eq!(Path::new("/a").join("/b").as_str(), "/b");
+
+    let mut buf = PathBuf::from("/a/b");
+    assert!(buf.pop());
+    assert_eq!(buf.as_str(), "/a");
+    assert!(buf.pop());
+    assert_eq!(buf.as_str(), "/");
+    assert!(!buf.pop());
+}
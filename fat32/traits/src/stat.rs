@@ -0,0 +1,26 @@
+/// A plain projection of an entry's metadata, in the spirit of C's
+/// `struct stat`, for code such as a system call layer that wants the same
+/// fields whatever the file system. Returned by `FileSystem::stat()`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FileStat {
+    /// The size of the file in bytes, or `0` for a directory.
+    pub size: u64,
+    pub is_dir: bool,
+    /// The entry's attribute bits as the file system records them, such as
+    /// the attribute byte of a FAT directory entry, or `0` if it has none.
+    pub attributes: u32,
+    pub read_only: bool,
+    pub hidden: bool,
+    /// The creation time, in seconds since the Unix epoch.
+    pub created: i64,
+    /// The last access time, in seconds since the Unix epoch.
+    pub accessed: i64,
+    /// The last modification time, in seconds since the Unix epoch.
+    pub modified: i64,
+    /// The first cluster (or block) of the entry's data, or `0` if it has
+    /// none or the file system does not say.
+    pub start_cluster: u64,
+    /// The number of names the entry has: always `1` on file systems without
+    /// hard links.
+    pub nlink: u32,
+}
@@ -0,0 +1,30 @@
+/// How `FileSystem::open_traced()` resolved a path.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OpenTrace {
+    /// The number of leading components of the path resolved from the file
+    /// system's cache of directories, without reading any directory.
+    pub cached_components: usize,
+    /// The components looked up by reading a directory, in order. The last
+    /// step is the one that failed, if resolution failed in a lookup.
+    pub steps: Vec<TraceStep>,
+}
+
+/// The lookup of one path component, part of an `OpenTrace`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceStep {
+    /// The component looked up.
+    pub component: String,
+    /// The directory searched, as the file system identifies it: for FAT,
+    /// its first cluster.
+    pub dir: u64,
+    /// Whether the entry was found in an index of the directory rather than
+    /// by scanning it.
+    pub indexed: bool,
+    /// The number of directory entries parsed while scanning.
+    pub entries_parsed: u64,
+    /// The number of sectors read from the device during the lookup. Sectors
+    /// already cached are not counted.
+    pub device_reads: u64,
+    /// Whether an entry with the component's name was found.
+    pub found: bool,
+}